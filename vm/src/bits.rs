@@ -128,6 +128,39 @@ macro_rules! alignement_impl {
 
 alignement_impl! { u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize }
 
+/// Trait for little endian reads over raw data
+pub trait LeBytes {
+    /// Read a little endian u16 at `offset`
+    fn u16_at(&self, offset: usize) -> u16;
+
+    /// Read a little endian u32 at `offset`
+    fn u32_at(&self, offset: usize) -> u32;
+
+    /// Read a little endian u64 at `offset`
+    fn u64_at(&self, offset: usize) -> u64;
+}
+
+impl LeBytes for [u8] {
+    #[inline]
+    fn u16_at(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self[offset], self[offset + 1]])
+    }
+
+    #[inline]
+    fn u32_at(&self, offset: usize) -> u32 {
+        let mut raw = [0u8; 4];
+        raw.copy_from_slice(&self[offset..offset + 4]);
+        u32::from_le_bytes(raw)
+    }
+
+    #[inline]
+    fn u64_at(&self, offset: usize) -> u64 {
+        let mut raw = [0u8; 8];
+        raw.copy_from_slice(&self[offset..offset + 8]);
+        u64::from_le_bytes(raw)
+    }
+}
+
 /// Convert a bound to an index
 fn bound_to_index(bound: Bound<&usize>, limit: usize, left: bool) -> usize {
     match left {
@@ -0,0 +1,543 @@
+//! Compact run results exchanged between fuzzing nodes
+
+use crate::bits::LeBytes;
+use crate::vm::{
+    AccessKind, BranchKind, CfiViolationDetail, HeapViolation, PageFaultDetail, VmExit, WatchAccess,
+};
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+/// Magic starting every batch of results ("TFCR")
+const BATCH_MAGIC: u32 = 0x5243_4654;
+/// Size of a batch header (magic, results count, payload size)
+const BATCH_HEADER_SIZE: usize = 12;
+
+/// Error during results (de)serialization
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaseError {
+    /// IO Error
+    IoError(String),
+    /// Parsing error
+    ParsingError(String),
+}
+
+impl From<std::io::Error> for CaseError {
+    fn from(err: std::io::Error) -> Self {
+        CaseError::IoError(err.to_string())
+    }
+}
+
+/// Result type in results (de)serialization
+type Result<T> = std::result::Result<T, CaseError>;
+
+/// Outcome of the execution of a single test case
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaseResult {
+    /// Exit ending the run
+    pub exit: VmExit,
+    /// Addresses covered for the first time by this run
+    pub coverage: Vec<u64>,
+    /// Crash signature (used for deduplication) if the run crashed
+    pub signature: Option<u64>,
+    /// Time spent executing the guest
+    pub exec_time: Duration,
+    /// Time spent resetting the vm
+    pub reset_time: Duration,
+}
+
+/// Append an unsigned LEB128 number
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Cursor over an encoded result
+struct Decoder<'a> {
+    /// Encoded data
+    data: &'a [u8],
+    /// Current offset in the data
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    /// Returns the next `size` bytes
+    fn take(&mut self, size: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.offset < size {
+            return Err(CaseError::ParsingError("Truncated result".to_string()));
+        }
+
+        let bytes = &self.data[self.offset..self.offset + size];
+        self.offset += size;
+        Ok(bytes)
+    }
+
+    /// Reads a byte
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a little endian u64
+    fn u64(&mut self) -> Result<u64> {
+        Ok(self.take(8)?.u64_at(0))
+    }
+
+    /// Reads an unsigned LEB128 number
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(CaseError::ParsingError("Invalid varint".to_string()))
+    }
+}
+
+/// Append the tag and fields of a vm exit
+fn encode_exit(out: &mut Vec<u8>, exit: &VmExit) {
+    let (tag, fields): (u8, Vec<u64>) = match *exit {
+        VmExit::Hlt => (0, vec![]),
+        VmExit::Breakpoint => (1, vec![]),
+        VmExit::Interrupted => (2, vec![]),
+        VmExit::InvalidInstruction => (3, vec![]),
+        VmExit::PageFault(pf) => (4, vec![pf.status as u64, pf.address]),
+        VmExit::Exception(vector) => (5, vec![vector]),
+        VmExit::Syscall => (6, vec![]),
+        VmExit::HookExit => (7, vec![]),
+        VmExit::HookCrash => (8, vec![]),
+        VmExit::RetCorruption { expected, found } => (9, vec![expected, found]),
+        VmExit::CfiViolation(cfi) => {
+            let kind = match cfi.kind {
+                BranchKind::Call => 0,
+                BranchKind::Jump => 1,
+                BranchKind::Unknown => 2,
+            };
+            (10, vec![cfi.site, cfi.target, kind])
+        }
+        VmExit::HeapViolation(violation) => match violation {
+            HeapViolation::OutOfBounds {
+                address,
+                allocation,
+                size,
+                allocation_site,
+            } => (11, vec![address, allocation, size, allocation_site]),
+            HeapViolation::UseAfterFree {
+                address,
+                allocation,
+                size,
+                allocation_site,
+                free_site,
+            } => (
+                12,
+                vec![address, allocation, size, allocation_site, free_site],
+            ),
+            HeapViolation::DoubleFree {
+                allocation,
+                allocation_site,
+                free_site,
+            } => (13, vec![allocation, allocation_site, free_site]),
+            HeapViolation::InvalidFree { address } => (14, vec![address]),
+        },
+        VmExit::Unhandled => (15, vec![]),
+        VmExit::Budget => (16, vec![]),
+        VmExit::Timeout => (17, vec![]),
+        VmExit::MmioFault { address, write } => (18, vec![address, write as u64]),
+        VmExit::Io { port, data, write } => (19, vec![port as u64, data as u64, write as u64]),
+        VmExit::GuestExit(code) => (20, vec![code]),
+        VmExit::RegionTimeout { start, end } => (21, vec![start, end]),
+        VmExit::Watchpoint { address, access } => (22, vec![address, access as u64]),
+        VmExit::MemAccess { addr, kind } => (23, vec![addr, kind as u64]),
+    };
+
+    out.push(tag);
+    for field in fields {
+        out.extend_from_slice(&field.to_le_bytes());
+    }
+}
+
+/// Read a vm exit written by `encode_exit`
+fn decode_exit(decoder: &mut Decoder) -> Result<VmExit> {
+    let exit = match decoder.u8()? {
+        0 => VmExit::Hlt,
+        1 => VmExit::Breakpoint,
+        2 => VmExit::Interrupted,
+        3 => VmExit::InvalidInstruction,
+        4 => VmExit::PageFault(PageFaultDetail {
+            status: decoder.u64()? as u32,
+            address: decoder.u64()?,
+        }),
+        5 => VmExit::Exception(decoder.u64()?),
+        6 => VmExit::Syscall,
+        7 => VmExit::HookExit,
+        8 => VmExit::HookCrash,
+        9 => VmExit::RetCorruption {
+            expected: decoder.u64()?,
+            found: decoder.u64()?,
+        },
+        10 => VmExit::CfiViolation(CfiViolationDetail {
+            site: decoder.u64()?,
+            target: decoder.u64()?,
+            kind: match decoder.u64()? {
+                0 => BranchKind::Call,
+                1 => BranchKind::Jump,
+                2 => BranchKind::Unknown,
+                kind => {
+                    return Err(CaseError::ParsingError(format!(
+                        "Unknown branch kind {}",
+                        kind
+                    )))
+                }
+            },
+        }),
+        11 => VmExit::HeapViolation(HeapViolation::OutOfBounds {
+            address: decoder.u64()?,
+            allocation: decoder.u64()?,
+            size: decoder.u64()?,
+            allocation_site: decoder.u64()?,
+        }),
+        12 => VmExit::HeapViolation(HeapViolation::UseAfterFree {
+            address: decoder.u64()?,
+            allocation: decoder.u64()?,
+            size: decoder.u64()?,
+            allocation_site: decoder.u64()?,
+            free_site: decoder.u64()?,
+        }),
+        13 => VmExit::HeapViolation(HeapViolation::DoubleFree {
+            allocation: decoder.u64()?,
+            allocation_site: decoder.u64()?,
+            free_site: decoder.u64()?,
+        }),
+        14 => VmExit::HeapViolation(HeapViolation::InvalidFree {
+            address: decoder.u64()?,
+        }),
+        15 => VmExit::Unhandled,
+        16 => VmExit::Budget,
+        17 => VmExit::Timeout,
+        18 => VmExit::MmioFault {
+            address: decoder.u64()?,
+            write: decoder.u64()? != 0,
+        },
+        19 => VmExit::Io {
+            port: decoder.u64()? as u16,
+            data: decoder.u64()? as u32,
+            write: decoder.u64()? != 0,
+        },
+        20 => VmExit::GuestExit(decoder.u64()?),
+        21 => VmExit::RegionTimeout {
+            start: decoder.u64()?,
+            end: decoder.u64()?,
+        },
+        22 => VmExit::Watchpoint {
+            address: decoder.u64()?,
+            access: match decoder.u64()? {
+                0 => WatchAccess::Execute,
+                1 => WatchAccess::Write,
+                2 => WatchAccess::ReadWrite,
+                access => {
+                    return Err(CaseError::ParsingError(format!(
+                        "Unknown watchpoint access {}",
+                        access
+                    )))
+                }
+            },
+        },
+        23 => VmExit::MemAccess {
+            addr: decoder.u64()?,
+            kind: match decoder.u64()? {
+                0 => AccessKind::Read,
+                1 => AccessKind::Write,
+                2 => AccessKind::Execute,
+                kind => {
+                    return Err(CaseError::ParsingError(format!(
+                        "Unknown memory access {}",
+                        kind
+                    )))
+                }
+            },
+        },
+        tag => return Err(CaseError::ParsingError(format!("Unknown exit tag {}", tag))),
+    };
+
+    Ok(exit)
+}
+
+impl CaseResult {
+    /// Appends the encoded result to `out`. The coverage is sorted and delta
+    /// encoded, so close addresses take a couple of bytes each.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        encode_exit(out, &self.exit);
+
+        match self.signature {
+            Some(signature) => {
+                out.push(1);
+                out.extend_from_slice(&signature.to_le_bytes());
+            }
+            None => out.push(0),
+        }
+
+        write_varint(out, self.exec_time.as_nanos() as u64);
+        write_varint(out, self.reset_time.as_nanos() as u64);
+
+        let mut coverage = self.coverage.clone();
+        coverage.sort_unstable();
+        coverage.dedup();
+
+        write_varint(out, coverage.len() as u64);
+        let mut previous = 0;
+        for address in coverage {
+            write_varint(out, address - previous);
+            previous = address;
+        }
+    }
+
+    /// Decodes a result from the start of `data`. Returns the result and the
+    /// number of bytes consumed.
+    pub fn decode(data: &[u8]) -> Result<(Self, usize)> {
+        let mut decoder = Decoder { data, offset: 0 };
+
+        let exit = decode_exit(&mut decoder)?;
+        let signature = match decoder.u8()? {
+            0 => None,
+            _ => Some(decoder.u64()?),
+        };
+        let exec_time = Duration::from_nanos(decoder.varint()?);
+        let reset_time = Duration::from_nanos(decoder.varint()?);
+
+        let count = decoder.varint()? as usize;
+        if count > data.len() {
+            return Err(CaseError::ParsingError("Invalid coverage size".to_string()));
+        }
+
+        let mut coverage = Vec::with_capacity(count);
+        let mut previous = 0u64;
+        for _ in 0..count {
+            previous = previous.wrapping_add(decoder.varint()?);
+            coverage.push(previous);
+        }
+
+        let result = CaseResult {
+            exit,
+            coverage,
+            signature,
+            exec_time,
+            reset_time,
+        };
+
+        Ok((result, decoder.offset))
+    }
+}
+
+/// Writes results in batches (one header per batch) to a stream
+pub struct CaseResultWriter<W: Write> {
+    /// Destination stream
+    writer: W,
+    /// Results per batch
+    batch_size: usize,
+    /// Number of results pending in `payload`
+    pending: usize,
+    /// Encoded pending results
+    payload: Vec<u8>,
+}
+
+impl<W: Write> CaseResultWriter<W> {
+    /// Creates a new `CaseResultWriter` sending a batch every `batch_size`
+    /// results
+    pub fn new(writer: W, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "Batch size must not be zero");
+
+        CaseResultWriter {
+            writer,
+            batch_size,
+            pending: 0,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Queues a result, writing the batch once full
+    pub fn push(&mut self, result: &CaseResult) -> Result<()> {
+        result.encode(&mut self.payload);
+        self.pending += 1;
+
+        if self.pending >= self.batch_size {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the pending results as a (possibly partial) batch
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+
+        let mut header = [0u8; BATCH_HEADER_SIZE];
+        header[0..4].copy_from_slice(&BATCH_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&(self.pending as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&(self.payload.len() as u32).to_le_bytes());
+
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&self.payload)?;
+        self.writer.flush()?;
+
+        self.pending = 0;
+        self.payload.clear();
+
+        Ok(())
+    }
+
+    /// Flushes the pending results and returns the underlying stream
+    pub fn into_inner(mut self) -> Result<W> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads batches of results written by a `CaseResultWriter`
+pub struct CaseResultReader<R: Read> {
+    /// Source stream
+    reader: R,
+}
+
+impl<R: Read> CaseResultReader<R> {
+    /// Creates a new `CaseResultReader` instance
+    pub fn new(reader: R) -> Self {
+        CaseResultReader { reader }
+    }
+
+    /// Reads the next batch of results. Returns None at the end of the
+    /// stream.
+    pub fn read_batch(&mut self) -> Result<Option<Vec<CaseResult>>> {
+        let mut header = [0u8; BATCH_HEADER_SIZE];
+
+        // A clean end of stream can only happen between batches
+        match self.reader.read(&mut header)? {
+            0 => return Ok(None),
+            n => self.reader.read_exact(&mut header[n..])?,
+        }
+
+        if header.u32_at(0) != BATCH_MAGIC {
+            return Err(CaseError::ParsingError("Invalid batch magic".to_string()));
+        }
+
+        // The sizes come from the stream, the payload is read rather than
+        // allocated upfront and each result takes at least a byte of it
+        let count = header.u32_at(4) as usize;
+        let size = header.u32_at(8) as u64;
+        let mut payload = Vec::new();
+        (&mut self.reader).take(size).read_to_end(&mut payload)?;
+        if payload.len() as u64 != size {
+            return Err(CaseError::ParsingError("Truncated batch".to_string()));
+        }
+        if count > payload.len() {
+            return Err(CaseError::ParsingError("Invalid batch count".to_string()));
+        }
+
+        let mut results = Vec::with_capacity(count);
+        let mut offset = 0;
+        for _ in 0..count {
+            let (result, size) = CaseResult::decode(&payload[offset..])?;
+            results.push(result);
+            offset += size;
+        }
+
+        if offset != payload.len() {
+            return Err(CaseError::ParsingError(
+                "Trailing data in batch".to_string(),
+            ));
+        }
+
+        Ok(Some(results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CaseResult, CaseResultReader, CaseResultWriter, Result};
+    use crate::vm::{BranchKind, CfiViolationDetail, HeapViolation, PageFaultDetail, VmExit};
+
+    use std::time::Duration;
+
+    #[test]
+    /// Streams results through batches
+    fn test_case_result_batches() -> Result<()> {
+        let results = [
+            CaseResult {
+                exit: VmExit::Hlt,
+                coverage: vec![0x401000, 0x401020, 0x7fff_f7a0_1000],
+                signature: None,
+                exec_time: Duration::from_micros(150),
+                reset_time: Duration::from_micros(12),
+            },
+            CaseResult {
+                exit: VmExit::PageFault(PageFaultDetail {
+                    status: 4,
+                    address: 0xdeadbeef,
+                }),
+                coverage: vec![],
+                signature: Some(0x1337),
+                exec_time: Duration::from_millis(3),
+                reset_time: Duration::from_nanos(900),
+            },
+            CaseResult {
+                exit: VmExit::HeapViolation(HeapViolation::DoubleFree {
+                    allocation: 0x6000_0000_0ff0,
+                    allocation_site: 0x401234,
+                    free_site: 0x401337,
+                }),
+                coverage: vec![0x402000],
+                signature: Some(0x42),
+                exec_time: Duration::from_secs(1),
+                reset_time: Duration::from_secs(0),
+            },
+        ];
+
+        let mut writer = CaseResultWriter::new(Vec::new(), 2);
+        for result in results.iter() {
+            writer.push(result)?;
+        }
+        let stream = writer.into_inner()?;
+
+        let mut reader = CaseResultReader::new(&stream[..]);
+        assert_eq!(reader.read_batch()?.as_deref(), Some(&results[..2]));
+        assert_eq!(reader.read_batch()?.as_deref(), Some(&results[2..]));
+        assert_eq!(reader.read_batch()?, None);
+
+        // Truncated streams are rejected
+        let mut reader = CaseResultReader::new(&stream[..stream.len() - 1]);
+        reader.read_batch()?;
+        assert!(reader.read_batch().is_err());
+
+        // As are the counts the payload cannot hold
+        let mut forged = stream.clone();
+        forged[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = CaseResultReader::new(&forged[..]);
+        assert!(reader.read_batch().is_err());
+
+        // And the unknown enumeration values
+        let result = CaseResult {
+            exit: VmExit::CfiViolation(CfiViolationDetail {
+                site: 0x401000,
+                target: 0x402000,
+                kind: BranchKind::Jump,
+            }),
+            ..results[0].clone()
+        };
+        let mut data = Vec::new();
+        result.encode(&mut data);
+        assert_eq!(CaseResult::decode(&data)?, (result, data.len()));
+        data[17] = 3;
+        assert!(CaseResult::decode(&data).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+//! DWARF debug information helpers
+
+use crate::elf;
+
+use gimli::{Dwarf, EndianSlice, LittleEndian, Reader};
+
+/// Source line to address mapping
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineRow {
+    /// Source file path
+    pub file: String,
+    /// Line number in the source file
+    pub line: u32,
+    /// Address of the first instruction of the line (unrelocated)
+    pub address: u64,
+}
+
+/// Returns the full path of the file referenced by a line table row
+fn row_path<R: Reader>(
+    dwarf: &Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    header: &gimli::LineProgramHeader<R>,
+    row: &gimli::LineRow,
+) -> Option<String> {
+    let file = header.file(row.file_index())?;
+    let name = dwarf.attr_string(unit, file.path_name()).ok()?;
+    let name = name.to_string_lossy().ok()?.into_owned();
+
+    // Absolute paths do not need the directory
+    if name.starts_with('/') {
+        return Some(name);
+    }
+
+    let dir = file
+        .directory(header)
+        .and_then(|dir| dwarf.attr_string(unit, dir).ok())
+        .and_then(|dir| dir.to_string_lossy().ok().map(|d| d.into_owned()));
+
+    match dir {
+        Some(dir) if !dir.is_empty() => Some(format!("{}/{}", dir, name)),
+        _ => Some(name),
+    }
+}
+
+/// Returns the statement rows starting a new line from the `.debug_line`
+/// tables of an ELF image.
+pub fn line_rows(data: &[u8]) -> Option<Vec<LineRow>> {
+    // Load the DWARF sections, the missing ones are empty
+    let dwarf = Dwarf::load(|id| -> Result<_, gimli::Error> {
+        let section = elf::section(data, id.name()).unwrap_or(&[]);
+        Ok(EndianSlice::new(section, LittleEndian))
+    })
+    .ok()?;
+
+    let mut rows = Vec::new();
+    let mut units = dwarf.units();
+
+    // Loop through compilation units
+    while let Some(header) = units.next().ok()? {
+        let unit = dwarf.unit(header).ok()?;
+        let program = match unit.line_program.clone() {
+            Some(program) => program,
+            None => continue,
+        };
+
+        let mut program_rows = program.rows();
+        let mut previous: Option<(u64, u64)> = None;
+
+        while let Some((header, row)) = program_rows.next_row().ok()? {
+            if row.end_sequence() {
+                previous = None;
+                continue;
+            }
+
+            let line = match row.line() {
+                Some(line) if row.is_stmt() => line.get(),
+                _ => continue,
+            };
+
+            // Only keep the first row of consecutive rows of the same line
+            let current = (row.file_index(), line);
+            if previous == Some(current) {
+                continue;
+            }
+            previous = Some(current);
+
+            if let Some(file) = row_path(&dwarf, &unit, header, row) {
+                rows.push(LineRow {
+                    file,
+                    line: line as u32,
+                    address: row.address(),
+                });
+            }
+        }
+    }
+
+    Some(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::line_rows;
+
+    #[test]
+    fn test_line_rows() {
+        // The test binary is built with debug information
+        let data = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let rows = line_rows(&data).expect("Could not parse DWARF");
+
+        let line = line!();
+        assert!(rows
+            .iter()
+            .any(|r| r.file.ends_with(file!()) && r.line == line + 1));
+    }
+}
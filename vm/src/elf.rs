@@ -0,0 +1,351 @@
+//! Minimal ELF64 parsing helpers
+
+use crate::bits::LeBytes;
+use crate::memory::PagePermissions;
+
+/// ELF magic
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+/// 64 bits ELF class
+const ELFCLASS64: u8 = 2;
+/// Little endian data encoding
+const ELFDATA2LSB: u8 = 1;
+
+/// Shared object file type
+pub const ET_DYN: u16 = 3;
+/// Core file type
+pub const ET_CORE: u16 = 4;
+/// AMD x86-64 machine
+pub const EM_X86_64: u16 = 62;
+
+/// Loadable segment
+pub const PT_LOAD: u32 = 1;
+/// Note segment
+pub const PT_NOTE: u32 = 4;
+
+/// Symbol table section
+const SHT_SYMTAB: u32 = 2;
+/// Dynamic symbol table section
+const SHT_DYNSYM: u32 = 11;
+
+/// Undefined section index
+const SHN_UNDEF: u16 = 0;
+
+/// Object symbol type
+const STT_OBJECT: u8 = 1;
+/// Function symbol type
+const STT_FUNC: u8 = 2;
+/// Indirect function symbol type
+const STT_GNU_IFUNC: u8 = 10;
+
+/// Segment is executable
+const PF_X: u32 = 1 << 0;
+/// Segment is writable
+const PF_W: u32 = 1 << 1;
+/// Segment is readable
+const PF_R: u32 = 1 << 2;
+
+/// Process status note (`elf_prstatus`)
+pub const NT_PRSTATUS: u32 = 1;
+/// Floating point registers note (`user_fpregs_struct`)
+pub const NT_FPREGSET: u32 = 2;
+/// x86 extended state note (XSAVE area)
+pub const NT_X86_XSTATE: u32 = 0x202;
+/// Mapped files note
+pub const NT_FILE: u32 = 0x4649_4c45;
+
+/// Size of the ELF64 header
+pub const EHDR_SIZE: usize = 64;
+/// Size of an ELF64 program header
+pub const PHDR_SIZE: usize = 56;
+/// Size of an ELF64 section header
+const SHDR_SIZE: usize = 64;
+/// Size of an ELF64 symbol
+const SYM_SIZE: usize = 24;
+
+/// ELF64 file header
+#[derive(Debug, Copy, Clone)]
+pub struct ElfHeader {
+    /// Object file type
+    pub e_type: u16,
+    /// Architecture
+    pub e_machine: u16,
+    /// Program header table offset
+    pub e_phoff: u64,
+    /// Program header table entry size
+    pub e_phentsize: u16,
+    /// Program header table entry count
+    pub e_phnum: u16,
+    /// Section header table offset
+    pub e_shoff: u64,
+    /// Section header table entry size
+    pub e_shentsize: u16,
+    /// Section header table entry count
+    pub e_shnum: u16,
+    /// Index of the section names string table
+    pub e_shstrndx: u16,
+}
+
+impl ElfHeader {
+    /// Parse a little endian ELF64 header
+    pub fn parse(data: &[u8]) -> Option<ElfHeader> {
+        if data.len() < EHDR_SIZE
+            || &data[0..4] != ELF_MAGIC
+            || data[4] != ELFCLASS64
+            || data[5] != ELFDATA2LSB
+        {
+            return None;
+        }
+
+        Some(ElfHeader {
+            e_type: data.u16_at(16),
+            e_machine: data.u16_at(18),
+            e_phoff: data.u64_at(32),
+            e_phentsize: data.u16_at(54),
+            e_phnum: data.u16_at(56),
+            e_shoff: data.u64_at(40),
+            e_shentsize: data.u16_at(58),
+            e_shnum: data.u16_at(60),
+            e_shstrndx: data.u16_at(62),
+        })
+    }
+}
+
+/// ELF64 program header
+#[derive(Debug, Copy, Clone)]
+pub struct ProgramHeader {
+    /// Segment type
+    pub p_type: u32,
+    /// Segment flags
+    pub p_flags: u32,
+    /// Segment file offset
+    pub p_offset: u64,
+    /// Segment virtual address
+    pub p_vaddr: u64,
+    /// Segment size in file
+    pub p_filesz: u64,
+    /// Segment size in memory
+    pub p_memsz: u64,
+}
+
+impl ProgramHeader {
+    /// Parse an ELF64 program header
+    pub fn parse(data: &[u8]) -> Option<ProgramHeader> {
+        if data.len() < PHDR_SIZE {
+            return None;
+        }
+
+        Some(ProgramHeader {
+            p_type: data.u32_at(0),
+            p_flags: data.u32_at(4),
+            p_offset: data.u64_at(8),
+            p_vaddr: data.u64_at(16),
+            p_filesz: data.u64_at(32),
+            p_memsz: data.u64_at(40),
+        })
+    }
+
+    /// Returns the segment permissions
+    pub fn permissions(&self) -> PagePermissions {
+        let mut perms = PagePermissions::new(0);
+
+        perms.set_readable(self.p_flags & PF_R != 0);
+        perms.set_writable(self.p_flags & PF_W != 0);
+        perms.set_executable(self.p_flags & PF_X != 0);
+
+        perms
+    }
+}
+
+/// ELF note entry
+#[derive(Debug, Copy, Clone)]
+pub struct Note<'a> {
+    /// Note type
+    pub n_type: u32,
+    /// Note owner name (without the NUL terminator)
+    pub name: &'a [u8],
+    /// Note descriptor
+    pub desc: &'a [u8],
+}
+
+/// Iterator over the notes of a note segment
+pub struct NoteIterator<'a> {
+    /// Remaining note data
+    data: &'a [u8],
+}
+
+impl<'a> NoteIterator<'a> {
+    /// Create a new `NoteIterator` over raw note segment data
+    pub fn new(data: &'a [u8]) -> Self {
+        NoteIterator { data }
+    }
+}
+
+impl<'a> Iterator for NoteIterator<'a> {
+    type Item = Note<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 12 {
+            return None;
+        }
+
+        let namesz = self.data.u32_at(0) as usize;
+        let descsz = self.data.u32_at(4) as usize;
+        let n_type = self.data.u32_at(8);
+
+        // Name and descriptor are both 4 bytes aligned
+        let name_end = namesz.checked_add(12)?;
+        let desc_start = name_end.checked_add(3)? & !3;
+        let desc_end = desc_start.checked_add(descsz)?;
+        let next = desc_end.checked_add(3)? & !3;
+
+        let name = self.data.get(12..name_end)?;
+        let name = name.strip_suffix(&[0]).unwrap_or(name);
+        let desc = self.data.get(desc_start..desc_end)?;
+
+        self.data = self.data.get(next..).unwrap_or_default();
+
+        Some(Note { n_type, name, desc })
+    }
+}
+
+/// ELF64 section header
+#[derive(Debug, Copy, Clone)]
+struct SectionHeader {
+    /// Offset of the section name in the section names string table
+    sh_name: u32,
+    /// Section type
+    sh_type: u32,
+    /// Section file offset
+    sh_offset: u64,
+    /// Section size in file
+    sh_size: u64,
+    /// Link to another section (string table for symbol tables)
+    sh_link: u32,
+}
+
+impl SectionHeader {
+    /// Parse an ELF64 section header
+    fn parse(data: &[u8]) -> Option<SectionHeader> {
+        if data.len() < SHDR_SIZE {
+            return None;
+        }
+
+        Some(SectionHeader {
+            sh_name: data.u32_at(0),
+            sh_type: data.u32_at(4),
+            sh_offset: data.u64_at(24),
+            sh_size: data.u64_at(32),
+            sh_link: data.u32_at(40),
+        })
+    }
+
+    /// Returns the section content
+    fn data<'a>(&self, elf: &'a [u8]) -> Option<&'a [u8]> {
+        let start = self.sh_offset as usize;
+        let end = start.checked_add(self.sh_size as usize)?;
+        elf.get(start..end)
+    }
+}
+
+/// ELF symbol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    /// Symbol name
+    pub name: String,
+    /// Symbol value (unrelocated address)
+    pub value: u64,
+}
+
+/// Returns the section headers of an ELF image
+fn section_headers(elf: &[u8]) -> Option<Vec<SectionHeader>> {
+    let header = ElfHeader::parse(elf)?;
+
+    Some(
+        (0..header.e_shnum as usize)
+            .filter_map(|i| {
+                let start = i
+                    .checked_mul(header.e_shentsize as usize)?
+                    .checked_add(header.e_shoff as usize)?;
+                SectionHeader::parse(elf.get(start..start.checked_add(SHDR_SIZE)?)?)
+            })
+            .collect(),
+    )
+}
+
+/// Returns the content of a section by name (e.g. `.debug_line`)
+#[cfg_attr(not(feature = "dwarf"), allow(dead_code))]
+pub fn section<'a>(elf: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let header = ElfHeader::parse(elf)?;
+    let sections = section_headers(elf)?;
+    let names = sections.get(header.e_shstrndx as usize)?.data(elf)?;
+
+    sections
+        .iter()
+        .find(|s| {
+            names
+                .get(s.sh_name as usize..)
+                .and_then(|raw| raw.split(|&c| c == 0).next())
+                == Some(name.as_bytes())
+        })?
+        .data(elf)
+}
+
+/// Returns the defined function and object symbols of an ELF image, from
+/// both `.symtab` and `.dynsym`.
+pub fn symbols(elf: &[u8]) -> Option<Vec<Symbol>> {
+    let sections = section_headers(elf)?;
+
+    let mut symbols = Vec::new();
+
+    // Loop through symbol tables
+    for table in sections
+        .iter()
+        .filter(|s| s.sh_type == SHT_SYMTAB || s.sh_type == SHT_DYNSYM)
+    {
+        let data = table.data(elf)?;
+        let strtab = sections.get(table.sh_link as usize)?.data(elf)?;
+
+        for sym in data.chunks_exact(SYM_SIZE) {
+            let sym_type = sym[4] & 0xf;
+            let shndx = sym.u16_at(6);
+            let value = sym.u64_at(8);
+
+            if shndx == SHN_UNDEF
+                || value == 0
+                || !matches!(sym_type, STT_OBJECT | STT_FUNC | STT_GNU_IFUNC)
+            {
+                continue;
+            }
+
+            // Get the NUL terminated name
+            let name_start = sym.u32_at(0) as usize;
+            let name = match strtab.get(name_start..) {
+                Some(raw) => raw.split(|&c| c == 0).next().unwrap_or(&[]),
+                None => continue,
+            };
+
+            symbols.push(Symbol {
+                name: String::from_utf8_lossy(name).into_owned(),
+                value,
+            });
+        }
+    }
+
+    Some(symbols)
+}
+
+/// Returns the lowest virtual address of the loadable segments
+pub fn load_base(elf: &[u8]) -> Option<u64> {
+    let header = ElfHeader::parse(elf)?;
+
+    (0..header.e_phnum as usize)
+        .filter_map(|i| {
+            let start = i
+                .checked_mul(header.e_phentsize as usize)?
+                .checked_add(header.e_phoff as usize)?;
+            ProgramHeader::parse(elf.get(start..start.checked_add(PHDR_SIZE)?)?)
+        })
+        .filter(|p| p.p_type == PT_LOAD)
+        .map(|p| p.p_vaddr & !0xfff)
+        .min()
+}
@@ -0,0 +1,258 @@
+//! LibAFL executor running the cases of a `Harness`
+//!
+//! `VmExecutor` plugs a `Harness` into a LibAFL fuzzer: each input is written
+//! to the guest (see `Harness::on_input` for the custom layouts) and run from
+//! the reset point, the coverage breakpoints reached feed a map observer and
+//! the exits are mapped to the LibAFL objectives (`ExitKind`).
+//!
+//! The coverage breakpoints being removed once reached, the map only records
+//! the first case reaching each of them: a `MaxMapFeedback` on the map finds
+//! the new coverage, but the map holds no hit counts and a case run twice
+//! (e.g. by a calibration stage) does not cover the same entries again.
+
+use super::template::{CaseOutcome, Harness};
+use crate::vm::{VmError, VmExit};
+
+use ::libafl::executors::{Executor, ExitKind, HasObservers};
+use ::libafl::inputs::HasTargetBytes;
+use ::libafl::observers::MapObserver;
+use ::libafl::state::HasExecutions;
+use ::libafl::Error;
+use ::libafl_bolts::tuples::{Handle, MatchNameRef, RefIndexable};
+
+use std::collections::BTreeMap;
+
+/// Returns the LibAFL error of a vm error
+fn vm_error(err: VmError) -> Error {
+    Error::unknown(format!("Vm error: {:?}", err))
+}
+
+/// Returns the LibAFL objective of an exit (see `CaseOutcome::from_exit`)
+pub fn exit_kind(exit: &VmExit) -> ExitKind {
+    match CaseOutcome::from_exit(exit) {
+        CaseOutcome::Ok => ExitKind::Ok,
+        CaseOutcome::Timeout => ExitKind::Timeout,
+        CaseOutcome::Crash => ExitKind::Crash,
+    }
+}
+
+/// LibAFL executor wrapping a `Harness`
+pub struct VmExecutor<M, OT> {
+    /// Harness running the cases
+    harness: Harness,
+    /// Observers of the executions
+    observers: OT,
+    /// Map observer receiving the coverage
+    map: Handle<M>,
+    /// Map entry of each coverage breakpoint reached
+    entries: BTreeMap<u64, usize>,
+}
+
+impl<M, OT> VmExecutor<M, OT>
+where
+    M: MapObserver<Entry = u8>,
+    OT: MatchNameRef,
+{
+    /// Creates an executor running the cases with `harness`, the coverage
+    /// going to the map observer `map` of `observers`
+    pub fn new(harness: Harness, map: Handle<M>, mut observers: OT) -> Result<Self, Error> {
+        match observers.get_mut(&map) {
+            Some(observer) if observer.len() > 0 => {}
+            _ => return Err(Error::key_not_found("Coverage map observer not found")),
+        }
+
+        Ok(VmExecutor {
+            harness,
+            observers,
+            map,
+            entries: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the harness running the cases
+    #[inline]
+    pub fn harness(&self) -> &Harness {
+        &self.harness
+    }
+
+    /// Returns the harness running the cases (mutable)
+    #[inline]
+    pub fn harness_mut(&mut self) -> &mut Harness {
+        &mut self.harness
+    }
+}
+
+impl<M, OT> HasObservers for VmExecutor<M, OT> {
+    type Observers = OT;
+
+    #[inline]
+    fn observers(&self) -> RefIndexable<&Self::Observers, Self::Observers> {
+        RefIndexable::from(&self.observers)
+    }
+
+    #[inline]
+    fn observers_mut(&mut self) -> RefIndexable<&mut Self::Observers, Self::Observers> {
+        RefIndexable::from(&mut self.observers)
+    }
+}
+
+impl<EM, I, M, OT, S, Z> Executor<EM, I, S, Z> for VmExecutor<M, OT>
+where
+    I: HasTargetBytes,
+    M: MapObserver<Entry = u8>,
+    OT: MatchNameRef,
+    S: HasExecutions,
+{
+    fn run_target(
+        &mut self,
+        _fuzzer: &mut Z,
+        state: &mut S,
+        _mgr: &mut EM,
+        input: &I,
+    ) -> Result<ExitKind, Error> {
+        *state.executions_mut() += 1;
+
+        let result = self.harness.run(&input.target_bytes()).map_err(vm_error)?;
+
+        let map = self
+            .observers
+            .get_mut(&self.map)
+            .ok_or_else(|| Error::key_not_found("Coverage map observer not found"))?;
+        for address in result.coverage {
+            let next = self.entries.len() % map.len();
+            let entry = *self.entries.entry(address).or_insert(next);
+            map.set(entry, 1);
+        }
+
+        Ok(exit_kind(&result.exit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exit_kind, vm_error, VmExecutor};
+    use crate::harness::template::{HarnessTemplate, InputLocation};
+    use crate::memory::{PagePermissions, PAGE_SIZE};
+    use crate::vm::{Register, Vm, VmExit};
+
+    use ::libafl::events::NopEventManager;
+    use ::libafl::executors::{Executor, ExitKind, HasObservers};
+    use ::libafl::fuzzer::NopFuzzer;
+    use ::libafl::inputs::BytesInput;
+    use ::libafl::observers::{MapObserver, StdMapObserver};
+    use ::libafl::state::HasExecutions;
+    use ::libafl::Error;
+    use ::libafl_bolts::tuples::{tuple_list, Handled};
+
+    /// Fuzzer state counting the executions
+    #[derive(Default)]
+    struct TestState {
+        executions: u64,
+    }
+
+    impl HasExecutions for TestState {
+        fn executions(&self) -> &u64 {
+            &self.executions
+        }
+
+        fn executions_mut(&mut self) -> &mut u64 {
+            &mut self.executions
+        }
+    }
+
+    #[test]
+    fn test_vm_executor() -> Result<(), Error> {
+        let mut vm = Vm::new(512 * PAGE_SIZE).map_err(vm_error)?;
+
+        let shellcode: &[u8] = &[
+            0x8a, 0x07, // mov al, [rdi]
+            0x3c, 0x41, // cmp al, 'A'
+            0x75, 0x07, // jne end
+            0x88, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, // mov [0], al
+            0xb8, 0x01, 0x00, 0x00, 0x00, // end: mov eax, EXIT
+            0x31, 0xff, // xor edi, edi
+            0xe5, 0x7f, // in eax, HYPERCALL_PORT
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)
+            .map_err(vm_error)?;
+        vm.write(0x1337000, shellcode).map_err(vm_error)?;
+        vm.mmap(
+            0x1338000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )
+        .map_err(vm_error)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rdi, 0x1338000);
+
+        let harness = HarnessTemplate::new(InputLocation::Buffer {
+            address: 0x1338000,
+            size: 1,
+        })
+        .build(&vm)
+        .map_err(vm_error)?;
+
+        let observer = StdMapObserver::owned("coverage", vec![0u8; 16]);
+        let map = observer.handle();
+        let mut executor = VmExecutor::new(harness, map.clone(), tuple_list!(observer))?;
+
+        let mut state = TestState::default();
+        let (mut fuzzer, mut mgr) = (NopFuzzer::new(), NopEventManager::new());
+        for (case, expected) in [(b"B", ExitKind::Ok), (b"A", ExitKind::Crash)] {
+            let input = BytesInput::new(case.to_vec());
+            let exit = executor.run_target(&mut fuzzer, &mut state, &mut mgr, &input)?;
+            assert_eq!(exit, expected);
+        }
+
+        // Each input runs from the reset point, without coverage breakpoints
+        assert_eq!(state.executions, 2);
+        assert_eq!(executor.harness().executions(), 2);
+        assert_eq!(executor.observers()[&map].count_bytes(), 0);
+
+        assert_eq!(exit_kind(&VmExit::Timeout), ExitKind::Timeout);
+
+        // The software breakpoints are not reported by every nested
+        // hypervisor
+        let mut probe = vm.clone();
+        probe.add_breakpoint(0x1337000).map_err(vm_error)?;
+        if probe.run().map_err(vm_error)? != VmExit::Breakpoint {
+            eprintln!("Software breakpoints not reported, skipping the coverage checks");
+            return Ok(());
+        }
+
+        // Coverage breakpoints on the entry, the crashing store and the exit
+        let harness = HarnessTemplate::new(InputLocation::Buffer {
+            address: 0x1338000,
+            size: 1,
+        })
+        .coverage([0x1337000, 0x1337006, 0x133700d])
+        .build(&vm)
+        .map_err(vm_error)?;
+
+        let observer = StdMapObserver::owned("coverage", vec![0u8; 16]);
+        let map = observer.handle();
+        let mut executor = VmExecutor::new(harness, map.clone(), tuple_list!(observer))?;
+
+        // The breakpoints reached for the first time fill the map
+        let cases = [
+            (b"B", ExitKind::Ok, 2),
+            (b"A", ExitKind::Crash, 3),
+            (b"A", ExitKind::Crash, 3),
+        ];
+        for (case, expected, covered) in cases {
+            let input = BytesInput::new(case.to_vec());
+            let exit = executor.run_target(&mut fuzzer, &mut state, &mut mgr, &input)?;
+            assert_eq!(exit, expected);
+            assert_eq!(executor.observers()[&map].count_bytes(), covered);
+        }
+
+        // Each edge gets its own entry
+        assert_eq!(executor.harness().covered().len(), 3);
+        let observers = executor.observers();
+        let entries: Vec<u8> = (0..4).map(|i| observers[&map].get(i)).collect();
+        assert_eq!(entries, [1, 1, 1, 0]);
+
+        Ok(())
+    }
+}
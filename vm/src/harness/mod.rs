@@ -0,0 +1,5 @@
+//! Fuzzing harnesses built on top of the `Vm`
+
+#[cfg(feature = "libafl")]
+pub mod libafl;
+pub mod template;
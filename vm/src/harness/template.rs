@@ -0,0 +1,489 @@
+//! Ready to run fuzzing loop assembled from a snapshot
+//!
+//! `HarnessTemplate` gathers the few parameters a snapshot fuzzer needs (where
+//! the test case goes, where a case ends, the coverage points, the time limit
+//! and where to keep the crashes) and builds a `Harness` running one case per
+//! call:
+//!
+//! 1. the execution vm is reset from the vm captured at the reset point;
+//! 2. the case is written to the input buffer and its length to the length
+//!    register;
+//! 3. the vm runs until an end of case address, a crash or the time limit.
+//!    The coverage breakpoints are removed once reached, each of them being
+//!    reported once by the first case reaching it;
+//! 4. the crashing cases are saved once per signature, along with their
+//!    crash report.
+
+use crate::case::CaseResult;
+use crate::vm::{HookResult, Register, Vm, VmError, VmExit};
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Result type of the harness operations
+type Result<T> = std::result::Result<T, VmError>;
+
+/// Default time limit of a case
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Handler of the syscalls reaching the harness (see `Vm::enable_syscalls`)
+pub type SyscallFn = dyn FnMut(&mut Vm) -> HookResult;
+
+/// Writer of the test cases into the guest (see `Harness::on_input`)
+pub type InputFn = dyn FnMut(&mut Vm, &[u8]) -> std::result::Result<(), VmError>;
+
+/// Location of the test case in the guest
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputLocation {
+    /// Buffer at a fixed address
+    Buffer {
+        /// Address of the buffer
+        address: u64,
+        /// Size of the buffer, the longer cases being truncated
+        size: usize,
+    },
+    /// Buffer whose address is held by a register at the reset point
+    Pointer {
+        /// Register holding the address of the buffer
+        register: Register,
+        /// Size of the buffer, the longer cases being truncated
+        size: usize,
+    },
+}
+
+/// Outcome of a case, deduced from its exit
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CaseOutcome {
+    /// End of case address reached or exit requested by the guest
+    Ok,
+    /// Time limit exceeded
+    Timeout,
+    /// Any other exit
+    Crash,
+}
+
+impl CaseOutcome {
+    /// Classifies the exit of a case
+    pub fn from_exit(exit: &VmExit) -> CaseOutcome {
+        match exit {
+            VmExit::HookExit | VmExit::GuestExit(_) => CaseOutcome::Ok,
+            VmExit::Timeout | VmExit::Interrupted => CaseOutcome::Timeout,
+            _ => CaseOutcome::Crash,
+        }
+    }
+}
+
+/// Parameters of a fuzzing harness
+#[derive(Clone, Debug)]
+pub struct HarnessTemplate {
+    /// Location of the test case
+    input: InputLocation,
+    /// Register receiving the length of the case
+    length_register: Option<Register>,
+    /// Address the snapshot runs to before the vm is captured
+    reset_point: Option<u64>,
+    /// Addresses ending a case
+    end_addresses: Vec<u64>,
+    /// Coverage breakpoints
+    coverage: Vec<u64>,
+    /// Time limit of a case
+    timeout: Duration,
+    /// Directory of the crashing cases
+    crash_dir: Option<PathBuf>,
+}
+
+impl HarnessTemplate {
+    /// Creates a template writing the cases at `input`, with a time limit of
+    /// one second
+    pub fn new(input: InputLocation) -> HarnessTemplate {
+        HarnessTemplate {
+            input,
+            length_register: None,
+            reset_point: None,
+            end_addresses: Vec::new(),
+            coverage: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+            crash_dir: None,
+        }
+    }
+
+    /// Writes the length of each case to a register
+    #[inline]
+    pub fn length_register(&mut self, register: Register) -> &mut Self {
+        self.length_register = Some(register);
+        self
+    }
+
+    /// Runs the snapshot to `address` before capturing the vm the cases are
+    /// reset from, e.g. to skip the setup of the target. The snapshot state
+    /// is used as is otherwise.
+    #[inline]
+    pub fn reset_point(&mut self, address: u64) -> &mut Self {
+        self.reset_point = Some(address);
+        self
+    }
+
+    /// Ends the cases reaching `address` (e.g. the return of the fuzzed
+    /// function or `exit`)
+    #[inline]
+    pub fn end_address(&mut self, address: u64) -> &mut Self {
+        self.end_addresses.push(address);
+        self
+    }
+
+    /// Adds coverage breakpoints (e.g. the basic blocks of the target)
+    #[inline]
+    pub fn coverage<I: IntoIterator<Item = u64>>(&mut self, addresses: I) -> &mut Self {
+        self.coverage.extend(addresses);
+        self
+    }
+
+    /// Sets the time limit of a case
+    #[inline]
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Saves the crashing cases in `directory`, one subdirectory per crash
+    /// signature holding the first case found and a description of its exit
+    #[inline]
+    pub fn crash_dir<P: AsRef<Path>>(&mut self, directory: P) -> &mut Self {
+        self.crash_dir = Some(directory.as_ref().to_path_buf());
+        self
+    }
+
+    /// Builds the harness from a vm in the snapshot state, which is not
+    /// modified
+    pub fn build(&self, vm: &Vm) -> Result<Harness> {
+        let mut reset_vm = vm.clone();
+
+        // Run to the reset point
+        if let Some(address) = self.reset_point {
+            reset_vm.add_breakpoint(address)?;
+            let exit = reset_vm.run_with_timeout(self.timeout)?;
+            reset_vm.remove_breakpoint(address)?;
+
+            if exit != VmExit::Breakpoint || reset_vm.get_reg(Register::Rip) != address {
+                return Err(VmError::InvalidOperation("Reset point not reached"));
+            }
+        }
+
+        let (input_address, input_size) = match self.input {
+            InputLocation::Buffer { address, size } => (address, size),
+            InputLocation::Pointer { register, size } => (reset_vm.get_reg(register), size),
+        };
+
+        // The breakpoints are part of the memory restored by the resets
+        for &address in self.coverage.iter() {
+            reset_vm.add_breakpoint(address)?;
+        }
+        for &address in self.end_addresses.iter() {
+            reset_vm.add_breakpoint(address)?;
+        }
+
+        let mut exec_vm = reset_vm.clone();
+        for &address in self.end_addresses.iter() {
+            exec_vm.hook(address, |_| HookResult::Exit)?;
+        }
+
+        if let Some(directory) = self.crash_dir.as_ref() {
+            fs::create_dir_all(directory)?;
+        }
+
+        Ok(Harness {
+            exec_vm,
+            reset_vm,
+            input_address,
+            input_size,
+            length_register: self.length_register,
+            coverage: self.coverage.iter().copied().collect(),
+            covered: BTreeSet::new(),
+            timeout: self.timeout,
+            crash_dir: self.crash_dir.clone(),
+            syscall_handler: None,
+            input_handler: None,
+            executions: 0,
+            crashes: BTreeSet::new(),
+        })
+    }
+
+    /// Loads a snapshot and builds the harness from it
+    pub fn build_from_snapshot<P: AsRef<Path>>(
+        &self,
+        snapshot_info: P,
+        memory_dump: P,
+        memory_size: usize,
+    ) -> Result<Harness> {
+        let vm = Vm::from_snapshot(snapshot_info, memory_dump, memory_size)?;
+        self.build(&vm)
+    }
+}
+
+/// Fuzzing loop built by `HarnessTemplate::build`
+pub struct Harness {
+    /// Vm running the cases
+    exec_vm: Vm,
+    /// Vm at the reset point
+    reset_vm: Vm,
+    /// Address of the input buffer
+    input_address: u64,
+    /// Size of the input buffer
+    input_size: usize,
+    /// Register receiving the length of the case
+    length_register: Option<Register>,
+    /// Coverage breakpoints not reached yet
+    coverage: BTreeSet<u64>,
+    /// Coverage breakpoints reached
+    covered: BTreeSet<u64>,
+    /// Time limit of a case
+    timeout: Duration,
+    /// Directory of the crashing cases
+    crash_dir: Option<PathBuf>,
+    /// Handler of the syscalls
+    syscall_handler: Option<Box<SyscallFn>>,
+    /// Writer of the cases replacing the input location
+    input_handler: Option<Box<InputFn>>,
+    /// Number of cases run
+    executions: u64,
+    /// Signatures of the crashes found
+    crashes: BTreeSet<u64>,
+}
+
+impl Harness {
+    /// Runs a test case from the reset point
+    pub fn run(&mut self, input: &[u8]) -> Result<CaseResult> {
+        let start = Instant::now();
+        self.exec_vm.reset(&self.reset_vm);
+        let reset_time = start.elapsed();
+
+        // Write the case
+        let input = match self.input_handler.as_mut() {
+            Some(handler) => {
+                handler(&mut self.exec_vm, input)?;
+                input
+            }
+            None => {
+                let input = &input[..input.len().min(self.input_size)];
+                self.exec_vm.write(self.input_address, input)?;
+                if let Some(register) = self.length_register {
+                    self.exec_vm.set_reg(register, input.len() as u64);
+                }
+                input
+            }
+        };
+
+        let start = Instant::now();
+        let mut coverage = Vec::new();
+        let exit = loop {
+            let remaining = match self.timeout.checked_sub(start.elapsed()) {
+                Some(remaining) => remaining,
+                None => break VmExit::Timeout,
+            };
+
+            let exit = self.exec_vm.run_with_timeout(remaining)?;
+            let rip = self.exec_vm.get_reg(Register::Rip);
+            match exit {
+                // New coverage, the breakpoint is removed for the next cases
+                VmExit::Breakpoint if self.coverage.remove(&rip) => {
+                    self.exec_vm.remove_breakpoint(rip)?;
+                    self.reset_vm.remove_breakpoint(rip)?;
+                    self.covered.insert(rip);
+                    coverage.push(rip);
+                }
+                VmExit::Syscall if self.syscall_handler.is_some() => {
+                    let handler = self.syscall_handler.as_mut().unwrap();
+                    match handler(&mut self.exec_vm) {
+                        HookResult::Continue | HookResult::Redirect => {}
+                        HookResult::Exit => break VmExit::HookExit,
+                        HookResult::Crash => break VmExit::HookCrash,
+                    }
+                }
+                exit => break exit,
+            }
+        };
+        let exec_time = start.elapsed();
+        self.executions += 1;
+
+        let signature = match CaseOutcome::from_exit(&exit) {
+            CaseOutcome::Crash => Some(self.save_crash(&exit, input)?),
+            _ => None,
+        };
+
+        Ok(CaseResult {
+            exit,
+            coverage,
+            signature,
+            exec_time,
+            reset_time,
+        })
+    }
+
+    /// Handles the syscalls of the guest (see `Vm::enable_syscalls`), a
+    /// syscall ending the case as a crash otherwise
+    pub fn on_syscall<F>(&mut self, handler: F)
+    where
+        F: FnMut(&mut Vm) -> HookResult + 'static,
+    {
+        self.syscall_handler = Some(Box::new(handler));
+    }
+
+    /// Writes the cases into the guest with `handler`, called on the vm
+    /// reset to the reset point, instead of the input location and length
+    /// register of the template (e.g. for structured inputs spread over
+    /// several buffers)
+    pub fn on_input<F>(&mut self, handler: F)
+    where
+        F: FnMut(&mut Vm, &[u8]) -> std::result::Result<(), VmError> + 'static,
+    {
+        self.input_handler = Some(Box::new(handler));
+    }
+
+    /// Installs a hook, kept across the resets
+    pub fn hook<F>(&mut self, address: u64, handler: F) -> Result<()>
+    where
+        F: FnMut(&mut Vm) -> HookResult + 'static,
+    {
+        self.exec_vm.hook(address, handler)?;
+        self.reset_vm.add_breakpoint(address)
+    }
+
+    /// Returns the coverage breakpoints reached so far
+    #[inline]
+    pub fn covered(&self) -> &BTreeSet<u64> {
+        &self.covered
+    }
+
+    /// Returns the number of cases run
+    #[inline]
+    pub fn executions(&self) -> u64 {
+        self.executions
+    }
+
+    /// Returns the signatures of the crashes found
+    #[inline]
+    pub fn crashes(&self) -> &BTreeSet<u64> {
+        &self.crashes
+    }
+
+    /// Returns the vm running the cases, in the state of the last case
+    #[inline]
+    pub fn vm(&self) -> &Vm {
+        &self.exec_vm
+    }
+
+    /// Records a crash, saving the case the first time its signature is
+    /// seen. Returns the signature, the crash bucket of the vm (see
+    /// `Vm::crash_hash`).
+    fn save_crash(&mut self, exit: &VmExit, input: &[u8]) -> Result<u64> {
+        let rip = self.exec_vm.get_reg(Register::Rip);
+        let signature = self.exec_vm.crash_hash(*exit);
+
+        if !self.crashes.insert(signature) {
+            return Ok(signature);
+        }
+
+        if let Some(directory) = self.crash_dir.as_ref() {
+            let bundle = directory.join(format!("crash-{:016x}", signature));
+            fs::create_dir_all(&bundle)?;
+            fs::write(bundle.join("input"), input)?;
+            fs::write(
+                bundle.join("exit.txt"),
+                format!(
+                    "{:?}\nrip={:#x}\nrsp={:#x}\n",
+                    exit,
+                    rip,
+                    self.exec_vm.get_reg(Register::Rsp)
+                ),
+            )?;
+            self.exec_vm
+                .crash_report(*exit)
+                .save(bundle.join("report.json"))?;
+        }
+
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CaseOutcome, HarnessTemplate, InputLocation, Result};
+    use crate::memory::{PagePermissions, PAGE_SIZE};
+    use crate::testutil::TempPath;
+    use crate::vm::{Register, Vm, VmExit};
+
+    use std::fs;
+
+    #[test]
+    fn test_harness_template() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x83, 0xfe, 0x04, // cmp rsi, 4
+            0x72, 0x15, // jb end
+            0x8b, 0x04, 0x25, 0x00, 0x80, 0x33, 0x01, // mov eax, [0x1338000]
+            0x3d, 0x41, 0x41, 0x41, 0x41, // cmp eax, 0x41414141
+            0x75, 0x07, // jne end
+            0x88, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, // mov [0], al
+            0xb8, 0x01, 0x00, 0x00, 0x00, // end: mov eax, EXIT
+            0x31, 0xff, // xor edi, edi
+            0xe5, 0x7f, // in eax, HYPERCALL_PORT
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x1338000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rdi, 0x1338000);
+
+        let crash_dir = TempPath::new("crashes");
+        let mut harness = HarnessTemplate::new(InputLocation::Pointer {
+            register: Register::Rdi,
+            size: 6,
+        })
+        .length_register(Register::Rsi)
+        .crash_dir(&crash_dir)
+        .build(&vm)?;
+
+        // Cases ending normally
+        let result = harness.run(b"AB")?;
+        assert_eq!(result.exit, VmExit::GuestExit(0));
+        assert_eq!(result.signature, None);
+        let result = harness.run(b"ABCD")?;
+        assert_eq!(CaseOutcome::from_exit(&result.exit), CaseOutcome::Ok);
+
+        // Crash, its case truncated to the buffer size is saved once
+        let result = harness.run(b"AAAAAAAA")?;
+        assert!(matches!(result.exit, VmExit::PageFault(_)));
+        let signature = result.signature.expect("Crash without signature");
+        assert_eq!(harness.run(b"AAAA")?.signature, Some(signature));
+        assert_eq!(harness.crashes().len(), 1);
+
+        let bundle = crash_dir.join(format!("crash-{:016x}", signature));
+        assert_eq!(fs::read(bundle.join("input"))?, b"AAAAAA");
+        assert!(fs::read_to_string(bundle.join("exit.txt"))?.starts_with("PageFault"));
+        assert!(fs::read_to_string(bundle.join("report.json"))?.contains("\"exit\":\"PageFault"));
+
+        // Each case starts from the snapshot state, which is left untouched
+        assert_eq!(harness.run(b"AB")?.exit, VmExit::GuestExit(0));
+        assert_eq!(harness.executions(), 5);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337000);
+
+        // Cases written by a handler instead of the input location
+        harness.on_input(|vm, input| {
+            vm.write(0x1338000, input)?;
+            vm.write(0x1338000 + input.len() as u64, b"AA")?;
+            vm.set_reg(Register::Rsi, input.len() as u64 + 2);
+            Ok(())
+        });
+        assert!(matches!(harness.run(b"AA")?.exit, VmExit::PageFault(_)));
+
+        Ok(())
+    }
+}
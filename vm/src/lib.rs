@@ -1,16 +1,43 @@
 //! Virtual Machine low-level management
 
 mod bits;
+mod case;
+#[cfg(feature = "dwarf")]
+mod dwarf;
+mod elf;
+pub mod harness;
 mod memory;
 mod snapshot;
+mod symbols;
+#[cfg(test)]
+mod testutil;
 mod vm;
 mod x64;
 
 #[macro_use]
 extern crate vmm_sys_util;
 
-pub use memory::{Mapping, PagePermissions};
+pub use case::{CaseError, CaseResult, CaseResultReader, CaseResultWriter};
+pub use memory::{
+    GuestSlice, GuestSliceMut, HugePages, Mapping, MemoryBank, MemoryError, PageEntry,
+    PagePermissions, PartialRead,
+};
 pub use snapshot::{
-    SnapshotError, SnapshotInfo, SnapshotMapping, SnapshotModule, SnapshotRegisters,
+    CompressionStats, HostDataKind, HostIdentity, MemoryDump, PortabilityIssue, Redaction,
+    RedactionRule, SnapshotData, SnapshotError, SnapshotInfo, SnapshotMapping, SnapshotModule,
+    SnapshotRegisters, SnapshotThread,
+};
+pub use symbols::{SymbolModule, Symbols, SymbolsError};
+pub use vm::{
+    diff, hypercall, msr, AccessKind, BranchKind, BreakpointOwner, CfiViolationDetail,
+    CheckpointConfig, CheckpointStats, CpuidEntry, CrashHashConfig, CrashReport, DeltaDump,
+    DeterminismConfig, DirtyLogStrategy, DirtyStats, Divergence, DivergenceKind, ExceptionStats,
+    ExecStats, FileSink, FlakinessDetector, HeapAllocation, HeapConfig, HeapReport, HeapRuntime,
+    HeapViolation, HookFn, HookId, HookResult, HotPage, HwBreakpointKind, IntegrityError,
+    InternalStructure, InterruptHandle, Lockstep, LockstepMode, LockstepResult, MappingDiff,
+    MappingDirtiness, MemoryWindow, MmioReadFn, MmioWriteFn, Nondeterminism, Normalization,
+    NormalizationKind, PageDiff, PageFaultDetail, PhysicalRegion, PhysicalRegionKind, PortInFn,
+    PortOutFn, Quarantine, Register, RegisterDiff, RingBufferSink, Segment, SegmentRegister,
+    SoftMmuFault, SplitMix64, StackFrame, TraceRecord, TraceRegisters, TraceSink, Tracer,
+    VdsoFunction, Vm, VmBuilder, VmDiff, VmError, VmExit, VmRng, WatchAccess, INTERRUPT_SIGNAL,
 };
-pub use vm::{PageFaultDetail, Register, Vm, VmError, VmExit};
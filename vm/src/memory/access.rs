@@ -0,0 +1,303 @@
+//! Checked and typed accesses to the guest virtual memory
+
+use super::paging::{VirtAddr, VirtRange};
+use super::virt::VirtualMemory;
+use super::{MemoryError, Result, PAGE_SIZE};
+
+use std::cmp::min;
+
+/// Read of a virtual area stopped by an inaccessible page
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PartialRead {
+    /// Number of bytes read before the inaccessible page
+    pub read: usize,
+    /// Reason of the stop
+    pub error: MemoryError,
+}
+
+impl VirtualMemory {
+    /// Returns the readable subranges `(start, end)` of a virtual area: the
+    /// pages mapped, present and backed by the vm memory
+    pub fn readable_ranges(&self, addr: u64, size: usize) -> Result<Vec<(u64, u64)>> {
+        let end = addr
+            .checked_add(size as u64)
+            .ok_or(MemoryError::IntegerOverflow)?;
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+
+        for page in VirtRange::new(VirtAddr::new(addr), VirtAddr::new(end)) {
+            let start = page.address().max(addr);
+            let stop = page.address().saturating_add(PAGE_SIZE as u64).min(end);
+
+            let readable = self
+                .translate(start)
+                .is_some_and(|(pa, _)| self.pmem.contains(pa));
+            if !readable || start >= stop {
+                continue;
+            }
+
+            // Merge the contiguous pages
+            match ranges.last_mut() {
+                Some(last) if last.1 == start => last.1 = stop,
+                _ => ranges.push((start, stop)),
+            }
+        }
+
+        Ok(ranges)
+    }
+
+    /// Reads a virtual area, the inaccessible pages being read as zeroes.
+    /// Returns the holes `(start, end)` filled.
+    pub fn read_zero_filled(&self, addr: u64, output: &mut [u8]) -> Result<Vec<(u64, u64)>> {
+        let end = addr
+            .checked_add(output.len() as u64)
+            .ok_or(MemoryError::IntegerOverflow)?;
+        let mut holes = Vec::new();
+        let mut cursor = addr;
+
+        for (start, stop) in self.readable_ranges(addr, output.len())? {
+            if start > cursor {
+                holes.push((cursor, start));
+            }
+
+            let offset = (start - addr) as usize;
+            self.read(start, &mut output[offset..(stop - addr) as usize])?;
+            cursor = stop;
+        }
+
+        if cursor < end {
+            holes.push((cursor, end));
+        }
+        for &(start, stop) in holes.iter() {
+            output[(start - addr) as usize..(stop - addr) as usize].fill(0);
+        }
+
+        Ok(holes)
+    }
+
+    /// Reads a virtual area up to its first inaccessible page, the bytes
+    /// before it being read even when the area is not fully accessible
+    pub fn read_prefix(
+        &self,
+        addr: u64,
+        output: &mut [u8],
+    ) -> std::result::Result<(), PartialRead> {
+        let partial = |read, error| PartialRead { read, error };
+
+        let ranges = self
+            .readable_ranges(addr, output.len())
+            .map_err(|error| partial(0, error))?;
+
+        // Only the range starting at the address is read
+        let read = match ranges.first() {
+            Some(&(start, stop)) if start == addr => (stop - addr) as usize,
+            _ => 0,
+        };
+        self.read(addr, &mut output[..read])
+            .map_err(|error| partial(0, error))?;
+
+        match read == output.len() {
+            true => Ok(()),
+            false => Err(partial(
+                read,
+                MemoryError::AddressUnmapped(addr + read as u64),
+            )),
+        }
+    }
+
+    /// Reads a NUL terminated string of at most `max` bytes (terminator
+    /// excluded), page by page so that it may end right before an unmapped
+    /// page
+    pub fn read_cstring(&self, addr: u64, max: usize) -> Result<Vec<u8>> {
+        let mut string = Vec::new();
+        let mut address = addr;
+
+        while string.len() <= max {
+            // Read up to the end of the page
+            let page_bytes = PAGE_SIZE - (address as usize & (PAGE_SIZE - 1));
+            let mut chunk = vec![0u8; min(page_bytes, max.saturating_add(1) - string.len())];
+            self.read(address, &mut chunk)?;
+
+            if let Some(end) = chunk.iter().position(|&c| c == 0) {
+                string.extend_from_slice(&chunk[..end]);
+                return Ok(string);
+            }
+
+            string.extend_from_slice(&chunk);
+            address = address
+                .checked_add(chunk.len() as u64)
+                .ok_or(MemoryError::IntegerOverflow)?;
+        }
+
+        Err(MemoryError::UnterminatedString(addr))
+    }
+
+    /// Reads a NUL terminated UTF-16 string of at most `max` characters
+    /// (terminator excluded), invalid characters being replaced
+    pub fn read_wstring(&self, addr: u64, max: usize) -> Result<String> {
+        let mut string = Vec::new();
+        let mut character = [0u8; 2];
+
+        for i in 0..=max as u64 {
+            let address = i
+                .checked_mul(2)
+                .and_then(|offset| addr.checked_add(offset))
+                .ok_or(MemoryError::IntegerOverflow)?;
+            self.read(address, &mut character)?;
+
+            match u16::from_le_bytes(character) {
+                0 => return Ok(String::from_utf16_lossy(&string)),
+                c => string.push(c),
+            }
+        }
+
+        Err(MemoryError::UnterminatedString(addr))
+    }
+
+    /// Checks that the guest could access a virtual area, writing to it if
+    /// `write` is set
+    pub(crate) fn check_access(&self, addr: u64, size: usize, write: bool) -> Result<()> {
+        let end = addr
+            .checked_add(size as u64)
+            .ok_or(MemoryError::IntegerOverflow)?;
+
+        for page in VirtRange::new(VirtAddr::new(addr), VirtAddr::new(end)) {
+            // The first page is checked at the accessed address
+            let address = page.address().max(addr);
+            let (_, perms) = self
+                .translate(address)
+                .ok_or(MemoryError::AddressUnmapped(address))?;
+
+            if !perms.readable() || (write && !perms.writable()) {
+                return Err(MemoryError::AccessViolation(address));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a value the guest could read (the area must be mapped and
+    /// present)
+    pub fn read_val_checked<T: Copy>(&self, addr: u64) -> Result<T> {
+        self.check_access(addr, core::mem::size_of::<T>(), false)?;
+        self.read_val(addr)
+    }
+
+    /// Writes a value where the guest could write (the area must be mapped,
+    /// present and writable)
+    pub fn write_val_checked<T: Copy>(&mut self, addr: u64, val: T) -> Result<()> {
+        self.check_access(addr, core::mem::size_of::<T>(), true)?;
+        self.write_val(addr, val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{
+        MemoryError, PagePermissions, PartialRead, Result, VirtualMemory, PAGE_SIZE,
+    };
+
+    #[test]
+    fn test_read_strings() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+
+        vm.mmap(0x1337000, PAGE_SIZE, perms)?;
+        vm.write(0x1337ffa, b"abcde\0")?;
+        vm.write(0x1337100, &[0x68, 0, 0xe9, 0, 0, 0])?;
+
+        assert_eq!(vm.read_cstring(0x1337ffa, 16)?, b"abcde");
+        assert_eq!(vm.read_cstring(0x1337ffa, 5)?, b"abcde");
+        assert_eq!(
+            vm.read_cstring(0x1337ffa, 4),
+            Err(MemoryError::UnterminatedString(0x1337ffa))
+        );
+        assert_eq!(vm.read_wstring(0x1337100, 16)?, "h\u{e9}");
+        assert_eq!(vm.read_cstring(0x1337ffa, usize::MAX)?, b"abcde");
+        assert_eq!(vm.read_wstring(0x1337100, usize::MAX)?, "h\u{e9}");
+        assert_eq!(
+            vm.read_zero_filled(u64::MAX - 1, &mut [0u8; 4]),
+            Err(MemoryError::IntegerOverflow)
+        );
+
+        // The string runs into an unmapped page
+        vm.write(0x1337ffa, b"abcdef")?;
+        assert_eq!(
+            vm.read_cstring(0x1337ffa, 16),
+            Err(MemoryError::AddressUnmapped(0x1338000))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checked_values() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::READ)?;
+        vm.mmap(
+            0x1338000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        vm.write_val_checked(0x1338ff8, 0x4142u64)?;
+        assert_eq!(vm.read_val_checked::<u64>(0x1338ff8)?, 0x4142);
+        assert_eq!(
+            vm.write_val_checked(0x1337ffc, 0u64),
+            Err(MemoryError::AccessViolation(0x1337ffc))
+        );
+        assert_eq!(
+            vm.read_val_checked::<u64>(0x1338ffc),
+            Err(MemoryError::AddressUnmapped(0x1339000))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lenient_reads() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+
+        // Two mapped pages around a hole, then a page not present
+        vm.mmap(0x1337000, PAGE_SIZE, perms)?;
+        vm.mmap(0x1339000, 2 * PAGE_SIZE, perms)?;
+        vm.set_page_present(0x133a000, false)?;
+        vm.write(0x1337ff8, &[0x41; 8])?;
+        vm.write(0x1339000, &[0x42; 8])?;
+
+        assert_eq!(
+            vm.readable_ranges(0x1337ff8, 3 * PAGE_SIZE)?,
+            vec![(0x1337ff8, 0x1338000), (0x1339000, 0x133a000)]
+        );
+        assert!(vm.readable_ranges(u64::MAX, 2).is_err());
+
+        // Holes read as zeroes
+        let mut data = vec![0xffu8; PAGE_SIZE + 16];
+        assert_eq!(
+            vm.read_zero_filled(0x1337ff8, &mut data)?,
+            vec![(0x1338000, 0x1339000)]
+        );
+        assert_eq!(&data[..8], &[0x41; 8]);
+        assert!(data[8..PAGE_SIZE + 8].iter().all(|&b| b == 0));
+        assert_eq!(&data[PAGE_SIZE + 8..], &[0x42; 8]);
+
+        // The valid prefix is read
+        let mut data = [0u8; 16];
+        assert_eq!(
+            vm.read_prefix(0x1337ff8, &mut data),
+            Err(PartialRead {
+                read: 8,
+                error: MemoryError::AddressUnmapped(0x1338000),
+            })
+        );
+        assert_eq!(&data[..8], &[0x41; 8]);
+        assert_eq!(vm.read_prefix(0x1339ff8, &mut data[..8]), Ok(()));
+        assert_eq!(
+            vm.read_prefix(0x133a000, &mut data).map_err(|e| e.read),
+            Err(0)
+        );
+
+        Ok(())
+    }
+}
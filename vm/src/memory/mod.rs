@@ -2,12 +2,18 @@
 
 #![warn(missing_docs)]
 
+mod access;
 mod paging;
 mod phys;
+mod slice;
 mod virt;
 
-pub use paging::{PagePermissions, PAGE_SIZE};
-pub use virt::{Mapping, VirtualMemory};
+pub use access::PartialRead;
+pub use paging::{PagePermissions, HUGE_PAGE_SIZE, PAGE_SIZE};
+pub(crate) use phys::PhysicalMemory;
+pub use phys::{HugePages, MemoryBank};
+pub use slice::{GuestSlice, GuestSliceMut};
+pub use virt::{Mapping, PageEntry, VirtualMemory};
 
 use std::{error, fmt};
 
@@ -19,8 +25,8 @@ pub type Result<T> = std::result::Result<T, MemoryError>;
 pub enum MemoryError {
     /// No more memory present
     OutOfMemory,
-    /// Could not allocate memory
-    PhysmemAlloc,
+    /// Could not allocate memory, with the error number of the mmap
+    PhysmemAlloc(i32),
     /// The `address` was already mapped
     AddressAlreadyMapped(u64),
     /// The `address` is not mapped
@@ -31,13 +37,23 @@ pub enum MemoryError {
     PhysWriteOutOfBounds(u64, usize),
     /// An integer overflow occured
     IntegerOverflow,
+    /// The page permissions at `address` forbid the access
+    AccessViolation(u64),
+    /// No string terminator found in the bytes read from `address`
+    UnterminatedString(u64),
+    /// The guest physical `address` is managed by the frame allocator
+    PhysicalAddressInUse(u64),
 }
 
 impl fmt::Display for MemoryError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             MemoryError::OutOfMemory => write!(f, "Out of memory"),
-            MemoryError::PhysmemAlloc => write!(f, "Physmem mmap failed"),
+            MemoryError::PhysmemAlloc(errno) => write!(
+                f,
+                "Physmem mmap failed: {}",
+                std::io::Error::from_raw_os_error(errno)
+            ),
             MemoryError::AddressAlreadyMapped(addr) => {
                 write!(f, "Virtual address already mapped 0x{:x}", addr)
             }
@@ -61,6 +77,15 @@ impl fmt::Display for MemoryError {
             MemoryError::IntegerOverflow => {
                 write!(f, "An integer overflow occured")
             }
+            MemoryError::AccessViolation(addr) => {
+                write!(f, "Access forbidden by the permissions at 0x{:x}", addr)
+            }
+            MemoryError::UnterminatedString(addr) => {
+                write!(f, "Unterminated string at 0x{:x}", addr)
+            }
+            MemoryError::PhysicalAddressInUse(addr) => {
+                write!(f, "Physical address 0x{:x} already in use", addr)
+            }
         }
     }
 }
@@ -69,12 +94,15 @@ impl error::Error for MemoryError {
     fn description(&self) -> &str {
         match *self {
             MemoryError::OutOfMemory => "Out of memory",
-            MemoryError::PhysmemAlloc => "Physmem mmap failed",
+            MemoryError::PhysmemAlloc(_) => "Physmem mmap failed",
             MemoryError::AddressAlreadyMapped(_) => "Virtual address already exists",
             MemoryError::PhysReadOutOfBounds(_, _) => "Physical read out of bounds",
             MemoryError::PhysWriteOutOfBounds(_, _) => "Physical write out of bounds",
             MemoryError::AddressUnmapped(_) => "Tried to access unmapped memory",
             MemoryError::IntegerOverflow => "An integer overflow occured",
+            MemoryError::AccessViolation(_) => "Access forbidden by the page permissions",
+            MemoryError::UnterminatedString(_) => "Unterminated string",
+            MemoryError::PhysicalAddressInUse(_) => "Physical address already in use",
         }
     }
 }
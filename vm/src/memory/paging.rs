@@ -8,6 +8,9 @@ use core::ops::Range;
 /// Page size
 pub const PAGE_SIZE: usize = 0x1000;
 
+/// Size of the pages mapped by a page directory entry
+pub const HUGE_PAGE_SIZE: usize = 0x20_0000;
+
 /// Trait implemented by frames allocator
 pub trait FrameAllocator {
     /// Allocate a frame
@@ -304,6 +307,13 @@ impl PageTableEntry {
         self.0.is_bit_set(Self::HUGE_PAGE_BIT)
     }
 
+    /// Set whether or not the entry points to a huge frame rather than to
+    /// the next page table
+    #[inline]
+    pub fn set_huge_page(&mut self, huge: bool) {
+        self.0.set_bit(Self::HUGE_PAGE_BIT, huge)
+    }
+
     /// Whether or not the page is global (flush or not from caches on
     /// address space switch)
     #[inline]
@@ -1,11 +1,44 @@
 //! Physical Memory Subsystem
 
-use super::paging::FrameAllocator;
+use super::paging::{FrameAllocator, HUGE_PAGE_SIZE};
 use super::MemoryError;
 use super::{Result, PAGE_SIZE};
 
 use crate::bits::Alignement;
-use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::mman::{madvise, mmap, munmap, MapFlags, MmapAdvise, ProtFlags};
+
+/// Host pages backing the guest memory
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HugePages {
+    /// Regular 4KB pages
+    Disabled,
+    /// Transparent hugepages (MADV_HUGEPAGE), the kernel falling back to
+    /// 4KB pages when it has no hugepage available
+    Transparent,
+    /// Hugetlbfs pages (MAP_HUGETLB), the allocation fails when not enough
+    /// hugepages are reserved (vm.nr_hugepages)
+    HugeTlb,
+}
+
+/// Guest physical range backed by a part of the physical memory, registered
+/// as its own kvm memory slot
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryBank {
+    /// Guest physical address of the bank
+    pub guest_address: u64,
+    /// Offset of the bank in the host allocation
+    pub host_offset: usize,
+    /// Size of the bank
+    pub size: usize,
+}
+
+impl MemoryBank {
+    /// Returns the guest physical end of the bank (excluded)
+    #[inline]
+    pub fn end(&self) -> u64 {
+        self.guest_address + self.size as u64
+    }
+}
 
 /// Virtual machine physical memory
 #[derive(Debug)]
@@ -14,34 +47,174 @@ pub struct PhysicalMemory {
     raw_data: *mut u8,
     /// Size of the physical memory
     size: usize,
+    /// Guest physical ranges backed by the memory, by address
+    banks: Vec<MemoryBank>,
     /// Top offset of the heap allocation
     top: usize,
+    /// Frames released below the top
+    free_frames: Vec<usize>,
 }
 
 impl PhysicalMemory {
     /// Create a new instance of `PhysicalMemory`
     pub fn new(memory_size: usize) -> Result<Self> {
+        Self::with_holes(memory_size, &[])
+    }
+
+    /// Creates a `PhysicalMemory` whose guest physical layout skips the
+    /// page aligned `holes` (start and size), e.g. the PCI hole below 4GB:
+    /// the memory is split in banks around them.
+    pub fn with_holes(memory_size: usize, holes: &[(u64, u64)]) -> Result<Self> {
+        Self::with_backing(memory_size, holes, HugePages::Disabled)
+    }
+
+    /// Creates a `PhysicalMemory` backed by `huge_pages`, its size then
+    /// being aligned up to 2MB
+    pub fn with_backing(
+        memory_size: usize,
+        holes: &[(u64, u64)],
+        huge_pages: HugePages,
+    ) -> Result<Self> {
         // Align size
-        let size = memory_size.align_power2(PAGE_SIZE);
+        let size = match huge_pages {
+            HugePages::Disabled => memory_size.align_power2(PAGE_SIZE),
+            _ => memory_size.align_up_power2(HUGE_PAGE_SIZE),
+        };
+
+        let raw_data = match huge_pages {
+            HugePages::Disabled => Self::map(size, MapFlags::empty())?,
+            HugePages::HugeTlb => Self::map(size, MapFlags::MAP_HUGETLB)?,
+            HugePages::Transparent => {
+                // Over allocate to align the start on a hugepage
+                let raw_data = Self::map(size + HUGE_PAGE_SIZE, MapFlags::empty())? as usize;
+                let start = raw_data.align_up_power2(HUGE_PAGE_SIZE);
+                let end = raw_data + size + HUGE_PAGE_SIZE;
+
+                unsafe {
+                    if start > raw_data {
+                        munmap(raw_data as *mut _, start - raw_data)
+                            .map_err(|err| MemoryError::PhysmemAlloc(err as i32))?;
+                    }
+                    if end > start + size {
+                        munmap((start + size) as *mut _, end - start - size)
+                            .map_err(|err| MemoryError::PhysmemAlloc(err as i32))?;
+                    }
+
+                    // Without transparent hugepages support the memory is
+                    // backed by regular pages
+                    let _ = madvise(start as *mut _, size, MmapAdvise::MADV_HUGEPAGE);
+                }
+
+                start as *mut _
+            }
+        };
+
+        Ok(Self {
+            raw_data: raw_data as *mut u8,
+            size: size,
+            banks: Self::layout(size, holes)?,
+            top: 0,
+            free_frames: Vec::new(),
+        })
+    }
 
-        // Mmap aligned requested size
-        let raw_data = unsafe {
+    /// Maps anonymous memory of `size` bytes
+    fn map(size: usize, flags: MapFlags) -> Result<*mut core::ffi::c_void> {
+        unsafe {
             mmap(
                 core::ptr::null_mut(),
                 size,
                 ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_ANONYMOUS | MapFlags::MAP_PRIVATE,
+                MapFlags::MAP_ANONYMOUS | MapFlags::MAP_PRIVATE | flags,
                 -1,
                 0,
             )
         }
-        .map_err(|_| MemoryError::PhysmemAlloc)?;
+        .map_err(|err| MemoryError::PhysmemAlloc(err as i32))
+    }
 
-        Ok(Self {
-            raw_data: raw_data as *mut u8,
-            size: size,
-            top: 0,
-        })
+    /// Places `size` bytes of memory around the holes
+    fn layout(size: usize, holes: &[(u64, u64)]) -> Result<Vec<MemoryBank>> {
+        let mut holes = holes.to_vec();
+        holes.sort_unstable();
+
+        let mut banks = Vec::new();
+        let (mut address, mut host_offset) = (0u64, 0usize);
+        for (start, hole_size) in holes {
+            let end = start
+                .checked_add(hole_size)
+                .ok_or(MemoryError::IntegerOverflow)?;
+
+            let bank_size = (start.saturating_sub(address) as usize).min(size - host_offset);
+            if bank_size > 0 {
+                banks.push(MemoryBank {
+                    guest_address: address,
+                    host_offset,
+                    size: bank_size,
+                });
+                host_offset += bank_size;
+            }
+
+            address = address.max(end);
+        }
+
+        if host_offset < size {
+            address
+                .checked_add((size - host_offset) as u64)
+                .ok_or(MemoryError::IntegerOverflow)?;
+            banks.push(MemoryBank {
+                guest_address: address,
+                host_offset,
+                size: size - host_offset,
+            });
+        }
+
+        Ok(banks)
+    }
+
+    /// Returns the guest physical ranges backed by the memory, by address
+    #[inline]
+    pub fn banks(&self) -> &[MemoryBank] {
+        &self.banks
+    }
+
+    /// Returns the guest physical end of the highest bank (excluded)
+    #[inline]
+    pub fn end(&self) -> u64 {
+        self.banks.last().map_or(0, MemoryBank::end)
+    }
+
+    /// Returns whether or not a guest physical address is backed by the
+    /// memory
+    #[inline]
+    pub fn contains(&self, pa: u64) -> bool {
+        self.host_offset(pa as usize, 1).is_some()
+    }
+
+    /// Returns the host offset of a guest physical range held by a bank
+    #[inline]
+    fn host_offset(&self, pa: usize, length: usize) -> Option<usize> {
+        let end = pa.checked_add(length)?;
+
+        self.banks
+            .iter()
+            .find(|bank| {
+                let start = bank.guest_address as usize;
+                pa >= start && end <= start + bank.size
+            })
+            .map(|bank| bank.host_offset + (pa - bank.guest_address as usize))
+    }
+
+    /// Returns the guest physical address of a host offset
+    #[inline]
+    fn guest_address(&self, offset: usize) -> usize {
+        let bank = self
+            .banks
+            .iter()
+            .find(|bank| offset < bank.host_offset + bank.size)
+            .expect("Offset outside of the physical memory");
+
+        bank.guest_address as usize + (offset - bank.host_offset)
     }
 
     /// Return the host region start address
@@ -60,17 +233,17 @@ impl PhysicalMemory {
     #[inline]
     pub fn raw_slice(&self, pa: usize, length: usize) -> Result<&[u8]> {
         // Bound check access
-        let end = pa.checked_add(length).ok_or(MemoryError::IntegerOverflow)?;
-        if end > self.size() {
-            return Err(MemoryError::PhysReadOutOfBounds(pa as u64, length));
-        }
-        if pa as isize > isize::MAX {
+        pa.checked_add(length).ok_or(MemoryError::IntegerOverflow)?;
+        let offset = self
+            .host_offset(pa, length)
+            .ok_or(MemoryError::PhysReadOutOfBounds(pa as u64, length))?;
+        if offset as isize > isize::MAX {
             return Err(MemoryError::IntegerOverflow);
         }
 
         // Get the slice
         let slice =
-            unsafe { std::slice::from_raw_parts(self.raw_data.offset(pa as isize), length) };
+            unsafe { std::slice::from_raw_parts(self.raw_data.offset(offset as isize), length) };
 
         Ok(slice)
     }
@@ -79,17 +252,18 @@ impl PhysicalMemory {
     #[inline]
     pub fn raw_slice_mut(&mut self, pa: usize, length: usize) -> Result<&mut [u8]> {
         // Bound check access
-        let end = pa.checked_add(length).ok_or(MemoryError::IntegerOverflow)?;
-        if end > self.size() {
-            return Err(MemoryError::PhysReadOutOfBounds(pa as u64, length));
-        }
-        if pa as isize > isize::MAX {
+        pa.checked_add(length).ok_or(MemoryError::IntegerOverflow)?;
+        let offset = self
+            .host_offset(pa, length)
+            .ok_or(MemoryError::PhysReadOutOfBounds(pa as u64, length))?;
+        if offset as isize > isize::MAX {
             return Err(MemoryError::IntegerOverflow);
         }
 
         // Get the slice
-        let slice =
-            unsafe { std::slice::from_raw_parts_mut(self.raw_data.offset(pa as isize), length) };
+        let slice = unsafe {
+            std::slice::from_raw_parts_mut(self.raw_data.offset(offset as isize), length)
+        };
 
         Ok(slice)
     }
@@ -115,33 +289,95 @@ impl PhysicalMemory {
         pdata.copy_from_slice(input);
         Ok(())
     }
+
+    /// Copies the content and the frames allocation state of another
+    /// physical memory with the same layout
+    pub(crate) fn copy_from(&mut self, other: &PhysicalMemory) {
+        assert_eq!(self.banks, other.banks, "Physical memory layout mismatch");
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(other.raw_data, self.raw_data, self.size);
+        }
+        self.restore_allocator(other);
+    }
+
+    /// Restores the frames allocation state of another physical memory,
+    /// leaving the content untouched
+    pub(crate) fn restore_allocator(&mut self, other: &PhysicalMemory) {
+        self.top = other.top;
+        self.free_frames.clone_from(&other.free_frames);
+    }
+}
+
+/// Bump allocator reusing the released frames
+impl PhysicalMemory {
+    /// Returns whether or not the next frame allocated is a released one
+    #[inline]
+    pub(crate) fn has_free_frames(&self) -> bool {
+        !self.free_frames.is_empty()
+    }
+
+    /// Allocates 512 contiguous frames backing a 2MB page, returns the guest
+    /// physical address of the first one. The frames skipped to align the
+    /// allocation stay available.
+    pub(crate) fn allocate_huge_frame(&mut self) -> Option<usize> {
+        let offset = self.top.align_up_power2(HUGE_PAGE_SIZE);
+        if offset.checked_add(HUGE_PAGE_SIZE)? > self.size {
+            return None;
+        }
+
+        // The frames must be contiguous and aligned in the guest too
+        let address = self.guest_address(offset);
+        let contiguous = self.host_offset(address, HUGE_PAGE_SIZE) == Some(offset);
+        if !address.is_multiple_of(HUGE_PAGE_SIZE) || !contiguous {
+            return None;
+        }
+
+        let skipped: Vec<usize> = (self.top..offset)
+            .step_by(PAGE_SIZE)
+            .map(|offset| self.guest_address(offset))
+            .collect();
+        self.free_frames.extend(skipped);
+
+        self.top = offset + HUGE_PAGE_SIZE;
+        Some(address)
+    }
 }
 
-/// Bump allocator
 impl FrameAllocator for PhysicalMemory {
     /// Allocate a frame
     #[inline]
     fn allocate_frame(&mut self) -> Option<usize> {
+        // Released frames are zeroed like fresh ones
+        if let Some(address) = self.free_frames.pop() {
+            self.raw_slice_mut(address, PAGE_SIZE).ok()?.fill(0);
+            return Some(address);
+        }
+
         if self.top >= self.size {
             return None;
         }
 
-        // Bump the heap top and return the last top
-        let address = self.top;
+        // Bump the heap top and return the frame at the last top
+        let address = self.guest_address(self.top);
         self.top += PAGE_SIZE;
         Some(address)
     }
 
     /// Deallocate a frame
     #[inline]
-    fn deallocate_frame(&mut self, _frame_address: usize) {
-        // TODO free capable allocator
+    fn deallocate_frame(&mut self, frame_address: usize) {
+        self.free_frames.push(frame_address);
     }
 
     // Translate a frame address to its virtual address
     #[inline]
     fn translate(&self, frame_address: usize) -> usize {
-        self.raw_data as usize + frame_address
+        let offset = self
+            .host_offset(frame_address, 1)
+            .expect("Frame outside of the physical memory");
+
+        self.raw_data as usize + offset
     }
 }
 
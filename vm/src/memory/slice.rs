@@ -0,0 +1,259 @@
+//! Zero-copy access to the guest virtual memory
+
+use super::paging::{FrameAllocator, VirtAddr, VirtRange};
+use super::virt::VirtualMemory;
+use super::{MemoryError, Result, PAGE_SIZE};
+
+use std::cmp::min;
+
+/// Guest buffer borrowed from the host memory backing it
+#[derive(Debug, PartialEq, Eq)]
+pub enum GuestSlice<'a> {
+    /// Buffer backed by consecutive frames
+    Contiguous(&'a [u8]),
+    /// Buffer spread over several runs of frames, in guest order
+    Scattered(Vec<&'a [u8]>),
+}
+
+/// Mutable guest buffer borrowed from the host memory backing it
+#[derive(Debug, PartialEq, Eq)]
+pub enum GuestSliceMut<'a> {
+    /// Buffer backed by consecutive frames
+    Contiguous(&'a mut [u8]),
+    /// Buffer spread over several runs of frames, in guest order
+    Scattered(Vec<&'a mut [u8]>),
+}
+
+impl<'a> GuestSlice<'a> {
+    /// Returns the buffer if it is contiguous in host memory
+    #[inline]
+    pub fn as_contiguous(&self) -> Option<&'a [u8]> {
+        match self {
+            GuestSlice::Contiguous(data) => Some(data),
+            GuestSlice::Scattered(_) => None,
+        }
+    }
+
+    /// Returns an iterator over the contiguous parts of the buffer
+    pub fn chunks(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        let chunks: &[&'a [u8]] = match self {
+            GuestSlice::Contiguous(data) => std::slice::from_ref(data),
+            GuestSlice::Scattered(chunks) => chunks,
+        };
+
+        chunks.iter().copied()
+    }
+
+    /// Returns the size of the buffer
+    pub fn len(&self) -> usize {
+        self.chunks().map(|c| c.len()).sum()
+    }
+
+    /// Returns whether or not the buffer is empty
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies the buffer in a vector
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.chunks().flatten().copied().collect()
+    }
+}
+
+impl PartialEq<[u8]> for GuestSlice<'_> {
+    fn eq(&self, other: &[u8]) -> bool {
+        if self.len() != other.len() {
+            return false;
+        }
+
+        let mut offset = 0;
+        self.chunks().all(|chunk| {
+            offset += chunk.len();
+            chunk == &other[offset - chunk.len()..offset]
+        })
+    }
+}
+
+impl<'a> GuestSliceMut<'a> {
+    /// Returns the buffer if it is contiguous in host memory
+    #[inline]
+    pub fn as_contiguous(&mut self) -> Option<&mut [u8]> {
+        match self {
+            GuestSliceMut::Contiguous(data) => Some(data),
+            GuestSliceMut::Scattered(_) => None,
+        }
+    }
+
+    /// Returns an iterator over the contiguous parts of the buffer
+    pub fn chunks_mut(&mut self) -> std::slice::IterMut<'_, &'a mut [u8]> {
+        let chunks: &mut [&'a mut [u8]] = match self {
+            GuestSliceMut::Contiguous(data) => std::slice::from_mut(data),
+            GuestSliceMut::Scattered(chunks) => chunks,
+        };
+
+        chunks.iter_mut()
+    }
+
+    /// Returns the size of the buffer
+    pub fn len(&self) -> usize {
+        match self {
+            GuestSliceMut::Contiguous(data) => data.len(),
+            GuestSliceMut::Scattered(chunks) => chunks.iter().map(|c| c.len()).sum(),
+        }
+    }
+
+    /// Returns whether or not the buffer is empty
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies `data` to the buffer, which must have the same size
+    pub fn copy_from_slice(&mut self, data: &[u8]) {
+        assert_eq!(self.len(), data.len(), "Guest slice size mismatch");
+
+        let mut offset = 0;
+        for chunk in self.chunks_mut() {
+            chunk.copy_from_slice(&data[offset..offset + chunk.len()]);
+            offset += chunk.len();
+        }
+    }
+
+    /// Fills the buffer with `value`
+    pub fn fill(&mut self, value: u8) {
+        for chunk in self.chunks_mut() {
+            chunk.fill(value);
+        }
+    }
+}
+
+impl VirtualMemory {
+    /// Returns the runs of consecutive physical memory (address, size)
+    /// backing a virtual area
+    fn physical_runs(&self, addr: u64, len: usize) -> Result<Vec<(usize, usize)>> {
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+
+        if len == 0 {
+            return Ok(runs);
+        }
+
+        // Compute the range of pages between VA and VA + len
+        let end = addr
+            .checked_add(len as u64)
+            .ok_or(MemoryError::IntegerOverflow)?;
+        let pages = VirtRange::new(VirtAddr::new(addr), VirtAddr::new(end));
+
+        let mut remaining = len;
+        let mut page_off = addr as usize & (PAGE_SIZE - 1);
+
+        for page in pages {
+            let pa = self
+                .get_page_pa(page)
+                .ok_or(MemoryError::AddressUnmapped(page.address()))?
+                + page_off;
+            let size = min(remaining, PAGE_SIZE - page_off);
+
+            // Merge with the previous run when the frames follow each other
+            match runs.last_mut() {
+                Some((start, run_size)) if *start + *run_size == pa => *run_size += size,
+                _ => runs.push((pa, size)),
+            }
+
+            page_off = 0;
+            remaining -= size;
+        }
+
+        // Bound check the runs once
+        for &(pa, size) in runs.iter() {
+            self.pmem.raw_slice(pa, size)?;
+        }
+
+        Ok(runs)
+    }
+
+    /// Returns the host memory backing a virtual area, without copy
+    pub fn slice(&self, addr: u64, len: usize) -> Result<GuestSlice<'_>> {
+        let mut chunks: Vec<&[u8]> = self
+            .physical_runs(addr, len)?
+            .into_iter()
+            .map(|(pa, size)| self.pmem.raw_slice(pa, size))
+            .collect::<Result<_>>()?;
+
+        match chunks.len() {
+            0 => Ok(GuestSlice::Contiguous(&[])),
+            1 => Ok(GuestSlice::Contiguous(chunks.remove(0))),
+            _ => Ok(GuestSlice::Scattered(chunks)),
+        }
+    }
+
+    /// Returns the mutable host memory backing a virtual area, without copy
+    pub fn slice_mut(&mut self, addr: u64, len: usize) -> Result<GuestSliceMut<'_>> {
+        let runs = self.physical_runs(addr, len)?;
+
+        // Each virtual page has its own frame, so the runs never overlap
+        let mut chunks: Vec<&mut [u8]> = runs
+            .into_iter()
+            .map(|(pa, size)| unsafe {
+                std::slice::from_raw_parts_mut(self.pmem.translate(pa) as *mut u8, size)
+            })
+            .collect();
+
+        match chunks.len() {
+            0 => Ok(GuestSliceMut::Contiguous(&mut [])),
+            1 => Ok(GuestSliceMut::Contiguous(chunks.remove(0))),
+            _ => Ok(GuestSliceMut::Scattered(chunks)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GuestSlice, GuestSliceMut};
+    use crate::memory::{PagePermissions, Result, VirtualMemory, PAGE_SIZE};
+
+    #[test]
+    fn test_slice_contiguous() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+
+        vm.mmap(0x1337000, PAGE_SIZE * 2, perms)?;
+
+        let mut slice = vm.slice_mut(0x1337ffe, 4)?;
+        assert!(matches!(slice, GuestSliceMut::Contiguous(_)));
+        slice.copy_from_slice(&[0x41, 0x42, 0x43, 0x44]);
+
+        let mut data = [0u8; 4];
+        vm.read(0x1337ffe, &mut data)?;
+        assert_eq!(data, [0x41, 0x42, 0x43, 0x44]);
+        assert_eq!(vm.slice(0x1337ffe, 4)?.as_contiguous(), Some(&data[..]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slice_scattered() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+
+        // The page tables of the second mapping separate the frames
+        vm.mmap(0x1337000, PAGE_SIZE, perms)?;
+        vm.mmap(0x1338000, PAGE_SIZE, perms)?;
+        vm.mmap(0x7fff0000, PAGE_SIZE, perms)?;
+        vm.mmap(0x1339000, PAGE_SIZE, perms)?;
+
+        let input: Vec<u8> = (0..3 * PAGE_SIZE).map(|i| i as u8).collect();
+        vm.slice_mut(0x1337000, input.len())?
+            .copy_from_slice(&input);
+
+        let slice = vm.slice(0x1337000, input.len())?;
+        assert!(matches!(&slice, GuestSlice::Scattered(chunks) if chunks.len() == 2));
+        assert_eq!(slice.len(), input.len());
+        assert!(slice == input[..]);
+        assert_eq!(slice.to_vec(), input);
+
+        assert!(vm.slice(0x1339000, PAGE_SIZE + 1).is_err());
+
+        Ok(())
+    }
+}
@@ -1,12 +1,13 @@
 //! Virtual Memory Subsystem
 
 use super::paging::{
-    FrameAllocator, PagePermissions, PageTable, PageTableEntry, VirtAddr, VirtRange,
+    FrameAllocator, PagePermissions, PageTable, PageTableEntry, VirtAddr, VirtRange, HUGE_PAGE_SIZE,
 };
-use super::phys::PhysicalMemory;
+use super::phys::{HugePages, MemoryBank, PhysicalMemory};
 use super::{MemoryError, Result, PAGE_SIZE};
 
 use std::cmp::min;
+use std::collections::BTreeSet;
 
 /// Virtual machine memory manager
 #[derive(Debug)]
@@ -15,18 +16,41 @@ pub struct VirtualMemory {
     pub(crate) pmem: PhysicalMemory,
     /// Current page_directory
     page_directory: usize,
+    /// Whether or not the areas covering 2MB pages are mapped with them
+    huge_mappings: bool,
+    /// Frames written by the host when changing the mappings (page tables
+    /// and reused frames), which kvm does not dirty log
+    written_frames: BTreeSet<u64>,
 }
 
 impl VirtualMemory {
     /// Create a new `VirtualMemory instance`
     pub fn new(memory_size: usize) -> Result<Self> {
+        Self::with_holes(memory_size, &[])
+    }
+
+    /// Creates a `VirtualMemory` whose guest physical layout skips the page
+    /// aligned `holes` (start and size), the memory being placed around them
+    /// (see `VirtualMemory::banks`)
+    pub fn with_holes(memory_size: usize, holes: &[(u64, u64)]) -> Result<Self> {
+        Self::with_backing(memory_size, holes, HugePages::Disabled)
+    }
+
+    /// Creates a `VirtualMemory` backed by `huge_pages` on the host. With
+    /// hugepages, the 2MB aligned parts of the mapped areas are mapped with
+    /// 2MB pages, split into 4KB pages when a part of them changes.
+    pub fn with_backing(
+        memory_size: usize,
+        holes: &[(u64, u64)],
+        huge_pages: HugePages,
+    ) -> Result<Self> {
         assert!(
             memory_size >= PAGE_SIZE,
             "Memory size must be at least a page"
         );
 
         // Create the physical memory manager
-        let mut pmem = PhysicalMemory::new(memory_size)?;
+        let mut pmem = PhysicalMemory::with_backing(memory_size, holes, huge_pages)?;
 
         // Setup the page directory
         let frame = pmem
@@ -37,22 +61,59 @@ impl VirtualMemory {
         Ok(VirtualMemory {
             pmem: pmem,
             page_directory: frame,
+            huge_mappings: huge_pages != HugePages::Disabled,
+            written_frames: BTreeSet::new(),
         })
     }
 
+    /// Returns the frames written by the host when changing the mappings
+    /// since the last `VirtualMemory::clear_written_frames`
+    #[inline]
+    pub(crate) fn written_frames(&self) -> &BTreeSet<u64> {
+        &self.written_frames
+    }
+
+    /// Forgets the frames written by the host, once restored
+    #[inline]
+    pub(crate) fn clear_written_frames(&mut self) {
+        self.written_frames.clear();
+    }
+
+    /// Records the page tables walked down to a page as written
+    fn record_walk(&mut self, addr: VirtAddr) {
+        let mut table = self.page_directory;
+        self.written_frames.insert(table as u64);
+
+        for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+            let entries = &PageTable::from_addr(self.pmem.translate(table)).entries;
+            table = match entries[index].unused() || entries[index].huge_page() {
+                true => return,
+                false => entries[index].address() as usize,
+            };
+            self.written_frames.insert(table as u64);
+        }
+    }
+
     /// Map a page to a frame
     fn map_page(&mut self, addr: VirtAddr, perms: PagePermissions) -> Result<()> {
         let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
         let p3 = p4.next_table_create(addr.p4_index(), &mut self.pmem, perms);
         let p2 = p3.next_table_create(addr.p3_index(), &mut self.pmem, perms);
+        if p2.entries[addr.p2_index()].huge_page() {
+            return Err(MemoryError::AddressAlreadyMapped(addr.address()));
+        }
         let p1 = p2.next_table_create(addr.p2_index(), &mut self.pmem, perms);
 
         if !p1.entries[addr.p1_index()].unused() {
             return Err(MemoryError::AddressAlreadyMapped(addr.address()));
         }
 
-        // Get a frame to map page to
+        // Get a frame to map page to, zeroed by the host when reused
+        let reused = self.pmem.has_free_frames();
         let frame = self.pmem.allocate_frame().ok_or(MemoryError::OutOfMemory)?;
+        if reused {
+            self.written_frames.insert(frame as u64);
+        }
 
         // Set p1 entry
         p1.entries[addr.p1_index()].set_address(frame as u64);
@@ -63,6 +124,88 @@ impl VirtualMemory {
         Ok(())
     }
 
+    /// Maps a 2MB page to contiguous frames if the area ending at `end`
+    /// covers it and nothing is mapped there yet, returns whether or not it
+    /// was mapped
+    fn map_huge_page(
+        &mut self,
+        addr: VirtAddr,
+        end: VirtAddr,
+        perms: PagePermissions,
+    ) -> Result<bool> {
+        if !self.covers_huge_page(addr, end) {
+            return Ok(false);
+        }
+
+        let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
+        let p3 = p4.next_table_create(addr.p4_index(), &mut self.pmem, perms);
+        let p2 = p3.next_table_create(addr.p3_index(), &mut self.pmem, perms);
+
+        let entry = &mut p2.entries[addr.p2_index()];
+        if !entry.unused() {
+            return Ok(false);
+        }
+
+        // Fall back to 4KB pages once the memory is too fragmented
+        let frame = match self.pmem.allocate_huge_frame() {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+
+        entry.set_address(frame as u64);
+        entry.set_huge_page(true);
+        entry.set_present(true);
+        entry.set_writable(perms.writable());
+        entry.set_executable(perms.executable());
+
+        Ok(true)
+    }
+
+    /// Returns whether or not the area from `addr` to `end` covers the 2MB
+    /// page starting at `addr`
+    fn covers_huge_page(&self, addr: VirtAddr, end: VirtAddr) -> bool {
+        let aligned = addr.address().is_multiple_of(HUGE_PAGE_SIZE as u64);
+        let huge_end = addr.address().checked_add(HUGE_PAGE_SIZE as u64);
+
+        aligned && huge_end.is_some_and(|huge_end| huge_end <= end.address())
+    }
+
+    /// Returns the page directory entry of the 2MB page holding an address,
+    /// if it is mapped with one
+    fn huge_page_entry(&mut self, addr: VirtAddr) -> Option<&mut PageTableEntry> {
+        let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
+        let p3 = p4.next_table(addr.p4_index(), &self.pmem)?;
+        let p2 = p3.next_table(addr.p3_index(), &self.pmem)?;
+
+        Some(&mut p2.entries[addr.p2_index()]).filter(|entry| entry.huge_page())
+    }
+
+    /// Splits the 2MB page holding an address into 4KB pages mapping the same
+    /// frames, before a part of it changes
+    fn split_huge_page(&mut self, addr: VirtAddr) -> Result<()> {
+        let huge = match self.huge_page_entry(addr) {
+            Some(entry) => *entry,
+            None => return Ok(()),
+        };
+
+        let table = self.pmem.allocate_frame().ok_or(MemoryError::OutOfMemory)?;
+        let p1 = PageTable::from_addr(self.pmem.translate(table));
+        for (index, entry) in p1.entries.iter_mut().enumerate() {
+            *entry = huge;
+            entry.set_huge_page(false);
+            entry.set_address(huge.address() + (index * PAGE_SIZE) as u64);
+        }
+
+        // The directory keeps the permissions of the page, now merged
+        let directory = self.huge_page_entry(addr).unwrap();
+        directory.set_huge_page(false);
+        directory.set_present(true);
+        directory.set_dirty(false);
+        directory.set_address(table as u64);
+
+        Ok(())
+    }
+
     /// Map virtual memory area
     pub fn mmap(&mut self, addr: u64, size: usize, perms: PagePermissions) -> Result<()> {
         // Compute pages range
@@ -70,23 +213,316 @@ impl VirtualMemory {
         assert!(start.aligned(), "Page address must be aligned");
 
         let end = VirtAddr::new(start.address() + size as u64);
-        let pages = VirtRange::new(start, end);
 
-        // Loop through pages to map
-        for page in pages {
-            self.map_page(page, perms)?;
+        // Loop through pages to map, by 2MB when possible
+        let mut page = start;
+        while page < end {
+            if self.huge_mappings && self.map_huge_page(page, end, perms)? {
+                self.record_walk(page);
+                page = VirtAddr::new(page.address() + HUGE_PAGE_SIZE as u64);
+                continue;
+            }
+
+            let mapped = self.map_page(page, perms);
+            self.record_walk(page);
+            mapped?;
+            page = VirtAddr::new(page.address() + PAGE_SIZE as u64);
+        }
+
+        Ok(())
+    }
+
+    /// Map virtual memory area to guest physical memory outside of the vm
+    /// memory (memory slots, MMIO regions). The frames are not released by
+    /// `munmap`.
+    pub fn map_physical(
+        &mut self,
+        addr: u64,
+        physical_address: u64,
+        size: usize,
+        perms: PagePermissions,
+    ) -> Result<()> {
+        let start = VirtAddr::new(addr);
+        assert!(
+            start.aligned() && VirtAddr::new(physical_address).aligned(),
+            "Page address must be aligned"
+        );
+
+        // The frames of the vm memory belong to the allocator
+        let physical_end = physical_address + size as u64;
+        let in_use = self
+            .pmem
+            .banks()
+            .iter()
+            .any(|bank| physical_address < bank.end() && bank.guest_address < physical_end);
+        if in_use {
+            return Err(MemoryError::PhysicalAddressInUse(physical_address));
+        }
+
+        let end = VirtAddr::new(start.address() + size as u64);
+        for (i, page) in VirtRange::new(start, end).enumerate() {
+            let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
+            let p3 = p4.next_table_create(page.p4_index(), &mut self.pmem, perms);
+            let p2 = p3.next_table_create(page.p3_index(), &mut self.pmem, perms);
+            if p2.entries[page.p2_index()].huge_page() {
+                self.record_walk(page);
+                return Err(MemoryError::AddressAlreadyMapped(page.address()));
+            }
+            let p1 = p2.next_table_create(page.p2_index(), &mut self.pmem, perms);
+
+            let entry = &mut p1.entries[page.p1_index()];
+            if !entry.unused() {
+                self.record_walk(page);
+                return Err(MemoryError::AddressAlreadyMapped(page.address()));
+            }
+
+            entry.set_address(physical_address + (i * PAGE_SIZE) as u64);
+            entry.set_present(true);
+            entry.set_writable(perms.writable());
+            entry.set_executable(perms.executable());
+            self.record_walk(page);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the level 1 entry of a page if it was mapped
+    fn page_entry(&mut self, addr: VirtAddr) -> Option<&mut PageTableEntry> {
+        let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
+        let p3 = p4.next_table(addr.p4_index(), &self.pmem)?;
+        let p2 = p3.next_table(addr.p3_index(), &self.pmem)?;
+        let p1 = p2.next_table(addr.p2_index(), &self.pmem)?;
+
+        Some(&mut p1.entries[addr.p1_index()]).filter(|entry| !entry.unused())
+    }
+
+    /// Unmap virtual memory area, releasing the frames of the mapped pages
+    /// (the pages not mapped are skipped)
+    pub fn munmap(&mut self, addr: u64, size: usize) -> Result<()> {
+        // Compute pages range
+        let start = VirtAddr::new(addr);
+        assert!(start.aligned(), "Page address must be aligned");
+
+        let end = VirtAddr::new(start.address() + size as u64);
+
+        // Loop through pages to unmap
+        let mut page = start;
+        while page < end {
+            // The 2MB pages covered are unmapped at once
+            if self.covers_huge_page(page, end) {
+                if let Some(entry) = self.huge_page_entry(page) {
+                    let frame = entry.address() as usize;
+                    entry.set_unused();
+
+                    for index in 0..HUGE_PAGE_SIZE / PAGE_SIZE {
+                        self.pmem.deallocate_frame(frame + index * PAGE_SIZE);
+                    }
+                    self.record_walk(page);
+                    page = VirtAddr::new(page.address() + HUGE_PAGE_SIZE as u64);
+                    continue;
+                }
+            }
+
+            self.split_huge_page(page)?;
+            self.record_walk(page);
+            let current = page;
+            page = VirtAddr::new(page.address() + PAGE_SIZE as u64);
+
+            let frame = match self.page_entry(current) {
+                Some(entry) => {
+                    let frame = entry.address() as usize;
+                    entry.set_unused();
+                    frame
+                }
+                None => continue,
+            };
+
+            // Pages mapped with `map_physical` have no frame to release
+            if self.pmem.contains(frame as u64) {
+                self.pmem.deallocate_frame(frame);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Change the permissions of a virtual memory area, which must be fully
+    /// mapped
+    pub fn mprotect(&mut self, addr: u64, size: usize, perms: PagePermissions) -> Result<()> {
+        // Compute pages range
+        let start = VirtAddr::new(addr);
+        assert!(start.aligned(), "Page address must be aligned");
+
+        let end = VirtAddr::new(start.address() + size as u64);
+
+        // Nothing is changed if a page is missing
+        let mut pages = VirtRange::new(start, end);
+        if let Some(page) = pages.find(|&page| self.get_page_pa(page).is_none()) {
+            return Err(MemoryError::AddressUnmapped(page.address()));
+        }
+
+        let mut page = start;
+        while page < end {
+            // The 2MB pages covered keep their mapping
+            if self.covers_huge_page(page, end) {
+                if let Some(entry) = self.huge_page_entry(page) {
+                    entry.set_writable(perms.writable());
+                    entry.set_executable(perms.executable());
+                    self.record_walk(page);
+
+                    page = VirtAddr::new(page.address() + HUGE_PAGE_SIZE as u64);
+                    continue;
+                }
+            }
+
+            self.split_huge_page(page)?;
+
+            // Merge the directories permissions (they all exist)
+            let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
+            let p3 = p4.next_table_create(page.p4_index(), &mut self.pmem, perms);
+            let p2 = p3.next_table_create(page.p3_index(), &mut self.pmem, perms);
+            let p1 = p2.next_table_create(page.p2_index(), &mut self.pmem, perms);
+
+            p1.entries[page.p1_index()].set_writable(perms.writable());
+            p1.entries[page.p1_index()].set_executable(perms.executable());
+            self.record_walk(page);
+
+            page = VirtAddr::new(page.address() + PAGE_SIZE as u64);
         }
 
         Ok(())
     }
 
+    /// Sets whether or not a mapped page is present. A page which is not
+    /// present keeps its frame but faults on any guest access.
+    pub(crate) fn set_page_present(&mut self, addr: u64, present: bool) -> Result<()> {
+        self.split_huge_page(VirtAddr::new(addr))?;
+
+        let entry = self
+            .page_entry(VirtAddr::new(addr))
+            .ok_or(MemoryError::AddressUnmapped(addr))?;
+
+        entry.set_present(present);
+        self.record_walk(VirtAddr::new(addr));
+        Ok(())
+    }
+
+    /// Walks the page tables down to the entry of a page, merging the
+    /// permissions of each level
+    fn page_walk(&self, addr: VirtAddr) -> Option<PageEntry> {
+        let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
+        let levels = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+
+        let mut table = p4;
+        let mut writable = true;
+        let mut executable = true;
+        let mut present = true;
+        let mut huge = None;
+        for (level, &index) in levels.iter().enumerate() {
+            let entry = table.entries[index];
+            writable &= entry.writable();
+            executable &= entry.executable();
+            present &= entry.present();
+
+            // The 2MB pages end the walk at the directory level
+            if level == 2 && entry.huge_page() {
+                huge = Some(entry);
+                break;
+            }
+            table = table.next_table(index, &self.pmem)?;
+        }
+
+        let mut entry = huge.unwrap_or(table.entries[addr.p1_index()]);
+        if entry.unused() {
+            return None;
+        }
+        if huge.is_some() {
+            entry.set_address(entry.address() + (addr.p1_index() * PAGE_SIZE) as u64);
+        }
+
+        let mut permissions = PagePermissions::READ;
+        permissions.set_writable(writable && entry.writable());
+        permissions.set_executable(executable && entry.executable());
+
+        Some(PageEntry {
+            address: addr.address(),
+            physical_address: entry.address(),
+            permissions,
+            present: present && entry.present(),
+            accessed: entry.accessed(),
+            dirty: entry.dirty(),
+        })
+    }
+
+    /// Translates a virtual address as the guest would, returns the physical
+    /// address and the effective permissions of its page
+    pub fn translate(&self, addr: u64) -> Option<(u64, PagePermissions)> {
+        let mut page = VirtAddr::new(addr);
+        page.align();
+
+        self.page_walk(page)
+            .filter(|entry| entry.present)
+            .map(|entry| {
+                let offset = addr & (PAGE_SIZE as u64 - 1);
+                (entry.physical_address + offset, entry.permissions)
+            })
+    }
+
+    /// Returns the entries of all the mapped pages (including the ones not
+    /// present), by increasing virtual address
+    pub fn page_entries(&self) -> impl Iterator<Item = PageEntry> + '_ {
+        let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
+        let mut pages = Vec::new();
+
+        for l4 in 0..PageTable::NB_ENTRIES {
+            let p3 = match p4.next_table(l4, &self.pmem) {
+                Some(table) => table,
+                None => continue,
+            };
+            for l3 in 0..PageTable::NB_ENTRIES {
+                let p2 = match p3.next_table(l3, &self.pmem) {
+                    Some(table) => table,
+                    None => continue,
+                };
+                for l2 in 0..PageTable::NB_ENTRIES {
+                    // The 2MB pages are listed by 4KB page
+                    if p2.entries[l2].huge_page() {
+                        pages.extend(
+                            (0..PageTable::NB_ENTRIES).map(|l1| VirtAddr::forge(l4, l3, l2, l1, 0)),
+                        );
+                        continue;
+                    }
+
+                    let p1 = match p2.next_table(l2, &self.pmem) {
+                        Some(table) => table,
+                        None => continue,
+                    };
+                    for l1 in 0..PageTable::NB_ENTRIES {
+                        if !p1.entries[l1].unused() {
+                            pages.push(VirtAddr::forge(l4, l3, l2, l1, 0));
+                        }
+                    }
+                }
+            }
+        }
+
+        pages
+            .into_iter()
+            .filter_map(move |page| self.page_walk(page))
+    }
+
     /// Returns the physical address of a page. Or nothing if the address is not mapped.
-    fn get_page_pa(&self, address: VirtAddr) -> Option<usize> {
+    pub(super) fn get_page_pa(&self, address: VirtAddr) -> Option<usize> {
         let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
         let p3 = p4.next_table(address.p4_index(), &self.pmem)?;
         let p2 = p3.next_table(address.p3_index(), &self.pmem)?;
-        let p1 = p2.next_table(address.p2_index(), &self.pmem)?;
 
+        let entry = p2.entries[address.p2_index()];
+        if entry.huge_page() {
+            return Some(entry.address() as usize + address.p1_index() * PAGE_SIZE);
+        }
+
+        let p1 = p2.next_table(address.p2_index(), &self.pmem)?;
         p1.next_table_address(address.p1_index())
     }
 
@@ -200,12 +636,23 @@ impl VirtualMemory {
         self.pmem.size()
     }
 
+    /// Returns the guest physical ranges backed by the guest memory, each
+    /// registered as a kvm memory slot
+    #[inline]
+    pub fn banks(&self) -> &[MemoryBank] {
+        self.pmem.banks()
+    }
+
     /// Returns an iterator over all mappings
     #[inline]
     pub fn mappings(&self) -> impl Iterator<Item = Mapping> + '_ {
         PageIterator::new(&self).map(|(addr, page)| Mapping {
             address: addr,
-            size: PAGE_SIZE,
+            size: if page.huge_page() {
+                HUGE_PAGE_SIZE
+            } else {
+                PAGE_SIZE
+            },
             dirty: page.dirty(),
         })
     }
@@ -217,12 +664,29 @@ impl VirtualMemory {
     }
 }
 
+/// Page table entry of a mapped page
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PageEntry {
+    /// Virtual address of the page
+    pub address: u64,
+    /// Physical address of the frame backing the page
+    pub physical_address: u64,
+    /// Effective permissions (merged from all the paging levels)
+    pub permissions: PagePermissions,
+    /// Whether or not the guest can access the page without faulting
+    pub present: bool,
+    /// Whether or not the page was accessed by the guest
+    pub accessed: bool,
+    /// Whether or not the page was written by the guest
+    pub dirty: bool,
+}
+
 /// Memory mapping inside the VirtualMemory
 #[derive(Debug, Copy, Clone)]
 pub struct Mapping {
     /// Address of the mapping
     pub address: u64,
-    /// Size of the page (here hardcoded to 4k)
+    /// Size of the page (4KB, or 2MB for the huge pages)
     pub size: usize,
     /// Is mapping dirty
     pub dirty: bool,
@@ -261,6 +725,13 @@ impl<'a> Iterator for PageIterator<'a> {
                 for l3 in self.l3_index..512 {
                     if let Some(p2) = p3.next_table(l3, &self.memory.pmem) {
                         for l2 in self.l2_index..512 {
+                            // The 2MB pages are a single entry
+                            if p2.entries[l2].huge_page() && p2.entries[l2].present() {
+                                self.l2_index += 1;
+                                let vaddr = VirtAddr::forge(l4, l3, l2, 0, 0);
+                                return Some((vaddr.address(), &p2.entries[l2]));
+                            }
+
                             if let Some(p1) = p2.next_table(l2, &self.memory.pmem) {
                                 for l1 in self.l1_index..512 {
                                     self.l1_index += 1;
@@ -320,6 +791,17 @@ impl<'a> Iterator for PageIteratorMut<'a> {
                 for l3 in self.l3_index..512 {
                     if let Some(p2) = p3.next_table(l3, &self.memory.pmem) {
                         for l2 in self.l2_index..512 {
+                            // The 2MB pages are a single entry
+                            if p2.entries[l2].huge_page() && p2.entries[l2].present() {
+                                self.l2_index += 1;
+                                let vaddr = VirtAddr::forge(l4, l3, l2, 0, 0);
+
+                                // Borrowed again from its address, as for the tables below
+                                let address = p3.next_table_address(l3).unwrap();
+                                let p2 = PageTable::from_addr(self.memory.pmem.translate(address));
+                                return Some((vaddr.address(), &mut p2.entries[l2]));
+                            }
+
                             if let Some(p1) = p2.next_table(l2, &self.memory.pmem) {
                                 for l1 in self.l1_index..512 {
                                     self.l1_index += 1;
@@ -348,7 +830,7 @@ impl<'a> Iterator for PageIteratorMut<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::{PagePermissions, Result};
+    use super::{HugePages, PagePermissions, Result, HUGE_PAGE_SIZE};
     use super::{VirtualMemory, PAGE_SIZE};
 
     #[test]
@@ -426,4 +908,145 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_translate() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::READ)?;
+        vm.mmap(
+            0x1338000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        let (pa, perms) = vm.translate(0x1338123).unwrap();
+        assert_eq!(perms, PagePermissions::READ | PagePermissions::WRITE);
+        vm.write(0x1338123, &[0x41])?;
+        assert_eq!(vm.pmem.raw_slice(pa as usize, 1)?, &[0x41]);
+
+        assert_eq!(vm.translate(0x1337000).unwrap().1, PagePermissions::READ);
+        assert_eq!(vm.translate(0x1339000), None);
+
+        // Pages not present are listed but not translated
+        vm.set_page_present(0x1337000, false)?;
+        assert_eq!(vm.translate(0x1337000), None);
+
+        let entries: Vec<_> = vm.page_entries().map(|e| (e.address, e.present)).collect();
+        assert_eq!(entries, vec![(0x1337000, false), (0x1338000, true)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_holes() -> Result<()> {
+        let mut vm = VirtualMemory::with_holes(8 * PAGE_SIZE, &[(0x2000, 0x1_0000_0000 - 0x2000)])?;
+        let banks: Vec<_> = vm
+            .banks()
+            .iter()
+            .map(|b| (b.guest_address, b.size))
+            .collect();
+        assert_eq!(banks, vec![(0, 0x2000), (0x1_0000_0000, 6 * PAGE_SIZE)]);
+
+        // The frames above the hole are used once the first bank is full
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::READ)?;
+        vm.write(0x1337000, &[0x41])?;
+        let (pa, _) = vm.translate(0x1337000).unwrap();
+        assert!(pa >= 0x1_0000_0000);
+        assert_eq!(vm.pmem.raw_slice(pa as usize, 1)?, &[0x41]);
+
+        assert!(vm.pmem.raw_slice(0x2000, 1).is_err());
+        assert!(vm.pmem.raw_slice(0x1000, 2 * PAGE_SIZE).is_err());
+        assert!(vm
+            .map_physical(0x1338000, 0x3000, PAGE_SIZE, PagePermissions::READ)
+            .is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_huge_pages() -> Result<()> {
+        let mut vm = VirtualMemory::with_backing(8 * HUGE_PAGE_SIZE, &[], HugePages::Transparent)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+
+        // The 2MB aligned part of the area is mapped with a 2MB page
+        vm.mmap(0x1ff000, HUGE_PAGE_SIZE + 2 * PAGE_SIZE, perms)?;
+        let mappings: Vec<_> = vm.mappings().map(|m| (m.address, m.size)).collect();
+        assert_eq!(
+            mappings,
+            vec![
+                (0x1ff000, PAGE_SIZE),
+                (0x200000, HUGE_PAGE_SIZE),
+                (0x400000, PAGE_SIZE)
+            ]
+        );
+        assert_eq!(vm.page_entries().count(), 514);
+
+        let (pa, _) = vm.translate(0x200000).unwrap();
+        assert_eq!(pa % HUGE_PAGE_SIZE as u64, 0);
+        assert_eq!(vm.translate(0x3ff123).unwrap().0, pa + 0x1ff123);
+        vm.write(0x3fffff, &[0x41, 0x42])?;
+
+        // Changing a part of it splits it into 4KB pages
+        vm.mprotect(0x201000, PAGE_SIZE, PagePermissions::READ)?;
+        assert_eq!(vm.mappings().count(), 514);
+        assert_eq!(
+            vm.translate(0x201000),
+            Some((pa + 0x1000, PagePermissions::READ))
+        );
+        assert_eq!(vm.translate(0x3ff123).unwrap().0, pa + 0x1ff123);
+        assert_eq!(vm.read_val::<u16>(0x3fffff)?, 0x4241);
+
+        // Nothing can be mapped over a 2MB page
+        vm.mmap(0x600000, HUGE_PAGE_SIZE, perms)?;
+        assert!(vm.mmap(0x601000, PAGE_SIZE, perms).is_err());
+        vm.munmap(0x600000, HUGE_PAGE_SIZE)?;
+        assert_eq!(vm.translate(0x600000), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_munmap() -> Result<()> {
+        let mut vm = VirtualMemory::new(8 * PAGE_SIZE)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+
+        vm.mmap(0x1337000, PAGE_SIZE * 2, perms)?;
+        vm.write(0x1337000, &[0x41; 2 * PAGE_SIZE])?;
+        vm.munmap(0x1337000, PAGE_SIZE * 3)?;
+
+        assert!(vm.read(0x1337000, &mut [0; 4]).is_err());
+        assert_eq!(vm.mappings().count(), 0);
+
+        // The released frames are reused (zeroed) once the memory is full
+        vm.mmap(0x1000, PAGE_SIZE * 3, perms)?;
+        let mut data = [0xff; 3 * PAGE_SIZE];
+        vm.read(0x1000, &mut data)?;
+        assert!(data.iter().all(|&b| b == 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mprotect() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+
+        vm.mmap(0x1337000, PAGE_SIZE * 2, PagePermissions::READ)?;
+        vm.mprotect(
+            0x1338000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::EXECUTE,
+        )?;
+
+        let entries: Vec<_> = vm.raw_pages_mut().map(|(_, e)| e.executable()).collect();
+        assert_eq!(entries, vec![false, true]);
+
+        // Partially unmapped areas are left untouched
+        assert!(vm
+            .mprotect(0x1337000, PAGE_SIZE * 3, PagePermissions::READ)
+            .is_err());
+        assert!(vm.raw_pages_mut().nth(1).unwrap().1.executable());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,431 @@
+//! Compressed memory dump container (snapshot format v2)
+//!
+//! The flat memory dump is split in pages, each of them compressed with zstd
+//! on its own so that any of them can be read without the others. The pages
+//! holding only zeroes are not stored and identical pages share the same
+//! compressed block. The file layout is:
+//!
+//! - the header: magic, version, checksum of the index, size of the flat dump,
+//!   number of pages and offset of the index (little endian);
+//! - the compressed blocks;
+//! - the page index, one entry per page of the flat dump: offset and size of
+//!   its block (zero for the elided pages) and CRC32 of its content.
+
+use super::{Result, SnapshotError};
+use crate::bits::LeBytes;
+use crate::memory::PAGE_SIZE;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Magic of the compressed dumps
+const MAGIC: &[u8; 8] = b"TRTFDUMP";
+/// Version of the container
+const VERSION: u32 = 2;
+/// Size of the header
+const HEADER_SIZE: usize = 40;
+/// Size of a page index entry
+const ENTRY_SIZE: usize = 16;
+
+/// Location and checksum of a page in the container
+#[derive(Copy, Clone, Debug, Default)]
+struct PageEntry {
+    /// Offset of the compressed block, 0 for the zero pages
+    offset: u64,
+    /// Size of the compressed block
+    size: u32,
+    /// CRC32 of the page content
+    checksum: u32,
+}
+
+impl PageEntry {
+    /// Serializes the entry
+    fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0u8; ENTRY_SIZE];
+        bytes[0..8].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.size.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Deserializes an entry
+    fn from_bytes(bytes: &[u8]) -> PageEntry {
+        PageEntry {
+            offset: bytes.u64_at(0),
+            size: bytes.u32_at(8),
+            checksum: bytes.u32_at(12),
+        }
+    }
+}
+
+/// Layout of the dump
+enum DumpFormat {
+    /// Flat file, read as is
+    Raw,
+    /// Compressed container
+    Compressed {
+        /// Page index
+        index: Vec<PageEntry>,
+        /// Last page decompressed
+        cache: Option<(usize, Vec<u8>)>,
+    },
+}
+
+/// Summary of `MemoryDump::compress`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Number of pages of the flat dump
+    pub pages: u64,
+    /// Pages holding only zeroes, not stored
+    pub zero_pages: u64,
+    /// Pages identical to an other one already stored
+    pub duplicate_pages: u64,
+    /// Size of the container
+    pub compressed_size: u64,
+}
+
+/// Snapshot memory dump, either a flat file or a compressed container
+/// (see `MemoryDump::compress`). The offsets are the ones of the flat dump
+/// given in the snapshot mappings.
+pub struct MemoryDump {
+    /// Dump file
+    file: File,
+    /// Size of the flat dump
+    size: u64,
+    /// Layout of the file
+    format: DumpFormat,
+}
+
+impl MemoryDump {
+    /// Opens a memory dump, the compressed containers being recognized by
+    /// their header. The index of a container is checked against its
+    /// checksum.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MemoryDump> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+
+        let mut header = [0u8; HEADER_SIZE];
+        let compressed = size >= HEADER_SIZE as u64 && {
+            file.read_exact(&mut header)?;
+            &header[..8] == MAGIC
+        };
+        if !compressed {
+            return Ok(MemoryDump {
+                file,
+                size,
+                format: DumpFormat::Raw,
+            });
+        }
+
+        if header.u32_at(8) != VERSION {
+            return Err(SnapshotError::ParsingError(format!(
+                "Unsupported dump version {}",
+                header.u32_at(8)
+            )));
+        }
+        let checksum = header.u32_at(12);
+        let dump_size = header.u64_at(16);
+        let pages = header.u64_at(24) as usize;
+        let index_offset = header.u64_at(32);
+
+        let index_end = index_offset.checked_add((pages * ENTRY_SIZE) as u64);
+        if pages as u64 != dump_size.div_ceil(PAGE_SIZE as u64) || index_end != Some(size) {
+            return Err(SnapshotError::CorruptedDump(0));
+        }
+
+        let mut index = vec![0u8; pages * ENTRY_SIZE];
+        file.seek(SeekFrom::Start(index_offset))
+            .and_then(|_| file.read_exact(&mut index))
+            .map_err(|_| SnapshotError::CorruptedDump(0))?;
+        if crc32fast::hash(&index) != checksum {
+            return Err(SnapshotError::CorruptedDump(0));
+        }
+
+        Ok(MemoryDump {
+            file,
+            size: dump_size,
+            format: DumpFormat::Compressed {
+                index: index
+                    .chunks(ENTRY_SIZE)
+                    .map(PageEntry::from_bytes)
+                    .collect(),
+                cache: None,
+            },
+        })
+    }
+
+    /// Returns the size of the flat dump
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns whether or not the dump is empty
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Returns whether or not the dump is a compressed container
+    #[inline]
+    pub fn is_compressed(&self) -> bool {
+        matches!(self.format, DumpFormat::Compressed { .. })
+    }
+
+    /// Reads the flat dump at `offset`, the bytes past its end being zero
+    /// filled. The pages of a container are checked against their checksum.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        buf.fill(0);
+        let available = self.size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let buf = &mut buf[..available];
+
+        let (index, cache) = match &mut self.format {
+            DumpFormat::Raw => {
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(buf)?;
+                return Ok(());
+            }
+            DumpFormat::Compressed { index, cache } => (index, cache),
+        };
+
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let page = (position / PAGE_SIZE as u64) as usize;
+            let page_offset = (position % PAGE_SIZE as u64) as usize;
+            let len = (PAGE_SIZE - page_offset).min(buf.len() - done);
+
+            let entry = index[page];
+            if entry.offset != 0 {
+                if cache.as_ref().map(|(cached, _)| *cached) != Some(page) {
+                    let data = read_page(&mut self.file, page, entry)?;
+                    *cache = Some((page, data));
+                }
+                let data = &cache.as_ref().unwrap().1;
+                buf[done..done + len].copy_from_slice(&data[page_offset..page_offset + len]);
+            }
+
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the checksums of all the pages of a container
+    pub fn verify(&mut self) -> Result<()> {
+        if let DumpFormat::Compressed { index, .. } = &self.format {
+            for (page, &entry) in index.iter().enumerate() {
+                if entry.offset != 0 {
+                    read_page(&mut self.file, page, entry)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Converts a flat memory dump to a compressed container, with the given
+    /// zstd compression level (0 for the default one)
+    pub fn compress<P: AsRef<Path>, Q: AsRef<Path>>(
+        memory_dump: P,
+        output: Q,
+        level: i32,
+    ) -> Result<CompressionStats> {
+        let mut dump = File::open(memory_dump)?;
+        let size = dump.metadata()?.len();
+        let mut out = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output)?;
+
+        let mut stats = CompressionStats {
+            pages: size.div_ceil(PAGE_SIZE as u64),
+            ..Default::default()
+        };
+        let mut index: Vec<PageEntry> = Vec::with_capacity(stats.pages as usize);
+        let mut blocks: HashMap<(u64, u32), Vec<PageEntry>> = HashMap::new();
+        let mut offset = HEADER_SIZE as u64;
+        let mut page = vec![0u8; PAGE_SIZE];
+
+        out.write_all(&[0u8; HEADER_SIZE])?;
+        for number in 0..stats.pages as usize {
+            page.fill(0);
+            let len = (size - (number * PAGE_SIZE) as u64).min(PAGE_SIZE as u64) as usize;
+            dump.read_exact(&mut page[..len])?;
+
+            let checksum = crc32fast::hash(&page);
+            if page.iter().all(|&b| b == 0) {
+                stats.zero_pages += 1;
+                index.push(PageEntry {
+                    checksum,
+                    ..Default::default()
+                });
+                continue;
+            }
+
+            // Identical pages share a block, the candidates with the same
+            // hashes being compared to rule out the collisions
+            let mut hasher = DefaultHasher::new();
+            hasher.write(&page);
+            let candidates = blocks.entry((hasher.finish(), checksum)).or_default();
+            let mut duplicate = None;
+            for &candidate in candidates.iter() {
+                if read_page(&mut out, number, candidate)? == page {
+                    duplicate = Some(candidate);
+                    break;
+                }
+            }
+            if let Some(entry) = duplicate {
+                stats.duplicate_pages += 1;
+                index.push(entry);
+                continue;
+            }
+
+            let block = zstd::bulk::compress(&page, level)?;
+            out.seek(SeekFrom::Start(offset))?;
+            out.write_all(&block)?;
+
+            let entry = PageEntry {
+                offset,
+                size: block.len() as u32,
+                checksum,
+            };
+            candidates.push(entry);
+            index.push(entry);
+            offset += block.len() as u64;
+        }
+
+        // Page index then header
+        let index: Vec<u8> = index.iter().flat_map(|entry| entry.to_bytes()).collect();
+        out.seek(SeekFrom::Start(offset))?;
+        out.write_all(&index)?;
+
+        let mut header = [0u8; HEADER_SIZE];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&crc32fast::hash(&index).to_le_bytes());
+        header[16..24].copy_from_slice(&size.to_le_bytes());
+        header[24..32].copy_from_slice(&stats.pages.to_le_bytes());
+        header[32..40].copy_from_slice(&offset.to_le_bytes());
+        out.seek(SeekFrom::Start(0))?;
+        out.write_all(&header)?;
+
+        stats.compressed_size = offset + index.len() as u64;
+        Ok(stats)
+    }
+
+    /// Writes the flat dump of a memory dump, e.g. to patch it with
+    /// `SnapshotInfo::redact` before compressing it again
+    pub fn decompress<P: AsRef<Path>>(&mut self, output: P) -> Result<()> {
+        let mut out = File::create(output)?;
+        let mut buf = vec![0u8; PAGE_SIZE];
+
+        for offset in (0..self.size).step_by(PAGE_SIZE) {
+            let len = (self.size - offset).min(PAGE_SIZE as u64) as usize;
+            self.read_at(offset, &mut buf[..len])?;
+            out.write_all(&buf[..len])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads and decompresses a page of a container, checking its checksum
+fn read_page(file: &mut File, page: usize, entry: PageEntry) -> Result<Vec<u8>> {
+    let corrupted = || SnapshotError::CorruptedDump((page * PAGE_SIZE) as u64);
+
+    let mut block = vec![0u8; entry.size as usize];
+    file.seek(SeekFrom::Start(entry.offset))
+        .and_then(|_| file.read_exact(&mut block))
+        .map_err(|_| corrupted())?;
+
+    match zstd::bulk::decompress(&block, PAGE_SIZE) {
+        Ok(data) if data.len() == PAGE_SIZE && crc32fast::hash(&data) == entry.checksum => Ok(data),
+        _ => Err(corrupted()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompressionStats, MemoryDump, HEADER_SIZE};
+    use crate::memory::PAGE_SIZE;
+    use crate::snapshot::SnapshotError;
+    use crate::testutil::TempPath;
+
+    use std::fs;
+
+    #[test]
+    fn test_compressed_dump() {
+        // Data page, zero page, duplicated data page and a truncated page
+        let mut data = vec![0u8; 3 * PAGE_SIZE + 0x100];
+        for (i, b) in data[..PAGE_SIZE].iter_mut().enumerate() {
+            *b = (i * 7 % 251) as u8;
+        }
+        data.copy_within(..PAGE_SIZE, 2 * PAGE_SIZE);
+        data[3 * PAGE_SIZE..].fill(0x41);
+
+        let raw = TempPath::new("raw");
+        let packed = TempPath::new("dump");
+        fs::write(&raw, &data).unwrap();
+
+        let stats = MemoryDump::compress(&raw, &packed, 0).expect("Could not compress");
+        let size = fs::metadata(&packed).unwrap().len();
+        assert_eq!(
+            stats,
+            CompressionStats {
+                pages: 4,
+                zero_pages: 1,
+                duplicate_pages: 1,
+                compressed_size: size,
+            }
+        );
+        assert!(size < data.len() as u64);
+
+        // Flat dumps are read as is
+        let dump = MemoryDump::open(&raw).expect("Could not open raw dump");
+        assert!(!dump.is_compressed());
+        assert_eq!(dump.len(), data.len() as u64);
+
+        // Random accesses across the pages, past the end zero filled
+        let mut dump = MemoryDump::open(&packed).expect("Could not open dump");
+        assert!(dump.is_compressed());
+        assert_eq!(dump.len(), data.len() as u64);
+        dump.verify().expect("Invalid checksums");
+
+        let mut buf = vec![0xffu8; 2 * PAGE_SIZE];
+        dump.read_at(PAGE_SIZE as u64 - 0x10, &mut buf).unwrap();
+        assert_eq!(buf, data[PAGE_SIZE - 0x10..3 * PAGE_SIZE - 0x10]);
+        dump.read_at(3 * PAGE_SIZE as u64 + 0x80, &mut buf[..0x100])
+            .unwrap();
+        assert!(buf[..0x80].iter().all(|&b| b == 0x41));
+        assert!(buf[0x80..0x100].iter().all(|&b| b == 0));
+
+        dump.decompress(&raw).unwrap();
+        assert_eq!(fs::read(&raw).unwrap(), data);
+
+        // Corrupted block, then corrupted index
+        let mut corrupted = fs::read(&packed).unwrap();
+        corrupted[HEADER_SIZE + 8] ^= 0xff;
+        fs::write(&packed, &corrupted).unwrap();
+        let mut dump = MemoryDump::open(&packed).expect("Index still valid");
+        assert_eq!(dump.verify(), Err(SnapshotError::CorruptedDump(0)));
+        assert_eq!(
+            dump.read_at(0x10, &mut buf[..0x10]),
+            Err(SnapshotError::CorruptedDump(0))
+        );
+        assert!(dump.read_at(PAGE_SIZE as u64, &mut buf[..0x10]).is_ok());
+
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        fs::write(&packed, &corrupted).unwrap();
+        let opened = MemoryDump::open(&packed).map(|_| ());
+
+        assert_eq!(opened, Err(SnapshotError::CorruptedDump(0)));
+    }
+}
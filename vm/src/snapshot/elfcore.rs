@@ -0,0 +1,363 @@
+//! ELF core dump snapshot loader
+
+use super::{
+    modules_from_mappings, registers_from_user_regs, xsave_from_fxsave, xsave_from_xstate, Result,
+    SnapshotError, SnapshotInfo, SnapshotMapping, SnapshotThread, USER_REGS_SIZE,
+};
+use crate::bits::{Alignement, LeBytes};
+use crate::elf::{
+    ElfHeader, NoteIterator, ProgramHeader, EHDR_SIZE, EM_X86_64, ET_CORE, NT_FILE, NT_FPREGSET,
+    NT_PRSTATUS, NT_X86_XSTATE, PHDR_SIZE, PT_LOAD, PT_NOTE,
+};
+use crate::memory::PAGE_SIZE;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Offset of `pr_pid` inside `elf_prstatus`
+const PRSTATUS_PID_OFFSET: usize = 32;
+/// Offset of `pr_reg` inside `elf_prstatus`
+const PRSTATUS_REGS_OFFSET: usize = 112;
+
+/// Register state of a thread of the core
+#[derive(Default)]
+struct ThreadState<'a> {
+    /// `elf_prstatus` descriptor
+    prstatus: Option<&'a [u8]>,
+    /// `user_fpregs_struct` descriptor
+    fpregs: Option<&'a [u8]>,
+    /// XSAVE area descriptor
+    xstate: Option<&'a [u8]>,
+}
+
+/// Read `size` bytes at `offset` in the core file
+fn read_at(file: &File, offset: u64, size: usize) -> Result<Vec<u8>> {
+    let truncated = || {
+        SnapshotError::ParsingError(format!(
+            "Truncated core file (0x{:x} bytes at 0x{:x})",
+            size, offset
+        ))
+    };
+
+    // The sizes come from the file, checked before allocating
+    let end = offset.checked_add(size as u64).ok_or_else(truncated)?;
+    if end > file.metadata()?.len() {
+        return Err(truncated());
+    }
+
+    let mut data = vec![0u8; size];
+    file.read_exact_at(&mut data, offset)
+        .map_err(|_| truncated())?;
+    Ok(data)
+}
+
+/// Parse a `NT_FILE` descriptor into (start, end, path) entries
+fn parse_files(desc: &[u8]) -> Result<Vec<(u64, u64, String)>> {
+    let mut files = Vec::new();
+
+    if desc.len() < 16 {
+        return Ok(files);
+    }
+
+    let count = desc.u64_at(0);
+    let names_start = count
+        .checked_mul(24)
+        .and_then(|size| size.checked_add(16))
+        .filter(|&start| start <= desc.len() as u64)
+        .ok_or_else(|| SnapshotError::ParsingError("Invalid NT_FILE count".to_string()))?
+        as usize;
+    let count = count as usize;
+
+    // File names are stored as consecutive NUL terminated strings
+    let names = desc[names_start..].split(|&c| c == 0);
+
+    for (i, name) in (0..count).zip(names) {
+        let entry = &desc[16 + i * 24..16 + (i + 1) * 24];
+        files.push((
+            entry.u64_at(0),
+            entry.u64_at(8),
+            String::from_utf8_lossy(name).into_owned(),
+        ));
+    }
+
+    Ok(files)
+}
+
+/// Create a new `SnapshotInfo` from an ELF core file
+pub fn load(path: &Path) -> Result<SnapshotInfo> {
+    let file = File::open(path)?;
+
+    // Parse and check the ELF header
+    let header = ElfHeader::parse(&read_at(&file, 0, EHDR_SIZE)?)
+        .ok_or_else(|| SnapshotError::ParsingError("Invalid ELF header".to_string()))?;
+
+    if header.e_type != ET_CORE || header.e_machine != EM_X86_64 {
+        return Err(SnapshotError::ParsingError(
+            "Not a x86_64 ELF core file".to_string(),
+        ));
+    }
+    if (header.e_phentsize as usize) < PHDR_SIZE {
+        return Err(SnapshotError::ParsingError(
+            "Invalid program header size".to_string(),
+        ));
+    }
+
+    // Parse the program headers
+    let phdrs_data = read_at(
+        &file,
+        header.e_phoff,
+        header.e_phnum as usize * header.e_phentsize as usize,
+    )?;
+    let phdrs: Vec<ProgramHeader> = phdrs_data
+        .chunks_exact(header.e_phentsize as usize)
+        .filter_map(ProgramHeader::parse)
+        .collect();
+
+    // Read the note segments
+    let notes_data = phdrs
+        .iter()
+        .filter(|p| p.p_type == PT_NOTE)
+        .map(|p| read_at(&file, p.p_offset, p.p_filesz as usize))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Collect the state of the threads and the mapped files, each thread
+    // starting with its NT_PRSTATUS note
+    let mut threads: Vec<ThreadState> = Vec::new();
+    let mut files = Vec::new();
+
+    for note in notes_data.iter().flat_map(|data| NoteIterator::new(data)) {
+        match (note.n_type, threads.last_mut()) {
+            (NT_PRSTATUS, _) => threads.push(ThreadState {
+                prstatus: Some(note.desc),
+                ..Default::default()
+            }),
+            (NT_FPREGSET, Some(thread)) => thread.fpregs = Some(note.desc),
+            (NT_X86_XSTATE, Some(thread)) if note.name == b"LINUX" => {
+                thread.xstate = Some(note.desc)
+            }
+            (NT_FILE, _) => files = parse_files(note.desc)?,
+            _ => {}
+        }
+    }
+
+    // Process the registers, the first thread being the current one
+    let mut threads = threads.iter().filter_map(|thread| {
+        let prstatus = thread
+            .prstatus
+            .filter(|p| p.len() >= PRSTATUS_REGS_OFFSET + USER_REGS_SIZE)?;
+
+        // Prefer the full XSAVE area over the legacy FXSAVE one
+        let xsave = match (thread.xstate, thread.fpregs) {
+            (Some(xstate), _) => Some(xsave_from_xstate(xstate)),
+            (None, Some(fpregs)) => Some(xsave_from_fxsave(fpregs)),
+            (None, None) => None,
+        };
+
+        Some(SnapshotThread {
+            tid: prstatus.u32_at(PRSTATUS_PID_OFFSET) as u64,
+            registers: registers_from_user_regs(&prstatus[PRSTATUS_REGS_OFFSET..], xsave),
+        })
+    });
+
+    let current = threads.next().ok_or_else(|| {
+        SnapshotError::ParsingError("Core file has no valid NT_PRSTATUS note".to_string())
+    })?;
+    let threads = threads.collect();
+
+    // Process the loadable segments
+    let mappings = phdrs
+        .iter()
+        .filter(|p| p.p_type == PT_LOAD && p.p_memsz != 0)
+        .map(|p| {
+            let start = p.p_vaddr.align_power2(PAGE_SIZE as u64);
+            let end = p
+                .p_vaddr
+                .checked_add(p.p_memsz)
+                .filter(|&end| end <= u64::MAX - PAGE_SIZE as u64)
+                .ok_or_else(|| SnapshotError::ParsingError("Invalid PT_LOAD range".to_string()))?
+                .align_up_power2(PAGE_SIZE as u64);
+
+            let image = files
+                .iter()
+                .find(|(s, e, _)| start >= *s && start < *e)
+                .map(|(_, _, name)| name.clone());
+
+            Ok(SnapshotMapping {
+                start,
+                end,
+                physical_offset: p.p_offset,
+                permissions: p.permissions(),
+                image,
+                // Segments not dumped by the kernel (or gcore) are zero filled
+                file_size: match p.p_filesz < end - start {
+                    true => Some(p.p_filesz),
+                    false => None,
+                },
+                data: Vec::new(),
+            })
+        })
+        .collect::<Result<Vec<SnapshotMapping>>>()?;
+
+    let modules = modules_from_mappings(&mappings);
+
+    Ok(SnapshotInfo {
+        mappings,
+        registers: current.registers,
+        tid: Some(current.tid),
+        threads,
+        modules,
+        symbols: BTreeMap::new(),
+        redactions: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, parse_files};
+    use crate::bits::LeBytes;
+    use crate::elf::{NT_FILE, NT_FPREGSET, NT_PRSTATUS};
+    use crate::memory::PAGE_SIZE;
+    use crate::testutil::TempPath;
+
+    /// Append a note to a note segment
+    fn push_note(notes: &mut Vec<u8>, n_type: u32, desc: &[u8]) {
+        notes.extend_from_slice(&5u32.to_le_bytes());
+        notes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        notes.extend_from_slice(&n_type.to_le_bytes());
+        notes.extend_from_slice(b"CORE\0\0\0\0");
+        notes.extend_from_slice(desc);
+        while !notes.len().is_multiple_of(4) {
+            notes.push(0);
+        }
+    }
+
+    /// Build a program header
+    fn phdr(p_type: u32, flags: u32, offset: u64, vaddr: u64, filesz: u64, memsz: u64) -> Vec<u8> {
+        let mut data = vec![0u8; 56];
+        data[0..4].copy_from_slice(&p_type.to_le_bytes());
+        data[4..8].copy_from_slice(&flags.to_le_bytes());
+        data[8..16].copy_from_slice(&offset.to_le_bytes());
+        data[16..24].copy_from_slice(&vaddr.to_le_bytes());
+        data[32..40].copy_from_slice(&filesz.to_le_bytes());
+        data[40..48].copy_from_slice(&memsz.to_le_bytes());
+        data
+    }
+
+    /// Build a core file with a thread, a mapped file and two segments
+    fn core() -> Vec<u8> {
+        // elf_prstatus with rip = 0x401000, rsp = 0x7ff000, fs_base = 0x1337
+        let mut prstatus = vec![0u8; 336];
+        prstatus[112 + 16 * 8..112 + 17 * 8].copy_from_slice(&0x401000u64.to_le_bytes());
+        prstatus[112 + 19 * 8..112 + 20 * 8].copy_from_slice(&0x7ff000u64.to_le_bytes());
+        prstatus[112 + 21 * 8..112 + 22 * 8].copy_from_slice(&0x1337u64.to_le_bytes());
+
+        // FXSAVE area with mxcsr = 0x1f80
+        let mut fpregs = vec![0u8; 512];
+        fpregs[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+
+        // A single mapped file covering the code segment
+        let mut files = Vec::new();
+        files.extend_from_slice(&1u64.to_le_bytes());
+        files.extend_from_slice(&(PAGE_SIZE as u64).to_le_bytes());
+        files.extend_from_slice(&0x400000u64.to_le_bytes());
+        files.extend_from_slice(&0x402000u64.to_le_bytes());
+        files.extend_from_slice(&0u64.to_le_bytes());
+        files.extend_from_slice(b"/usr/bin/target\0");
+
+        let mut notes = Vec::new();
+        push_note(&mut notes, NT_PRSTATUS, &prstatus);
+        push_note(&mut notes, NT_FPREGSET, &fpregs);
+        push_note(&mut notes, NT_FILE, &files);
+
+        // Layout: header | 3 phdrs | notes | page aligned segments data
+        let notes_offset = 64 + 3 * 56;
+        let data_offset = (notes_offset + notes.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        let mut data = vec![0u8; 64];
+        data[0..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2;
+        data[5] = 1;
+        data[16..18].copy_from_slice(&4u16.to_le_bytes());
+        data[18..20].copy_from_slice(&62u16.to_le_bytes());
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&3u16.to_le_bytes());
+
+        let page = PAGE_SIZE as u64;
+        let off = data_offset as u64;
+        data.extend(phdr(4, 0, notes_offset as u64, 0, notes.len() as u64, 0));
+        // Code segment (r-x) with a non dumped second page
+        data.extend(phdr(1, 5, off, 0x400000, page, 2 * page));
+        // Stack segment (rw-)
+        data.extend(phdr(1, 6, off + page, 0x7fe000, page, page));
+        data.extend(&notes);
+        data.resize(data_offset + 2 * PAGE_SIZE, 0x41);
+
+        data
+    }
+
+    #[test]
+    fn test_load_core() {
+        let data = core();
+        let page = PAGE_SIZE as u64;
+        let off = data.as_slice().u64_at(64 + 56 + 8);
+
+        let path = TempPath::new("core");
+        std::fs::write(&path, &data).unwrap();
+        let info = load(&path).expect("Could not parse core file");
+
+        assert_eq!(info.registers.rip, 0x401000);
+        assert_eq!(info.registers.rsp, 0x7ff000);
+        assert_eq!(info.registers.fs_base, 0x1337);
+
+        let xsave = info.registers.xsave.expect("Missing floating point state");
+        assert_eq!(xsave.as_slice().u32_at(24), 0x1f80);
+
+        assert_eq!(info.mappings.len(), 2);
+        assert_eq!(info.mappings[0].start, 0x400000);
+        assert_eq!(info.mappings[0].end, 0x402000);
+        assert_eq!(info.mappings[0].file_size, Some(page));
+        assert!(info.mappings[0].permissions.executable());
+        assert!(!info.mappings[0].permissions.writable());
+        assert_eq!(info.mappings[1].physical_offset, off + page);
+        assert_eq!(info.mappings[1].file_size, None);
+        assert!(info.mappings[1].permissions.writable());
+
+        let module = info.modules.get("target").expect("Missing module");
+        assert_eq!((module.start, module.end), (0x400000, 0x402000));
+    }
+
+    #[test]
+    fn test_load_core_overflow() {
+        let path = TempPath::new("core");
+
+        // The end of a crafted segment overflows once page aligned
+        for (vaddr, memsz) in [(0x7fe000, u64::MAX), (u64::MAX - 0x800, 0x100)] {
+            let mut data = core();
+            let phdr = 64 + 2 * 56;
+            data[phdr + 16..phdr + 24].copy_from_slice(&vaddr.to_le_bytes());
+            data[phdr + 40..phdr + 48].copy_from_slice(&memsz.to_le_bytes());
+
+            std::fs::write(&path, &data).unwrap();
+            assert!(load(&path).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_files_count() {
+        // The entries count of a crafted NT_FILE overflows its size
+        let mut files = Vec::new();
+        files.extend_from_slice(&(u64::MAX / 12).to_le_bytes());
+        files.extend_from_slice(&(PAGE_SIZE as u64).to_le_bytes());
+        files.extend_from_slice(&[0x41; 24]);
+        assert!(parse_files(&files).is_err());
+
+        files[0..8].copy_from_slice(&2u64.to_le_bytes());
+        assert!(parse_files(&files).is_err());
+
+        files[0..8].copy_from_slice(&1u64.to_le_bytes());
+        assert_eq!(parse_files(&files).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,749 @@
+//! Windows minidump (.dmp) snapshot loader
+
+use super::{
+    xsave_from_fxsave, Result, SnapshotData, SnapshotError, SnapshotInfo, SnapshotMapping,
+    SnapshotModule, SnapshotRegisters, SnapshotThread,
+};
+use crate::bits::{Alignement, LeBytes};
+use crate::memory::{PagePermissions, PAGE_SIZE};
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Minidump header signature ("MDMP")
+const MINIDUMP_SIGNATURE: u32 = 0x504d_444d;
+
+/// Stream containing the list of threads
+const THREAD_LIST_STREAM: u32 = 3;
+/// Stream containing the list of loaded modules
+const MODULE_LIST_STREAM: u32 = 4;
+/// Stream containing partial memory ranges
+const MEMORY_LIST_STREAM: u32 = 5;
+/// Stream containing the exception information
+const EXCEPTION_STREAM: u32 = 6;
+/// Stream containing full memory ranges
+const MEMORY64_LIST_STREAM: u32 = 9;
+/// Stream containing the memory regions information
+const MEMORY_INFO_LIST_STREAM: u32 = 16;
+
+/// Size of an AMD64 `CONTEXT` structure
+const CONTEXT_AMD64_SIZE: usize = 0x4d0;
+/// Size of a `MINIDUMP_THREAD` entry
+const THREAD_ENTRY_SIZE: usize = 48;
+/// Size of a `MINIDUMP_MODULE` entry
+const MODULE_ENTRY_SIZE: usize = 108;
+/// Size of a `MINIDUMP_MEMORY_DESCRIPTOR` entry
+const MEMORY_ENTRY_SIZE: usize = 16;
+/// Size of a `MINIDUMP_MEMORY_DESCRIPTOR64` entry
+const MEMORY64_ENTRY_SIZE: usize = 16;
+
+/// Context contains the floating point state
+const CONTEXT_FLOATING_POINT: u32 = 0x0010_0008;
+
+/// Memory region is committed
+const MEM_COMMIT: u32 = 0x1000;
+
+/// Memory region information (from the `MemoryInfoListStream`)
+struct MemoryInfo {
+    /// Starting address of the region
+    start: u64,
+    /// Ending address of the region (excluded)
+    end: u64,
+    /// Page permissions of the region
+    permissions: PagePermissions,
+}
+
+/// Returns the `size` bytes at `offset` of a stream, or a parsing error
+/// naming the truncated structure
+fn field<'a>(data: &'a [u8], offset: usize, size: usize, name: &str) -> Result<&'a [u8]> {
+    offset
+        .checked_add(size)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| SnapshotError::ParsingError(format!("Truncated {}", name)))
+}
+
+/// Returns the end of a `size` bytes range at `start`, or a parsing error
+/// if it overflows once page aligned
+fn range_end(start: u64, size: u64, name: &str) -> Result<u64> {
+    start
+        .checked_add(size)
+        .filter(|&end| end <= u64::MAX - PAGE_SIZE as u64)
+        .ok_or_else(|| SnapshotError::ParsingError(format!("Invalid {} range", name)))
+}
+
+/// Opened minidump file
+struct Minidump {
+    /// Minidump file
+    file: File,
+    /// Size of the minidump file, bounding the sizes read from it
+    size: u64,
+    /// Map of stream type to (rva, size)
+    streams: BTreeMap<u32, (u64, usize)>,
+}
+
+impl Minidump {
+    /// Open a minidump and read its stream directory
+    fn open(path: &Path) -> Result<Minidump> {
+        let file = File::open(path)?;
+        let mut dump = Minidump {
+            size: file.metadata()?.len(),
+            file,
+            streams: BTreeMap::new(),
+        };
+
+        // Check the header
+        let header = dump.read_at(0, 32)?;
+        if header.u32_at(0) != MINIDUMP_SIGNATURE {
+            return Err(SnapshotError::ParsingError(
+                "Invalid minidump signature".to_string(),
+            ));
+        }
+
+        let stream_count = header.u32_at(8) as usize;
+        let directory_rva = header.u32_at(12) as u64;
+
+        // Loop through the stream directory
+        let directory = dump.read_at(directory_rva, stream_count * 12)?;
+        for entry in directory.chunks_exact(12) {
+            let stream_type = entry.u32_at(0);
+            let size = entry.u32_at(4) as usize;
+            let rva = entry.u32_at(8) as u64;
+
+            // Keep the first occurrence of each stream
+            dump.streams.entry(stream_type).or_insert((rva, size));
+        }
+
+        Ok(dump)
+    }
+
+    /// Read `size` bytes at `offset` in the minidump file
+    fn read_at(&self, offset: u64, size: usize) -> Result<Vec<u8>> {
+        let truncated = || {
+            SnapshotError::ParsingError(format!(
+                "Truncated minidump (0x{:x} bytes at 0x{:x})",
+                size, offset
+            ))
+        };
+
+        // The sizes come from the file, checked before allocating
+        let end = offset.checked_add(size as u64).ok_or_else(truncated)?;
+        if end > self.size {
+            return Err(truncated());
+        }
+
+        let mut data = vec![0u8; size];
+        self.file
+            .read_exact_at(&mut data, offset)
+            .map_err(|_| truncated())?;
+        Ok(data)
+    }
+
+    /// Returns the raw data of a stream, if present
+    fn stream(&self, stream_type: u32) -> Result<Option<Vec<u8>>> {
+        match self.streams.get(&stream_type) {
+            Some(&(rva, size)) => Ok(Some(self.read_at(rva, size)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read a `MINIDUMP_STRING` (UTF-16) at `rva`
+    fn read_string(&self, rva: u64) -> Result<String> {
+        let length = self.read_at(rva, 4)?.u32_at(0) as usize;
+        let raw = self.read_at(range_end(rva, 4, "string")?, length)?;
+        let units: Vec<u16> = raw.chunks_exact(2).map(|c| c.u16_at(0)).collect();
+
+        Ok(String::from_utf16_lossy(&units))
+    }
+
+    /// Parse the memory regions permissions
+    fn memory_infos(&self) -> Result<Vec<MemoryInfo>> {
+        let mut infos = Vec::new();
+
+        let stream = match self.stream(MEMORY_INFO_LIST_STREAM)? {
+            Some(stream) => stream,
+            None => return Ok(infos),
+        };
+
+        let header = field(&stream, 0, 16, "memory info list")?;
+        let header_size = header.u32_at(0) as usize;
+        let entry_size = header.u32_at(4) as usize;
+        let count = header.u64_at(8);
+
+        // The entries must fit in the stream
+        let entries = stream.len().saturating_sub(header_size) / entry_size.max(48);
+        if count > entries as u64 {
+            return Err(SnapshotError::ParsingError(
+                "Invalid memory info list count".to_string(),
+            ));
+        }
+
+        // Loop through the memory info entries
+        for i in 0..count as usize {
+            let entry = field(
+                &stream,
+                header_size + i * entry_size,
+                48,
+                "memory info list",
+            )?;
+
+            let start = entry.u64_at(0);
+            let size = entry.u64_at(24);
+            let state = entry.u32_at(32);
+            let protect = entry.u32_at(36);
+
+            if state != MEM_COMMIT {
+                continue;
+            }
+
+            infos.push(MemoryInfo {
+                start,
+                end: range_end(start, size, "memory info")?,
+                permissions: protect_to_perms(protect),
+            });
+        }
+
+        Ok(infos)
+    }
+
+    /// Parse the memory ranges as (start, size, rva) triplets
+    fn memory_ranges(&self) -> Result<Vec<(u64, u64, u64)>> {
+        let mut ranges = Vec::new();
+
+        // Full memory dumps
+        if let Some(stream) = self.stream(MEMORY64_LIST_STREAM)? {
+            let header = field(&stream, 0, 16, "memory64 list")?;
+            let count = header.u64_at(0);
+            let mut rva = header.u64_at(8);
+
+            if count > ((stream.len() - 16) / MEMORY64_ENTRY_SIZE) as u64 {
+                return Err(SnapshotError::ParsingError(
+                    "Invalid memory64 list count".to_string(),
+                ));
+            }
+
+            for i in 0..count as usize {
+                let off = 16 + i * MEMORY64_ENTRY_SIZE;
+                let entry = field(&stream, off, MEMORY64_ENTRY_SIZE, "memory64 list")?;
+
+                let start = entry.u64_at(0);
+                let size = entry.u64_at(8);
+                range_end(start, size, "memory64")?;
+                ranges.push((start, size, rva));
+
+                // Memory64 data is stored contiguously from the base rva
+                rva = range_end(rva, size, "memory64")?;
+            }
+        }
+
+        // Partial memory dumps
+        if let Some(stream) = self.stream(MEMORY_LIST_STREAM)? {
+            let count = field(&stream, 0, 4, "memory list")?.u32_at(0) as usize;
+
+            for i in 0..count {
+                let off = 4 + i * MEMORY_ENTRY_SIZE;
+                let entry = field(&stream, off, MEMORY_ENTRY_SIZE, "memory list")?;
+
+                let start = entry.u64_at(0);
+                let size = entry.u32_at(8) as u64;
+                let rva = entry.u32_at(12) as u64;
+                let end = range_end(start, size, "memory")?;
+
+                // Skip ranges overlapping the ones already listed
+                if !ranges.iter().any(|&(s, sz, _)| start < s + sz && s < end) {
+                    ranges.push((start, size, rva));
+                }
+            }
+        }
+
+        // The memory of the ranges must be in the file
+        for &(_, size, rva) in ranges.iter() {
+            if range_end(rva, size, "memory")? > self.size {
+                return Err(SnapshotError::ParsingError(
+                    "Memory range outside of the minidump".to_string(),
+                ));
+            }
+        }
+
+        Ok(ranges)
+    }
+
+    /// Parse the loaded modules
+    fn modules(&self) -> Result<Vec<SnapshotModule>> {
+        let mut modules = Vec::new();
+
+        let stream = match self.stream(MODULE_LIST_STREAM)? {
+            Some(stream) => stream,
+            None => return Ok(modules),
+        };
+
+        let count = field(&stream, 0, 4, "module list")?.u32_at(0) as usize;
+
+        // Loop through the module entries
+        for i in 0..count {
+            let off = 4 + i * MODULE_ENTRY_SIZE;
+            let entry = field(&stream, off, MODULE_ENTRY_SIZE, "module list")?;
+
+            let start = entry.u64_at(0);
+            let size = entry.u32_at(8) as u64;
+            let name = self.read_string(entry.u32_at(20) as u64)?;
+
+            modules.push(SnapshotModule {
+                start,
+                end: range_end(start, size, "module")?,
+                name,
+            });
+        }
+
+        Ok(modules)
+    }
+
+    /// Parse the threads, the faulting one (or the first one) being returned
+    /// first
+    fn threads(&self) -> Result<(SnapshotThread, Vec<SnapshotThread>)> {
+        let threads = self.stream(THREAD_LIST_STREAM)?.ok_or_else(|| {
+            SnapshotError::ParsingError("Minidump has no thread list".to_string())
+        })?;
+
+        let count = field(&threads, 0, 4, "thread list")?.u32_at(0) as usize;
+        if count == 0 || (threads.len() - 4) / THREAD_ENTRY_SIZE < count {
+            return Err(SnapshotError::ParsingError(
+                "Invalid minidump thread list".to_string(),
+            ));
+        }
+
+        // Prefer the thread which raised the exception, if any
+        let thread_id = match self.stream(EXCEPTION_STREAM)? {
+            Some(exception) => Some(field(&exception, 0, 4, "exception")?.u32_at(0)),
+            None => None,
+        };
+
+        let entries: Vec<&[u8]> = threads[4..]
+            .chunks_exact(THREAD_ENTRY_SIZE)
+            .take(count)
+            .collect();
+        let current = entries
+            .iter()
+            .position(|e| Some(e.u32_at(0)) == thread_id)
+            .unwrap_or(0);
+
+        // The other threads are skipped if their context is not supported
+        let others = entries
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != current)
+            .filter_map(|(_, entry)| self.thread(entry).ok())
+            .collect();
+
+        Ok((self.thread(entries[current])?, others))
+    }
+
+    /// Parse the registers of a thread list entry
+    fn thread(&self, entry: &[u8]) -> Result<SnapshotThread> {
+        let teb = entry.u64_at(16);
+        let context_size = entry.u32_at(40) as usize;
+        let context_rva = entry.u32_at(44) as u64;
+
+        if context_size < CONTEXT_AMD64_SIZE {
+            return Err(SnapshotError::ParsingError(
+                "Unsupported thread context (not AMD64)".to_string(),
+            ));
+        }
+
+        let ctx = self.read_at(context_rva, CONTEXT_AMD64_SIZE)?;
+
+        // Legacy FXSAVE area (`FltSave`)
+        let xsave = match ctx.u32_at(0x30) & CONTEXT_FLOATING_POINT {
+            CONTEXT_FLOATING_POINT => Some(xsave_from_fxsave(&ctx[0x100..0x300])),
+            _ => None,
+        };
+
+        let registers = SnapshotRegisters {
+            rflags: ctx.u32_at(0x44) as u64,
+            rax: ctx.u64_at(0x78),
+            rcx: ctx.u64_at(0x80),
+            rdx: ctx.u64_at(0x88),
+            rbx: ctx.u64_at(0x90),
+            rsp: ctx.u64_at(0x98),
+            rbp: ctx.u64_at(0xa0),
+            rsi: ctx.u64_at(0xa8),
+            rdi: ctx.u64_at(0xb0),
+            r8: ctx.u64_at(0xb8),
+            r9: ctx.u64_at(0xc0),
+            r10: ctx.u64_at(0xc8),
+            r11: ctx.u64_at(0xd0),
+            r12: ctx.u64_at(0xd8),
+            r13: ctx.u64_at(0xe0),
+            r14: ctx.u64_at(0xe8),
+            r15: ctx.u64_at(0xf0),
+            rip: ctx.u64_at(0xf8),
+            // Windows x64 userland uses gs to point to the TEB
+            fs_base: 0,
+            gs_base: teb,
+            xsave,
+            msrs: BTreeMap::new(),
+        };
+
+        Ok(SnapshotThread {
+            tid: entry.u32_at(0) as u64,
+            registers,
+        })
+    }
+}
+
+/// Convert a Windows page protection to `PagePermissions`
+fn protect_to_perms(protect: u32) -> PagePermissions {
+    let mut perms = PagePermissions::new(0);
+
+    // Ignore modifiers (PAGE_GUARD, PAGE_NOCACHE, ...)
+    match protect & 0xff {
+        0x02 => perms.set_readable(true),
+        0x04 | 0x08 => {
+            perms.set_readable(true);
+            perms.set_writable(true);
+        }
+        0x10 | 0x20 => {
+            perms.set_readable(true);
+            perms.set_executable(true);
+        }
+        0x40 | 0x80 => {
+            perms.set_readable(true);
+            perms.set_writable(true);
+            perms.set_executable(true);
+        }
+        // PAGE_NOACCESS: keep the mapping readable, there is no non-present
+        // permission in `PagePermissions`
+        _ => perms.set_readable(true),
+    }
+
+    perms
+}
+
+/// Returns the basename of a Windows or Unix path
+fn basename(path: &str) -> &str {
+    path.rsplit(['\\', '/']).next().unwrap_or(path)
+}
+
+/// Create a new `SnapshotInfo` from a minidump file
+pub fn load(path: &Path) -> Result<SnapshotInfo> {
+    let dump = Minidump::open(path)?;
+
+    let infos = dump.memory_infos()?;
+    let modules = dump.modules()?;
+    let (current, threads) = dump.threads()?;
+
+    let mut mappings = Vec::new();
+
+    // Partial dumps (stacks) are not always page aligned, gather the ranges
+    // sharing pages in page aligned blocks
+    let mut ranges = dump.memory_ranges()?;
+    ranges.retain(|&(_, size, _)| size != 0);
+    ranges.sort_unstable();
+
+    let mut blocks: Vec<(u64, u64, Vec<SnapshotData>)> = Vec::new();
+    for (start, size, rva) in ranges {
+        let part = SnapshotData {
+            start,
+            end: start + size,
+            physical_offset: rva,
+        };
+        let block_start = start.align_power2(PAGE_SIZE as u64);
+        let block_end = part.end.align_up_power2(PAGE_SIZE as u64);
+
+        match blocks.last_mut() {
+            Some((_, end, parts)) if block_start < *end => {
+                *end = std::cmp::max(*end, block_end);
+                parts.push(part);
+            }
+            _ => blocks.push((block_start, block_end, vec![part])),
+        }
+    }
+
+    // Loop through the memory blocks
+    for (block_start, block_end, parts) in blocks {
+        // Split the block on the permission regions boundaries
+        let mut cursor = block_start;
+        while cursor < block_end {
+            let info = infos.iter().find(|i| cursor >= i.start && cursor < i.end);
+
+            let (end, permissions) = match info {
+                Some(info) => (
+                    std::cmp::min(info.end.align_up_power2(PAGE_SIZE as u64), block_end),
+                    info.permissions,
+                ),
+                // No information, use a conservative RW mapping
+                None => (block_end, PagePermissions::READ | PagePermissions::WRITE),
+            };
+
+            let image = modules
+                .iter()
+                .find(|m| cursor >= m.start && cursor < m.end)
+                .map(|m| m.name.clone());
+
+            // The padding up to the pages boundaries is zero filled
+            let data: Vec<SnapshotData> = parts
+                .iter()
+                .filter_map(|part| {
+                    let start = std::cmp::max(part.start, cursor);
+                    let part_end = std::cmp::min(part.end, end);
+                    (start < part_end).then(|| SnapshotData {
+                        start,
+                        end: part_end,
+                        physical_offset: part.physical_offset + (start - part.start),
+                    })
+                })
+                .collect();
+
+            let mapping = match data.as_slice() {
+                // Mapping fully backed by a single range
+                [part] if part.start == cursor && part.end == end => SnapshotMapping {
+                    start: cursor,
+                    end,
+                    physical_offset: part.physical_offset,
+                    permissions,
+                    image,
+                    file_size: None,
+                    data: Vec::new(),
+                },
+                _ => SnapshotMapping {
+                    start: cursor,
+                    end,
+                    physical_offset: 0,
+                    permissions,
+                    image,
+                    file_size: Some(0),
+                    data,
+                },
+            };
+            mappings.push(mapping);
+
+            cursor = end;
+        }
+    }
+
+    // Index modules by their basename
+    let modules = modules
+        .into_iter()
+        .map(|m| {
+            let name = basename(&m.name).to_string();
+            (
+                name.clone(),
+                SnapshotModule {
+                    start: m.start,
+                    end: m.end,
+                    name,
+                },
+            )
+        })
+        .collect();
+
+    Ok(SnapshotInfo {
+        mappings,
+        registers: current.registers,
+        tid: Some(current.tid),
+        threads,
+        modules,
+        symbols: BTreeMap::new(),
+        redactions: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, CONTEXT_AMD64_SIZE};
+    use crate::memory::PAGE_SIZE;
+    use crate::snapshot::{SnapshotData, SnapshotInfo};
+    use crate::testutil::TempPath;
+    use crate::vm::Vm;
+
+    /// Push a stream directory entry
+    fn push_stream(dir: &mut Vec<u8>, stream_type: u32, size: usize, rva: usize) {
+        dir.extend_from_slice(&stream_type.to_le_bytes());
+        dir.extend_from_slice(&(size as u32).to_le_bytes());
+        dir.extend_from_slice(&(rva as u32).to_le_bytes());
+    }
+
+    /// Build a minidump with a module, a thread and two pages of memory
+    fn minidump() -> Vec<u8> {
+        // Layout: header | directory | module name | module list |
+        //         thread list | context | memory64 list | memory
+        let mut data = vec![0u8; 0x1000];
+        let mut dir = Vec::new();
+
+        // Module name (UTF-16)
+        let name: Vec<u16> = "C:\\Windows\\target.exe".encode_utf16().collect();
+        let name_rva = 0x100;
+        data[name_rva..name_rva + 4].copy_from_slice(&((name.len() * 2) as u32).to_le_bytes());
+        for (i, c) in name.iter().enumerate() {
+            data[name_rva + 4 + i * 2..name_rva + 6 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+
+        // Module list
+        let modules_rva = 0x200;
+        data[modules_rva..modules_rva + 4].copy_from_slice(&1u32.to_le_bytes());
+        data[modules_rva + 4..modules_rva + 12].copy_from_slice(&0x140000000u64.to_le_bytes());
+        data[modules_rva + 12..modules_rva + 16].copy_from_slice(&0x2000u32.to_le_bytes());
+        data[modules_rva + 24..modules_rva + 28].copy_from_slice(&(name_rva as u32).to_le_bytes());
+        push_stream(&mut dir, 4, 4 + 108, modules_rva);
+
+        // Thread list
+        let threads_rva = 0x300;
+        let context_rva = 0x400;
+        data[threads_rva..threads_rva + 4].copy_from_slice(&1u32.to_le_bytes());
+        data[threads_rva + 20..threads_rva + 28].copy_from_slice(&0x7ff000u64.to_le_bytes());
+        data[threads_rva + 44..threads_rva + 48]
+            .copy_from_slice(&(CONTEXT_AMD64_SIZE as u32).to_le_bytes());
+        data[threads_rva + 48..threads_rva + 52]
+            .copy_from_slice(&(context_rva as u32).to_le_bytes());
+        push_stream(&mut dir, 3, 4 + 48, threads_rva);
+
+        // Thread context
+        data.resize(0x2000, 0);
+        data[context_rva + 0x44..context_rva + 0x48].copy_from_slice(&0x246u32.to_le_bytes());
+        data[context_rva + 0x78..context_rva + 0x80].copy_from_slice(&0x1337u64.to_le_bytes());
+        data[context_rva + 0xf8..context_rva + 0x100]
+            .copy_from_slice(&0x140001000u64.to_le_bytes());
+
+        // Memory64 list with two pages stored at 0x2000
+        let memory_rva = 0x2000;
+        let list_rva = 0x900;
+        data[list_rva..list_rva + 8].copy_from_slice(&1u64.to_le_bytes());
+        data[list_rva + 8..list_rva + 16].copy_from_slice(&(memory_rva as u64).to_le_bytes());
+        data[list_rva + 16..list_rva + 24].copy_from_slice(&0x140000000u64.to_le_bytes());
+        data[list_rva + 24..list_rva + 32].copy_from_slice(&(2 * PAGE_SIZE as u64).to_le_bytes());
+        push_stream(&mut dir, 9, 32, list_rva);
+        data.resize(memory_rva + 2 * PAGE_SIZE, 0x41);
+
+        // Header and directory
+        let dir_rva = 0x20;
+        data[0..4].copy_from_slice(b"MDMP");
+        data[8..12].copy_from_slice(&3u32.to_le_bytes());
+        data[12..16].copy_from_slice(&(dir_rva as u32).to_le_bytes());
+        data[dir_rva..dir_rva + dir.len()].copy_from_slice(&dir);
+
+        data
+    }
+
+    #[test]
+    fn test_load_minidump() {
+        let data = minidump();
+        let path = TempPath::new("dmp");
+        std::fs::write(&path, &data).unwrap();
+        let info = load(&path).expect("Could not parse minidump");
+
+        assert_eq!(info.registers.rax, 0x1337);
+        assert_eq!(info.registers.rip, 0x140001000);
+        assert_eq!(info.registers.rflags, 0x246);
+        assert_eq!(info.registers.gs_base, 0x7ff000);
+
+        assert_eq!(info.mappings.len(), 1);
+        assert_eq!(info.mappings[0].start, 0x140000000);
+        assert_eq!(info.mappings[0].end, 0x140002000);
+        assert_eq!(info.mappings[0].physical_offset, 0x2000);
+
+        let module = info.modules.get("target.exe").expect("Missing module");
+        assert_eq!(module.start, 0x140000000);
+        assert_eq!(module.end, 0x140002000);
+    }
+
+    #[test]
+    fn test_load_partial_ranges() {
+        let mut data = minidump();
+
+        // Memory list surrounded by unrelated data, with two ranges sharing
+        // a page and a range ending in the full memory
+        let list_rva = data.len();
+        data.resize(list_rva + 0x3000, 0x50);
+        let ranges: [(u64, u32, u32); 3] = [
+            (0x7ff100, 0x20, 0x100),
+            (0x7ff800, 0x1000, 0x200),
+            (0x13ffff800, 0x1000, 0x1200),
+        ];
+        data[list_rva..list_rva + 4].copy_from_slice(&3u32.to_le_bytes());
+        for (i, &(start, size, offset)) in ranges.iter().enumerate() {
+            let entry = list_rva + 4 + i * 16;
+            let rva = list_rva as u32 + offset;
+            data[entry..entry + 8].copy_from_slice(&start.to_le_bytes());
+            data[entry + 8..entry + 12].copy_from_slice(&size.to_le_bytes());
+            data[entry + 12..entry + 16].copy_from_slice(&rva.to_le_bytes());
+            data[rva as usize..(rva + size) as usize].fill(0x42 + i as u8);
+        }
+        data[8..12].copy_from_slice(&4u32.to_le_bytes());
+        push_stream(&mut data, 5, 4 + 3 * 16, list_rva);
+        let entry = data.split_off(data.len() - 12);
+        data[0x20 + 3 * 12..0x20 + 4 * 12].copy_from_slice(&entry);
+
+        let path = TempPath::new("dmp");
+        std::fs::write(&path, &data).unwrap();
+        let info = load(&path).expect("Could not parse minidump");
+
+        // The ranges sharing a page are mapped once, the overlapping one is
+        // skipped
+        assert_eq!(info.mappings.len(), 2);
+        let mapping = &info.mappings[0];
+        assert_eq!((mapping.start, mapping.end), (0x7ff000, 0x801000));
+        assert_eq!(
+            mapping.data,
+            [
+                SnapshotData {
+                    start: 0x7ff100,
+                    end: 0x7ff120,
+                    physical_offset: list_rva as u64 + 0x100,
+                },
+                SnapshotData {
+                    start: 0x7ff800,
+                    end: 0x800800,
+                    physical_offset: list_rva as u64 + 0x200,
+                },
+            ]
+        );
+        assert_eq!(info.mappings[1].start, 0x140000000);
+
+        let saved = SnapshotInfo::from_string(info.to_json()).unwrap();
+        assert_eq!(saved.mappings[0].data, mapping.data);
+
+        // The padding is zero filled rather than read from the file
+        let vm = Vm::from_snapshot_info(&info, &*path, 64 * PAGE_SIZE).unwrap();
+        let mut memory = vec![0u8; 0x2000];
+        vm.read(0x7ff000, &mut memory).unwrap();
+        for (offset, &byte) in memory.iter().enumerate() {
+            let expected = match offset {
+                0x100..=0x11f => 0x42,
+                0x800..=0x17ff => 0x43,
+                _ => 0,
+            };
+            assert_eq!(byte, expected, "Unexpected byte at {:#x}", offset);
+        }
+    }
+
+    #[test]
+    fn test_load_truncated_minidump() {
+        let data = minidump();
+        let path = TempPath::new("dmp");
+
+        // Truncated files and counts overflowing their stream are rejected
+        let mut forged = Vec::new();
+        for size in (0..0x1000).step_by(4).chain([data.len() - 1]) {
+            forged.push(data[..size].to_vec());
+        }
+        for offset in [0x8, 0x200, 0x300, 0x900] {
+            let mut data = data.clone();
+            data[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            forged.push(data);
+        }
+
+        // As are the streams too short for their header
+        for stream in 0..3 {
+            let mut data = data.clone();
+            let size = 0x20 + stream * 12 + 4;
+            data[size..size + 4].copy_from_slice(&2u32.to_le_bytes());
+            forged.push(data);
+        }
+
+        for data in forged {
+            std::fs::write(&path, &data).unwrap();
+            assert!(load(&path).is_err());
+        }
+    }
+}
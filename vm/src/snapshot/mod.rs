@@ -0,0 +1,583 @@
+mod container;
+mod elfcore;
+mod minidump;
+mod portability;
+mod process;
+mod redact;
+
+pub use container::{CompressionStats, MemoryDump};
+pub use portability::{HostDataKind, HostIdentity, PortabilityIssue};
+pub use redact::{Redaction, RedactionRule};
+
+use crate::bits::LeBytes;
+use crate::memory::PagePermissions;
+use serde::{de::Error, Deserialize};
+use serde_json::{json, Value};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Error during snapshot manipulation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// IO Error
+    IoError(String),
+    /// Parsing error
+    ParsingError(String),
+    /// Checksum mismatch in a compressed memory dump, at the given offset of
+    /// the flat dump
+    CorruptedDump(u64),
+}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(err: std::io::Error) -> Self {
+        SnapshotError::IoError(err.to_string())
+    }
+}
+
+/// Size of the XSAVE area handled by KVM
+const XSAVE_SIZE: usize = 4096;
+/// XSTATE_BV components fitting in the KVM XSAVE area (x87 to PKRU)
+const XSTATE_BV_MASK: u64 = 0x2ff;
+/// Size of the Linux `user_regs_struct`
+const USER_REGS_SIZE: usize = 27 * 8;
+
+/// Result type in snapshot manipulation
+type Result<T> = std::result::Result<T, SnapshotError>;
+
+/// Parse an unsigned 64 bits number in hex form
+fn parse_u64<'de, D>(d: D) -> std::result::Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: &str = Deserialize::deserialize(d)?;
+    u64::from_str_radix(s, 16).map_err(D::Error::custom)
+}
+
+/// Parse an optional unsigned 64 bits number in hex form
+fn parse_opt_u64<'de, D>(d: D) -> std::result::Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    parse_u64(d).map(Some)
+}
+
+/// Parse an optional byte buffer in hex form
+fn parse_opt_hex<'de, D>(d: D) -> std::result::Result<Option<Vec<u8>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: &str = Deserialize::deserialize(d)?;
+
+    if !s.len().is_multiple_of(2) {
+        return Err(D::Error::custom("odd length hex string"));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(D::Error::custom))
+        .collect::<std::result::Result<Vec<u8>, D::Error>>()
+        .map(Some)
+}
+
+/// Parse a map of model specific registers in hex form
+fn parse_msrs<'de, D>(d: D) -> std::result::Result<BTreeMap<u32, u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let msrs: BTreeMap<&str, &str> = Deserialize::deserialize(d)?;
+
+    msrs.iter()
+        .map(|(index, value)| {
+            Ok((
+                u32::from_str_radix(index, 16).map_err(D::Error::custom)?,
+                u64::from_str_radix(value, 16).map_err(D::Error::custom)?,
+            ))
+        })
+        .collect()
+}
+
+/// Parse permission in string form
+fn parse_perms<'de, D>(d: D) -> std::result::Result<PagePermissions, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: &str = Deserialize::deserialize(d)?;
+    let mut perms = PagePermissions::new(0);
+
+    // TODO: Proper checking instead of this hack
+    perms.set_readable(true); // No execute only in x64 iirc
+    perms.set_writable(s.contains('w'));
+    perms.set_executable(s.contains('x'));
+
+    Ok(perms)
+}
+
+/// Snapshot registers
+#[derive(Deserialize, Debug, Default)]
+pub struct SnapshotRegisters {
+    /// RAX
+    #[serde(deserialize_with = "parse_u64")]
+    pub rax: u64,
+    /// RBX
+    #[serde(deserialize_with = "parse_u64")]
+    pub rbx: u64,
+    /// RCX
+    #[serde(deserialize_with = "parse_u64")]
+    pub rcx: u64,
+    /// RDX
+    #[serde(deserialize_with = "parse_u64")]
+    pub rdx: u64,
+    /// RSI
+    #[serde(deserialize_with = "parse_u64")]
+    pub rsi: u64,
+    /// RDI
+    #[serde(deserialize_with = "parse_u64")]
+    pub rdi: u64,
+    /// RSP
+    #[serde(deserialize_with = "parse_u64")]
+    pub rsp: u64,
+    /// RBP
+    #[serde(deserialize_with = "parse_u64")]
+    pub rbp: u64,
+    /// R8
+    #[serde(deserialize_with = "parse_u64")]
+    pub r8: u64,
+    /// R9
+    #[serde(deserialize_with = "parse_u64")]
+    pub r9: u64,
+    /// R10
+    #[serde(deserialize_with = "parse_u64")]
+    pub r10: u64,
+    /// R11
+    #[serde(deserialize_with = "parse_u64")]
+    pub r11: u64,
+    /// R12
+    #[serde(deserialize_with = "parse_u64")]
+    pub r12: u64,
+    /// R13
+    #[serde(deserialize_with = "parse_u64")]
+    pub r13: u64,
+    /// R14
+    #[serde(deserialize_with = "parse_u64")]
+    pub r14: u64,
+    /// R15
+    #[serde(deserialize_with = "parse_u64")]
+    pub r15: u64,
+    /// RIP
+    #[serde(deserialize_with = "parse_u64")]
+    pub rip: u64,
+    /// RFLAGS
+    #[serde(deserialize_with = "parse_u64")]
+    pub rflags: u64,
+    /// FS BASE
+    #[serde(deserialize_with = "parse_u64")]
+    pub fs_base: u64,
+    /// GS BASE
+    #[serde(deserialize_with = "parse_u64")]
+    pub gs_base: u64,
+    /// Raw XSAVE area (standard format) holding the x87/SSE/AVX state
+    #[serde(default, deserialize_with = "parse_opt_hex")]
+    pub xsave: Option<Vec<u8>>,
+    /// Model specific registers (e.g. LSTAR or KERNEL_GS_BASE)
+    #[serde(default, deserialize_with = "parse_msrs")]
+    pub msrs: BTreeMap<u32, u64>,
+}
+
+/// Snapshot mapping
+#[derive(Deserialize, Debug)]
+pub struct SnapshotMapping {
+    /// Starting address
+    #[serde(deserialize_with = "parse_u64")]
+    pub start: u64,
+    /// Ending address (excluded)
+    #[serde(deserialize_with = "parse_u64")]
+    pub end: u64,
+    /// Offset in the binary dump
+    #[serde(deserialize_with = "parse_u64")]
+    pub physical_offset: u64,
+    /// Page permissions
+    #[serde(deserialize_with = "parse_perms")]
+    pub permissions: PagePermissions,
+    /// File image owning this mapping
+    pub image: Option<String>,
+    /// Number of bytes backed by the dump, the remaining ones being zero
+    /// filled (the whole mapping when absent)
+    #[serde(default, deserialize_with = "parse_opt_u64")]
+    pub file_size: Option<u64>,
+    /// Parts backed by the dump at their own offsets, replacing
+    /// `physical_offset` and `file_size` when not empty, the other bytes
+    /// being zero filled (e.g. the minidump memory ranges sharing pages)
+    #[serde(default)]
+    pub data: Vec<SnapshotData>,
+}
+
+/// Part of a mapping backed by the dump
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnapshotData {
+    /// Starting address
+    #[serde(deserialize_with = "parse_u64")]
+    pub start: u64,
+    /// Ending address (excluded)
+    #[serde(deserialize_with = "parse_u64")]
+    pub end: u64,
+    /// Offset in the binary dump
+    #[serde(deserialize_with = "parse_u64")]
+    pub physical_offset: u64,
+}
+
+impl SnapshotMapping {
+    /// Returns the parts of the mapping backed by the dump
+    pub fn backed_parts(&self) -> Vec<SnapshotData> {
+        if !self.data.is_empty() {
+            return self.data.clone();
+        }
+
+        let size = self.end.saturating_sub(self.start);
+        let size = self
+            .file_size
+            .map_or(size, |file_size| cmp::min(file_size, size));
+
+        vec![SnapshotData {
+            start: self.start,
+            end: self.start + size,
+            physical_offset: self.physical_offset,
+        }]
+    }
+}
+
+/// Register state of a thread
+#[derive(Deserialize, Debug)]
+pub struct SnapshotThread {
+    /// Thread identifier
+    #[serde(deserialize_with = "parse_u64")]
+    pub tid: u64,
+    /// Register state
+    pub registers: SnapshotRegisters,
+}
+
+/// Snapshot raw information contained in JSON form
+#[derive(Deserialize)]
+struct SnapshotInfoRaw {
+    /// List of all memory mappings
+    pub mappings: Vec<SnapshotMapping>,
+    /// Register state
+    pub registers: SnapshotRegisters,
+    /// Thread owning the register state
+    #[serde(default, deserialize_with = "parse_opt_u64")]
+    pub tid: Option<u64>,
+    /// Register state of the other threads
+    #[serde(default)]
+    pub threads: Vec<SnapshotThread>,
+    /// Map of symbols
+    pub symbols: Option<BTreeMap<String, String>>,
+    /// Memory ranges zeroed in the dump
+    #[serde(default)]
+    pub redactions: Vec<Redaction>,
+}
+
+/// Mapped code object
+#[derive(Debug)]
+pub struct SnapshotModule {
+    /// Starting address of the module
+    pub start: u64,
+    /// Ending address of the module (excluded)
+    pub end: u64,
+    /// Name of the loaded object
+    pub name: String,
+}
+
+/// Tartiflette snapshot info
+#[derive(Debug)]
+pub struct SnapshotInfo {
+    /// List of all memory mappings
+    pub mappings: Vec<SnapshotMapping>,
+    /// Register state
+    pub registers: SnapshotRegisters,
+    /// Thread owning the register state, if known
+    pub tid: Option<u64>,
+    /// Register state of the other threads
+    pub threads: Vec<SnapshotThread>,
+    /// List of named code modules
+    pub modules: BTreeMap<String, SnapshotModule>,
+    /// Map of symbols
+    pub symbols: BTreeMap<String, u64>,
+    /// Memory ranges zeroed in the dump (see `SnapshotInfo::redact`)
+    pub redactions: Vec<Redaction>,
+}
+
+impl SnapshotInfo {
+    /// Create a new `SnapshotInfo` instance from a snapshot path
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<SnapshotInfo> {
+        let contents = fs::read_to_string(path)?;
+        SnapshotInfo::from_string(contents)
+    }
+
+    /// Create a new `SnapshotInfo` instance from a Windows minidump.
+    ///
+    /// Mapping offsets point inside the minidump file itself, which can then
+    /// be used as the memory dump.
+    pub fn from_minidump<P: AsRef<Path>>(path: P) -> Result<SnapshotInfo> {
+        minidump::load(path.as_ref())
+    }
+
+    /// Create a new `SnapshotInfo` instance from an ELF core dump (`gcore`
+    /// output).
+    ///
+    /// Mapping offsets point inside the core file itself, which can then be
+    /// used as the memory dump.
+    pub fn from_elf_core<P: AsRef<Path>>(path: P) -> Result<SnapshotInfo> {
+        elfcore::load(path.as_ref())
+    }
+
+    /// Create a new `SnapshotInfo` instance from a live Linux process.
+    ///
+    /// The process is stopped with ptrace while its main thread registers and
+    /// readable mappings are collected, the memory is written to
+    /// `memory_dump`. File backed mappings are skipped if `anonymous_only` is
+    /// set.
+    pub fn from_pid<P: AsRef<Path>>(
+        pid: i32,
+        memory_dump: P,
+        anonymous_only: bool,
+    ) -> Result<SnapshotInfo> {
+        process::load(pid, memory_dump.as_ref(), anonymous_only)
+    }
+
+    /// Create a new `SnapshotInfo` from str data
+    pub fn from_string<S: AsRef<str>>(data: S) -> Result<SnapshotInfo> {
+        // Get a `SnapshotInfoRaw` from parsing
+        let info: SnapshotInfoRaw = serde_json::from_str(data.as_ref())
+            .map_err(|e| SnapshotError::ParsingError(e.to_string()))?;
+
+        // Process the symbols
+        let mut symbols: BTreeMap<String, u64> = BTreeMap::new();
+
+        // Handle symbols if present
+        if let Some(syms) = info.symbols {
+            // Loop through symbols
+            for (k, v) in syms.iter() {
+                // Convert hex address into integer
+                let address = u64::from_str_radix(v, 16)
+                    .map_err(|e| SnapshotError::ParsingError(e.to_string()))?;
+
+                // Add the symbol
+                symbols.insert(k.clone(), address);
+            }
+        }
+
+        // Process the modules
+        let modules = modules_from_mappings(&info.mappings);
+
+        // Return a new `SnapshotInfo`
+        Ok(SnapshotInfo {
+            mappings: info.mappings,
+            registers: info.registers,
+            tid: info.tid,
+            threads: info.threads,
+            modules: modules,
+            symbols: symbols,
+            redactions: info.redactions,
+        })
+    }
+
+    /// Returns the snapshot information in the JSON form read by
+    /// `SnapshotInfo::from_string`
+    pub fn to_json(&self) -> String {
+        let hex = |value: u64| Value::String(format!("{:x}", value));
+
+        let mappings: Vec<Value> = self
+            .mappings
+            .iter()
+            .map(|m| {
+                let mut mapping = json!({
+                    "start": hex(m.start),
+                    "end": hex(m.end),
+                    "physical_offset": hex(m.physical_offset),
+                    "permissions": format!(
+                        "r{}{}p",
+                        if m.permissions.writable() { 'w' } else { '-' },
+                        if m.permissions.executable() { 'x' } else { '-' }
+                    ),
+                });
+                if let Some(image) = m.image.as_ref() {
+                    mapping["image"] = json!(image);
+                }
+                if let Some(size) = m.file_size {
+                    mapping["file_size"] = hex(size);
+                }
+                if !m.data.is_empty() {
+                    let data: Vec<Value> = m
+                        .data
+                        .iter()
+                        .map(|d| {
+                            json!({
+                                "start": hex(d.start),
+                                "end": hex(d.end),
+                                "physical_offset": hex(d.physical_offset),
+                            })
+                        })
+                        .collect();
+                    mapping["data"] = json!(data);
+                }
+                mapping
+            })
+            .collect();
+
+        let threads: Vec<Value> = self
+            .threads
+            .iter()
+            .map(|t| json!({ "tid": hex(t.tid), "registers": registers_to_json(&t.registers) }))
+            .collect();
+
+        let symbols: BTreeMap<&str, Value> = self
+            .symbols
+            .iter()
+            .map(|(name, &address)| (name.as_str(), hex(address)))
+            .collect();
+
+        let redactions: Vec<Value> = self
+            .redactions
+            .iter()
+            .map(|r| json!({ "start": hex(r.start), "end": hex(r.end) }))
+            .collect();
+
+        let mut info = json!({
+            "mappings": mappings,
+            "registers": registers_to_json(&self.registers),
+            "threads": threads,
+            "symbols": symbols,
+            "redactions": redactions,
+        });
+        if let Some(tid) = self.tid {
+            info["tid"] = hex(tid);
+        }
+
+        info.to_string()
+    }
+
+    /// Writes the snapshot information to a file, in JSON form
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_json())?;
+        Ok(())
+    }
+}
+
+/// Returns registers in the JSON form read by `SnapshotInfo::from_string`
+fn registers_to_json(r: &SnapshotRegisters) -> Value {
+    let hex = |value: u64| Value::String(format!("{:x}", value));
+
+    let mut registers = json!({
+        "rax": hex(r.rax), "rbx": hex(r.rbx), "rcx": hex(r.rcx), "rdx": hex(r.rdx),
+        "rsi": hex(r.rsi), "rdi": hex(r.rdi), "rsp": hex(r.rsp), "rbp": hex(r.rbp),
+        "r8": hex(r.r8), "r9": hex(r.r9), "r10": hex(r.r10), "r11": hex(r.r11),
+        "r12": hex(r.r12), "r13": hex(r.r13), "r14": hex(r.r14), "r15": hex(r.r15),
+        "rip": hex(r.rip), "rflags": hex(r.rflags),
+        "fs_base": hex(r.fs_base), "gs_base": hex(r.gs_base),
+    });
+    if let Some(xsave) = r.xsave.as_ref() {
+        let data: String = xsave.iter().map(|b| format!("{:02x}", b)).collect();
+        registers["xsave"] = json!(data);
+    }
+    if !r.msrs.is_empty() {
+        let msrs: BTreeMap<String, Value> = r
+            .msrs
+            .iter()
+            .map(|(&index, &value)| (format!("{:x}", index), hex(value)))
+            .collect();
+        registers["msrs"] = json!(msrs);
+    }
+
+    registers
+}
+
+/// Build the modules list from the mappings images
+fn modules_from_mappings(mappings: &[SnapshotMapping]) -> BTreeMap<String, SnapshotModule> {
+    let mut modules: BTreeMap<String, SnapshotModule> = BTreeMap::new();
+
+    // Loop through mappings
+    for mapping in mappings.iter() {
+        if let Some(module_path) = mapping.image.as_deref() {
+            // Get the module name, equivalent ton path basename
+            let module_name = module_path.split("/").last().unwrap().to_string();
+
+            // Handle module
+            match modules.get_mut(&module_name) {
+                Some(module) => {
+                    // Update module mapping region
+                    module.start = cmp::min(module.start, mapping.start);
+                    module.end = cmp::max(module.end, mapping.end);
+                }
+                None => {
+                    // Add module
+                    modules.insert(
+                        module_name.clone(),
+                        SnapshotModule {
+                            start: mapping.start,
+                            end: mapping.end,
+                            name: module_name,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    modules
+}
+
+/// Build a standard format XSAVE area from a legacy FXSAVE area
+fn xsave_from_fxsave(fxsave: &[u8]) -> Vec<u8> {
+    let mut xsave = vec![0u8; XSAVE_SIZE];
+    let len = cmp::min(fxsave.len(), 512);
+    xsave[..len].copy_from_slice(&fxsave[..len]);
+
+    // XSTATE_BV: x87 and SSE states are valid
+    xsave[512..520].copy_from_slice(&0b11u64.to_le_bytes());
+
+    xsave
+}
+
+/// Build an XSAVE area from a Linux `NT_X86_XSTATE` regset
+fn xsave_from_xstate(xstate: &[u8]) -> Vec<u8> {
+    let mut xsave = vec![0u8; XSAVE_SIZE];
+    let len = cmp::min(xstate.len(), XSAVE_SIZE);
+    xsave[..len].copy_from_slice(&xstate[..len]);
+
+    // Drop the components which do not fit in the KVM area
+    let xstate_bv = xsave.u64_at(512) & XSTATE_BV_MASK;
+    xsave[512..520].copy_from_slice(&xstate_bv.to_le_bytes());
+
+    xsave
+}
+
+/// Build the registers from a Linux `user_regs_struct`
+fn registers_from_user_regs(user_regs: &[u8], xsave: Option<Vec<u8>>) -> SnapshotRegisters {
+    let reg = |index: usize| user_regs.u64_at(index * 8);
+
+    SnapshotRegisters {
+        r15: reg(0),
+        r14: reg(1),
+        r13: reg(2),
+        r12: reg(3),
+        rbp: reg(4),
+        rbx: reg(5),
+        r11: reg(6),
+        r10: reg(7),
+        r9: reg(8),
+        r8: reg(9),
+        rax: reg(10),
+        rcx: reg(11),
+        rdx: reg(12),
+        rsi: reg(13),
+        rdi: reg(14),
+        rip: reg(16),
+        rflags: reg(18),
+        rsp: reg(19),
+        fs_base: reg(21),
+        gs_base: reg(22),
+        xsave,
+        msrs: BTreeMap::new(),
+    }
+}
@@ -0,0 +1,402 @@
+//! Detection of the host specific data and features a snapshot relies on
+
+use super::redact::find_pattern;
+use super::{Result, SnapshotInfo, XSAVE_SIZE, XSTATE_BV_MASK};
+use crate::bits::LeBytes;
+use crate::vm::msr;
+
+use std::cmp;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Size of a page, the granularity of the vm mappings
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Directories holding the user files, unlikely to exist on an other host
+const USER_DIRECTORIES: &[&str] = &["/home/", "/root/", "/Users/"];
+
+/// Byte used to overwrite the host data in memory (keeping its length)
+const ANONYMIZED_BYTE: u8 = b'x';
+
+/// Host specific values searched in a snapshot
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostIdentity {
+    /// Host name
+    pub hostname: Option<String>,
+    /// User name
+    pub username: Option<String>,
+    /// Home directory of the user
+    pub home: Option<String>,
+    /// Environment variables
+    pub environment: Vec<(String, String)>,
+}
+
+impl HostIdentity {
+    /// Identity of the current host, to use on the capture machine
+    pub fn current() -> HostIdentity {
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .ok()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
+
+        HostIdentity {
+            hostname,
+            username: env::var("USER").ok(),
+            home: env::var("HOME").ok(),
+            environment: env::vars().collect(),
+        }
+    }
+
+    /// Byte strings searched in the snapshot memory
+    fn needles(&self) -> Vec<(HostDataKind, Vec<u8>)> {
+        let mut needles = Vec::new();
+
+        if let Some(hostname) = self.hostname.as_ref() {
+            needles.push((HostDataKind::Hostname, hostname.as_bytes().to_vec()));
+        }
+
+        // The root directory is not host specific
+        if let Some(home) = self.home.as_ref().filter(|home| home.len() > 1) {
+            needles.push((HostDataKind::HomeDirectory, home.as_bytes().to_vec()));
+        }
+
+        for (name, value) in self.environment.iter() {
+            if !value.is_empty() {
+                let entry = format!("{}={}", name, value);
+                needles.push((HostDataKind::Environment(name.clone()), entry.into_bytes()));
+            }
+        }
+
+        needles
+    }
+
+    /// Returns true if the path depends on the host or its user
+    fn is_host_path(&self, path: &str) -> bool {
+        if !path.starts_with('/') {
+            return false;
+        }
+
+        let values = [
+            self.hostname.as_deref(),
+            self.username.as_deref(),
+            self.home.as_deref().filter(|home| home.len() > 1),
+        ];
+
+        USER_DIRECTORIES.iter().any(|dir| path.starts_with(dir))
+            || values
+                .iter()
+                .flatten()
+                .any(|value| !value.is_empty() && path.contains(value))
+    }
+}
+
+/// Kind of host data found in the snapshot memory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostDataKind {
+    /// Host name
+    Hostname,
+    /// Home directory of the user
+    HomeDirectory,
+    /// Environment variable (`NAME=value` entry)
+    Environment(String),
+}
+
+/// Problem preventing a snapshot from being used on an other host
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortabilityIssue {
+    /// Mapping image with a host specific absolute path
+    HostPath {
+        /// Starting address of the mapping
+        start: u64,
+        /// Image path
+        image: String,
+    },
+    /// Host data in the snapshot memory
+    HostData {
+        /// Address of the data
+        address: u64,
+        /// Length of the data
+        length: usize,
+        /// Kind of data
+        kind: HostDataKind,
+    },
+    /// Mapping not aligned on pages
+    UnalignedMapping {
+        /// Starting address
+        start: u64,
+        /// Ending address (excluded)
+        end: u64,
+    },
+    /// Mapping overlapping the previous one
+    OverlappingMapping {
+        /// Starting address
+        start: u64,
+        /// Ending address (excluded)
+        end: u64,
+    },
+    /// Mapping data missing from the dump, zero filled when loaded
+    TruncatedMapping {
+        /// Starting address
+        start: u64,
+        /// Number of bytes backed by the dump
+        available: u64,
+    },
+    /// Model specific register not carried by the resets
+    UnsupportedMsr(u32),
+    /// XSAVE area larger than the KVM one
+    UnsupportedXsaveSize(usize),
+    /// XSAVE state components not handled by KVM
+    UnsupportedXstate(u64),
+}
+
+impl SnapshotInfo {
+    /// Checks the snapshot for host specific data (image paths, host name,
+    /// home directory and environment in memory) and for state Tartiflette
+    /// does not restore.
+    pub fn check_portability<P: AsRef<Path>>(
+        &self,
+        memory_dump: P,
+        host: &HostIdentity,
+    ) -> Result<Vec<PortabilityIssue>> {
+        let mut dump = OpenOptions::new().read(true).open(memory_dump)?;
+        let dump_size = dump.metadata()?.len();
+        let needles = host.needles();
+        let mut issues = Vec::new();
+        let mut previous_end = 0;
+
+        for mapping in self.mappings.iter() {
+            let start = mapping.start;
+            let end = mapping.end;
+
+            if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+                issues.push(PortabilityIssue::UnalignedMapping { start, end });
+            }
+
+            if start < previous_end {
+                issues.push(PortabilityIssue::OverlappingMapping { start, end });
+            }
+            previous_end = cmp::max(previous_end, end);
+
+            if let Some(image) = mapping.image.as_ref().filter(|i| host.is_host_path(i)) {
+                issues.push(PortabilityIssue::HostPath {
+                    start,
+                    image: image.clone(),
+                });
+            }
+
+            // Only the parts backed by the dump hold data
+            for part in mapping.backed_parts() {
+                let size = part.end.saturating_sub(part.start);
+                let available = cmp::min(size, dump_size.saturating_sub(part.physical_offset));
+
+                if available < size {
+                    issues.push(PortabilityIssue::TruncatedMapping {
+                        start: part.start,
+                        available,
+                    });
+                }
+
+                if needles.is_empty() || available == 0 {
+                    continue;
+                }
+
+                let mut data = vec![0u8; available as usize];
+                dump.seek(SeekFrom::Start(part.physical_offset))?;
+                dump.read_exact(&mut data)?;
+
+                let mut found = Vec::new();
+                for (kind, needle) in needles.iter() {
+                    for offset in find_pattern(&data, needle) {
+                        found.push(PortabilityIssue::HostData {
+                            address: part.start + offset as u64,
+                            length: needle.len(),
+                            kind: kind.clone(),
+                        });
+                    }
+                }
+
+                found.sort_by_key(|issue| match issue {
+                    PortabilityIssue::HostData { address, .. } => *address,
+                    _ => unreachable!(),
+                });
+                issues.extend(found);
+            }
+        }
+
+        for &index in self.registers.msrs.keys() {
+            if !msr::SNAPSHOT_MSRS.contains(&index) {
+                issues.push(PortabilityIssue::UnsupportedMsr(index));
+            }
+        }
+
+        if let Some(xsave) = self.registers.xsave.as_deref() {
+            if xsave.len() > XSAVE_SIZE {
+                issues.push(PortabilityIssue::UnsupportedXsaveSize(xsave.len()));
+            }
+
+            if xsave.len() >= 520 {
+                let components = xsave.u64_at(512) & !XSTATE_BV_MASK;
+                if components != 0 {
+                    issues.push(PortabilityIssue::UnsupportedXstate(components));
+                }
+            }
+        }
+
+        Ok(issues)
+    }
+
+    /// Rewrites the host specific data reported by `check_portability`: the
+    /// image paths are reduced to the file names and the data in memory is
+    /// overwritten in place (keeping its length). Returns the number of
+    /// issues rewritten, the other ones being left untouched.
+    pub fn anonymize<P: AsRef<Path>>(
+        &mut self,
+        memory_dump: P,
+        issues: &[PortabilityIssue],
+    ) -> Result<usize> {
+        let mut dump = OpenOptions::new().write(true).open(memory_dump)?;
+        let mut count = 0;
+
+        for issue in issues.iter() {
+            match issue {
+                PortabilityIssue::HostPath { start, image } => {
+                    let mapping = self
+                        .mappings
+                        .iter_mut()
+                        .find(|m| m.start == *start && m.image.as_ref() == Some(image));
+
+                    if let Some(mapping) = mapping {
+                        let name = image.rsplit('/').next().unwrap_or_default();
+                        mapping.image = Some(name.to_string());
+                        count += 1;
+                    }
+                }
+                PortabilityIssue::HostData {
+                    address, length, ..
+                } => {
+                    let mapping = self
+                        .mappings
+                        .iter()
+                        .find(|m| m.start <= *address && *address + *length as u64 <= m.end);
+
+                    if let Some(mapping) = mapping {
+                        let offset = mapping.physical_offset + (address - mapping.start);
+                        dump.seek(SeekFrom::Start(offset))?;
+                        dump.write_all(&vec![ANONYMIZED_BYTE; *length])?;
+                        count += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HostDataKind, HostIdentity, PortabilityIssue};
+    use crate::snapshot::SnapshotInfo;
+    use crate::testutil::TempPath;
+
+    use std::fs;
+
+    #[test]
+    fn test_check_portability() {
+        let info = r#"{
+            "mappings": [
+                {"start": "1000", "end": "3000", "physical_offset": "0", "permissions": "r-xp",
+                 "image": "/home/alice/fuzz/target"},
+                {"start": "7000", "end": "8000", "physical_offset": "2000", "permissions": "rw-p",
+                 "image": "/usr/lib/libc.so.6"},
+                {"start": "7800", "end": "9000", "physical_offset": "3000", "permissions": "rw-p"}
+            ],
+            "registers": {
+                "rax": "0", "rbx": "0", "rcx": "0", "rdx": "0", "rsi": "0", "rdi": "0",
+                "rsp": "0", "rbp": "0", "r8": "0", "r9": "0", "r10": "0", "r11": "0",
+                "r12": "0", "r13": "0", "r14": "0", "r15": "0", "rip": "1337",
+                "rflags": "202", "fs_base": "0", "gs_base": "0",
+                "msrs": {"c0000082": "401000", "c0000080": "d01"}
+            }
+        }"#;
+        let mut info = SnapshotInfo::from_string(info).expect("Could not parse info");
+
+        let mut data = vec![0u8; 0x3400];
+        data[0x100..0x10b].copy_from_slice(b"/home/alice");
+        data[0x2010..0x2017].copy_from_slice(b"capture");
+        data[0x2800..0x280e].copy_from_slice(b"TOKEN=hunter22");
+
+        let path = TempPath::new("port");
+        fs::write(&path, &data).unwrap();
+
+        let host = HostIdentity {
+            hostname: Some("captur".to_string()),
+            username: Some("alice".to_string()),
+            home: Some("/home/alice".to_string()),
+            environment: vec![
+                ("TOKEN".to_string(), "hunter22".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ],
+        };
+        let issues = info.check_portability(&path, &host).unwrap();
+
+        assert_eq!(
+            issues,
+            [
+                PortabilityIssue::HostPath {
+                    start: 0x1000,
+                    image: "/home/alice/fuzz/target".to_string()
+                },
+                PortabilityIssue::HostData {
+                    address: 0x1100,
+                    length: 11,
+                    kind: HostDataKind::HomeDirectory
+                },
+                PortabilityIssue::HostData {
+                    address: 0x7010,
+                    length: 6,
+                    kind: HostDataKind::Hostname
+                },
+                PortabilityIssue::HostData {
+                    address: 0x7800,
+                    length: 14,
+                    kind: HostDataKind::Environment("TOKEN".to_string())
+                },
+                PortabilityIssue::UnalignedMapping {
+                    start: 0x7800,
+                    end: 0x9000
+                },
+                PortabilityIssue::OverlappingMapping {
+                    start: 0x7800,
+                    end: 0x9000
+                },
+                PortabilityIssue::TruncatedMapping {
+                    start: 0x7800,
+                    available: 0x400
+                },
+                PortabilityIssue::UnsupportedMsr(0xc000_0080),
+            ]
+        );
+
+        // The rewritten snapshot is clean
+        assert_eq!(info.anonymize(&path, &issues), Ok(4));
+        let anonymized = fs::read(&path).unwrap();
+        assert_eq!(&anonymized[0x100..0x10b], b"xxxxxxxxxxx");
+        assert_eq!(&anonymized[0x2010..0x2017], b"xxxxxxe");
+        assert_eq!(info.mappings[0].image.as_deref(), Some("target"));
+        assert_eq!(
+            info.mappings[1].image.as_deref(),
+            Some("/usr/lib/libc.so.6")
+        );
+
+        let issues = info.check_portability(&path, &host).unwrap();
+
+        assert_eq!(issues.len(), 4);
+        assert!(issues
+            .iter()
+            .all(|issue| !matches!(issue, PortabilityIssue::HostData { .. })));
+    }
+}
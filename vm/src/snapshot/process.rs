@@ -0,0 +1,400 @@
+//! Live Linux process snapshot loader (procfs + ptrace)
+
+use super::{
+    modules_from_mappings, registers_from_user_regs, xsave_from_fxsave, xsave_from_xstate, Result,
+    SnapshotError, SnapshotInfo, SnapshotMapping, SnapshotRegisters, SnapshotThread,
+    USER_REGS_SIZE, XSAVE_SIZE,
+};
+use crate::elf::{NT_FPREGSET, NT_PRSTATUS, NT_X86_XSTATE};
+use crate::memory::{PagePermissions, PAGE_SIZE};
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::ptrace;
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::Pid;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Size of the legacy FXSAVE area (`user_fpregs_struct`)
+const FXSAVE_SIZE: usize = 512;
+
+/// Mapping described in `/proc/<pid>/maps`
+#[derive(Debug)]
+struct ProcMapping {
+    /// Starting address
+    start: u64,
+    /// Ending address (excluded)
+    end: u64,
+    /// Page permissions
+    permissions: PagePermissions,
+    /// Mapped file path or pseudo name (`[heap]`, `[stack]`...)
+    path: Option<String>,
+}
+
+impl ProcMapping {
+    /// Returns whether or not the mapping is backed by a file
+    fn file_backed(&self) -> bool {
+        self.path.as_deref().is_some_and(|p| p.starts_with('/'))
+    }
+}
+
+/// Parse the content of `/proc/<pid>/maps`
+fn parse_maps(maps: &str) -> Result<Vec<ProcMapping>> {
+    let invalid = |line: &str| SnapshotError::ParsingError(format!("Invalid maps line: {}", line));
+
+    maps.lines()
+        .map(|line| {
+            // start-end perms offset dev inode [path]
+            let mut fields = line.splitn(6, ' ');
+            let range = fields.next().ok_or_else(|| invalid(line))?;
+            let perms = fields.next().ok_or_else(|| invalid(line))?;
+            let path = fields.nth(3).map(str::trim_start).filter(|p| !p.is_empty());
+
+            let (start, end) = range.split_once('-').ok_or_else(|| invalid(line))?;
+            let start = u64::from_str_radix(start, 16).map_err(|_| invalid(line))?;
+            let end = u64::from_str_radix(end, 16).map_err(|_| invalid(line))?;
+
+            let mut permissions = PagePermissions::new(0);
+            permissions.set_readable(perms.contains('r'));
+            permissions.set_writable(perms.contains('w'));
+            permissions.set_executable(perms.contains('x'));
+
+            Ok(ProcMapping {
+                start,
+                end,
+                permissions,
+                path: path.map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Read a register set of a stopped thread, returns the number of bytes
+/// written by the kernel.
+fn get_regset(pid: Pid, n_type: u32, data: &mut [u8]) -> Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GETREGSET,
+            pid.as_raw(),
+            n_type as usize as *mut libc::c_void,
+            &mut iov as *mut libc::iovec,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(iov.iov_len)
+}
+
+/// Copy the readable mappings of the process to the memory dump
+fn dump_mappings(
+    pid: Pid,
+    maps: &[ProcMapping],
+    memory_dump: &Path,
+    anonymous_only: bool,
+) -> Result<Vec<SnapshotMapping>> {
+    let mem = File::open(format!("/proc/{}/mem", pid))?;
+    let mut dump = BufWriter::new(File::create(memory_dump)?);
+    let mut physical_offset = 0;
+    let mut mappings = Vec::new();
+    let mut buf = vec![0u8; PAGE_SIZE];
+
+    for map in maps.iter() {
+        // Guard pages and filtered out mappings
+        if !map.permissions.readable() || (anonymous_only && map.file_backed()) {
+            continue;
+        }
+
+        // Kernel provided pages like [vvar] or [vsyscall] cannot be read
+        if mem.read_exact_at(&mut buf, map.start).is_err() {
+            continue;
+        }
+
+        // Copy each page, the unreadable ones (e.g. beyond the end of a
+        // mapped file) are zero filled
+        for address in (map.start..map.end).step_by(PAGE_SIZE) {
+            if mem.read_exact_at(&mut buf, address).is_err() {
+                buf.fill(0);
+            }
+            dump.write_all(&buf)?;
+        }
+
+        // Only real files and the vdso are considered as images
+        let image = match map.path.as_deref() {
+            Some(path) if map.file_backed() || path == "[vdso]" => Some(path.to_string()),
+            _ => None,
+        };
+
+        mappings.push(SnapshotMapping {
+            start: map.start,
+            end: map.end,
+            physical_offset,
+            permissions: map.permissions,
+            image,
+            file_size: None,
+            data: Vec::new(),
+        });
+
+        physical_offset += map.end - map.start;
+    }
+
+    dump.flush()?;
+
+    Ok(mappings)
+}
+
+/// Read the registers of a stopped thread
+fn thread_registers(tid: Pid) -> Result<SnapshotRegisters> {
+    // Read the general purpose registers
+    let mut user_regs = vec![0u8; USER_REGS_SIZE];
+    if get_regset(tid, NT_PRSTATUS, &mut user_regs)? < USER_REGS_SIZE {
+        return Err(SnapshotError::ParsingError(
+            "Truncated general purpose registers".to_string(),
+        ));
+    }
+
+    // Read the floating point state, falling back to FXSAVE without XSAVE
+    let mut xstate = vec![0u8; XSAVE_SIZE];
+    let mut fpregs = vec![0u8; FXSAVE_SIZE];
+    let xsave = match get_regset(tid, NT_X86_XSTATE, &mut xstate) {
+        Ok(size) => Some(xsave_from_xstate(&xstate[..size])),
+        Err(_) => get_regset(tid, NT_FPREGSET, &mut fpregs)
+            .ok()
+            .map(|size| xsave_from_fxsave(&fpregs[..size])),
+    };
+
+    Ok(registers_from_user_regs(&user_regs, xsave))
+}
+
+/// Collect the snapshot of a process whose threads are all stopped
+fn snapshot(
+    pid: Pid,
+    tids: &[Pid],
+    memory_dump: &Path,
+    anonymous_only: bool,
+) -> Result<SnapshotInfo> {
+    let registers = thread_registers(pid)?;
+    let threads = tids
+        .iter()
+        .filter(|&&tid| tid != pid)
+        .map(|&tid| {
+            Ok(SnapshotThread {
+                tid: tid.as_raw() as u64,
+                registers: thread_registers(tid)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Dump the memory
+    let maps = parse_maps(&fs::read_to_string(format!("/proc/{}/maps", pid))?)?;
+    let mappings = dump_mappings(pid, &maps, memory_dump, anonymous_only)?;
+
+    let modules = modules_from_mappings(&mappings);
+
+    Ok(SnapshotInfo {
+        mappings,
+        registers,
+        tid: Some(pid.as_raw() as u64),
+        threads,
+        modules,
+        symbols: BTreeMap::new(),
+        redactions: Vec::new(),
+    })
+}
+
+/// Returns the error of a failed ptrace operation
+fn ptrace_error(e: nix::Error) -> SnapshotError {
+    SnapshotError::IoError(format!("ptrace: {}", e))
+}
+
+/// Stop every thread of the process, `attached` receiving the threads to
+/// detach. The threads list is read again until no new thread shows up, the
+/// ones spawned during the attach being stopped too.
+fn attach_threads(pid: Pid, attached: &mut Vec<Pid>) -> Result<()> {
+    loop {
+        let mut tids = Vec::new();
+        for entry in fs::read_dir(format!("/proc/{}/task", pid))? {
+            if let Some(tid) = entry?.file_name().to_str().and_then(|t| t.parse().ok()) {
+                tids.push(Pid::from_raw(tid));
+            }
+        }
+        tids.retain(|tid| !attached.contains(tid));
+
+        if tids.is_empty() {
+            return Ok(());
+        }
+
+        for tid in tids {
+            match ptrace::attach(tid) {
+                Ok(()) => attached.push(tid),
+                // The thread exited in the meantime
+                Err(Errno::ESRCH) if tid != pid => continue,
+                Err(e) => return Err(ptrace_error(e)),
+            }
+            waitpid(tid, Some(WaitPidFlag::__WALL)).map_err(ptrace_error)?;
+        }
+    }
+}
+
+/// Create a new `SnapshotInfo` from a live process, dumping its memory. All
+/// the threads are stopped while the memory is read, their registers
+/// recorded in `SnapshotInfo::threads`.
+pub fn load(pid: i32, memory_dump: &Path, anonymous_only: bool) -> Result<SnapshotInfo> {
+    let pid = Pid::from_raw(pid);
+
+    // Stop the process
+    let mut attached = Vec::new();
+    let info = attach_threads(pid, &mut attached)
+        .and_then(|()| snapshot(pid, &attached, memory_dump, anonymous_only));
+
+    // Let the process go, whatever the snapshot result
+    let mut detached = Ok(());
+    for tid in attached {
+        if let Err(e) = ptrace::detach(tid, None) {
+            detached = Err(ptrace_error(e));
+        }
+    }
+
+    let info = info?;
+    detached.map(|()| info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load, parse_maps};
+    use crate::testutil::TempPath;
+
+    use std::fs;
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_maps() {
+        let maps = "\
+55d0c0a00000-55d0c0a02000 r--p 00000000 08:01 1234    /usr/bin/cat with space
+55d0c2000000-55d0c2021000 rw-p 00000000 00:00 0       [heap]
+7ffd3c9fe000-7ffd3ca00000 r-xp 00000000 00:00 0       [vdso]
+7ffd3ca00000-7ffd3ca01000 ---p 00000000 00:00 0 ";
+
+        let maps = parse_maps(maps).expect("Could not parse maps");
+
+        assert_eq!(maps.len(), 4);
+        assert_eq!(maps[0].start, 0x55d0c0a00000);
+        assert_eq!(maps[0].end, 0x55d0c0a02000);
+        assert_eq!(maps[0].path.as_deref(), Some("/usr/bin/cat with space"));
+        assert!(maps[0].file_backed());
+        assert!(maps[1].permissions.writable());
+        assert!(!maps[1].file_backed());
+        assert!(maps[2].permissions.executable());
+        assert!(!maps[3].permissions.readable());
+        assert_eq!(maps[3].path, None);
+
+        assert!(parse_maps("not a mapping").is_err());
+    }
+
+    #[test]
+    fn test_load_process() {
+        let mut child = Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .expect("Could not spawn process");
+        let pid = child.id() as i32;
+
+        // Wait for the exec to complete
+        for _ in 0..100 {
+            if fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default() == "sleep\n" {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let path = TempPath::new("dump");
+        let info = load(pid, &path, false);
+        let anonymous = load(pid, &path, true);
+        let dump_size = fs::metadata(&path).map(|m| m.len()).ok();
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let info = info.expect("Could not snapshot process");
+        let anonymous = anonymous.expect("Could not snapshot process");
+
+        assert_ne!(info.registers.rip, 0);
+        assert_ne!(info.registers.rsp, 0);
+        assert!(info.registers.xsave.is_some());
+        assert!(info.modules.contains_key("sleep"));
+
+        // The file backed mappings are filtered out
+        assert!(anonymous.mappings.len() < info.mappings.len());
+        assert!(anonymous
+            .mappings
+            .iter()
+            .all(|m| m.image.is_none() || m.image.as_deref() == Some("[vdso]")));
+
+        // The mappings are laid out contiguously in the dump
+        let size: u64 = anonymous.mappings.iter().map(|m| m.end - m.start).sum();
+        assert_eq!(dump_size, Some(size));
+        assert!(anonymous
+            .mappings
+            .iter()
+            .any(|m| m.start <= anonymous.registers.rsp && anonymous.registers.rsp < m.end));
+    }
+
+    #[test]
+    #[ignore = "target of test_load_process_threads"]
+    fn sleeping_threads() {
+        let threads: Vec<_> = (0..2)
+            .map(|_| thread::spawn(|| thread::sleep(Duration::from_secs(10))))
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_load_process_threads() {
+        // The test binary running `sleeping_threads`
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--ignored",
+                "--exact",
+                "snapshot::process::tests::sleeping_threads",
+            ])
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Could not spawn process");
+        let pid = child.id() as i32;
+
+        // Wait for the threads to be spawned
+        let task = format!("/proc/{}/task", pid);
+        for _ in 0..100 {
+            if fs::read_dir(&task).map(|d| d.count()).unwrap_or(0) >= 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let path = TempPath::new("dump");
+        let info = load(pid, &path, true);
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        // Every thread is stopped and its registers recorded
+        let info = info.expect("Could not snapshot process");
+        assert_eq!(info.tid, Some(pid as u64));
+        assert!(info.threads.len() >= 2);
+        assert!(info.threads.iter().all(|t| t.tid != pid as u64));
+        assert!(info.threads.iter().all(|t| t.registers.rip != 0));
+    }
+}
@@ -0,0 +1,198 @@
+//! Redaction of sensitive memory from the snapshot dumps
+
+use super::{parse_u64, Result, SnapshotInfo};
+
+use serde::Deserialize;
+use std::cmp;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Memory to zero in a snapshot dump
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RedactionRule {
+    /// Virtual address range
+    Range {
+        /// Starting address
+        start: u64,
+        /// Ending address (excluded)
+        end: u64,
+    },
+    /// Every occurrence of a byte pattern (e.g. a key or token prefix)
+    Pattern {
+        /// Bytes searched in the mappings
+        pattern: Vec<u8>,
+        /// Number of bytes zeroed from the start of each occurrence
+        length: usize,
+    },
+}
+
+/// Memory range zeroed in the dump, recorded in the snapshot info
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Redaction {
+    /// Starting address
+    #[serde(deserialize_with = "parse_u64")]
+    pub start: u64,
+    /// Ending address (excluded)
+    #[serde(deserialize_with = "parse_u64")]
+    pub end: u64,
+}
+
+/// Returns the start of each occurrence of `pattern` in `data`
+pub(super) fn find_pattern<'a>(
+    data: &'a [u8],
+    pattern: &'a [u8],
+) -> impl Iterator<Item = usize> + 'a {
+    data.windows(pattern.len().max(1))
+        .enumerate()
+        .filter(move |(_, window)| !pattern.is_empty() && *window == pattern)
+        .map(|(offset, _)| offset)
+}
+
+impl SnapshotInfo {
+    /// Zeroes the memory matching the rules in the dump of the snapshot, and
+    /// records the ranges zeroed. Returns the number of ranges added.
+    pub fn redact<P: AsRef<Path>>(
+        &mut self,
+        memory_dump: P,
+        rules: &[RedactionRule],
+    ) -> Result<usize> {
+        let mut dump = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(memory_dump)?;
+        let mut redactions: Vec<Redaction> = Vec::new();
+
+        // Only the parts backed by the dump hold data
+        for part in self.mappings.iter().flat_map(|m| m.backed_parts()) {
+            let size = part.end - part.start;
+            let end = part.end;
+            let mut data: Option<Vec<u8>> = None;
+            let mut ranges: Vec<(u64, u64)> = Vec::new();
+
+            for rule in rules.iter() {
+                match rule {
+                    RedactionRule::Range { start: s, end: e } => {
+                        ranges.push((cmp::max(*s, part.start), cmp::min(*e, end)));
+                    }
+                    RedactionRule::Pattern { pattern, length } => {
+                        // Read the mapping once for all the patterns
+                        if data.is_none() {
+                            let mut buf = vec![0u8; size as usize];
+                            dump.seek(SeekFrom::Start(part.physical_offset))?;
+                            dump.read_exact(&mut buf)?;
+                            data = Some(buf);
+                        }
+
+                        for offset in find_pattern(data.as_deref().unwrap(), pattern) {
+                            let start = part.start + offset as u64;
+                            ranges.push((start, cmp::min(start + *length as u64, end)));
+                        }
+                    }
+                }
+            }
+
+            // Zero the ranges, merging the overlapping ones
+            ranges.retain(|(start, end)| start < end);
+            ranges.sort_unstable();
+
+            let mut merged: Vec<Redaction> = Vec::new();
+            for (start, end) in ranges {
+                match merged.last_mut() {
+                    Some(last) if start <= last.end => last.end = cmp::max(last.end, end),
+                    _ => merged.push(Redaction { start, end }),
+                }
+            }
+
+            for redaction in merged.iter() {
+                let offset = part.physical_offset + (redaction.start - part.start);
+                dump.seek(SeekFrom::Start(offset))?;
+                dump.write_all(&vec![0u8; (redaction.end - redaction.start) as usize])?;
+            }
+
+            redactions.extend(merged);
+        }
+
+        let count = redactions.len();
+        self.redactions.extend(redactions);
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Redaction, RedactionRule};
+    use crate::snapshot::SnapshotInfo;
+    use crate::testutil::TempPath;
+
+    use std::fs;
+
+    #[test]
+    fn test_redact() {
+        let info = r#"{
+            "mappings": [
+                {"start": "1000", "end": "2000", "physical_offset": "0", "permissions": "rw-p"},
+                {"start": "5000", "end": "7000", "physical_offset": "1000", "permissions": "rw-p",
+                 "file_size": "1000"}
+            ],
+            "registers": {
+                "rax": "0", "rbx": "0", "rcx": "0", "rdx": "0", "rsi": "0", "rdi": "0",
+                "rsp": "0", "rbp": "0", "r8": "0", "r9": "0", "r10": "0", "r11": "0",
+                "r12": "0", "r13": "0", "r14": "0", "r15": "0", "rip": "1337",
+                "rflags": "202", "fs_base": "0", "gs_base": "0"
+            }
+        }"#;
+        let mut info = SnapshotInfo::from_string(info).expect("Could not parse info");
+
+        let mut data = vec![0x41u8; 0x2000];
+        data[0x1100..0x1108].copy_from_slice(b"token=42");
+        data[0x1ffc..0x2000].copy_from_slice(b"toke");
+
+        let path = TempPath::new("redact");
+        fs::write(&path, &data).unwrap();
+
+        let rules = [
+            RedactionRule::Range {
+                start: 0x1800,
+                end: 0x5010,
+            },
+            RedactionRule::Pattern {
+                pattern: b"token=".to_vec(),
+                length: 8,
+            },
+        ];
+        let count = info.redact(&path, &rules);
+        let redacted = fs::read(&path).unwrap();
+
+        assert_eq!(count, Ok(3));
+        assert_eq!(
+            info.redactions,
+            [
+                Redaction {
+                    start: 0x1800,
+                    end: 0x2000
+                },
+                Redaction {
+                    start: 0x5000,
+                    end: 0x5010
+                },
+                Redaction {
+                    start: 0x5100,
+                    end: 0x5108
+                },
+            ]
+        );
+
+        assert!(redacted[..0x800].iter().all(|&b| b == 0x41));
+        assert!(redacted[0x800..0x1010].iter().all(|&b| b == 0));
+        assert!(redacted[0x1100..0x1108].iter().all(|&b| b == 0));
+        assert_eq!(&redacted[0x1ffc..], b"toke");
+
+        // The redactions are saved along with the snapshot
+        let saved = SnapshotInfo::from_string(info.to_json()).expect("Could not parse saved info");
+        assert_eq!(saved.redactions, info.redactions);
+        assert_eq!(saved.mappings[1].file_size, Some(0x1000));
+        assert_eq!(saved.registers.rip, 0x1337);
+    }
+}
@@ -0,0 +1,608 @@
+//! Guest symbols resolution
+
+use crate::elf;
+use crate::snapshot::SnapshotInfo;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Error during symbols manipulation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SymbolsError {
+    /// IO Error
+    IoError(String),
+    /// Parsing error
+    ParsingError(String),
+    /// The module is not known
+    UnknownModule(String),
+    /// The symbol could not be resolved
+    UnknownSymbol(String),
+    /// No code is associated with the source line
+    UnknownLine(String),
+}
+
+impl From<std::io::Error> for SymbolsError {
+    fn from(err: std::io::Error) -> Self {
+        SymbolsError::IoError(err.to_string())
+    }
+}
+
+/// Result type in symbols manipulation
+type Result<T> = std::result::Result<T, SymbolsError>;
+
+/// Loaded module and its symbols
+#[derive(Debug, Clone)]
+pub struct SymbolModule {
+    /// Name of the module
+    pub name: String,
+    /// Starting address of the module
+    pub start: u64,
+    /// Ending address of the module (excluded)
+    pub end: u64,
+    /// Map of symbol names to their address
+    symbols: BTreeMap<String, u64>,
+    /// Map of symbol addresses to their name (the first one by name)
+    addresses: BTreeMap<u64, String>,
+    /// Map of source files to their lines addresses
+    lines: BTreeMap<String, BTreeMap<u32, Vec<u64>>>,
+}
+
+impl SymbolModule {
+    /// Returns whether or not an address is inside the module
+    #[inline]
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start && address < self.end
+    }
+
+    /// Returns the address of a symbol of the module
+    #[inline]
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Returns an iterator over the module symbols
+    #[inline]
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, u64)> + '_ {
+        self.symbols.iter().map(|(k, v)| (k.as_str(), *v))
+    }
+
+    /// Returns the closest symbol at or before an address of the module, and
+    /// its address
+    pub fn symbol_at(&self, address: u64) -> Option<(&str, u64)> {
+        if !self.contains(address) {
+            return None;
+        }
+
+        self.addresses
+            .range(..=address)
+            .next_back()
+            .map(|(&start, name)| (name.as_str(), start))
+    }
+
+    /// Adds a symbol, replacing its previous address
+    fn insert_symbol(&mut self, name: String, address: u64) {
+        if let Some(previous) = self.symbols.insert(name.clone(), address) {
+            if self.addresses.get(&previous) == Some(&name) {
+                self.addresses.remove(&previous);
+            }
+        }
+
+        match self.addresses.get(&address) {
+            Some(alias) if *alias <= name => {}
+            _ => {
+                self.addresses.insert(address, name);
+            }
+        }
+    }
+}
+
+/// Symbols of the guest, grouped by module
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    /// Map of module names to modules
+    modules: BTreeMap<String, SymbolModule>,
+    /// Symbols without module information
+    globals: BTreeMap<String, u64>,
+}
+
+impl Symbols {
+    /// Create a new empty `Symbols` instance
+    pub fn new() -> Self {
+        Symbols::default()
+    }
+
+    /// Create a new `Symbols` instance from the snapshot modules and symbols
+    pub fn from_snapshot(info: &SnapshotInfo) -> Self {
+        let mut symbols = Symbols::new();
+
+        // Add the modules
+        for module in info.modules.values() {
+            symbols.add_module(&module.name, module.start, module.end);
+        }
+
+        // Add the snapshot symbols to their owning module
+        for (name, &address) in info.symbols.iter() {
+            match symbols.modules.values_mut().find(|m| m.contains(address)) {
+                Some(module) => module.insert_symbol(name.clone(), address),
+                None => {
+                    symbols.globals.insert(name.clone(), address);
+                }
+            }
+        }
+
+        symbols
+    }
+
+    /// Adds a module (keeps its symbols if already present)
+    pub fn add_module(&mut self, name: &str, start: u64, end: u64) {
+        let module = self
+            .modules
+            .entry(name.to_string())
+            .or_insert_with(|| SymbolModule {
+                name: name.to_string(),
+                start,
+                end,
+                symbols: BTreeMap::new(),
+                addresses: BTreeMap::new(),
+                lines: BTreeMap::new(),
+            });
+
+        module.start = start;
+        module.end = end;
+    }
+
+    /// Adds a symbol to a module
+    pub fn add_symbol(&mut self, module: &str, name: &str, address: u64) -> Result<()> {
+        let module = self
+            .modules
+            .get_mut(module)
+            .ok_or_else(|| SymbolsError::UnknownModule(module.to_string()))?;
+
+        module.insert_symbol(name.to_string(), address);
+        Ok(())
+    }
+
+    /// Adds the address of a source line to a module
+    pub fn add_line(&mut self, module: &str, file: &str, line: u32, address: u64) -> Result<()> {
+        let module = self
+            .modules
+            .get_mut(module)
+            .ok_or_else(|| SymbolsError::UnknownModule(module.to_string()))?;
+
+        let addresses = module
+            .lines
+            .entry(file.to_string())
+            .or_default()
+            .entry(line)
+            .or_default();
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+
+        Ok(())
+    }
+
+    /// Adds the modules of a process memory map (the `/proc/<pid>/maps`
+    /// format), named after their file and spanning all their mappings.
+    /// Returns the number of modules added.
+    pub fn add_maps(&mut self, maps: &str) -> usize {
+        let mut ranges: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+
+        for line in maps.lines() {
+            // start-end perms offset dev inode [path], the path may hold spaces
+            let mut fields = line.splitn(6, ' ');
+            let range = fields.next().and_then(|range| range.split_once('-'));
+            let path = fields.nth(4).map(str::trim_start);
+
+            let (start, end, path) = match (range, path) {
+                (Some((start, end)), Some(path)) if path.starts_with('/') => {
+                    match (u64::from_str_radix(start, 16), u64::from_str_radix(end, 16)) {
+                        (Ok(start), Ok(end)) => (start, end, path),
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+
+            let name = path.rsplit('/').next().unwrap_or(path);
+            let range = ranges.entry(name).or_insert((start, end));
+            range.0 = range.0.min(start);
+            range.1 = range.1.max(end);
+        }
+
+        for (name, &(start, end)) in ranges.iter() {
+            self.add_module(name, start, end);
+        }
+
+        ranges.len()
+    }
+
+    /// Loads the symbols of an ELF file for a module, returns the number of
+    /// symbols loaded.
+    pub fn load_elf<P: AsRef<Path>>(&mut self, module: &str, path: P) -> Result<usize> {
+        let data = fs::read(path)?;
+        self.load_elf_data(module, &data)
+    }
+
+    /// Loads the symbols of an in-memory ELF image for a module, returns the
+    /// number of symbols loaded.
+    pub fn load_elf_data(&mut self, module: &str, data: &[u8]) -> Result<usize> {
+        let symbols = elf::symbols(data)
+            .ok_or_else(|| SymbolsError::ParsingError("Invalid ELF sections".to_string()))?;
+
+        let module = self
+            .modules
+            .get_mut(module)
+            .ok_or_else(|| SymbolsError::UnknownModule(module.to_string()))?;
+        let bias = elf_bias(module, data)?;
+
+        let count = symbols.len();
+        for symbol in symbols {
+            module.insert_symbol(symbol.name, symbol.value.wrapping_add(bias));
+        }
+
+        Ok(count)
+    }
+
+    /// Loads the DWARF line tables of an ELF file for a module, returns the
+    /// number of lines loaded.
+    #[cfg(feature = "dwarf")]
+    pub fn load_dwarf<P: AsRef<Path>>(&mut self, module: &str, path: P) -> Result<usize> {
+        let data = fs::read(path)?;
+        self.load_dwarf_data(module, &data)
+    }
+
+    /// Loads the DWARF line tables of an in-memory ELF image for a module,
+    /// returns the number of lines loaded.
+    #[cfg(feature = "dwarf")]
+    pub fn load_dwarf_data(&mut self, module: &str, data: &[u8]) -> Result<usize> {
+        let rows = crate::dwarf::line_rows(data)
+            .ok_or_else(|| SymbolsError::ParsingError("Invalid DWARF information".to_string()))?;
+
+        let bias = {
+            let module = self
+                .modules
+                .get(module)
+                .ok_or_else(|| SymbolsError::UnknownModule(module.to_string()))?;
+            elf_bias(module, data)?
+        };
+
+        let count = rows.len();
+        for row in rows {
+            self.add_line(module, &row.file, row.line, row.address.wrapping_add(bias))?;
+        }
+
+        Ok(count)
+    }
+
+    /// Loads the public symbols of a PDB file for a Windows module (e.g. a
+    /// minidump module loaded at its image base), returns the number of
+    /// symbols loaded.
+    #[cfg(feature = "pdb")]
+    pub fn load_pdb<P: AsRef<Path>>(&mut self, module: &str, path: P) -> Result<usize> {
+        let data = fs::read(path)?;
+        self.load_pdb_data(module, &data)
+    }
+
+    /// Loads the public symbols of an in-memory PDB file for a Windows
+    /// module, returns the number of symbols loaded.
+    #[cfg(feature = "pdb")]
+    pub fn load_pdb_data(&mut self, module: &str, data: &[u8]) -> Result<usize> {
+        let symbols =
+            pdb_symbols(data).map_err(|err| SymbolsError::ParsingError(err.to_string()))?;
+
+        let module = self
+            .modules
+            .get_mut(module)
+            .ok_or_else(|| SymbolsError::UnknownModule(module.to_string()))?;
+
+        // The addresses are relative to the image base
+        let count = symbols.len();
+        for (name, rva) in symbols {
+            module.insert_symbol(name, module.start.wrapping_add(rva));
+        }
+
+        Ok(count)
+    }
+
+    /// Returns a module by name. If there is no exact match, the first module
+    /// starting with `name` is returned (e.g. `libc.so` for `libc.so.6`).
+    pub fn module(&self, name: &str) -> Option<&SymbolModule> {
+        self.modules
+            .get(name)
+            .or_else(|| self.modules.values().find(|m| m.name.starts_with(name)))
+    }
+
+    /// Returns the module containing an address
+    pub fn module_at(&self, address: u64) -> Option<&SymbolModule> {
+        self.modules.values().find(|m| m.contains(address))
+    }
+
+    /// Returns an iterator over all modules
+    pub fn modules(&self) -> impl Iterator<Item = &SymbolModule> + '_ {
+        self.modules.values()
+    }
+
+    /// Resolves the address of a symbol inside a module
+    pub fn resolve(&self, module: &str, symbol: &str) -> Result<u64> {
+        let module = self
+            .module(module)
+            .ok_or_else(|| SymbolsError::UnknownModule(module.to_string()))?;
+
+        module
+            .symbol(symbol)
+            .ok_or_else(|| SymbolsError::UnknownSymbol(format!("{}!{}", module.name, symbol)))
+    }
+
+    /// Resolves the addresses of a source line. The file is matched on its
+    /// path suffix (`parser.c` matches `src/parser.c`), and a line without
+    /// code resolves to the next one with code.
+    pub fn resolve_line(&self, file: &str, line: u32) -> Result<Vec<u64>> {
+        let suffix = format!("/{}", file);
+        let mut addresses: Vec<u64> = self
+            .modules
+            .values()
+            .flat_map(|m| m.lines.iter())
+            .filter(|(path, _)| *path == file || path.ends_with(&suffix))
+            .filter_map(|(_, lines)| lines.range(line..).next())
+            .flat_map(|(_, addresses)| addresses.iter().copied())
+            .collect();
+
+        addresses.sort_unstable();
+        addresses.dedup();
+
+        if addresses.is_empty() {
+            return Err(SymbolsError::UnknownLine(format!("{}:{}", file, line)));
+        }
+
+        Ok(addresses)
+    }
+
+    /// Returns the symbolic form of an address: `module!symbol+0x42` after the
+    /// closest symbol of its module, `module+0x1234` when the module has no
+    /// symbol before it and `symbol+0x42` after a symbol without module
+    /// information. Returns None for an unknown address.
+    pub fn symbolize(&self, address: u64) -> Option<String> {
+        let offset = |base: u64| match address - base {
+            0 => String::new(),
+            offset => format!("+0x{:x}", offset),
+        };
+
+        if let Some(module) = self.module_at(address) {
+            return Some(match module.symbol_at(address) {
+                Some((name, start)) => format!("{}!{}{}", module.name, name, offset(start)),
+                None => format!("{}{}", module.name, offset(module.start)),
+            });
+        }
+
+        self.globals
+            .iter()
+            .filter(|(_, &start)| start <= address)
+            .max_by_key(|(_, &start)| start)
+            .map(|(name, &start)| format!("{}{}", name, offset(start)))
+    }
+
+    /// Resolves the address of a symbol without module information
+    pub fn lookup(&self, symbol: &str) -> Option<u64> {
+        self.globals
+            .get(symbol)
+            .copied()
+            .or_else(|| self.modules.values().find_map(|m| m.symbol(symbol)))
+    }
+}
+
+/// Returns the relocation bias of an ELF image loaded at the module start
+fn elf_bias(module: &SymbolModule, data: &[u8]) -> Result<u64> {
+    let header = elf::ElfHeader::parse(data)
+        .ok_or_else(|| SymbolsError::ParsingError("Invalid ELF header".to_string()))?;
+
+    // Position independent images are relocated at the module start
+    Ok(match header.e_type {
+        elf::ET_DYN => module.start.wrapping_sub(elf::load_base(data).unwrap_or(0)),
+        _ => 0,
+    })
+}
+
+/// Returns the public symbols of a PDB file along with their relative
+/// virtual address
+#[cfg(feature = "pdb")]
+fn pdb_symbols(data: &[u8]) -> pdb::Result<Vec<(String, u64)>> {
+    use pdb::FallibleIterator;
+
+    let mut pdb = pdb::PDB::open(std::io::Cursor::new(data))?;
+    let address_map = pdb.address_map()?;
+    let globals = pdb.global_symbols()?;
+
+    let mut symbols = Vec::new();
+    let mut iter = globals.iter();
+    while let Some(symbol) = iter.next()? {
+        if let Ok(pdb::SymbolData::Public(public)) = symbol.parse() {
+            if let Some(rva) = public.offset.to_rva(&address_map) {
+                symbols.push((public.name.to_string().into_owned(), u64::from(rva.0)));
+            }
+        }
+    }
+
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Symbols, SymbolsError};
+
+    /// Build a minimal shared object with a `.dynsym` table
+    fn build_elf(symbols: &[(&str, u64)]) -> Vec<u8> {
+        // Layout: header | dynstr | dynsym | 3 section headers | 1 phdr
+        let mut strtab = vec![0u8];
+        let mut symtab = vec![0u8; 24];
+
+        for (name, value) in symbols {
+            let mut sym = vec![0u8; 24];
+            sym[0..4].copy_from_slice(&(strtab.len() as u32).to_le_bytes());
+            sym[4] = 0x12; // Global function
+            sym[6..8].copy_from_slice(&1u16.to_le_bytes());
+            sym[8..16].copy_from_slice(&value.to_le_bytes());
+            symtab.extend(sym);
+
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+
+        let strtab_off = 64;
+        let symtab_off = strtab_off + strtab.len();
+        let shdrs_off = symtab_off + symtab.len();
+        let phdr_off = shdrs_off + 3 * 64;
+
+        let mut data = vec![0u8; 64];
+        data[0..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2;
+        data[5] = 1;
+        data[16..18].copy_from_slice(&3u16.to_le_bytes());
+        data[18..20].copy_from_slice(&62u16.to_le_bytes());
+        data[32..40].copy_from_slice(&(phdr_off as u64).to_le_bytes());
+        data[40..48].copy_from_slice(&(shdrs_off as u64).to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&1u16.to_le_bytes());
+        data[58..60].copy_from_slice(&64u16.to_le_bytes());
+        data[60..62].copy_from_slice(&3u16.to_le_bytes());
+        data.extend(&strtab);
+        data.extend(&symtab);
+
+        // Null section, .dynstr and .dynsym
+        let mut shdrs = vec![0u8; 3 * 64];
+        shdrs[64 + 4..64 + 8].copy_from_slice(&3u32.to_le_bytes());
+        shdrs[64 + 24..64 + 32].copy_from_slice(&(strtab_off as u64).to_le_bytes());
+        shdrs[64 + 32..64 + 40].copy_from_slice(&(strtab.len() as u64).to_le_bytes());
+        shdrs[128 + 4..128 + 8].copy_from_slice(&11u32.to_le_bytes());
+        shdrs[128 + 24..128 + 32].copy_from_slice(&(symtab_off as u64).to_le_bytes());
+        shdrs[128 + 32..128 + 40].copy_from_slice(&(symtab.len() as u64).to_le_bytes());
+        shdrs[128 + 40..128 + 44].copy_from_slice(&1u32.to_le_bytes());
+        data.extend(shdrs);
+
+        // Single PT_LOAD at 0
+        let mut phdr = vec![0u8; 56];
+        phdr[0..4].copy_from_slice(&1u32.to_le_bytes());
+        data.extend(phdr);
+
+        data
+    }
+
+    #[test]
+    fn test_resolve_elf_symbols() {
+        let mut symbols = Symbols::new();
+        symbols.add_module("libc.so.6", 0x7fff_0000_0000, 0x7fff_0020_0000);
+
+        let elf = build_elf(&[("malloc", 0x1000), ("free", 0x2000)]);
+        assert_eq!(symbols.load_elf_data("libc.so.6", &elf), Ok(2));
+
+        // Prefix match on the module name and relocation on the module base
+        assert_eq!(symbols.resolve("libc.so", "malloc"), Ok(0x7fff_0000_1000));
+        assert_eq!(symbols.resolve("libc.so.6", "free"), Ok(0x7fff_0000_2000));
+        assert_eq!(symbols.lookup("free"), Some(0x7fff_0000_2000));
+
+        assert_eq!(
+            symbols.resolve("libc.so", "calloc"),
+            Err(SymbolsError::UnknownSymbol("libc.so.6!calloc".to_string()))
+        );
+        assert_eq!(
+            symbols.resolve("libm.so", "sin"),
+            Err(SymbolsError::UnknownModule("libm.so".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_resolve_lines() {
+        let mut symbols = Symbols::new();
+        symbols.add_module("target", 0x400000, 0x402000);
+        symbols
+            .add_line("target", "/src/parser.c", 10, 0x401000)
+            .unwrap();
+        symbols
+            .add_line("target", "/src/parser.c", 12, 0x401010)
+            .unwrap();
+        symbols
+            .add_line("target", "/src/parser.c", 12, 0x401080)
+            .unwrap();
+
+        assert_eq!(symbols.resolve_line("parser.c", 10), Ok(vec![0x401000]));
+        assert_eq!(
+            symbols.resolve_line("src/parser.c", 11),
+            Ok(vec![0x401010, 0x401080])
+        );
+        assert_eq!(
+            symbols.resolve_line("arser.c", 10),
+            Err(SymbolsError::UnknownLine("arser.c:10".to_string()))
+        );
+        assert!(symbols.resolve_line("parser.c", 13).is_err());
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let maps = "\
+00400000-00401000 r--p 00000000 08:01 1234 /usr/bin/target
+00401000-00402000 r-xp 00001000 08:01 1234 /usr/bin/target
+7fff00000000-7fff00200000 r-xp 00000000 08:01 5678 /usr/lib/libfoo.so
+7fff00200000-7fff00210000 rw-p 00000000 00:00 0
+7fff00400000-7fff00401000 r-xp 00000000 08:01 9012                       /opt/my app/lib bar.so
+7ffffffde000-7ffffffff000 rw-p 00000000 00:00 0                          [stack]
+";
+        let mut symbols = Symbols::new();
+        assert_eq!(symbols.add_maps(maps), 3);
+        assert_eq!(symbols.module_at(0x401800).unwrap().name, "target");
+        assert_eq!(
+            symbols.module_at(0x7fff00400000).unwrap().name,
+            "lib bar.so"
+        );
+
+        let elf = build_elf(&[("parse_header", 0x1000), ("parse_body", 0x1100)]);
+        assert_eq!(symbols.load_elf_data("libfoo.so", &elf), Ok(2));
+
+        assert_eq!(
+            symbols.symbolize(0x7fff_0000_1042).as_deref(),
+            Some("libfoo.so!parse_header+0x42")
+        );
+        assert_eq!(
+            symbols.symbolize(0x7fff_0000_1100).as_deref(),
+            Some("libfoo.so!parse_body")
+        );
+        assert_eq!(
+            symbols.symbolize(0x7fff_0000_0010).as_deref(),
+            Some("libfoo.so+0x10")
+        );
+        assert_eq!(
+            symbols.symbolize(0x401234).as_deref(),
+            Some("target+0x1234")
+        );
+        assert_eq!(symbols.symbolize(0x7fff_0020_0000), None);
+    }
+
+    #[cfg(feature = "pdb")]
+    #[test]
+    fn test_resolve_pdb_symbols() {
+        // Linked by lld from `mainCRTStartup` and `parse_input` at 0x10
+        let compressed = include_bytes!("../tests/data/target.pdb.zst");
+        let data = zstd::decode_all(&compressed[..]).unwrap();
+
+        let mut symbols = Symbols::new();
+        assert_eq!(
+            symbols.load_pdb_data("target.exe", &data),
+            Err(SymbolsError::UnknownModule("target.exe".to_string()))
+        );
+
+        symbols.add_module("target.exe", 0x140000000, 0x140003000);
+        assert_eq!(symbols.load_pdb_data("target.exe", &data), Ok(2));
+        assert_eq!(
+            symbols.resolve("target.exe", "parse_input"),
+            Ok(0x140001010)
+        );
+        assert_eq!(
+            symbols.symbolize(0x140001011).as_deref(),
+            Some("target.exe!parse_input+0x1")
+        );
+
+        assert!(symbols
+            .load_pdb_data("target.exe", &data[..0x1000])
+            .is_err());
+    }
+}
@@ -0,0 +1,292 @@
+//! Memory access tracing through the page permissions
+//!
+//! The pages covered by `Vm::watch_range` are stripped of their presence (or
+//! of their write permission when only the writes are traced) while the vm
+//! runs. A fault on one of them restores the page and single steps the
+//! faulting instruction, the page being stripped again once it executed. The
+//! accesses landing in a watched range are reported with `VmExit::MemAccess`
+//! before the instruction executes, the other ones are transparent.
+
+use super::hooks::DEBUG_VECTOR;
+use super::{Result, Vm, VmExit};
+use crate::memory::{PagePermissions, PAGE_SIZE};
+
+use std::collections::BTreeMap;
+use std::mem;
+
+/// Page fault status bit set by the write accesses
+const PF_WRITE: u32 = 1 << 1;
+/// Page fault status bit set by the instruction fetches
+const PF_FETCH: u32 = 1 << 4;
+
+/// Memory access traced by `Vm::watch_range`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    /// Data read
+    Read,
+    /// Data write
+    Write,
+    /// Instruction fetch
+    Execute,
+}
+
+impl AccessKind {
+    /// Returns the access of a page fault status
+    fn from_status(status: u32) -> Self {
+        if status & PF_FETCH != 0 {
+            AccessKind::Execute
+        } else if status & PF_WRITE != 0 {
+            AccessKind::Write
+        } else {
+            AccessKind::Read
+        }
+    }
+}
+
+/// Address range watched for an access
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct WatchRange {
+    /// Starting address
+    start: u64,
+    /// Ending address (excluded)
+    end: u64,
+    /// Access reported
+    kind: AccessKind,
+}
+
+/// Page stripped of its permissions
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct StrippedPage {
+    /// Original permissions
+    permissions: PagePermissions,
+    /// Whether the presence was cleared, rather than the write permission
+    hidden: bool,
+}
+
+/// Watched ranges and the state of their pages
+#[derive(Clone, Debug, Default)]
+pub(super) struct AccessWatch {
+    /// Ranges watched
+    ranges: Vec<WatchRange>,
+    /// Pages stripped while the vm runs
+    stripped: BTreeMap<u64, StrippedPage>,
+    /// Pages restored for the single step of an access
+    stepping: BTreeMap<u64, StrippedPage>,
+}
+
+impl AccessWatch {
+    /// Returns whether or not the single step of an access is in progress
+    #[inline]
+    pub(super) fn stepping(&self) -> bool {
+        !self.stepping.is_empty()
+    }
+
+    /// Cancels the single step of an access, returns whether or not one was
+    /// in progress
+    pub(super) fn cancel_step(&mut self) -> bool {
+        !mem::take(&mut self.stepping).is_empty()
+    }
+
+    /// Returns the pages to strip and whether their presence is cleared
+    fn pages(&self) -> BTreeMap<u64, bool> {
+        let mut pages = BTreeMap::new();
+
+        for range in self.ranges.iter() {
+            let hidden = range.kind != AccessKind::Write;
+            let start = range.start & !(PAGE_SIZE as u64 - 1);
+
+            for page in (start..range.end).step_by(PAGE_SIZE) {
+                *pages.entry(page).or_insert(false) |= hidden;
+            }
+        }
+
+        pages
+    }
+
+    /// Returns whether or not an access is watched
+    fn watched(&self, address: u64, kind: AccessKind) -> bool {
+        self.ranges
+            .iter()
+            .any(|range| range.kind == kind && (range.start..range.end).contains(&address))
+    }
+}
+
+impl Vm {
+    /// Traces the accesses of kind `kind` to a memory range, reported with
+    /// `VmExit::MemAccess` before the accessing instruction executes. Unlike
+    /// the hardware watchpoints, any number of ranges of any size can be
+    /// watched, at the cost of a fault and a single step for every access to
+    /// their pages. A range is watched for several kinds of accesses by
+    /// watching it once for each.
+    pub fn watch_range(&mut self, vaddr: u64, len: usize, kind: AccessKind) {
+        let range = WatchRange {
+            start: vaddr,
+            end: vaddr.saturating_add(len as u64),
+            kind,
+        };
+
+        if range.start < range.end && !self.access_watch.ranges.contains(&range) {
+            self.access_watch.ranges.push(range);
+        }
+    }
+
+    /// Stops tracing the accesses to the ranges starting at `vaddr`
+    pub fn unwatch_range(&mut self, vaddr: u64) {
+        self.access_watch
+            .ranges
+            .retain(|range| range.start != vaddr);
+    }
+
+    /// Runs the vm until an instruction is fetched from one of `ranges`, the
+    /// pages of the ranges being stripped like the watched ones. Returns
+    /// `None` once the fetching instruction is reached, otherwise the exit
+    /// raised before.
+    pub(super) fn run_until_fetch(&mut self, ranges: &[(u64, u64)]) -> Result<Option<VmExit>> {
+        let added: Vec<WatchRange> = ranges
+            .iter()
+            .map(|&(start, end)| WatchRange {
+                start,
+                end,
+                kind: AccessKind::Execute,
+            })
+            .filter(|range| range.start < range.end && !self.access_watch.ranges.contains(range))
+            .collect();
+        self.access_watch.ranges.extend(added.iter().copied());
+
+        let result = self.run();
+        self.access_watch
+            .ranges
+            .retain(|range| !added.contains(range));
+
+        match result? {
+            // Unless the user watches the fetch too, the instruction is
+            // executed by the caller with its page restored
+            VmExit::MemAccess {
+                addr,
+                kind: AccessKind::Execute,
+            } if !self.access_watch.watched(addr, AccessKind::Execute) => {
+                if self.access_watch.cancel_step() {
+                    self.update_guest_debug()?;
+                }
+                Ok(None)
+            }
+            exit => Ok(Some(exit)),
+        }
+    }
+
+    /// Strips the watched pages of their permissions before running, except
+    /// the ones of the access being single stepped
+    pub(super) fn strip_watched_pages(&mut self) -> Result<()> {
+        for (page, hidden) in self.access_watch.pages() {
+            if self.access_watch.stepping.contains_key(&page) {
+                continue;
+            }
+
+            // The unmapped pages fault anyway
+            let permissions = match self.memory.translate(page) {
+                Some((_, permissions)) => permissions,
+                None => continue,
+            };
+
+            // Nothing to catch on the read only pages
+            if !hidden && !permissions.writable() {
+                continue;
+            }
+
+            let stripped = StrippedPage {
+                permissions,
+                hidden,
+            };
+            self.strip_page(page, stripped)?;
+            self.access_watch.stripped.insert(page, stripped);
+        }
+
+        Ok(())
+    }
+
+    /// Restores the permissions of the pages stripped for the run
+    pub(super) fn restore_watched_pages(&mut self) -> Result<()> {
+        for (page, stripped) in mem::take(&mut self.access_watch.stripped) {
+            self.unstrip_page(page, stripped)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the permissions of a page needed by the traced accesses
+    fn strip_page(&mut self, page: u64, stripped: StrippedPage) -> Result<()> {
+        if stripped.hidden {
+            self.memory.set_page_present(page, false)?;
+        } else {
+            let mut permissions = stripped.permissions;
+            permissions.set_writable(false);
+            self.memory.mprotect(page, PAGE_SIZE, permissions)?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives back its original permissions to a page
+    fn unstrip_page(&mut self, page: u64, stripped: StrippedPage) -> Result<()> {
+        self.memory.set_page_present(page, true)?;
+        self.memory
+            .mprotect(page, PAGE_SIZE, stripped.permissions)?;
+
+        Ok(())
+    }
+
+    /// Handles a page fault, `status` being its error code. Returns `None` if
+    /// the fault is not caused by a watched page, otherwise the exit to
+    /// report or `Some(None)` to resume.
+    pub(super) fn handle_access_fault(
+        &mut self,
+        address: u64,
+        status: u32,
+    ) -> Result<Option<Option<VmExit>>> {
+        let page = address & !(PAGE_SIZE as u64 - 1);
+        let stripped = match self.access_watch.stripped.get(&page) {
+            Some(&stripped) => stripped,
+            None => return Ok(None),
+        };
+
+        // Faults the original permissions raise too
+        let kind = AccessKind::from_status(status);
+        let allowed = match kind {
+            AccessKind::Read => true,
+            AccessKind::Write => stripped.permissions.writable(),
+            AccessKind::Execute => stripped.permissions.executable(),
+        };
+        if !allowed {
+            return Ok(None);
+        }
+
+        // Single step the instruction with the page restored
+        self.access_watch.stripped.remove(&page);
+        self.unstrip_page(page, stripped)?;
+        self.access_watch.stepping.insert(page, stripped);
+        self.update_guest_debug()?;
+
+        Ok(Some(self.access_watch.watched(address, kind).then_some(
+            VmExit::MemAccess {
+                addr: address,
+                kind,
+            },
+        )))
+    }
+
+    /// Strips again the pages of an access once its instruction executed.
+    /// Returns whether or not such an access was single stepped.
+    pub(super) fn finish_access_step(&mut self, exception: u32) -> Result<bool> {
+        if exception != DEBUG_VECTOR || !self.access_watch.stepping() {
+            return Ok(false);
+        }
+
+        for (page, stripped) in mem::take(&mut self.access_watch.stepping) {
+            self.strip_page(page, stripped)?;
+            self.access_watch.stripped.insert(page, stripped);
+        }
+
+        self.update_guest_debug()?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,329 @@
+//! Page allocator over a dedicated guest region
+//!
+//! In sanitizer mode (see `Vm::set_guest_alloc_sanitizer`) each allocation is
+//! placed at the end of its pages, between two unmapped guard pages, the
+//! unused bytes of the pages being poisoned. The freed allocations stay
+//! unmapped until the next reset, so out of bounds accesses and uses after
+//! free stop the guest with a `VmExit::PageFault` rather than silently
+//! landing in another buffer.
+
+use super::{Result, Vm, VmError};
+use crate::memory::{PagePermissions, PAGE_SIZE};
+
+use std::collections::BTreeMap;
+
+/// Default start address of the allocations region
+const DEFAULT_BASE: u64 = 0x7000_0000_0000;
+
+/// Default size of the allocations region
+const DEFAULT_SIZE: u64 = 0x1000_0000;
+
+/// Byte filling the unused part of the sanitized allocation pages
+const POISON_BYTE: u8 = 0xfa;
+
+/// Alignment of the sanitized allocations
+const SANITIZER_ALIGNMENT: u64 = 16;
+
+/// Allocation made by `Vm::guest_alloc`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Allocation {
+    /// Address of the first page
+    start: u64,
+    /// Number of pages
+    pages: u64,
+    /// Permissions of the pages
+    permissions: PagePermissions,
+    /// Size requested, for the redzones check of the sanitized allocations
+    size: u64,
+    /// Whether the allocation is surrounded by guard pages
+    guarded: bool,
+}
+
+impl Allocation {
+    /// Returns the range reserved in the region, guard pages included
+    fn reserved(&self) -> (u64, u64) {
+        let guards = if self.guarded { 2 } else { 0 };
+        let start = if self.guarded {
+            self.start - PAGE_SIZE as u64
+        } else {
+            self.start
+        };
+
+        (start, self.pages + guards)
+    }
+}
+
+/// Bump and free list allocator of guest pages
+#[derive(Clone, Debug)]
+pub(super) struct GuestAllocator {
+    /// Start address of the region
+    base: u64,
+    /// Size of the region
+    size: u64,
+    /// First address never allocated
+    cursor: u64,
+    /// Live allocations by address
+    allocations: BTreeMap<u64, Allocation>,
+    /// Free ranges below the cursor (address and number of pages)
+    free: BTreeMap<u64, u64>,
+    /// Sanitizer mode
+    sanitize: bool,
+}
+
+impl Default for GuestAllocator {
+    fn default() -> Self {
+        GuestAllocator {
+            base: DEFAULT_BASE,
+            size: DEFAULT_SIZE,
+            cursor: DEFAULT_BASE,
+            allocations: BTreeMap::new(),
+            free: BTreeMap::new(),
+            sanitize: false,
+        }
+    }
+}
+
+impl GuestAllocator {
+    /// Reserves pages, first from the free ranges then from the cursor
+    fn reserve(&mut self, pages: u64) -> Option<u64> {
+        let page_size = PAGE_SIZE as u64;
+
+        let range = self
+            .free
+            .iter()
+            .find(|(_, &free)| free >= pages)
+            .map(|(&start, &free)| (start, free));
+        if let Some((start, free)) = range {
+            self.free.remove(&start);
+            if free > pages {
+                self.free.insert(start + pages * page_size, free - pages);
+            }
+            return Some(start);
+        }
+
+        let end = self.cursor.checked_add(pages.checked_mul(page_size)?)?;
+        if end > self.base + self.size {
+            return None;
+        }
+
+        let start = self.cursor;
+        self.cursor = end;
+        Some(start)
+    }
+
+    /// Releases pages, merging them with the adjacent free ranges
+    fn release(&mut self, mut start: u64, mut pages: u64) {
+        let page_size = PAGE_SIZE as u64;
+
+        let previous = self
+            .free
+            .range(..start)
+            .next_back()
+            .map(|(&address, &free)| (address, free));
+        if let Some((address, free)) = previous {
+            if address + free * page_size == start {
+                self.free.remove(&address);
+                start = address;
+                pages += free;
+            }
+        }
+
+        let end = start + pages * page_size;
+        if let Some(free) = self.free.remove(&end) {
+            pages += free;
+        }
+
+        // The range ending at the cursor goes back to the bump allocator
+        if start + pages * page_size == self.cursor {
+            self.cursor = start;
+        } else {
+            self.free.insert(start, pages);
+        }
+    }
+}
+
+impl Vm {
+    /// Sets the guest region of `Vm::guest_alloc` (by default 256MB at
+    /// 0x7000_0000_0000), it must not overlap the snapshot mappings. Fails
+    /// when allocations were already made.
+    pub fn set_guest_alloc_region(&mut self, base: u64, size: u64) -> Result<()> {
+        let page_size = PAGE_SIZE as u64;
+        let invalid =
+            !base.is_multiple_of(page_size) || size == 0 || base.checked_add(size).is_none();
+
+        if invalid || !self.allocator.allocations.is_empty() {
+            return Err(VmError::InvalidOperation("Invalid guest allocation region"));
+        }
+
+        self.allocator = GuestAllocator {
+            base,
+            size,
+            cursor: base,
+            sanitize: self.allocator.sanitize,
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    /// Enables or disables the sanitizer mode of `Vm::guest_alloc`: the
+    /// allocations are surrounded by unmapped guard pages and poisoned
+    /// redzones, and freed ones stay unmapped until the next reset. Fails
+    /// when allocations are live.
+    pub fn set_guest_alloc_sanitizer(&mut self, enabled: bool) -> Result<()> {
+        if !self.allocator.allocations.is_empty() {
+            return Err(VmError::InvalidOperation("Guest allocations are live"));
+        }
+
+        self.allocator.sanitize = enabled;
+        Ok(())
+    }
+
+    /// Changes the guest visibility of the pages of an allocation, mapping
+    /// the pages missing (e.g. with their page tables restored by a reset)
+    fn set_allocation_present(&mut self, allocation: Allocation, present: bool) -> Result<()> {
+        let start = allocation.start;
+
+        for page in (0..allocation.pages).map(|i| start + i * PAGE_SIZE as u64) {
+            if self.memory.set_page_present(page, present).is_err() && present {
+                self.memory.mmap(page, PAGE_SIZE, allocation.permissions)?;
+            }
+        }
+
+        if present {
+            let size = allocation.pages as usize * PAGE_SIZE;
+            self.memory.mprotect(start, size, allocation.permissions)?;
+        }
+
+        Ok(())
+    }
+
+    /// Allocates zeroed pages in the guest allocation region with the
+    /// permissions `perms`, e.g. to place an input, an argv array or a
+    /// replacement buffer. Returns the address of the allocation.
+    ///
+    /// The allocations made before a vm is used as the source of a reset
+    /// persist in the reset vm, the ones made after are reclaimed by the
+    /// reset (their pages faulting on any guest access).
+    ///
+    /// In sanitizer mode the allocation ends at most 15 bytes before the
+    /// trailing guard page (it is 16 bytes aligned).
+    pub fn guest_alloc(&mut self, size: usize, perms: PagePermissions) -> Result<u64> {
+        let guarded = self.allocator.sanitize;
+        let pages = (size.max(1).div_ceil(PAGE_SIZE)) as u64;
+        let guards = if guarded { 2 } else { 0 };
+
+        let reserved = self
+            .allocator
+            .reserve(pages + guards)
+            .ok_or(VmError::InvalidOperation(
+                "Guest allocation region exhausted",
+            ))?;
+
+        let allocation = Allocation {
+            start: reserved + guards / 2 * PAGE_SIZE as u64,
+            pages,
+            permissions: perms,
+            size: size as u64,
+            guarded,
+        };
+        let start = allocation.start;
+        let end = start + pages * PAGE_SIZE as u64;
+        let address = if guarded {
+            (end - size as u64) & !(SANITIZER_ALIGNMENT - 1)
+        } else {
+            start
+        };
+
+        self.set_allocation_present(allocation, true)?;
+        self.memory
+            .write(start, &vec![0u8; pages as usize * PAGE_SIZE])?;
+
+        // Poison the redzones around the sanitized allocation
+        if guarded {
+            self.memory
+                .write(start, &vec![POISON_BYTE; (address - start) as usize])?;
+            self.memory.write(
+                address + size as u64,
+                &vec![POISON_BYTE; (end - address) as usize - size],
+            )?;
+        }
+
+        self.allocator.allocations.insert(address, allocation);
+        Ok(address)
+    }
+
+    /// Returns whether or not the redzones of a sanitized allocation are
+    /// intact
+    fn redzones_intact(&self, address: u64, allocation: Allocation) -> Result<bool> {
+        let end = allocation.start + allocation.pages * PAGE_SIZE as u64;
+        let mut data = vec![0u8; (end - allocation.start) as usize];
+        self.memory.read(allocation.start, &mut data)?;
+
+        let offset = (address - allocation.start) as usize;
+        let (before, rest) = data.split_at(offset);
+        let after = &rest[allocation.size as usize..];
+
+        Ok(before.iter().chain(after).all(|&byte| byte == POISON_BYTE))
+    }
+
+    /// Frees an allocation made by `Vm::guest_alloc`, its pages fault on any
+    /// guest access until reused (until the next reset in sanitizer mode).
+    /// Fails after freeing a sanitized allocation whose redzones were
+    /// overwritten.
+    pub fn guest_free(&mut self, address: u64) -> Result<()> {
+        let allocation = self
+            .allocator
+            .allocations
+            .remove(&address)
+            .ok_or(VmError::InvalidOperation("Invalid guest allocation"))?;
+
+        let intact = !allocation.guarded || self.redzones_intact(address, allocation)?;
+
+        self.set_allocation_present(allocation, false)?;
+        if !allocation.guarded {
+            let (start, pages) = allocation.reserved();
+            self.allocator.release(start, pages);
+        }
+
+        if !intact {
+            return Err(VmError::InvalidOperation(
+                "Guest allocation redzone overwritten",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Restores the allocations of an other vm: the allocations made since
+    /// are hidden, the ones freed since are visible again
+    pub(super) fn reset_guest_allocations(&mut self, other: &Vm) {
+        let hidden: Vec<Allocation> = self
+            .allocator
+            .allocations
+            .iter()
+            .filter(|(address, allocation)| {
+                other.allocator.allocations.get(address) != Some(allocation)
+            })
+            .map(|(_, &allocation)| allocation)
+            .collect();
+        let restored: Vec<Allocation> = other
+            .allocator
+            .allocations
+            .iter()
+            .filter(|(address, allocation)| {
+                self.allocator.allocations.get(address) != Some(allocation)
+            })
+            .map(|(_, &allocation)| allocation)
+            .collect();
+
+        for allocation in hidden {
+            self.set_allocation_present(allocation, false)
+                .expect("Could not hide guest allocation");
+        }
+        for allocation in restored {
+            self.set_allocation_present(allocation, true)
+                .expect("Could not restore guest allocation");
+        }
+
+        self.allocator.clone_from(&other.allocator);
+    }
+}
@@ -0,0 +1,183 @@
+//! Vm construction options
+
+use super::{Result, Vm};
+use crate::memory::HugePages;
+
+/// Default base address of the exception handling region
+const DEFAULT_EXCEPTION_REGION: u64 = 0xffff_ffff_ff00_0000;
+
+/// Dirty pages tracking used to reset the vm memory
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DirtyLogStrategy {
+    /// `ManualProtect` when the kernel supports it, `GetDirtyLog` otherwise
+    /// (see `Vm::dirty_log_strategy` for the strategy selected)
+    Auto,
+    /// Dirty log cleared explicitly after each reset
+    /// (KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2)
+    ManualProtect,
+    /// Dirty log cleared by kvm each time it is read, available on all the
+    /// kernels but slower to reset
+    GetDirtyLog,
+}
+
+/// Additional guest physical memory region
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) struct MemorySlot {
+    /// Guest physical address of the slot
+    pub(super) guest_address: u64,
+    /// Size of the slot
+    pub(super) size: usize,
+}
+
+impl Vm {
+    /// Returns the dirty pages tracking strategy selected when building the
+    /// vm (never `DirtyLogStrategy::Auto`)
+    #[inline]
+    pub fn dirty_log_strategy(&self) -> DirtyLogStrategy {
+        self.config.dirty_log
+    }
+
+    /// Returns the content of an additional memory region (see
+    /// `VmBuilder::memory_slot`)
+    pub fn memory_slot(&self, index: usize) -> Option<&[u8]> {
+        let slot = self.memory_slots.get(index)?;
+        slot.raw_slice(0, slot.size()).ok()
+    }
+
+    /// Returns the mutable content of an additional memory region
+    pub fn memory_slot_mut(&mut self, index: usize) -> Option<&mut [u8]> {
+        let slot = self.memory_slots.get_mut(index)?;
+        let size = slot.size();
+        slot.raw_slice_mut(0, size).ok()
+    }
+}
+
+/// `Vm` builder
+#[derive(Clone, Debug)]
+pub struct VmBuilder {
+    /// Size of the vm memory
+    pub(super) memory_size: usize,
+    /// Base address of the exception handling region
+    pub(super) exception_region: u64,
+    /// Dirty pages tracking strategy
+    pub(super) dirty_log: DirtyLogStrategy,
+    /// Additional KVM_GUESTDBG_* flags
+    pub(super) guest_debug: u32,
+    /// Guest physical ranges skipped by the vm memory (start and size)
+    pub(super) memory_holes: Vec<(u64, u64)>,
+    /// Host pages backing the vm memory
+    pub(super) huge_pages: HugePages,
+    /// Additional guest physical memory regions
+    pub(super) memory_slots: Vec<MemorySlot>,
+    /// Seed of the vm randomness source
+    pub(super) seed: u64,
+}
+
+impl VmBuilder {
+    /// Creates a new `VmBuilder` for a vm with a given memory size (the size
+    /// will be aligned to the nearest page multiple).
+    pub fn new(memory_size: usize) -> Self {
+        VmBuilder {
+            memory_size,
+            exception_region: DEFAULT_EXCEPTION_REGION,
+            dirty_log: DirtyLogStrategy::Auto,
+            guest_debug: 0,
+            memory_holes: Vec::new(),
+            huge_pages: HugePages::Disabled,
+            memory_slots: Vec::new(),
+            seed: 0,
+        }
+    }
+
+    /// Sets the size of the vm memory
+    #[inline]
+    pub fn memory_size(&mut self, memory_size: usize) -> &mut Self {
+        self.memory_size = memory_size;
+        self
+    }
+
+    /// Sets the base address of the 5 pages holding the IDT, the exception
+    /// handlers, the GDT, the TSS and the exceptions stack. It must not
+    /// overlap the guest mappings, building the vm fails if it is not page
+    /// aligned.
+    #[inline]
+    pub fn exception_region(&mut self, base: u64) -> &mut Self {
+        self.exception_region = base;
+        self
+    }
+
+    /// Sets the dirty pages tracking strategy (`DirtyLogStrategy::Auto` by
+    /// default), building the vm fails if the kernel lacks its capability
+    #[inline]
+    pub fn dirty_log(&mut self, strategy: DirtyLogStrategy) -> &mut Self {
+        self.dirty_log = strategy;
+        self
+    }
+
+    /// Sets additional KVM_GUESTDBG_* flags (software breakpoints exits are
+    /// always enabled)
+    #[inline]
+    pub fn guest_debug(&mut self, flags: u32) -> &mut Self {
+        self.guest_debug = flags;
+        self
+    }
+
+    /// Leaves a guest physical range out of the vm memory, e.g. the 32 bit
+    /// PCI hole of the snapshots of big machines: the memory is split in
+    /// banks placed around the holes, each registered as a kvm memory slot
+    /// (see `VirtualMemory::banks`), dirty logged and restored on resets.
+    /// The holes can be filled with `VmBuilder::memory_slot`. Building the vm
+    /// fails if a hole is not page aligned.
+    #[inline]
+    pub fn memory_hole(&mut self, start: u64, size: u64) -> &mut Self {
+        self.memory_holes.push((start, size));
+        self
+    }
+
+    /// Backs the vm memory with hugepages (by default regular pages), its
+    /// size being aligned up to 2MB. The 2MB aligned parts of the guest
+    /// mappings are then mapped with 2MB pages, lowering the TLB pressure of
+    /// the big snapshots. The memory holes must be 2MB aligned.
+    #[inline]
+    pub fn huge_pages(&mut self, huge_pages: HugePages) -> &mut Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    /// Adds a guest physical memory region outside of the vm memory, zeroed
+    /// and restored on resets. It is not mapped in the guest address space.
+    /// Building the vm fails if the region is not page aligned.
+    #[inline]
+    pub fn memory_slot(&mut self, guest_address: u64, size: usize) -> &mut Self {
+        self.memory_slots.push(MemorySlot {
+            guest_address,
+            size,
+        });
+        self
+    }
+
+    /// Sets the seed of the vm randomness source (see `Vm::set_rng`)
+    #[inline]
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Creates the `Vm`
+    pub fn build(&self) -> Result<Vm> {
+        // Create minimal vm
+        let mut vm = Vm::setup_barebones(self)?;
+
+        // Setup special registers
+        vm.setup_registers()?;
+
+        // Setup exception handling
+        vm.setup_exception_handling()?;
+        vm.capture_internal_state()?;
+
+        // Flush registers
+        vm.flush_registers()?;
+
+        Ok(vm)
+    }
+}
@@ -0,0 +1,126 @@
+//! Indirect branch targets checking (control flow integrity)
+
+use super::{Result, Vm, VmExit};
+
+use std::collections::BTreeSet;
+
+/// Kind of indirect branch
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BranchKind {
+    /// Indirect call (`call reg/mem`)
+    Call,
+    /// Indirect jump (`jmp reg/mem`)
+    Jump,
+    /// Instruction not recognized as an indirect branch
+    Unknown,
+}
+
+/// Additional details behind a CFI violation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CfiViolationDetail {
+    /// Address of the branch instruction
+    pub site: u64,
+    /// Address reached by the branch
+    pub target: u64,
+    /// Kind of the branch
+    pub kind: BranchKind,
+}
+
+/// Indirect branch sites and their allowed targets
+#[derive(Clone, Debug, Default)]
+pub(super) struct CfiPolicy {
+    /// Checked indirect branch instructions
+    sites: BTreeSet<u64>,
+    /// Allowed branch targets
+    targets: BTreeSet<u64>,
+}
+
+impl CfiPolicy {
+    /// Returns whether or not an address is a checked branch site
+    #[inline]
+    pub(super) fn contains(&self, address: u64) -> bool {
+        self.sites.contains(&address)
+    }
+}
+
+/// Decode the kind of an indirect branch from its instruction bytes
+fn branch_kind(code: &[u8]) -> BranchKind {
+    // Skip the legacy and REX prefixes (notrack, bnd, operand size...)
+    let mut bytes = code
+        .iter()
+        .skip_while(|&&b| matches!(b, 0x3e | 0x66 | 0x67 | 0xf2 | 0x40..=0x4f));
+
+    match (bytes.next(), bytes.next()) {
+        (Some(0xff), Some(modrm)) => match (modrm >> 3) & 7 {
+            2 | 3 => BranchKind::Call,
+            4 | 5 => BranchKind::Jump,
+            _ => BranchKind::Unknown,
+        },
+        _ => BranchKind::Unknown,
+    }
+}
+
+impl Vm {
+    /// Checks the target of the indirect branch at `site` against the allowed
+    /// targets, stopping with `VmExit::CfiViolation` when it is not allowed.
+    pub fn add_cfi_site(&mut self, site: u64) -> Result<()> {
+        self.add_breakpoint(site)?;
+        self.cfi.sites.insert(site);
+
+        Ok(())
+    }
+
+    /// Allows indirect branches to reach the given targets
+    pub fn add_cfi_targets<I: IntoIterator<Item = u64>>(&mut self, targets: I) {
+        self.cfi.targets.extend(targets);
+    }
+
+    /// Allows indirect branches to reach all the known symbols, returns the
+    /// number of allowed targets.
+    pub fn add_cfi_targets_from_symbols(&mut self) -> usize {
+        let symbols: Vec<u64> = self
+            .symbols
+            .modules()
+            .flat_map(|m| m.symbols().map(|(_, address)| address))
+            .collect();
+
+        self.add_cfi_targets(symbols);
+        self.cfi.targets.len()
+    }
+
+    /// Stops checking indirect branches and removes the sites breakpoints
+    pub fn clear_cfi(&mut self) -> Result<()> {
+        let cfi = std::mem::take(&mut self.cfi);
+
+        for &site in cfi.sites.iter() {
+            if !self.breakpoint_claimed(site) {
+                self.remove_breakpoint(site)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validates the target of an indirect branch after it was single
+    /// stepped. Returns the exit to report on violation.
+    pub(super) fn check_cfi(&mut self, site: u64) -> Result<Option<VmExit>> {
+        let target = self.registers.rip;
+
+        if !self.cfi.sites.contains(&site) || self.cfi.targets.contains(&target) {
+            return Ok(None);
+        }
+
+        // Decode the branch (the breakpoint is re-armed at this point)
+        let mut code = [0u8; 8];
+        let _ = self.memory.read(site, &mut code);
+        if let Some(&orig_byte) = self.breakpoints.get(&site) {
+            code[0] = orig_byte;
+        }
+
+        Ok(Some(VmExit::CfiViolation(CfiViolationDetail {
+            site,
+            target,
+            kind: branch_kind(&code),
+        })))
+    }
+}
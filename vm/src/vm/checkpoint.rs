@@ -0,0 +1,356 @@
+//! Periodic checkpoints of the dirty memory, written on a background thread
+
+use super::{Result, Vm, VmError};
+use crate::bits::LeBytes;
+use crate::memory::PAGE_SIZE;
+use crate::snapshot::{SnapshotError, SnapshotRegisters};
+
+use kvm_bindings::kvm_regs;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Magic number starting the delta dumps
+const DELTA_MAGIC: &[u8; 8] = b"TFDELTA1";
+/// Number of registers saved in the delta dumps
+const DELTA_REGISTERS_COUNT: usize = 20;
+/// Size of the delta dump header (magic, index, pages count and registers)
+const DELTA_HEADER_SIZE: usize = 24 + DELTA_REGISTERS_COUNT * 8;
+/// Number of bytes written at once by the writeback thread
+const WRITE_CHUNK_SIZE: usize = 256 * PAGE_SIZE;
+/// Number of dirty pages copies, one filled while the other is written
+const BUFFERS_COUNT: usize = 2;
+
+/// Periodic checkpoints options
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointConfig {
+    /// Directory receiving the delta dumps
+    pub directory: PathBuf,
+    /// Minimum time between two checkpoints
+    pub interval: Duration,
+}
+
+impl CheckpointConfig {
+    /// Returns the path of a delta dump
+    pub fn delta_path(&self, index: u64) -> PathBuf {
+        self.directory
+            .join(format!("checkpoint-{:06}.delta", index))
+    }
+}
+
+/// Checkpoints taken since `Vm::enable_checkpoints`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckpointStats {
+    /// Delta dumps written
+    pub written: u64,
+    /// Checkpoints skipped, the writeback thread still using both buffers
+    pub skipped: u64,
+}
+
+/// Vm state captured by a checkpoint: the registers and the pages dirtied
+/// since the last reset, read back from a delta dump. Also built by
+/// `Vm::snapshot_delta`, along with the extended state and the model specific
+/// registers.
+#[derive(Debug, Default)]
+pub struct DeltaDump {
+    /// Checkpoint number
+    pub index: u64,
+    /// General purpose registers, fs and gs base (no extended state)
+    pub registers: SnapshotRegisters,
+    /// Dirty pages by guest physical address
+    pub pages: BTreeMap<u64, Vec<u8>>,
+}
+
+impl DeltaDump {
+    /// Reads a delta dump written by the checkpoints
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<DeltaDump> {
+        let data = fs::read(path)?;
+        let invalid =
+            || VmError::SnapshotError(SnapshotError::ParsingError("Invalid delta dump".into()));
+
+        if data.len() < DELTA_HEADER_SIZE || &data[..8] != DELTA_MAGIC {
+            return Err(invalid());
+        }
+
+        let index = data.u64_at(8);
+        let reg = |i: usize| data.u64_at(24 + i * 8);
+
+        // Each page takes its address and its content, the count read from
+        // the file must match its size before sizing anything with it
+        let count = data.u64_at(16);
+        let entries = ((data.len() - DELTA_HEADER_SIZE) / (8 + PAGE_SIZE)) as u64;
+        if count != entries || data.len() != DELTA_HEADER_SIZE + entries as usize * (8 + PAGE_SIZE)
+        {
+            return Err(invalid());
+        }
+        let count = count as usize;
+        let pages_offset = DELTA_HEADER_SIZE + count * 8;
+
+        let pages = (0..count)
+            .map(|i| {
+                let address = data.u64_at(DELTA_HEADER_SIZE + i * 8);
+                let offset = pages_offset + i * PAGE_SIZE;
+                (address, data[offset..offset + PAGE_SIZE].to_vec())
+            })
+            .collect();
+
+        let registers = SnapshotRegisters {
+            rax: reg(0),
+            rbx: reg(1),
+            rcx: reg(2),
+            rdx: reg(3),
+            rsi: reg(4),
+            rdi: reg(5),
+            rsp: reg(6),
+            rbp: reg(7),
+            r8: reg(8),
+            r9: reg(9),
+            r10: reg(10),
+            r11: reg(11),
+            r12: reg(12),
+            r13: reg(13),
+            r14: reg(14),
+            r15: reg(15),
+            rip: reg(16),
+            rflags: reg(17),
+            fs_base: reg(18),
+            gs_base: reg(19),
+            ..Default::default()
+        };
+
+        Ok(DeltaDump {
+            index,
+            registers,
+            pages,
+        })
+    }
+}
+
+/// Copy of the dirty pages handed to the writeback thread
+#[derive(Default)]
+pub(super) struct DeltaBuffer {
+    /// Checkpoint number
+    index: u64,
+    /// Registers in the `DeltaDump` order
+    registers: [u64; DELTA_REGISTERS_COUNT],
+    /// Guest physical address of the pages
+    addresses: Vec<u64>,
+    /// Content of the pages
+    data: Vec<u8>,
+}
+
+impl DeltaBuffer {
+    /// Adds a dirty page, before it is restored
+    #[inline]
+    pub(super) fn push_page(&mut self, address: u64, page: &[u8]) {
+        self.addresses.push(address);
+        self.data.extend_from_slice(page);
+    }
+
+    /// Writes the delta dump, the pages by chunks
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(DELTA_MAGIC)?;
+        out.write_all(&self.index.to_le_bytes())?;
+        out.write_all(&(self.addresses.len() as u64).to_le_bytes())?;
+
+        for value in self.registers.iter().chain(self.addresses.iter()) {
+            out.write_all(&value.to_le_bytes())?;
+        }
+
+        for chunk in self.data.chunks(WRITE_CHUNK_SIZE) {
+            out.write_all(chunk)?;
+        }
+
+        out.flush()
+    }
+
+    /// Writes the delta dump in the directory, renamed once complete
+    fn save(&self, config: &CheckpointConfig) -> io::Result<()> {
+        let path = config.delta_path(self.index);
+        let partial = path.with_extension("delta.partial");
+
+        let mut out = BufWriter::new(File::create(&partial)?);
+        self.write_to(&mut out)?;
+        drop(out);
+
+        fs::rename(partial, path)
+    }
+}
+
+/// Periodic checkpoints state, the delta dumps being written by a background
+/// thread.
+pub(super) struct Checkpointer {
+    /// Minimum time between two checkpoints
+    interval: Duration,
+    /// Time of the last checkpoint
+    last: Instant,
+    /// Number of the next checkpoint
+    next_index: u64,
+    /// Buffers available for the next checkpoints
+    free: Vec<DeltaBuffer>,
+    /// Buffers sent to the writeback thread
+    sender: Option<Sender<DeltaBuffer>>,
+    /// Buffers given back by the writeback thread once written
+    written: Receiver<(DeltaBuffer, io::Result<()>)>,
+    /// Writeback thread
+    thread: Option<JoinHandle<()>>,
+    /// Checkpoints statistics
+    stats: CheckpointStats,
+    /// First writeback error
+    error: Option<io::Error>,
+}
+
+impl Checkpointer {
+    /// Starts the writeback thread
+    fn new(config: CheckpointConfig) -> Checkpointer {
+        let (sender, receiver) = mpsc::channel::<DeltaBuffer>();
+        let (written_sender, written) = mpsc::channel();
+        let interval = config.interval;
+
+        let thread = thread::spawn(move || {
+            for buffer in receiver {
+                let result = buffer.save(&config);
+                if written_sender.send((buffer, result)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Checkpointer {
+            interval,
+            last: Instant::now(),
+            next_index: 0,
+            free: (0..BUFFERS_COUNT).map(|_| DeltaBuffer::default()).collect(),
+            sender: Some(sender),
+            written,
+            thread: Some(thread),
+            stats: CheckpointStats::default(),
+            error: None,
+        }
+    }
+
+    /// Takes back the buffers written by the thread
+    fn collect(&mut self) {
+        while let Ok((buffer, result)) = self.written.try_recv() {
+            match result {
+                Ok(()) => self.stats.written += 1,
+                Err(err) => {
+                    self.error.get_or_insert(err);
+                }
+            }
+            self.free.push(buffer);
+        }
+    }
+
+    /// Returns a buffer to fill with the dirty pages if a checkpoint is due,
+    /// the checkpoint being skipped if both buffers are in use.
+    pub(super) fn begin(
+        &mut self,
+        registers: &kvm_regs,
+        fs_base: u64,
+        gs_base: u64,
+    ) -> Option<DeltaBuffer> {
+        self.collect();
+
+        if self.last.elapsed() < self.interval {
+            return None;
+        }
+
+        let mut buffer = match self.free.pop() {
+            Some(buffer) => buffer,
+            None => {
+                self.stats.skipped += 1;
+                return None;
+            }
+        };
+
+        let r = registers;
+        buffer.index = self.next_index;
+        buffer.registers = [
+            r.rax, r.rbx, r.rcx, r.rdx, r.rsi, r.rdi, r.rsp, r.rbp, r.r8, r.r9, r.r10, r.r11,
+            r.r12, r.r13, r.r14, r.r15, r.rip, r.rflags, fs_base, gs_base,
+        ];
+        buffer.addresses.clear();
+        buffer.data.clear();
+
+        Some(buffer)
+    }
+
+    /// Hands a filled buffer to the writeback thread
+    pub(super) fn submit(&mut self, buffer: DeltaBuffer) {
+        self.last = Instant::now();
+        self.next_index += 1;
+
+        if let Some(sender) = self.sender.as_ref() {
+            let _ = sender.send(buffer);
+        }
+    }
+
+    /// Waits for the pending delta dumps to be written
+    fn finish(&mut self) {
+        self.sender = None;
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        self.collect();
+    }
+}
+
+impl Drop for Checkpointer {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl Vm {
+    /// Enables periodic checkpoints: when a reset happens at least `interval`
+    /// after the previous checkpoint, the dirty pages are copied before being
+    /// restored and written along with the registers as a delta dump (see
+    /// `DeltaDump`) by a background thread. The copies are double buffered,
+    /// a checkpoint being skipped rather than stalling the reset when the
+    /// writeback falls behind.
+    pub fn enable_checkpoints(&mut self, config: CheckpointConfig) -> Result<()> {
+        self.disable_checkpoints()?;
+
+        fs::create_dir_all(&config.directory)?;
+        self.checkpoints = Some(Checkpointer::new(config));
+
+        Ok(())
+    }
+
+    /// Disables the periodic checkpoints, waiting for the pending delta dumps
+    /// to be written. Returns the checkpoints statistics or the first
+    /// writeback error.
+    pub fn disable_checkpoints(&mut self) -> Result<CheckpointStats> {
+        let mut checkpoints = match self.checkpoints.take() {
+            Some(checkpoints) => checkpoints,
+            None => return Ok(CheckpointStats::default()),
+        };
+
+        checkpoints.finish();
+
+        match checkpoints.error.take() {
+            Some(err) => Err(err.into()),
+            None => Ok(checkpoints.stats),
+        }
+    }
+
+    /// Returns the statistics of the enabled checkpoints (the delta dumps
+    /// still being written are not counted)
+    pub fn checkpoint_stats(&mut self) -> CheckpointStats {
+        match self.checkpoints.as_mut() {
+            Some(checkpoints) => {
+                checkpoints.collect();
+                checkpoints.stats
+            }
+            None => CheckpointStats::default(),
+        }
+    }
+}
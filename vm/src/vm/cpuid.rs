@@ -0,0 +1,144 @@
+//! Guest CPUID configuration
+
+use super::{HvError, Result, Vm};
+
+use kvm_bindings::{kvm_cpuid_entry2, kvm_xcrs, CpuId, KVM_CPUID_FLAG_SIGNIFCANT_INDEX};
+use kvm_ioctls::VcpuFd;
+
+/// XCR0 state components enabled when supported (x87, SSE, AVX and AVX-512),
+/// all fitting in the KVM XSAVE area
+const XCR0_MASK: u64 = 0xe7;
+
+/// CR4 bit enabling the XSAVE feature set
+pub(super) const CR4_OSXSAVE: u64 = 1 << 18;
+
+/// CPUID.01H:ECX XSAVE support bit
+const CPUID_XSAVE: u32 = 1 << 26;
+
+/// CPUID leaf of the guest
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuidEntry {
+    /// Leaf (eax input)
+    pub function: u32,
+    /// Sub-leaf (ecx input)
+    pub index: u32,
+    /// eax output
+    pub eax: u32,
+    /// ebx output
+    pub ebx: u32,
+    /// ecx output
+    pub ecx: u32,
+    /// edx output
+    pub edx: u32,
+}
+
+impl From<&kvm_cpuid_entry2> for CpuidEntry {
+    fn from(entry: &kvm_cpuid_entry2) -> Self {
+        CpuidEntry {
+            function: entry.function,
+            index: entry.index,
+            eax: entry.eax,
+            ebx: entry.ebx,
+            ecx: entry.ecx,
+            edx: entry.edx,
+        }
+    }
+}
+
+/// Returns whether or not a cpuid exposes the XSAVE feature set
+pub(super) fn xsave_supported(cpuid: &CpuId) -> bool {
+    cpuid
+        .as_slice()
+        .iter()
+        .find(|e| e.function == 1)
+        .is_some_and(|e| e.ecx & CPUID_XSAVE != 0)
+}
+
+/// Sets the cpuid of a vcpu and enables the extended states (XCR0) it
+/// supports
+pub(super) fn apply_cpuid(vcpu: &VcpuFd, cpuid: &CpuId) -> Result<()> {
+    vcpu.set_cpuid2(cpuid)
+        .map_err(|err| HvError::os("Could not set vcpu cpuid", err.errno()))?;
+
+    if !xsave_supported(cpuid) {
+        return Ok(());
+    }
+
+    // Enable the supported extended states for the SSE/AVX registers
+    let xcr0 = cpuid
+        .as_slice()
+        .iter()
+        .find(|e| e.function == 0xd && e.index == 0)
+        .map(|e| (e.eax as u64 | (e.edx as u64) << 32) & XCR0_MASK)
+        .unwrap_or(1);
+    let mut xcrs = kvm_xcrs {
+        nr_xcrs: 1,
+        ..Default::default()
+    };
+    xcrs.xcrs[0].value = xcr0;
+
+    vcpu.set_xcrs(&xcrs)
+        .map_err(|err| HvError::os("Could not set xcr0", err.errno()).into())
+}
+
+impl Vm {
+    /// Returns the CPUID leaves seen by the guest
+    pub fn cpuid(&self) -> Vec<CpuidEntry> {
+        self.cpuid.as_slice().iter().map(CpuidEntry::from).collect()
+    }
+
+    /// Overrides or adds CPUID leaves seen by the guest (the host supported
+    /// ones by default). KVM only accepts this before the first run.
+    pub fn set_cpuid(&mut self, entries: &[CpuidEntry]) -> Result<()> {
+        let mut cpuid = self.cpuid.clone();
+
+        for entry in entries.iter() {
+            let existing = cpuid
+                .as_mut_slice()
+                .iter_mut()
+                .find(|e| e.function == entry.function && e.index == entry.index);
+
+            if let Some(existing) = existing {
+                existing.eax = entry.eax;
+                existing.ebx = entry.ebx;
+                existing.ecx = entry.ecx;
+                existing.edx = entry.edx;
+                continue;
+            }
+
+            // New sub-leaves of a leaf depend on the index like their siblings
+            let flags = match entry.index {
+                0 => cpuid
+                    .as_slice()
+                    .iter()
+                    .find(|e| e.function == entry.function)
+                    .map_or(0, |e| e.flags),
+                _ => KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            };
+
+            cpuid
+                .push(kvm_cpuid_entry2 {
+                    function: entry.function,
+                    index: entry.index,
+                    flags,
+                    eax: entry.eax,
+                    ebx: entry.ebx,
+                    ecx: entry.ecx,
+                    edx: entry.edx,
+                    ..Default::default()
+                })
+                .map_err(|_| HvError::new("Too many cpuid entries"))?;
+        }
+
+        apply_cpuid(&self.kvm_vcpu, &cpuid)?;
+        self.cpuid = cpuid;
+
+        // The XSAVE feature set can only be enabled when exposed
+        match xsave_supported(&self.cpuid) {
+            true => self.special_registers.cr4 |= CR4_OSXSAVE,
+            false => self.special_registers.cr4 &= !CR4_OSXSAVE,
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,389 @@
+//! Crash reports of the faulting exits
+
+use super::lockstep::COMPARED_REGISTERS;
+use super::{Register, Result, Vm, VmExit};
+
+use serde_json::{json, Value};
+
+use std::fs;
+use std::path::Path;
+
+/// Maximum size of an x86 instruction
+const MAX_INSTRUCTION_SIZE: usize = 15;
+
+/// Maximum number of frames of a stack trace
+const MAX_FRAMES: usize = 32;
+
+/// Number of stack slots scanned for return addresses when the frame pointers
+/// chain is unusable
+const STACK_SCAN_SLOTS: usize = 512;
+
+/// Memory captured below and above the stack pointer
+const STACK_WINDOW: (u64, u64) = (0x40, 0x100);
+
+/// Memory captured around the faulting address
+const FAULT_WINDOW: u64 = 0x40;
+
+/// Crash bucketing options of `Vm::crash_hash`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CrashHashConfig {
+    /// Number of stack frames hashed after the faulting instruction
+    pub frames: usize,
+    /// Hash the symbolic form of the faulting instruction and of the frames
+    /// (see `Vm::symbolize`), stable across the module load addresses,
+    /// rather than their addresses
+    pub symbolized: bool,
+    /// Hash the kind of exit: the exception vector and the page fault
+    /// status, but not the faulting address
+    pub exit_kind: bool,
+}
+
+impl Default for CrashHashConfig {
+    fn default() -> Self {
+        CrashHashConfig {
+            frames: 3,
+            symbolized: true,
+            exit_kind: true,
+        }
+    }
+}
+
+/// Frame of a crash stack trace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackFrame {
+    /// Instruction pointer or return address of the frame
+    pub address: u64,
+    /// Symbolic form of the address (see `Vm::symbolize`)
+    pub symbol: String,
+}
+
+/// Readable guest memory captured around a crash
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryWindow {
+    /// Address of the first byte
+    pub address: u64,
+    /// Content of the memory
+    pub data: Vec<u8>,
+}
+
+/// State of a vm stopped on a crash (see `Vm::crash_report`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrashReport {
+    /// Exit the vm stopped on
+    pub exit: VmExit,
+    /// General purpose registers, segment bases, flags and CR2
+    pub registers: Vec<(Register, u64)>,
+    /// Bytes of the faulting instruction (the next 15 readable bytes without
+    /// the `disasm` feature)
+    pub instruction: Vec<u8>,
+    /// Faulting instruction, in Intel syntax
+    pub disassembly: Option<String>,
+    /// Stack trace, starting with the faulting instruction
+    pub backtrace: Vec<StackFrame>,
+    /// Memory around the stack pointer and the faulting address
+    pub memory: Vec<MemoryWindow>,
+}
+
+impl CrashReport {
+    /// Returns the crash report in JSON form, the addresses and values as
+    /// hexadecimal strings
+    pub fn to_json(&self) -> String {
+        let hex = |value: u64| Value::String(format!("{:x}", value));
+        let bytes = |data: &[u8]| -> String { data.iter().map(|b| format!("{:02x}", b)).collect() };
+
+        let registers: serde_json::Map<String, Value> = self
+            .registers
+            .iter()
+            .map(|&(register, value)| (format!("{:?}", register).to_lowercase(), hex(value)))
+            .collect();
+
+        let backtrace: Vec<Value> = self
+            .backtrace
+            .iter()
+            .map(|f| json!({ "address": hex(f.address), "symbol": f.symbol }))
+            .collect();
+
+        let memory: Vec<Value> = self
+            .memory
+            .iter()
+            .map(|w| json!({ "address": hex(w.address), "data": bytes(&w.data) }))
+            .collect();
+
+        let mut report = json!({
+            "exit": format!("{:?}", self.exit),
+            "registers": registers,
+            "instruction": bytes(&self.instruction),
+            "backtrace": backtrace,
+            "memory": memory,
+        });
+        if let Some(disassembly) = self.disassembly.as_ref() {
+            report["disassembly"] = json!(disassembly);
+        }
+
+        report.to_string()
+    }
+
+    /// Writes the crash report to a file, in JSON form
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_json())?;
+        Ok(())
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`, returns its size and its
+/// Intel syntax
+#[cfg(feature = "disasm")]
+fn disassemble(bytes: &[u8], rip: u64) -> Option<(usize, String)> {
+    use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+
+    let mut decoder = Decoder::with_ip(64, bytes, rip, DecoderOptions::NONE);
+    let instruction = decoder.decode();
+    if instruction.is_invalid() {
+        return None;
+    }
+
+    let mut text = String::new();
+    IntelFormatter::new().format(&instruction, &mut text);
+    Some((instruction.len(), text))
+}
+
+/// Without a disassembler, the instruction bytes are kept whole
+#[cfg(not(feature = "disasm"))]
+fn disassemble(_bytes: &[u8], _rip: u64) -> Option<(usize, String)> {
+    None
+}
+
+/// FNV-1a hash of the crash buckets. Unlike `DefaultHasher`, its algorithm
+/// is specified: the buckets stay the same across the toolchains and the
+/// machines sharing them.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    /// Creates a hash from the FNV offset basis
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Adds bytes to the hash
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    /// Adds a string to the hash, terminated by a byte never found in UTF-8
+    fn write_str(&mut self, text: &str) {
+        self.write(text.as_bytes());
+        self.write(&[0xff]);
+    }
+}
+
+/// Returns the name of the kind of an exit, hashed by the crash buckets
+fn exit_kind_name(exit: &VmExit) -> &'static str {
+    match exit {
+        VmExit::Hlt => "Hlt",
+        VmExit::Breakpoint => "Breakpoint",
+        VmExit::Interrupted => "Interrupted",
+        VmExit::InvalidInstruction => "InvalidInstruction",
+        VmExit::PageFault(_) => "PageFault",
+        VmExit::Exception(_) => "Exception",
+        VmExit::Syscall => "Syscall",
+        VmExit::HookExit => "HookExit",
+        VmExit::HookCrash => "HookCrash",
+        VmExit::RetCorruption { .. } => "RetCorruption",
+        VmExit::CfiViolation(_) => "CfiViolation",
+        VmExit::HeapViolation(_) => "HeapViolation",
+        VmExit::Unhandled => "Unhandled",
+        VmExit::Budget => "Budget",
+        VmExit::Timeout => "Timeout",
+        VmExit::MmioFault { .. } => "MmioFault",
+        VmExit::Io { .. } => "Io",
+        VmExit::GuestExit(_) => "GuestExit",
+        VmExit::RegionTimeout { .. } => "RegionTimeout",
+        VmExit::Watchpoint { .. } => "Watchpoint",
+        VmExit::MemAccess { .. } => "MemAccess",
+    }
+}
+
+/// Hashes the kind of an exit, without the addresses varying between the
+/// cases hitting the same bug
+fn hash_exit_kind(exit: &VmExit, hasher: &mut Fnv1a) {
+    hasher.write_str(exit_kind_name(exit));
+
+    match *exit {
+        VmExit::PageFault(detail) => hasher.write(&detail.status.to_le_bytes()),
+        VmExit::Exception(vector) => hasher.write(&vector.to_le_bytes()),
+        _ => {}
+    }
+}
+
+impl Vm {
+    /// Returns whether or not an address is mapped executable
+    fn is_code(&self, address: u64) -> bool {
+        self.translate(address)
+            .is_some_and(|(_, permissions)| permissions.executable())
+    }
+
+    /// Returns whether or not a return address follows a call instruction
+    /// (direct `e8 rel32` or indirect `ff /2`)
+    fn follows_call(&self, address: u64) -> bool {
+        let mut bytes = [0u8; 7];
+        if address < 7 || self.read(address - 7, &mut bytes).is_err() {
+            return false;
+        }
+
+        bytes[2] == 0xe8
+            || [5, 4, 3, 1, 0]
+                .iter()
+                .any(|&i| bytes[i] == 0xff && (bytes[i + 1] >> 3) & 7 == 2)
+    }
+
+    /// Walks the frame pointers chain from RBP, returns the return addresses
+    fn frame_pointer_trace(&self) -> Vec<u64> {
+        let mut addresses = Vec::new();
+        let mut frame = self.get_reg(Register::Rbp);
+
+        while addresses.len() < MAX_FRAMES && frame.is_multiple_of(8) {
+            let slots = (
+                self.read_value_checked::<u64>(frame),
+                self.read_value_checked::<u64>(frame.wrapping_add(8)),
+            );
+            let (next, ret) = match slots {
+                (Ok(next), Ok(ret)) if self.is_code(ret) => (next, ret),
+                _ => break,
+            };
+
+            addresses.push(ret);
+            if next <= frame {
+                break;
+            }
+            frame = next;
+        }
+
+        addresses
+    }
+
+    /// Scans the stack from RSP for the return addresses following a call
+    fn stack_scan_trace(&self) -> Vec<u64> {
+        let rsp = self.get_reg(Register::Rsp);
+
+        (0..STACK_SCAN_SLOTS as u64)
+            .map_while(|slot| {
+                self.read_value_checked::<u64>(rsp.wrapping_add(slot * 8))
+                    .ok()
+            })
+            .filter(|&address| self.is_code(address) && self.follows_call(address))
+            .take(MAX_FRAMES)
+            .collect()
+    }
+
+    /// Returns the readable parts of a memory area
+    fn memory_windows(&self, address: u64, size: usize) -> Vec<MemoryWindow> {
+        let ranges = self.readable_ranges(address, size).unwrap_or_default();
+
+        ranges
+            .into_iter()
+            .filter_map(|(start, end)| {
+                let mut data = vec![0u8; (end - start) as usize];
+                self.read(start, &mut data).ok()?;
+                Some(MemoryWindow {
+                    address: start,
+                    data,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the stack trace of the vm, starting with the current
+    /// instruction. The trace follows the frame pointers chain, falling back
+    /// to the return addresses found on the stack.
+    pub fn backtrace(&self) -> Vec<StackFrame> {
+        let mut addresses = self.frame_pointer_trace();
+        if addresses.is_empty() {
+            addresses = self.stack_scan_trace();
+        }
+
+        std::iter::once(self.get_reg(Register::Rip))
+            .chain(addresses)
+            .map(|address| StackFrame {
+                address,
+                symbol: self.symbolize(address),
+            })
+            .collect()
+    }
+
+    /// Sets the options of `Vm::crash_hash`
+    #[inline]
+    pub fn set_crash_hash_config(&mut self, config: CrashHashConfig) {
+        self.crash_hash_config = config;
+    }
+
+    /// Returns the bucket of the crash the vm stopped on, for deduplication:
+    /// a hash of the faulting instruction, the top stack frames and the kind
+    /// of exit (see `CrashHashConfig`)
+    pub fn crash_hash(&self, exit: VmExit) -> u64 {
+        let config = self.crash_hash_config;
+        let mut hasher = Fnv1a::new();
+
+        if config.exit_kind {
+            hash_exit_kind(&exit, &mut hasher);
+        }
+
+        for frame in self.backtrace().iter().take(config.frames + 1) {
+            if config.symbolized {
+                hasher.write_str(&frame.symbol);
+            } else {
+                hasher.write(&frame.address.to_le_bytes());
+            }
+        }
+
+        hasher.0
+    }
+
+    /// Builds the report of a crash from the state of the vm stopped on
+    /// `exit` (usually a `VmExit::PageFault` or `VmExit::Exception`): the
+    /// registers, the faulting instruction (disassembled with the `disasm`
+    /// feature), a stack trace and the memory around the stack pointer and
+    /// the faulting address.
+    pub fn crash_report(&self, exit: VmExit) -> CrashReport {
+        let rip = self.get_reg(Register::Rip);
+        let rsp = self.get_reg(Register::Rsp);
+
+        let registers = COMPARED_REGISTERS
+            .iter()
+            .chain([Register::Cr2].iter())
+            .map(|&register| (register, self.get_reg(register)))
+            .collect();
+
+        let mut instruction = [0u8; MAX_INSTRUCTION_SIZE];
+        let read = match self.read_prefix(rip, &mut instruction) {
+            Ok(()) => instruction.len(),
+            Err(partial) => partial.read,
+        };
+        let mut instruction = instruction[..read].to_vec();
+        let disassembly = disassemble(&instruction, rip).map(|(size, text)| {
+            instruction.truncate(size);
+            text
+        });
+
+        let backtrace = self.backtrace();
+        let mut memory = self.memory_windows(
+            rsp.saturating_sub(STACK_WINDOW.0),
+            (STACK_WINDOW.0 + STACK_WINDOW.1) as usize,
+        );
+        if let VmExit::PageFault(detail) = exit {
+            let start = (detail.address & !0xf).saturating_sub(FAULT_WINDOW);
+            memory.extend(self.memory_windows(start, FAULT_WINDOW as usize * 2));
+        }
+
+        CrashReport {
+            exit,
+            registers,
+            instruction,
+            disassembly,
+            backtrace,
+            memory,
+        }
+    }
+}
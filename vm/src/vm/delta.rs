@@ -0,0 +1,104 @@
+//! Differential snapshots built from the dirty pages
+
+use super::checkpoint::DeltaDump;
+use super::{msr, Result, Vm, VmError};
+use crate::memory::PAGE_SIZE;
+use crate::snapshot::SnapshotRegisters;
+
+impl Vm {
+    /// Returns the state of the vm as a delta from `base`, the vm it was last
+    /// reset (or cloned) from: the registers, the x87/SSE/AVX state and the
+    /// model specific registers differing from `base`, and the pages dirtied
+    /// since the reset whose content differs. The dirty log is left for the
+    /// next reset. The pages written through the memory API (`Vm::write`)
+    /// are not dirty logged, they are not part of the delta.
+    pub fn snapshot_delta(&mut self, base: &Vm) -> Result<DeltaDump> {
+        if self.memory.banks() != base.memory.banks() {
+            return Err(VmError::InvalidOperation("Vm memory mismatch"));
+        }
+
+        let xsave = self.xsave()?;
+        let mut msrs = self.get_msrs(msr::SNAPSHOT_MSRS)?;
+        let base_msrs = base.get_msrs(msr::SNAPSHOT_MSRS)?;
+        msrs.retain(|index, value| base_msrs.get(index) != Some(value));
+
+        let r = &self.registers;
+        let registers = SnapshotRegisters {
+            rax: r.rax,
+            rbx: r.rbx,
+            rcx: r.rcx,
+            rdx: r.rdx,
+            rsi: r.rsi,
+            rdi: r.rdi,
+            rsp: r.rsp,
+            rbp: r.rbp,
+            r8: r.r8,
+            r9: r.r9,
+            r10: r.r10,
+            r11: r.r11,
+            r12: r.r12,
+            r13: r.r13,
+            r14: r.r14,
+            r15: r.r15,
+            rip: r.rip,
+            rflags: r.rflags,
+            fs_base: self.fs_base,
+            gs_base: self.gs_base,
+            xsave: (xsave != base.xsave()?).then_some(xsave),
+            msrs,
+        };
+
+        let mut delta = DeltaDump {
+            registers,
+            ..Default::default()
+        };
+
+        for (index, entry) in self.dirty_log().iter().enumerate() {
+            let mut bitmap = *entry;
+
+            while bitmap != 0 {
+                let pa = (index * 64 + bitmap.trailing_zeros() as usize) * PAGE_SIZE;
+                let page = self.memory.pmem.raw_slice(pa, PAGE_SIZE)?;
+                if page != base.memory.pmem.raw_slice(pa, PAGE_SIZE)? {
+                    delta.pages.insert(pa as u64, page.to_vec());
+                }
+
+                bitmap &= bitmap - 1;
+            }
+        }
+
+        Ok(delta)
+    }
+
+    /// Applies a delta (see `Vm::snapshot_delta`) to a vm in the state of its
+    /// base, e.g. a clone of the base or a vm just reset from it. The pages
+    /// written are restored by the next reset like the dirty ones.
+    pub fn apply_delta(&mut self, delta: &DeltaDump) -> Result<()> {
+        let invalid = delta.pages.iter().any(|(&pa, page)| {
+            !pa.is_multiple_of(PAGE_SIZE as u64)
+                || !self.memory.pmem.contains(pa)
+                || page.len() != PAGE_SIZE
+        });
+        if invalid {
+            return Err(VmError::InvalidOperation("Invalid delta page"));
+        }
+
+        for (&pa, page) in delta.pages.iter() {
+            self.memory
+                .pmem
+                .raw_slice_mut(pa as usize, PAGE_SIZE)?
+                .copy_from_slice(page);
+            self.written_pages.insert(pa);
+        }
+
+        self.set_regs_snapshot(&delta.registers);
+        self.flush_registers()?;
+
+        if let Some(xsave) = delta.registers.xsave.as_deref() {
+            self.set_xsave(xsave)?;
+        }
+        self.set_msrs(&delta.registers.msrs)?;
+
+        Ok(())
+    }
+}
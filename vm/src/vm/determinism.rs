@@ -0,0 +1,77 @@
+//! Deterministic execution controls
+//!
+//! KVM offers no exit on `rdtsc`, the guest TSC keeps counting with the host
+//! clock. In determinism mode it is rewound to a pinned value by the resets
+//! and clones, and optionally slowed down with the TSC scaling of the host, so
+//! runs shorter than a tick read the same counter. `rdrand` and `rdseed` are
+//! emulated with the vm randomness source, restored by the resets, and the
+//! CPUID leaves are pinned rather than taken from the host.
+
+use super::msr::IA32_TIME_STAMP_COUNTER;
+use super::{CpuidEntry, HvError, Result, Vm};
+
+/// Determinism mode options (see `Vm::enable_determinism`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeterminismConfig {
+    /// Guest TSC value at the start of the runs following a reset
+    pub tsc: u64,
+    /// Guest TSC frequency in kHz (KVM_SET_TSC_KHZ, requires the TSC
+    /// scaling of the host), a low frequency keeping the counter still
+    /// during the short runs. `None` keeps the host frequency.
+    pub tsc_khz: Option<u32>,
+    /// Emulates `rdrand` and `rdseed` with the vm randomness source (see
+    /// `Vm::trap_rdrand`, Intel hosts only)
+    pub trap_rdrand: bool,
+    /// CPUID leaves replacing the host ones (see `Vm::set_cpuid`), e.g. the
+    /// ones of the machine the snapshot was taken on
+    pub cpuid: Vec<CpuidEntry>,
+}
+
+impl Default for DeterminismConfig {
+    fn default() -> Self {
+        DeterminismConfig {
+            tsc: 0,
+            tsc_khz: None,
+            trap_rdrand: true,
+            cpuid: Vec::new(),
+        }
+    }
+}
+
+impl Vm {
+    /// Enables the determinism mode, so that two runs from the same state
+    /// and input execute identically: the guest TSC is pinned, `rdrand` and
+    /// `rdseed` are emulated and the CPUID leaves are pinned. Only effective
+    /// before the first run (KVM refusing CPUID changes afterwards).
+    pub fn enable_determinism(&mut self, config: DeterminismConfig) -> Result<()> {
+        if let Some(khz) = config.tsc_khz {
+            self.kvm_vcpu
+                .set_tsc_khz(khz)
+                .map_err(|err| HvError::os("Could not set tsc frequency", err.errno()))?;
+        }
+
+        if !config.cpuid.is_empty() {
+            self.set_cpuid(&config.cpuid)?;
+        }
+        if config.trap_rdrand {
+            self.trap_rdrand()?;
+        }
+
+        self.determinism = Some(config);
+        self.pin_tsc()
+    }
+
+    /// Returns the options of the determinism mode, if enabled
+    #[inline]
+    pub fn determinism(&self) -> Option<&DeterminismConfig> {
+        self.determinism.as_ref()
+    }
+
+    /// Rewinds the guest TSC to its pinned value in determinism mode
+    pub(super) fn pin_tsc(&mut self) -> Result<()> {
+        match self.determinism.as_ref() {
+            Some(config) => self.set_msr(IA32_TIME_STAMP_COUNTER, config.tsc),
+            None => Ok(()),
+        }
+    }
+}
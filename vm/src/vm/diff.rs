@@ -0,0 +1,164 @@
+//! Structured differences between two vm states
+
+use super::lockstep::COMPARED_REGISTERS;
+use super::{Register, Result, Vm};
+use crate::memory::{PageEntry, PagePermissions, PAGE_SIZE};
+
+use std::collections::BTreeMap;
+
+/// Control registers compared on top of the general purpose ones
+const CONTROL_REGISTERS: [Register; 5] = [
+    Register::Cr0,
+    Register::Cr2,
+    Register::Cr3,
+    Register::Cr4,
+    Register::Efer,
+];
+
+/// Register holding different values
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegisterDiff {
+    /// Changed register
+    pub register: Register,
+    /// Value in the left vm
+    pub left: u64,
+    /// Value in the right vm
+    pub right: u64,
+}
+
+/// Page mapped in both vms whose contents diverge
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PageDiff {
+    /// Virtual address of the page
+    pub address: u64,
+    /// Runs of differing bytes, as offsets in the page and sizes
+    pub ranges: Vec<(usize, usize)>,
+}
+
+/// Page mapped in a single vm, or with different permissions
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MappingDiff {
+    /// Virtual address of the page
+    pub address: u64,
+    /// Permissions in the left vm (None when not mapped)
+    pub left: Option<PagePermissions>,
+    /// Permissions in the right vm (None when not mapped)
+    pub right: Option<PagePermissions>,
+}
+
+/// Differences between two vm states (see `diff`)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmDiff {
+    /// Registers that changed
+    pub registers: Vec<RegisterDiff>,
+    /// Pages whose contents diverge, sorted by address
+    pub pages: Vec<PageDiff>,
+    /// Mappings present in one vm but not the other, or with different
+    /// permissions, sorted by address
+    pub mappings: Vec<MappingDiff>,
+}
+
+impl VmDiff {
+    /// Returns true if the vm states are identical
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.pages.is_empty() && self.mappings.is_empty()
+    }
+}
+
+/// Returns the runs of differing bytes between two pages
+fn diff_ranges(left: &[u8], right: &[u8]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+
+    for (offset, _) in left
+        .iter()
+        .zip(right)
+        .enumerate()
+        .filter(|(_, (l, r))| l != r)
+    {
+        match ranges.last_mut() {
+            Some((start, size)) if *start + *size == offset => *size += 1,
+            _ => ranges.push((offset, 1)),
+        }
+    }
+
+    ranges
+}
+
+/// Returns the differences between two vms: the registers that changed,
+/// the pages mapped in both whose contents diverge and the pages mapped in a
+/// single vm. Diffing a crashing vm against the clean vm it was reset from
+/// pinpoints the corrupted structures.
+pub fn diff(left: &Vm, right: &Vm) -> Result<VmDiff> {
+    let mut result = VmDiff::default();
+
+    for &register in COMPARED_REGISTERS.iter().chain(CONTROL_REGISTERS.iter()) {
+        let (l, r) = (left.get_reg(register), right.get_reg(register));
+        if l != r {
+            result.registers.push(RegisterDiff {
+                register,
+                left: l,
+                right: r,
+            });
+        }
+    }
+
+    let entries = |vm: &Vm| -> BTreeMap<u64, PageEntry> {
+        vm.memory
+            .page_entries()
+            .map(|entry| (entry.address, entry))
+            .collect()
+    };
+    let left_pages = entries(left);
+    let right_pages = entries(right);
+
+    for (&address, entry) in left_pages.iter() {
+        let other = match right_pages.get(&address) {
+            Some(other) => other,
+            None => {
+                result.mappings.push(MappingDiff {
+                    address,
+                    left: Some(entry.permissions),
+                    right: None,
+                });
+                continue;
+            }
+        };
+
+        if entry.permissions != other.permissions {
+            result.mappings.push(MappingDiff {
+                address,
+                left: Some(entry.permissions),
+                right: Some(other.permissions),
+            });
+        }
+
+        let left_data = left
+            .memory
+            .pmem
+            .raw_slice(entry.physical_address as usize, PAGE_SIZE)?;
+        let right_data = right
+            .memory
+            .pmem
+            .raw_slice(other.physical_address as usize, PAGE_SIZE)?;
+        if left_data != right_data {
+            result.pages.push(PageDiff {
+                address,
+                ranges: diff_ranges(left_data, right_data),
+            });
+        }
+    }
+
+    for (&address, entry) in right_pages.iter() {
+        if !left_pages.contains_key(&address) {
+            result.mappings.push(MappingDiff {
+                address,
+                left: None,
+                right: Some(entry.permissions),
+            });
+        }
+    }
+    result.mappings.sort_by_key(|mapping| mapping.address);
+
+    Ok(result)
+}
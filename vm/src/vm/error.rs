@@ -0,0 +1,186 @@
+//! Vm errors
+
+use super::integrity::IntegrityError;
+use super::Register;
+use crate::memory::MemoryError;
+use crate::snapshot::SnapshotError;
+use crate::symbols::SymbolsError;
+
+use std::{error, fmt, io};
+
+/// Object a failing hypervisor operation applied to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorContext {
+    /// Kvm memory slot
+    Slot(u32),
+    /// Guest address
+    Address(u64),
+    /// Vcpu register
+    Register(Register),
+    /// Model specific register
+    Msr(u32),
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorContext::Slot(slot) => write!(f, "slot {}", slot),
+            ErrorContext::Address(address) => write!(f, "address 0x{:x}", address),
+            ErrorContext::Register(register) => write!(f, "register {:?}", register),
+            ErrorContext::Msr(index) => write!(f, "msr 0x{:x}", index),
+        }
+    }
+}
+
+/// Failure of a hypervisor operation (kvm ioctls, capabilities, timers)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HvError {
+    /// Operation which failed
+    pub operation: &'static str,
+    /// Error number returned by the host, if any
+    pub errno: Option<i32>,
+    /// Object the operation applied to, if relevant
+    pub context: Option<ErrorContext>,
+}
+
+impl HvError {
+    /// Creates the error of an operation failing without an error number
+    pub(crate) fn new(operation: &'static str) -> Self {
+        HvError {
+            operation,
+            errno: None,
+            context: None,
+        }
+    }
+
+    /// Creates the error of an operation failing with `errno`
+    pub(crate) fn os(operation: &'static str, errno: i32) -> Self {
+        HvError {
+            errno: Some(errno),
+            ..HvError::new(operation)
+        }
+    }
+
+    /// Adds the object the operation applied to
+    pub(crate) fn with_context(self, context: ErrorContext) -> Self {
+        HvError {
+            context: Some(context),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for HvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.operation)?;
+        if let Some(context) = self.context {
+            write!(f, " ({})", context)?;
+        }
+        if let Some(errno) = self.errno {
+            write!(f, ": {}", io::Error::from_raw_os_error(errno))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl error::Error for HvError {}
+
+/// Vm manipulation error
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    /// Error during a memory access
+    MemoryError(MemoryError),
+    /// Error during snapshot loading
+    SnapshotError(SnapshotError),
+    /// Hypervisor error
+    HvError(HvError),
+    /// Operation refused because of its arguments or of the vm state
+    InvalidOperation(&'static str),
+    /// Error during symbol resolution
+    SymbolsError(SymbolsError),
+    /// Corruption of the internal structures (see `Vm::verify_internal_state`)
+    IntegrityError(IntegrityError),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::MemoryError(err) => write!(f, "Memory error: {}", err),
+            VmError::SnapshotError(err) => write!(f, "Snapshot error: {:?}", err),
+            VmError::HvError(err) => write!(f, "Hypervisor error: {}", err),
+            VmError::InvalidOperation(reason) => write!(f, "Invalid operation: {}", reason),
+            VmError::SymbolsError(err) => write!(f, "Symbols error: {:?}", err),
+            VmError::IntegrityError(err) => write!(f, "Integrity error: {}", err),
+        }
+    }
+}
+
+impl error::Error for VmError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            VmError::MemoryError(err) => Some(err),
+            VmError::HvError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<MemoryError> for VmError {
+    fn from(err: MemoryError) -> VmError {
+        VmError::MemoryError(err)
+    }
+}
+
+impl From<HvError> for VmError {
+    fn from(err: HvError) -> VmError {
+        VmError::HvError(err)
+    }
+}
+
+impl From<std::io::Error> for VmError {
+    fn from(err: std::io::Error) -> VmError {
+        VmError::SnapshotError(SnapshotError::IoError(err.to_string()))
+    }
+}
+
+impl From<SnapshotError> for VmError {
+    fn from(err: SnapshotError) -> VmError {
+        VmError::SnapshotError(err)
+    }
+}
+
+impl From<SymbolsError> for VmError {
+    fn from(err: SymbolsError) -> VmError {
+        VmError::SymbolsError(err)
+    }
+}
+
+impl From<IntegrityError> for VmError {
+    fn from(err: IntegrityError) -> VmError {
+        VmError::IntegrityError(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorContext, HvError, VmError};
+    use crate::vm::Register;
+
+    #[test]
+    fn test_error_display() {
+        let err = HvError::os("Could not commit registers", 22)
+            .with_context(ErrorContext::Register(Register::Rip));
+        assert_eq!(err.errno, Some(22));
+        assert!(err
+            .to_string()
+            .starts_with("Could not commit registers (register Rip): Invalid argument"));
+
+        let err = VmError::from(HvError::new("SyncRegs capability not present"));
+        assert_eq!(
+            err.to_string(),
+            "Hypervisor error: SyncRegs capability not present"
+        );
+        assert!(std::error::Error::source(&err).is_some());
+    }
+}
@@ -0,0 +1,61 @@
+//! Injection of exceptions and interrupts in the guest
+
+use super::{HvError, Result, Vm, VmError};
+use crate::x64::ExceptionType;
+
+use kvm_bindings::kvm_vcpu_events;
+
+/// First vector available to the interrupts
+const FIRST_INTERRUPT_VECTOR: u8 = 32;
+
+impl Vm {
+    /// Injects an exception delivered on the next run, before executing any
+    /// instruction. The error code must be given for the exceptions pushing
+    /// one, and the faulting address of a page fault set in `Register::Cr2`.
+    /// With the vm IDT, the exception then stops the run as if raised by the
+    /// instruction at rip.
+    pub fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) -> Result<()> {
+        if vector >= FIRST_INTERRUPT_VECTOR {
+            return Err(VmError::InvalidOperation("Invalid exception vector"));
+        }
+        if ExceptionType::from(vector as u64).has_error_code() != error_code.is_some() {
+            return Err(VmError::InvalidOperation(
+                "Invalid error code for the exception",
+            ));
+        }
+
+        let mut events = self.vcpu_events()?;
+        events.exception.injected = 1;
+        events.exception.nr = vector;
+        events.exception.has_error_code = error_code.is_some() as u8;
+        events.exception.error_code = error_code.unwrap_or(0);
+
+        self.set_vcpu_events(&events)
+    }
+
+    /// Injects an external interrupt delivered on the next run, whatever the
+    /// interrupt flag. The vm IDT only handles the exceptions, other vectors
+    /// raise a general protection fault unless the guest installed its own.
+    pub fn inject_interrupt(&mut self, vector: u8) -> Result<()> {
+        let mut events = self.vcpu_events()?;
+        events.interrupt.injected = 1;
+        events.interrupt.nr = vector;
+        events.interrupt.soft = 0;
+
+        self.set_vcpu_events(&events)
+    }
+
+    /// Returns the pending events of the vcpu
+    fn vcpu_events(&self) -> Result<kvm_vcpu_events> {
+        self.kvm_vcpu
+            .get_vcpu_events()
+            .map_err(|err| HvError::os("Could not get vcpu events", err.errno()).into())
+    }
+
+    /// Sets the pending events of the vcpu
+    fn set_vcpu_events(&self, events: &kvm_vcpu_events) -> Result<()> {
+        self.kvm_vcpu
+            .set_vcpu_events(events)
+            .map_err(|err| HvError::os("Could not set vcpu events", err.errno()).into())
+    }
+}
@@ -0,0 +1,628 @@
+//! Guest heap allocator emulation detecting memory safety errors
+
+use super::{Result, Vm, VmExit};
+use crate::memory::{PagePermissions, PAGE_SIZE};
+use crate::symbols::SymbolsError;
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// Byte filling the unused part of the allocation pages
+const REDZONE_BYTE: u8 = 0xfb;
+
+/// Minimal alignment of the returned allocations
+const MIN_ALIGNMENT: u64 = 16;
+
+/// `posix_memalign` out of memory error code
+const ENOMEM: u64 = 12;
+/// `posix_memalign` invalid alignment error code
+const EINVAL: u64 = 22;
+
+/// Heap allocator runtimes with ready-made hooks
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeapRuntime {
+    /// GNU C library allocator (`libc.so.6`)
+    Glibc,
+    /// jemalloc (`libjemalloc.so.2`), with or without the `je_` prefix
+    Jemalloc,
+    /// musl C library allocator (`ld-musl-x86_64.so.1`)
+    Musl,
+}
+
+/// Allocator functions emulated by the heap
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum HeapFunction {
+    /// `malloc(size)`
+    Malloc,
+    /// `calloc(count, size)`
+    Calloc,
+    /// `realloc(ptr, size)`
+    Realloc,
+    /// `free(ptr)`
+    Free,
+    /// `memalign(alignment, size)` and `aligned_alloc(alignment, size)`
+    Memalign,
+    /// `posix_memalign(memptr, alignment, size)`
+    PosixMemalign,
+}
+
+/// Names of the allocator functions exported by all the runtimes
+const COMMON_FUNCTIONS: &[(&str, HeapFunction)] = &[
+    ("malloc", HeapFunction::Malloc),
+    ("calloc", HeapFunction::Calloc),
+    ("realloc", HeapFunction::Realloc),
+    ("free", HeapFunction::Free),
+    ("memalign", HeapFunction::Memalign),
+    ("aligned_alloc", HeapFunction::Memalign),
+    ("posix_memalign", HeapFunction::PosixMemalign),
+];
+
+/// Internal glibc names of the allocator functions
+const GLIBC_FUNCTIONS: &[(&str, HeapFunction)] = &[
+    ("__libc_malloc", HeapFunction::Malloc),
+    ("__libc_calloc", HeapFunction::Calloc),
+    ("__libc_realloc", HeapFunction::Realloc),
+    ("__libc_free", HeapFunction::Free),
+    ("__libc_memalign", HeapFunction::Memalign),
+];
+
+/// Prefixed jemalloc names of the allocator functions
+const JEMALLOC_FUNCTIONS: &[(&str, HeapFunction)] = &[
+    ("je_malloc", HeapFunction::Malloc),
+    ("je_calloc", HeapFunction::Calloc),
+    ("je_realloc", HeapFunction::Realloc),
+    ("je_free", HeapFunction::Free),
+    ("je_memalign", HeapFunction::Memalign),
+    ("je_aligned_alloc", HeapFunction::Memalign),
+    ("je_posix_memalign", HeapFunction::PosixMemalign),
+];
+
+impl HeapRuntime {
+    /// Names of the modules exporting the allocator (matched on prefix)
+    fn modules(&self) -> &'static [&'static str] {
+        match self {
+            HeapRuntime::Glibc => &["libc.so"],
+            HeapRuntime::Jemalloc => &["libjemalloc.so"],
+            HeapRuntime::Musl => &["ld-musl", "libc.musl"],
+        }
+    }
+
+    /// Exported names of the allocator functions
+    fn functions(&self) -> impl Iterator<Item = &'static (&'static str, HeapFunction)> {
+        let specific = match self {
+            HeapRuntime::Glibc => GLIBC_FUNCTIONS,
+            HeapRuntime::Jemalloc => JEMALLOC_FUNCTIONS,
+            HeapRuntime::Musl => &[],
+        };
+
+        COMMON_FUNCTIONS.iter().chain(specific.iter())
+    }
+}
+
+/// How long freed allocations stay unmapped before their pages are reused.
+/// Any access to a chunk in quarantine is reported as a use after free.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Quarantine {
+    /// Keeps at most this number of bytes of freed chunks
+    Bytes(u64),
+    /// Keeps the freed chunks until this number of allocations were made
+    Allocations(u64),
+}
+
+/// Guest heap configuration
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HeapConfig {
+    /// Start address of the region the allocations are made from, it must not
+    /// overlap the snapshot mappings
+    pub base: u64,
+    /// Size of the allocations region
+    pub size: u64,
+    /// Quarantine of the freed allocations
+    pub quarantine: Quarantine,
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self {
+        HeapConfig {
+            base: 0x6000_0000_0000,
+            size: 0x1_0000_0000,
+            quarantine: Quarantine::Bytes(0x100_0000),
+        }
+    }
+}
+
+/// Memory safety error detected by the guest heap
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeapViolation {
+    /// Access to the redzone of an allocation
+    OutOfBounds {
+        /// Address accessed (or first corrupted redzone byte)
+        address: u64,
+        /// Address of the allocation
+        allocation: u64,
+        /// Size of the allocation
+        size: u64,
+        /// Return address of the allocation call
+        allocation_site: u64,
+    },
+    /// Access to a freed allocation
+    UseAfterFree {
+        /// Address accessed
+        address: u64,
+        /// Address of the freed allocation
+        allocation: u64,
+        /// Size of the freed allocation
+        size: u64,
+        /// Return address of the allocation call
+        allocation_site: u64,
+        /// Return address of the free call
+        free_site: u64,
+    },
+    /// Free of an allocation already freed
+    DoubleFree {
+        /// Address of the allocation
+        allocation: u64,
+        /// Return address of the allocation call
+        allocation_site: u64,
+        /// Return address of the first free call
+        free_site: u64,
+    },
+    /// Free of an address which was not returned by the allocator
+    InvalidFree {
+        /// Address freed
+        address: u64,
+    },
+}
+
+/// Live guest heap allocation
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HeapAllocation {
+    /// Address of the allocation
+    pub address: u64,
+    /// Size of the allocation
+    pub size: u64,
+    /// Return address of the allocation call
+    pub site: u64,
+}
+
+/// Guest heap usage since the last reset
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapReport {
+    /// Number of allocations made
+    pub allocations: u64,
+    /// Number of allocations freed
+    pub frees: u64,
+    /// Number of bytes currently allocated
+    pub live: u64,
+    /// Highest number of bytes allocated at once
+    pub peak: u64,
+    /// Allocations made and not freed
+    pub leaks: Vec<HeapAllocation>,
+}
+
+/// Pages backing an allocation. The allocation is placed at the end of its
+/// pages, right before an unmapped guard page.
+#[derive(Copy, Clone, Debug)]
+struct Chunk {
+    /// Number of pages of the chunk
+    pages: u64,
+    /// Address returned to the guest
+    address: u64,
+    /// Requested size
+    size: u64,
+    /// Return address of the allocation call
+    allocation_site: u64,
+    /// Return address of the free call, if freed
+    free_site: Option<u64>,
+    /// Number of allocations made before this one
+    index: u64,
+}
+
+/// Emulated guest heap state
+#[derive(Clone, Debug, Default)]
+pub(super) struct GuestHeap {
+    /// Configuration of the heap
+    config: HeapConfig,
+    /// Emulated allocator functions by address
+    functions: BTreeMap<u64, HeapFunction>,
+    /// Next address never used by a chunk
+    cursor: u64,
+    /// Chunks by start address
+    chunks: BTreeMap<u64, Chunk>,
+    /// Number of allocations made
+    allocations: u64,
+    /// Number of allocations made at the last reset
+    reset_point: u64,
+    /// Number of allocations freed since the last reset
+    frees: u64,
+    /// Number of bytes currently allocated
+    live: u64,
+    /// Highest number of bytes allocated at once since the last reset
+    peak: u64,
+    /// Freed chunks not yet reusable and the allocations count at the time
+    /// they were freed, oldest first
+    quarantine: VecDeque<(u64, u64)>,
+    /// Number of bytes in quarantine
+    quarantined: u64,
+    /// Reusable chunks by number of pages
+    reusable: BTreeMap<u64, Vec<u64>>,
+    /// Pages whose presence changed since the last reset
+    touched: BTreeSet<u64>,
+}
+
+impl GuestHeap {
+    /// Returns whether or not an address is an emulated allocator function
+    #[inline]
+    pub(super) fn contains(&self, address: u64) -> bool {
+        self.functions.contains_key(&address)
+    }
+
+    /// Returns the chunk covering an address or the guard page after it
+    fn chunk_at(&self, address: u64) -> Option<(u64, &Chunk)> {
+        let (&start, chunk) = self.chunks.range(..=address).next_back()?;
+
+        match address < start + (chunk.pages + 1) * PAGE_SIZE as u64 {
+            true => Some((start, chunk)),
+            false => None,
+        }
+    }
+
+    /// Explains a page fault caused by the heap pages
+    pub(super) fn classify(&self, address: u64) -> Option<HeapViolation> {
+        let (start, chunk) = self.chunk_at(address)?;
+        let end = start + chunk.pages * PAGE_SIZE as u64;
+
+        match (chunk.free_site, address < end) {
+            (Some(free_site), true) => Some(HeapViolation::UseAfterFree {
+                address,
+                allocation: chunk.address,
+                size: chunk.size,
+                allocation_site: chunk.allocation_site,
+                free_site,
+            }),
+            (_, false) => Some(HeapViolation::OutOfBounds {
+                address,
+                allocation: chunk.address,
+                size: chunk.size,
+                allocation_site: chunk.allocation_site,
+            }),
+            (None, true) => None,
+        }
+    }
+
+    /// Restores the heap state from an other one, returns the pages whose
+    /// presence must be restored
+    pub(super) fn reset(&mut self, other: &GuestHeap) -> BTreeSet<u64> {
+        let touched = std::mem::take(&mut self.touched);
+
+        self.cursor = other.cursor;
+        self.chunks.clone_from(&other.chunks);
+        self.allocations = other.allocations;
+        self.reset_point = other.allocations;
+        self.frees = 0;
+        self.live = other.live;
+        self.peak = other.live;
+        self.quarantine.clone_from(&other.quarantine);
+        self.quarantined = other.quarantined;
+        self.reusable.clone_from(&other.reusable);
+
+        touched
+    }
+
+    /// Returns whether or not a heap page is accessible to the guest
+    pub(super) fn page_present(&self, page: u64) -> bool {
+        match self.chunk_at(page) {
+            Some((start, chunk)) => {
+                chunk.free_site.is_none() && page < start + chunk.pages * PAGE_SIZE as u64
+            }
+            None => false,
+        }
+    }
+
+    /// Returns an iterator over the live allocations
+    fn live_allocations(&self) -> impl Iterator<Item = (&Chunk, HeapAllocation)> + '_ {
+        self.chunks
+            .values()
+            .filter(|chunk| chunk.free_site.is_none())
+            .map(|chunk| {
+                let allocation = HeapAllocation {
+                    address: chunk.address,
+                    size: chunk.size,
+                    site: chunk.allocation_site,
+                };
+                (chunk, allocation)
+            })
+    }
+
+    /// Makes the chunks which spent enough time in quarantine reusable
+    fn release_quarantine(&mut self) {
+        while let Some(&(start, freed_at)) = self.quarantine.front() {
+            let expired = match self.config.quarantine {
+                Quarantine::Bytes(limit) => self.quarantined > limit,
+                Quarantine::Allocations(count) => self.allocations - freed_at >= count,
+            };
+            if !expired {
+                break;
+            }
+
+            let pages = self.chunks[&start].pages;
+            self.quarantine.pop_front();
+            self.quarantined -= pages * PAGE_SIZE as u64;
+            self.reusable.entry(pages).or_default().push(start);
+        }
+    }
+}
+
+/// Rounds a value up to an alignment (power of two)
+#[inline]
+fn align_up(value: u64, alignment: u64) -> Option<u64> {
+    value
+        .checked_add(alignment - 1)
+        .map(|value| value & !(alignment - 1))
+}
+
+impl Vm {
+    /// Emulates the allocator of a runtime resolved through the vm `Symbols`.
+    /// Allocations are made from the configured region, each one is followed
+    /// by a guard page and freed ones are kept unmapped in a quarantine. Heap
+    /// errors stop the execution with `VmExit::HeapViolation`. Returns the
+    /// number of emulated functions.
+    pub fn install_heap(&mut self, runtime: HeapRuntime, config: HeapConfig) -> Result<usize> {
+        let module = runtime
+            .modules()
+            .iter()
+            .find_map(|name| self.symbols.module(name))
+            .ok_or_else(|| SymbolsError::UnknownModule(runtime.modules().join(" or ")))?;
+
+        let functions: BTreeMap<u64, HeapFunction> = runtime
+            .functions()
+            .filter_map(|&(name, function)| module.symbol(name).map(|address| (address, function)))
+            .collect();
+
+        for &address in functions.keys() {
+            self.add_breakpoint(address)?;
+        }
+
+        self.heap.config = config;
+        self.heap.cursor = self.heap.cursor.max(config.base);
+        self.heap.functions.extend(functions.iter());
+
+        Ok(functions.len())
+    }
+
+    /// Returns an iterator over the live allocations of the emulated heap
+    pub fn heap_allocations(&self) -> impl Iterator<Item = HeapAllocation> + '_ {
+        self.heap
+            .live_allocations()
+            .map(|(_, allocation)| allocation)
+    }
+
+    /// Reports the emulated heap usage since the last reset. The leaks are
+    /// the allocations made since then which are still live.
+    pub fn heap_report(&self) -> HeapReport {
+        let heap = &self.heap;
+
+        HeapReport {
+            allocations: heap.allocations - heap.reset_point,
+            frees: heap.frees,
+            live: heap.live,
+            peak: heap.peak,
+            leaks: heap
+                .live_allocations()
+                .filter(|(chunk, _)| chunk.index >= heap.reset_point)
+                .map(|(_, allocation)| allocation)
+                .collect(),
+        }
+    }
+
+    /// Changes the guest visibility of the pages of a chunk
+    fn set_chunk_present(&mut self, start: u64, pages: u64, present: bool) -> Result<()> {
+        for page in (0..pages).map(|i| start + i * PAGE_SIZE as u64) {
+            match self.memory.set_page_present(page, present) {
+                Err(_) if present => self.memory.mmap(
+                    page,
+                    PAGE_SIZE,
+                    PagePermissions::READ | PagePermissions::WRITE,
+                )?,
+                result => result?,
+            }
+            self.heap.touched.insert(page);
+        }
+
+        Ok(())
+    }
+
+    /// Allocates memory in the guest heap, returns a null pointer when the
+    /// heap is exhausted
+    fn heap_alloc(&mut self, size: u64, alignment: u64, site: u64) -> Result<u64> {
+        let alignment = alignment.max(MIN_ALIGNMENT);
+        let page_size = PAGE_SIZE as u64;
+
+        // Alignments bigger than a page cannot rely on the chunk end
+        let span = align_up(size, alignment)
+            .and_then(|span| span.checked_add(if alignment > page_size { alignment } else { 0 }))
+            .and_then(|span| align_up(span.max(1), page_size));
+        let pages = match span {
+            Some(span) => span / page_size,
+            None => return Ok(0),
+        };
+
+        // Reuse a chunk out of quarantine or take new pages
+        self.heap.release_quarantine();
+        let start = match self.heap.reusable.get_mut(&pages).and_then(|c| c.pop()) {
+            Some(start) => start,
+            None => {
+                let start = self.heap.cursor;
+                let end = (pages + 1)
+                    .checked_mul(page_size)
+                    .and_then(|size| start.checked_add(size));
+
+                match end {
+                    Some(end) if end <= self.heap.config.base + self.heap.config.size => {
+                        self.heap.cursor = end;
+                        start
+                    }
+                    _ => return Ok(0),
+                }
+            }
+        };
+
+        let end = start + pages * page_size;
+        let address = (end - size) & !(alignment - 1);
+        self.set_chunk_present(start, pages, true)?;
+
+        // Fill the redzones around the allocation to detect small overflows
+        self.memory
+            .write(start, &vec![REDZONE_BYTE; (address - start) as usize])?;
+        self.memory.write(
+            address + size,
+            &vec![REDZONE_BYTE; (end - address - size) as usize],
+        )?;
+
+        self.heap.chunks.insert(
+            start,
+            Chunk {
+                pages,
+                address,
+                size,
+                allocation_site: site,
+                free_site: None,
+                index: self.heap.allocations,
+            },
+        );
+        self.heap.allocations += 1;
+        self.heap.live += size;
+        self.heap.peak = self.heap.peak.max(self.heap.live);
+
+        Ok(address)
+    }
+
+    /// Frees memory of the guest heap
+    fn heap_free(&mut self, address: u64, site: u64) -> Result<Option<HeapViolation>> {
+        let (start, chunk) = match self.heap.chunk_at(address) {
+            Some((start, &chunk)) if chunk.address == address => (start, chunk),
+            _ => return Ok(Some(HeapViolation::InvalidFree { address })),
+        };
+
+        if let Some(free_site) = chunk.free_site {
+            return Ok(Some(HeapViolation::DoubleFree {
+                allocation: address,
+                allocation_site: chunk.allocation_site,
+                free_site,
+            }));
+        }
+
+        // Check the redzones
+        let end = start + chunk.pages * PAGE_SIZE as u64;
+        let mut pages = vec![0u8; (end - start) as usize];
+        self.memory.read(start, &mut pages)?;
+
+        let allocation = (address - start) as usize..(address - start + chunk.size) as usize;
+        let corrupted = pages
+            .iter()
+            .enumerate()
+            .find(|&(offset, &byte)| byte != REDZONE_BYTE && !allocation.contains(&offset));
+        if let Some((offset, _)) = corrupted {
+            return Ok(Some(HeapViolation::OutOfBounds {
+                address: start + offset as u64,
+                allocation: address,
+                size: chunk.size,
+                allocation_site: chunk.allocation_site,
+            }));
+        }
+
+        // Quarantine the chunk, its pages are not accessible anymore
+        self.set_chunk_present(start, chunk.pages, false)?;
+        self.heap.chunks.insert(
+            start,
+            Chunk {
+                free_site: Some(site),
+                ..chunk
+            },
+        );
+        self.heap
+            .quarantine
+            .push_back((start, self.heap.allocations));
+        self.heap.quarantined += chunk.pages * PAGE_SIZE as u64;
+        self.heap.frees += 1;
+        self.heap.live -= chunk.size;
+        self.heap.release_quarantine();
+
+        Ok(None)
+    }
+
+    /// Emulates a call to an allocator function, returning to the caller.
+    /// Returns the exit to report on heap errors.
+    pub(super) fn emulate_heap_call(&mut self, rip: u64) -> Result<Option<VmExit>> {
+        let (arg0, arg1, arg2) = (self.registers.rdi, self.registers.rsi, self.registers.rdx);
+        let site: u64 = self.memory.read_val(self.registers.rsp)?;
+
+        let result = match self.heap.functions[&rip] {
+            HeapFunction::Malloc => self.heap_alloc(arg0, MIN_ALIGNMENT, site)?,
+            HeapFunction::Calloc => match arg0.checked_mul(arg1) {
+                Some(size) => {
+                    let address = self.heap_alloc(size, MIN_ALIGNMENT, site)?;
+                    if address != 0 {
+                        self.memory.write(address, &vec![0; size as usize])?;
+                    }
+                    address
+                }
+                None => 0,
+            },
+            HeapFunction::Realloc => {
+                let old = self.heap.chunk_at(arg0).map(|(_, chunk)| *chunk);
+                let address = match (arg0, arg1) {
+                    (0, size) => self.heap_alloc(size, MIN_ALIGNMENT, site)?,
+                    (_, 0) => 0,
+                    (_, size) => self.heap_alloc(size, MIN_ALIGNMENT, site)?,
+                };
+
+                // Move the data of a valid allocation before freeing it
+                if let Some(old) = old.filter(|old| old.address == arg0 && old.free_site.is_none())
+                {
+                    if address != 0 {
+                        let mut data = vec![0u8; old.size.min(arg1) as usize];
+                        self.memory.read(arg0, &mut data)?;
+                        self.memory.write(address, &data)?;
+                    }
+                }
+
+                // A failed realloc keeps the original allocation
+                if arg0 != 0 && (address != 0 || arg1 == 0) {
+                    if let Some(violation) = self.heap_free(arg0, site)? {
+                        return Ok(Some(VmExit::HeapViolation(violation)));
+                    }
+                }
+                address
+            }
+            HeapFunction::Free => {
+                if arg0 != 0 {
+                    if let Some(violation) = self.heap_free(arg0, site)? {
+                        return Ok(Some(VmExit::HeapViolation(violation)));
+                    }
+                }
+                0
+            }
+            HeapFunction::Memalign => match arg0.is_power_of_two() {
+                true => self.heap_alloc(arg1, arg0, site)?,
+                false => 0,
+            },
+            HeapFunction::PosixMemalign => match arg1.is_power_of_two() && arg1.is_multiple_of(8) {
+                true => match self.heap_alloc(arg2, arg1, site)? {
+                    0 => ENOMEM,
+                    address => {
+                        self.memory.write_val(arg0, address)?;
+                        0
+                    }
+                },
+                false => EINVAL,
+            },
+        };
+
+        // Return to the caller
+        self.registers.rax = result;
+        self.registers.rip = site;
+        self.registers.rsp += 8;
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,364 @@
+//! Breakpoint based execution hooks
+//!
+//! Several subsystems may claim the breakpoint of an address, they are
+//! dispatched in this order when it is reached:
+//!
+//! 1. the return address monitor, reporting `VmExit::RetCorruption`;
+//! 2. the user hooks, by decreasing priority then installation order. A hook
+//!    returning `HookResult::Continue` passes to the next one, the other
+//!    results end the chain;
+//! 3. the emulated heap functions, unless a hook moved rip;
+//! 4. the indirect branch check of the CFI policy, after the instruction is
+//!    stepped over.
+//!
+//! A breakpoint claimed by none of them stops with `VmExit::Breakpoint`.
+
+use super::{Result, Vm, VmExit};
+
+/// Software breakpoint instruction (int3)
+pub(super) const BREAKPOINT_OPCODE: u8 = 0xcc;
+
+/// Debug exception vector (single step)
+pub(super) const DEBUG_VECTOR: u32 = 1;
+
+/// Action to take once a hook returns
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HookResult {
+    /// Continue the execution with the hooked instruction
+    Continue,
+    /// The hook modified rip, resume the execution from there
+    Redirect,
+    /// Stop the execution (`VmExit::HookExit`)
+    Exit,
+    /// Stop the execution reporting a crash (`VmExit::HookCrash`)
+    Crash,
+}
+
+/// Handler called when the guest reaches a hooked address
+pub type HookFn = dyn FnMut(&mut Vm) -> HookResult;
+
+/// Identifier of an installed hook
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HookId(u64);
+
+/// Subsystem claiming a breakpoint, see the dispatch order above
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BreakpointOwner {
+    /// Return address monitor (`Vm::monitor_returns`)
+    ReturnMonitor,
+    /// User hook
+    Hook {
+        /// Hook identifier
+        id: HookId,
+        /// Hook priority
+        priority: i32,
+    },
+    /// Emulated heap function
+    Heap,
+    /// Indirect branch site (`Vm::add_cfi_site`)
+    Cfi,
+    /// Breakpoint without any owner, stopping the execution
+    Breakpoint,
+}
+
+/// Hook installed at an address
+pub(super) struct HookEntry {
+    /// Identifier
+    id: HookId,
+    /// Priority, the highest running first
+    priority: i32,
+    /// Handler, taken out while it runs
+    handler: Option<Box<HookFn>>,
+}
+
+impl Vm {
+    /// Installs a software breakpoint. Reaching it stops the execution with a
+    /// `VmExit::Breakpoint`, rip pointing to the breakpoint.
+    pub fn add_breakpoint(&mut self, address: u64) -> Result<()> {
+        if self.breakpoints.contains_key(&address) {
+            return Ok(());
+        }
+
+        // Save the original byte and patch the instruction
+        let orig_byte: u8 = self.memory.read_val(address)?;
+        self.memory.write_val(address, BREAKPOINT_OPCODE)?;
+        self.breakpoints.insert(address, orig_byte);
+
+        Ok(())
+    }
+
+    /// Removes a software breakpoint (and the associated hook if any)
+    pub fn remove_breakpoint(&mut self, address: u64) -> Result<()> {
+        self.hooks.remove(&address);
+
+        if let Some(orig_byte) = self.breakpoints.remove(&address) {
+            self.memory.write_val(address, orig_byte)?;
+        }
+
+        // The breakpoint is gone, nothing to re-arm
+        if self.stepping_over == Some(address) {
+            self.stepping_over = None;
+        }
+
+        Ok(())
+    }
+
+    /// Installs a hook called each time the guest reaches `address`, with
+    /// the default priority (0)
+    pub fn hook<F>(&mut self, address: u64, handler: F) -> Result<()>
+    where
+        F: FnMut(&mut Vm) -> HookResult + 'static,
+    {
+        self.hook_with_priority(address, 0, handler)?;
+
+        Ok(())
+    }
+
+    /// Installs a hook called each time the guest reaches `address`, before
+    /// the hooks of lower priority. Returns its identifier.
+    pub fn hook_with_priority<F>(
+        &mut self,
+        address: u64,
+        priority: i32,
+        handler: F,
+    ) -> Result<HookId>
+    where
+        F: FnMut(&mut Vm) -> HookResult + 'static,
+    {
+        self.add_breakpoint(address)?;
+
+        let id = HookId(self.next_hook_id);
+        self.next_hook_id += 1;
+
+        let hooks = self.hooks.entry(address).or_default();
+        let index = hooks
+            .iter()
+            .position(|hook| hook.priority < priority)
+            .unwrap_or(hooks.len());
+        hooks.insert(
+            index,
+            HookEntry {
+                id,
+                priority,
+                handler: Some(Box::new(handler)),
+            },
+        );
+
+        Ok(id)
+    }
+
+    /// Removes a single hook, the breakpoint being removed if nothing else
+    /// claims it
+    pub fn remove_hook(&mut self, id: HookId) -> Result<()> {
+        let address = self
+            .hooks
+            .iter()
+            .find(|(_, hooks)| hooks.iter().any(|hook| hook.id == id))
+            .map(|(&address, _)| address);
+
+        if let Some(address) = address {
+            let hooks = self.hooks.get_mut(&address).unwrap();
+            hooks.retain(|hook| hook.id != id);
+            if hooks.is_empty() {
+                self.hooks.remove(&address);
+            }
+
+            if !self.breakpoint_claimed(address) {
+                self.remove_breakpoint(address)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the subsystems claiming the breakpoint of an address, in
+    /// dispatch order (empty without breakpoint)
+    pub fn registered_at(&self, address: u64) -> Vec<BreakpointOwner> {
+        let mut owners = Vec::new();
+
+        if !self.breakpoints.contains_key(&address) {
+            return owners;
+        }
+
+        if self.return_monitor.contains(address) {
+            owners.push(BreakpointOwner::ReturnMonitor);
+        }
+
+        for hook in self.hooks.get(&address).into_iter().flatten() {
+            owners.push(BreakpointOwner::Hook {
+                id: hook.id,
+                priority: hook.priority,
+            });
+        }
+
+        if self.heap.contains(address) {
+            owners.push(BreakpointOwner::Heap);
+        }
+
+        if self.cfi.contains(address) {
+            owners.push(BreakpointOwner::Cfi);
+        }
+
+        if owners.is_empty() {
+            owners.push(BreakpointOwner::Breakpoint);
+        }
+
+        owners
+    }
+
+    /// Returns whether or not a subsystem other than the plain breakpoints
+    /// claims an address
+    pub(super) fn breakpoint_claimed(&self, address: u64) -> bool {
+        self.hooks.contains_key(&address)
+            || self.return_monitor.contains(address)
+            || self.heap.contains(address)
+            || self.cfi.contains(address)
+    }
+
+    /// Installs a hook on a symbol resolved through the vm `Symbols`, returns
+    /// the hooked address.
+    pub fn hook_symbol<F>(&mut self, module: &str, symbol: &str, handler: F) -> Result<u64>
+    where
+        F: FnMut(&mut Vm) -> HookResult + 'static,
+    {
+        let address = self.symbols.resolve(module, symbol)?;
+        self.hook(address, handler)?;
+
+        Ok(address)
+    }
+
+    /// Installs breakpoints on the addresses of a source line (see
+    /// `Symbols::resolve_line`), returns the addresses.
+    pub fn break_at_line(&mut self, file: &str, line: u32) -> Result<Vec<u64>> {
+        let addresses = self.symbols.resolve_line(file, line)?;
+
+        for &address in addresses.iter() {
+            self.add_breakpoint(address)?;
+        }
+
+        Ok(addresses)
+    }
+
+    /// Removes all the hooks of an address and its breakpoint
+    #[inline]
+    pub fn unhook(&mut self, address: u64) -> Result<()> {
+        self.remove_breakpoint(address)
+    }
+
+    /// Re-arms the breakpoint we were stepping over once rip moved away from
+    /// it.
+    pub(super) fn finish_step_over(&mut self) -> Result<()> {
+        let address = match self.stepping_over {
+            Some(address) if address != self.registers.rip => address,
+            _ => return Ok(()),
+        };
+
+        self.stepping_over = None;
+        self.memory.write_val(address, BREAKPOINT_OPCODE)?;
+        self.set_singlestep(false)
+    }
+
+    /// Restores the original instruction of a breakpoint and single steps over
+    /// it
+    fn step_over(&mut self, address: u64) -> Result<()> {
+        if let Some(&orig_byte) = self.breakpoints.get(&address) {
+            self.memory.write_val(address, orig_byte)?;
+            self.stepping_over = Some(address);
+            self.set_singlestep(true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles a debug vm exit. Returns the exit to report to the user or
+    /// `None` if the execution should be resumed.
+    pub(super) fn handle_debug_exit(&mut self, exception: u32) -> Result<Option<VmExit>> {
+        // Single step over an access to a watched page
+        let traced = self.finish_access_step(exception)?;
+        if traced && self.stepping_over.is_none() && !self.stepping {
+            return Ok(None);
+        }
+
+        // Single step used to move over a hooked instruction
+        if let (DEBUG_VECTOR, Some(address)) = (exception, self.stepping_over) {
+            self.finish_step_over()?;
+            let exit = self.check_cfi(address)?;
+            return Ok(exit.or_else(|| self.complete_step()));
+        }
+
+        // Single step requested by `Vm::step`, the hooks run on the next one
+        if exception == DEBUG_VECTOR && self.stepping {
+            return Ok(self.complete_step());
+        }
+
+        let rip = self.registers.rip;
+
+        // Check the return addresses first
+        if let Some(exit) = self.check_return_monitor(rip)? {
+            return Ok(Some(exit));
+        }
+
+        // Breakpoint without any owner (or not ours)
+        if !self.breakpoint_claimed(rip) {
+            return Ok(Some(VmExit::Breakpoint));
+        }
+
+        // Chain the hooks
+        if let Some(exit) = self.dispatch_hooks(rip) {
+            return Ok(exit);
+        }
+
+        // A hook moved rip or removed the breakpoint
+        if self.registers.rip != rip || !self.breakpoints.contains_key(&rip) {
+            return Ok(None);
+        }
+
+        // Emulated heap allocator functions
+        if self.heap.contains(rip) {
+            return self.emulate_heap_call(rip);
+        }
+
+        self.step_over(rip)?;
+        Ok(None)
+    }
+
+    /// Runs the hooks of an address by priority. Returns `None` if they all
+    /// continued, otherwise the result of the debug exit.
+    fn dispatch_hooks(&mut self, rip: u64) -> Option<Option<VmExit>> {
+        let ids: Vec<HookId> = match self.hooks.get(&rip) {
+            Some(hooks) => hooks.iter().map(|hook| hook.id).collect(),
+            None => return None,
+        };
+
+        for id in ids {
+            // The handlers may install or remove hooks themselves
+            let mut handler = match self.hook_entry(rip, id).and_then(|h| h.handler.take()) {
+                Some(handler) => handler,
+                None => continue,
+            };
+
+            let result = handler(self);
+
+            if let Some(hook) = self.hook_entry(rip, id) {
+                hook.handler = Some(handler);
+            }
+
+            match result {
+                HookResult::Continue => {}
+                HookResult::Redirect => return Some(None),
+                HookResult::Exit => return Some(Some(VmExit::HookExit)),
+                HookResult::Crash => return Some(Some(VmExit::HookCrash)),
+            }
+        }
+
+        None
+    }
+
+    /// Returns a hook installed at an address
+    fn hook_entry(&mut self, address: u64, id: HookId) -> Option<&mut HookEntry> {
+        self.hooks
+            .get_mut(&address)?
+            .iter_mut()
+            .find(|hook| hook.id == id)
+    }
+}
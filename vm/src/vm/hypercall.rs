@@ -0,0 +1,92 @@
+//! Guest to host communication channel
+//!
+//! The guest sets the hypercall number in rax and the arguments in rdi and
+//! rsi, then executes `in eax, HYPERCALL_PORT`: eax receives the result
+//! (`ERROR` on failure).
+
+use super::{Vm, VmExit};
+
+/// IO port reserved to the hypercalls
+pub const HYPERCALL_PORT: u16 = 0x7f;
+
+/// Appends rsi bytes read at rdi to the guest output (see
+/// `Vm::take_guest_output`), returns the number of bytes
+pub const PRINT: u64 = 0;
+/// Stops the run with `VmExit::GuestExit(rdi)`
+pub const EXIT: u64 = 1;
+/// Copies the guest input (see `Vm::set_guest_input`) to the rsi bytes buffer
+/// at rdi, returns the number of bytes copied
+pub const REQUEST_INPUT: u64 = 2;
+
+/// Result of the failed hypercalls
+pub const ERROR: u32 = u32::MAX;
+
+/// Maximum number of bytes printed at once
+const MAX_PRINT_SIZE: u64 = 1 << 20;
+
+impl Vm {
+    /// Sets the data given to the guest by the `REQUEST_INPUT` hypercalls
+    pub fn set_guest_input(&mut self, data: &[u8]) {
+        self.guest_input.clear();
+        self.guest_input.extend_from_slice(data);
+    }
+
+    /// Returns the data printed by the guest through the `PRINT` hypercalls
+    #[inline]
+    pub fn guest_output(&self) -> &[u8] {
+        &self.guest_output
+    }
+
+    /// Returns and clears the data printed by the guest
+    pub fn take_guest_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.guest_output)
+    }
+
+    /// Handles a hypercall, `data` receiving the result. Returns the exit to
+    /// report or `None` to resume.
+    pub(super) fn handle_hypercall(&mut self, data: &mut [u8]) -> Option<VmExit> {
+        let address = self.registers.rdi;
+        let size = self.registers.rsi;
+
+        let (result, exit) = match self.registers.rax {
+            PRINT => (self.hypercall_print(address, size), None),
+            EXIT => (0, Some(VmExit::GuestExit(address))),
+            REQUEST_INPUT => (self.hypercall_request_input(address, size), None),
+            _ => (ERROR, None),
+        };
+
+        let len = data.len().min(4);
+        data[..len].copy_from_slice(&result.to_le_bytes()[..len]);
+
+        exit
+    }
+
+    /// `PRINT` hypercall
+    fn hypercall_print(&mut self, address: u64, size: u64) -> u32 {
+        if size > MAX_PRINT_SIZE {
+            return ERROR;
+        }
+
+        let mut buffer = vec![0u8; size as usize];
+        if self.read(address, &mut buffer).is_err() {
+            return ERROR;
+        }
+
+        self.guest_output.extend_from_slice(&buffer);
+        size as u32
+    }
+
+    /// `REQUEST_INPUT` hypercall
+    fn hypercall_request_input(&mut self, address: u64, size: u64) -> u32 {
+        let input = std::mem::take(&mut self.guest_input);
+        let len = input.len().min(size as usize);
+
+        let result = match self.write(address, &input[..len]) {
+            Ok(()) => len as u32,
+            Err(_) => ERROR,
+        };
+
+        self.guest_input = input;
+        result
+    }
+}
@@ -0,0 +1,263 @@
+//! Integrity checks of the structures injected in the guest
+
+use super::hooks::BREAKPOINT_OPCODE;
+use super::syscall::SYSCALL_STUB_OFFSET;
+use super::{Result, Vm, VmError};
+use crate::memory::{PagePermissions, PAGE_SIZE};
+
+use std::fmt;
+
+/// Opcode of the hlt instruction ending the handlers and the syscall stub
+const HLT_OPCODE: u8 = 0xf4;
+
+/// Structure injected in the guest to handle the exceptions and syscalls
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InternalStructure {
+    /// Interrupt descriptor table
+    Idt,
+    /// Exception handlers (hypercall page)
+    Handlers,
+    /// Global descriptor table
+    Gdt,
+    /// Task state segment
+    Tss,
+    /// Stack of the exception handlers
+    Stack,
+    /// Stub stopping the syscalls (see `Vm::enable_syscalls`)
+    SyscallStub,
+}
+
+/// Corruption found by `Vm::verify_internal_state`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// A page of a structure is unmapped or its permissions changed
+    Unmapped {
+        /// Corrupted structure
+        structure: InternalStructure,
+        /// Virtual address of the page
+        address: u64,
+    },
+    /// The content of a structure changed
+    Corrupted {
+        /// Corrupted structure
+        structure: InternalStructure,
+        /// Virtual address of the first byte changed
+        address: u64,
+    },
+    /// The descriptor table register (GDTR, IDTR or TR) of a structure no
+    /// longer references it
+    DescriptorRegister(InternalStructure),
+    /// The guest debug flags or debug registers applied to the vcpu differ
+    /// from the breakpoints and single step state of the vm
+    GuestDebug,
+    /// The software breakpoint at the address was overwritten
+    Breakpoint(u64),
+    /// Hooks are left at the address without a breakpoint to trigger them
+    DanglingHook(u64),
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IntegrityError::Unmapped { structure, address } => {
+                write!(f, "{:?} page unmapped 0x{:x}", structure, address)
+            }
+            IntegrityError::Corrupted { structure, address } => {
+                write!(f, "{:?} corrupted at 0x{:x}", structure, address)
+            }
+            IntegrityError::DescriptorRegister(structure) => {
+                write!(f, "{:?} descriptor register changed", structure)
+            }
+            IntegrityError::GuestDebug => write!(f, "Guest debug state out of sync"),
+            IntegrityError::Breakpoint(address) => {
+                write!(f, "Breakpoint overwritten at 0x{:x}", address)
+            }
+            IntegrityError::DanglingHook(address) => {
+                write!(f, "Hook without breakpoint at 0x{:x}", address)
+            }
+        }
+    }
+}
+
+/// Structure copied once the vm is built
+#[derive(Clone, Debug)]
+struct StructureCopy {
+    /// Structure copied
+    structure: InternalStructure,
+    /// Virtual address of the structure
+    address: u64,
+    /// Size of the mapping holding it
+    size: usize,
+    /// Permissions of its pages
+    permissions: PagePermissions,
+    /// Content of the structure (the stack content is not checked)
+    content: Vec<u8>,
+}
+
+/// Reference state of the integrity checks
+#[derive(Clone, Debug, Default)]
+pub(super) struct InternalState {
+    /// Structures injected in the guest
+    structures: Vec<StructureCopy>,
+    /// IDTR base and limit
+    idt: (u64, u16),
+    /// GDTR base and limit
+    gdt: (u64, u16),
+    /// TR base, limit and selector
+    tr: (u64, u32, u16),
+}
+
+impl Vm {
+    /// Records the structures set up by `Vm::setup_exception_handling`, the
+    /// reference of `Vm::verify_internal_state`
+    pub(super) fn capture_internal_state(&mut self) -> Result<()> {
+        let idt_address = self.config.exception_region;
+        let gdt_address = idt_address + (PAGE_SIZE * 2) as u64;
+        let tss_address = idt_address + (PAGE_SIZE * 3) as u64;
+        let stack_address = idt_address + (PAGE_SIZE * 4) as u64;
+        let sregs = &self.special_registers;
+
+        let structures = [
+            (
+                InternalStructure::Idt,
+                idt_address,
+                PagePermissions::READ,
+                sregs.idt.limit as usize + 1,
+            ),
+            (
+                InternalStructure::Handlers,
+                self.hypercall_page,
+                PagePermissions::READ | PagePermissions::EXECUTE,
+                SYSCALL_STUB_OFFSET as usize,
+            ),
+            (
+                InternalStructure::Gdt,
+                gdt_address,
+                PagePermissions::READ | PagePermissions::WRITE,
+                sregs.gdt.limit as usize + 1,
+            ),
+            (
+                InternalStructure::Tss,
+                tss_address,
+                PagePermissions::READ,
+                sregs.tr.limit as usize + 1,
+            ),
+            (
+                InternalStructure::Stack,
+                stack_address,
+                PagePermissions::READ | PagePermissions::WRITE,
+                0,
+            ),
+        ];
+
+        let mut state = InternalState {
+            structures: Vec::with_capacity(structures.len()),
+            idt: (sregs.idt.base, sregs.idt.limit),
+            gdt: (sregs.gdt.base, sregs.gdt.limit),
+            tr: (sregs.tr.base, sregs.tr.limit, sregs.tr.selector),
+        };
+
+        for (structure, address, permissions, size) in structures {
+            let mut content = vec![0u8; size];
+            self.memory.read(address, &mut content)?;
+
+            state.structures.push(StructureCopy {
+                structure,
+                address,
+                size: size.max(1).next_multiple_of(PAGE_SIZE),
+                permissions,
+                content,
+            });
+        }
+
+        self.internal_state = state;
+        Ok(())
+    }
+
+    /// Checks that the structures injected in the guest are intact: the IDT,
+    /// GDT, TSS, exception handlers and their stack are still mapped with
+    /// their content, the descriptor table registers still reference them,
+    /// the syscall stub is in place, the software breakpoints were not
+    /// overwritten and the guest debug state applied to the vcpu matches the
+    /// vm. Meant to be called after the resets and clones, a corruption
+    /// otherwise only showing up later as a triple fault or a missed
+    /// breakpoint.
+    pub fn verify_internal_state(&self) -> Result<()> {
+        let corrupted = |err| Err(VmError::IntegrityError(err));
+
+        for copy in self.internal_state.structures.iter() {
+            for offset in (0..copy.size).step_by(PAGE_SIZE) {
+                let address = copy.address + offset as u64;
+                match self.memory.translate(address) {
+                    Some((_, permissions)) if permissions == copy.permissions => {}
+                    _ => {
+                        return corrupted(IntegrityError::Unmapped {
+                            structure: copy.structure,
+                            address,
+                        })
+                    }
+                }
+            }
+
+            let mut content = vec![0u8; copy.content.len()];
+            self.memory.read(copy.address, &mut content)?;
+            let changed = content.iter().zip(&copy.content).position(|(a, b)| a != b);
+            if let Some(offset) = changed {
+                return corrupted(IntegrityError::Corrupted {
+                    structure: copy.structure,
+                    address: copy.address + offset as u64,
+                });
+            }
+        }
+
+        // The stub returning from the syscalls
+        if self.syscall_segments.is_some() {
+            let stub = self.hypercall_page + SYSCALL_STUB_OFFSET;
+            if self.memory.read_val::<u8>(stub)? != HLT_OPCODE {
+                return corrupted(IntegrityError::Corrupted {
+                    structure: InternalStructure::SyscallStub,
+                    address: stub,
+                });
+            }
+        }
+
+        // The descriptor table registers
+        let sregs = &self.special_registers;
+        let state = &self.internal_state;
+        if (sregs.idt.base, sregs.idt.limit) != state.idt {
+            return corrupted(IntegrityError::DescriptorRegister(InternalStructure::Idt));
+        }
+        if (sregs.gdt.base, sregs.gdt.limit) != state.gdt {
+            return corrupted(IntegrityError::DescriptorRegister(InternalStructure::Gdt));
+        }
+        if (sregs.tr.base, sregs.tr.limit, sregs.tr.selector) != state.tr {
+            return corrupted(IntegrityError::DescriptorRegister(InternalStructure::Tss));
+        }
+
+        // The software breakpoints, but the one being stepped over
+        for &address in self.breakpoints.keys() {
+            if self.stepping_over == Some(address) {
+                continue;
+            }
+            if self.memory.read_val::<u8>(address)? != BREAKPOINT_OPCODE {
+                return corrupted(IntegrityError::Breakpoint(address));
+            }
+        }
+
+        if let Some(&address) = self
+            .hooks
+            .keys()
+            .find(|address| !self.breakpoints.contains_key(address))
+        {
+            return corrupted(IntegrityError::DanglingHook(address));
+        }
+
+        // The guest debug state last applied to the vcpu
+        let expected = self.guest_debug(self.stepping_over.is_some());
+        if (expected.control, expected.arch.debugreg) != self.applied_guest_debug {
+            return corrupted(IntegrityError::GuestDebug);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Interruption of the running vm from other threads and timeouts
+
+use super::{HvError, Result, Vm, VmExit};
+
+use nix::sys::pthread::{pthread_kill, pthread_self, Pthread};
+use nix::sys::signal::{
+    self, SaFlags, SigAction, SigEvent, SigHandler, SigSet, SigevNotify, Signal,
+};
+use nix::sys::time::TimeSpec;
+use nix::sys::timer::{Expiration, Timer, TimerSetTimeFlags};
+use nix::time::ClockId;
+use nix::unistd::{gettid, Pid};
+
+use std::cell::Cell;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+
+/// Signal kicking the vcpu thread out of KVM_RUN
+pub const INTERRUPT_SIGNAL: Signal = Signal::SIGUSR2;
+
+thread_local! {
+    /// `immediate_exit` flag of the vm running on this thread
+    static IMMEDIATE_EXIT: Cell<*mut u8> = const { Cell::new(ptr::null_mut()) };
+}
+
+/// Makes the next KVM_RUN of the vm running on the thread return at once
+extern "C" fn handle_interrupt(_: nix::libc::c_int) {
+    let flag = IMMEDIATE_EXIT.with(|flag| flag.get());
+    if !flag.is_null() {
+        unsafe { ptr::write_volatile(flag, 1) };
+    }
+}
+
+/// Installs the handler of `INTERRUPT_SIGNAL` (without `SA_RESTART`, so that
+/// KVM_RUN returns)
+fn install_handler() {
+    static INSTALL: Once = Once::new();
+
+    INSTALL.call_once(|| {
+        let action = SigAction::new(
+            SigHandler::Handler(handle_interrupt),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        unsafe { signal::sigaction(INTERRUPT_SIGNAL, &action) }
+            .expect("Could not install the interrupt signal handler");
+    });
+}
+
+/// Interruption state shared with the handles
+#[derive(Debug, Default)]
+pub(super) struct InterruptState {
+    /// Interruption requested and not handled yet
+    pending: AtomicBool,
+    /// Thread running the vm, locked by the handles while they signal it
+    thread: Mutex<Option<Pthread>>,
+}
+
+/// Registration of the vm on the thread running it, undone on drop even if
+/// the run panics: the handles never signal a thread which left the run
+pub(super) struct RunGuard {
+    /// State of the vm
+    state: Arc<InterruptState>,
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        // Waits for a handle signaling the thread
+        *self.state.thread.lock().unwrap() = None;
+        IMMEDIATE_EXIT.with(|f| f.set(ptr::null_mut()));
+    }
+}
+
+/// Handle stopping the execution of a vm from another thread, the vm
+/// returning `VmExit::Interrupted`
+#[derive(Clone, Debug)]
+pub struct InterruptHandle {
+    /// State of the vm
+    state: Arc<InterruptState>,
+}
+
+impl InterruptHandle {
+    /// Interrupts the running vm, or its next run if it is not running
+    pub fn interrupt(&self) {
+        self.state.pending.store(true, Ordering::SeqCst);
+
+        // The lock is held across the signal, the vm thread cannot leave the
+        // run (and exit) before it is sent
+        let thread = self.state.thread.lock().unwrap();
+        if let Some(thread) = *thread {
+            let _ = pthread_kill(thread, INTERRUPT_SIGNAL);
+        }
+    }
+}
+
+/// Timer interrupting the vcpu thread
+pub(super) struct RunTimer {
+    /// Thread receiving the timer signal
+    thread: Pid,
+    /// Per-thread timer
+    timer: Timer,
+}
+
+impl RunTimer {
+    /// Returns the timer of `slot`, created on first use or when the vm moved
+    /// to an other thread (the timer signals the thread it was created on)
+    pub(super) fn for_current_thread(slot: &mut Option<RunTimer>) -> Result<&mut RunTimer> {
+        install_handler();
+
+        let thread = gettid();
+        if slot.as_ref().map(|t| t.thread) != Some(thread) {
+            let event = SigEvent::new(SigevNotify::SigevThreadId {
+                signal: INTERRUPT_SIGNAL,
+                thread_id: thread.as_raw(),
+                si_value: 0,
+            });
+            let timer = Timer::new(ClockId::CLOCK_MONOTONIC, event)
+                .map_err(|err| HvError::os("Could not create the run timer", err as i32))?;
+            *slot = Some(RunTimer { thread, timer });
+        }
+
+        Ok(slot.as_mut().unwrap())
+    }
+
+    /// Arms the timer
+    pub(super) fn set(&mut self, expiration: Expiration) -> Result<()> {
+        self.timer
+            .set(expiration, TimerSetTimeFlags::empty())
+            .map_err(|err| HvError::os("Could not set the run timer", err as i32).into())
+    }
+
+    /// Disarms the timer
+    #[inline]
+    pub(super) fn disarm(&mut self) -> Result<()> {
+        self.set(Expiration::OneShot(TimeSpec::from_duration(Duration::ZERO)))
+    }
+}
+
+impl Vm {
+    /// Returns a handle interrupting the vm from other threads (the thread
+    /// running the vm receives `INTERRUPT_SIGNAL`)
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        install_handler();
+
+        InterruptHandle {
+            state: self.interrupt.clone(),
+        }
+    }
+
+    /// Runs the vm like `Vm::run`, returning `VmExit::Timeout` if it is still
+    /// running after `timeout`
+    pub fn run_with_timeout(&mut self, timeout: Duration) -> Result<VmExit> {
+        // A zero timeout would disarm the timer
+        let timeout = timeout.max(Duration::from_nanos(1));
+        let run_timer = RunTimer::for_current_thread(&mut self.run_timer)?;
+        run_timer.set(Expiration::OneShot(TimeSpec::from_duration(timeout)))?;
+        self.run_deadline = Some(Instant::now() + timeout);
+
+        let result = self.run();
+
+        // The timer is disarmed once expired
+        let run_timer = self.run_timer.as_mut().unwrap();
+        let expired = run_timer.timer.get().ok() == Some(None);
+        run_timer.disarm()?;
+        self.run_deadline = None;
+
+        match result? {
+            VmExit::Interrupted if expired => Ok(VmExit::Timeout),
+            exit => Ok(exit),
+        }
+    }
+
+    /// Returns whether or not the timeout of `Vm::run_with_timeout` expired
+    #[inline]
+    pub(super) fn run_timer_expired(&self) -> bool {
+        self.run_deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    /// Clears the `immediate_exit` flag set by a signal, for the execution to
+    /// resume
+    pub(super) fn clear_immediate_exit(&mut self) {
+        let flag = &mut self.kvm_vcpu_run.as_mut_ref().immediate_exit as *mut u8;
+        unsafe { ptr::write_volatile(flag, 0) };
+    }
+
+    /// Registers the vm as running on the current thread, the interrupt
+    /// signal then stopping its next KVM_RUN, until the returned guard is
+    /// dropped
+    pub(super) fn enter_run(&mut self) -> RunGuard {
+        let flag = &mut self.kvm_vcpu_run.as_mut_ref().immediate_exit as *mut u8;
+        unsafe { ptr::write_volatile(flag, 0) };
+
+        IMMEDIATE_EXIT.with(|f| f.set(flag));
+        *self.interrupt.thread.lock().unwrap() = Some(pthread_self());
+
+        RunGuard {
+            state: self.interrupt.clone(),
+        }
+    }
+
+    /// Returns whether or not an interruption was requested through a handle,
+    /// clearing the request
+    #[inline]
+    pub(super) fn take_interrupt(&self) -> bool {
+        self.interrupt.pending.swap(false, Ordering::SeqCst)
+    }
+}
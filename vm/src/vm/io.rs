@@ -0,0 +1,90 @@
+//! Port IO instructions emulated by user handlers
+
+use super::hypercall::HYPERCALL_PORT;
+use super::{HookResult, Result, Vm, VmError, VmExit};
+
+/// Handler of the `in` instructions on a port, called with the port and the
+/// buffer to fill
+pub type PortInFn = dyn FnMut(&mut Vm, u16, &mut [u8]) -> HookResult;
+
+/// Handler of the `out` instructions on a port, called with the port and the
+/// data written
+pub type PortOutFn = dyn FnMut(&mut Vm, u16, &[u8]) -> HookResult;
+
+/// IO port emulated by handlers
+pub(super) struct PortHandlers {
+    /// `in` handler
+    read: Box<PortInFn>,
+    /// `out` handler
+    write: Box<PortOutFn>,
+}
+
+impl Vm {
+    /// Registers handlers for the guest accesses to an IO port (the
+    /// hypercall port excepted). A handler returning `HookResult::Exit` or
+    /// `HookResult::Crash` stops the run after the instruction, the other
+    /// results resume the execution. As for MMIO, the registers changed by
+    /// the handlers are overwritten once the instruction completes. The
+    /// accesses to the ports without handlers stop the run with a
+    /// `VmExit::Io`.
+    pub fn register_port<R, W>(&mut self, port: u16, read: R, write: W) -> Result<()>
+    where
+        R: FnMut(&mut Vm, u16, &mut [u8]) -> HookResult + 'static,
+        W: FnMut(&mut Vm, u16, &[u8]) -> HookResult + 'static,
+    {
+        if port == HYPERCALL_PORT || self.ports.contains_key(&port) {
+            return Err(VmError::InvalidOperation("Invalid IO port"));
+        }
+
+        self.ports.insert(
+            port,
+            PortHandlers {
+                read: Box::new(read),
+                write: Box::new(write),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Removes the handlers of an IO port
+    pub fn unregister_port(&mut self, port: u16) {
+        self.ports.remove(&port);
+    }
+
+    /// Handles a port IO exit, `data` holding the data written or receiving
+    /// the data read. Returns the exit to report or `None` to resume.
+    pub(super) fn handle_io(&mut self, port: u16, data: &mut [u8], write: bool) -> Option<VmExit> {
+        if port == HYPERCALL_PORT && !write {
+            return self.handle_hypercall(data);
+        }
+
+        // The handlers may register or remove ports themselves
+        let mut handlers = match self.ports.remove(&port) {
+            Some(handlers) => handlers,
+            None => {
+                let mut value = [0u8; 4];
+                let len = data.len().min(4);
+                value[..len].copy_from_slice(&data[..len]);
+
+                return Some(VmExit::Io {
+                    port,
+                    data: u32::from_le_bytes(value),
+                    write,
+                });
+            }
+        };
+
+        let result = match write {
+            true => (handlers.write)(self, port, data),
+            false => (handlers.read)(self, port, data),
+        };
+        self.ports.entry(port).or_insert(handlers);
+
+        match result {
+            HookResult::Continue | HookResult::Redirect => None,
+            HookResult::Exit => Some(VmExit::HookExit),
+            HookResult::Crash => Some(VmExit::HookCrash),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! Guest physical address space layout and reservations
+
+use super::{Result, Vm, VmError};
+use crate::memory::PAGE_SIZE;
+
+use std::collections::BTreeMap;
+
+/// Owner of a guest physical region
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PhysicalRegionKind {
+    /// Vm memory, its frames handed out to the mappings, the page tables and
+    /// the exception handling structures
+    Memory,
+    /// Additional memory slot (see `VmBuilder::memory_slot`)
+    MemorySlot(usize),
+    /// MMIO region (see `Vm::register_mmio`)
+    Mmio,
+    /// Window reserved with `Vm::reserve_physical`
+    Reserved(String),
+}
+
+/// Guest physical region
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PhysicalRegion {
+    /// Guest physical address of the region
+    pub start: u64,
+    /// Size of the region
+    pub size: u64,
+    /// Owner of the region
+    pub kind: PhysicalRegionKind,
+}
+
+impl PhysicalRegion {
+    /// Returns the end of the region (excluded)
+    #[inline]
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    /// Returns whether or not the region overlaps a range
+    #[inline]
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end()
+    }
+}
+
+/// Window of the guest physical address space kept for a later use
+#[derive(Clone, Debug)]
+pub(super) struct Reservation {
+    /// Size of the window
+    size: u64,
+    /// Name of the owner
+    name: String,
+}
+
+impl Vm {
+    /// Returns the guest physical layout sorted by address: the vm memory
+    /// (a region for each bank), the memory slots, the MMIO regions and the
+    /// reserved windows. The MMIO regions are nested in the reservations they
+    /// were placed in.
+    pub fn physical_layout(&self) -> Vec<PhysicalRegion> {
+        let banks = self.memory.banks().iter();
+        let mut layout: Vec<PhysicalRegion> = banks
+            .map(|bank| PhysicalRegion {
+                start: bank.guest_address,
+                size: bank.size as u64,
+                kind: PhysicalRegionKind::Memory,
+            })
+            .collect();
+
+        let slots = self.config.memory_slots.iter().enumerate();
+        layout.extend(slots.map(|(index, slot)| PhysicalRegion {
+            start: slot.guest_address,
+            size: slot.size as u64,
+            kind: PhysicalRegionKind::MemorySlot(index),
+        }));
+
+        layout.extend(self.mmio.iter().map(|(&start, region)| PhysicalRegion {
+            start,
+            size: region.size(),
+            kind: PhysicalRegionKind::Mmio,
+        }));
+
+        layout.extend(
+            self.reservations
+                .iter()
+                .map(|(&start, reservation)| PhysicalRegion {
+                    start,
+                    size: reservation.size,
+                    kind: PhysicalRegionKind::Reserved(reservation.name.clone()),
+                }),
+        );
+
+        layout.sort_by_key(|region| (region.start, region.kind == PhysicalRegionKind::Mmio));
+        layout
+    }
+
+    /// Returns the region holding a guest physical address, the innermost one
+    /// for the MMIO regions of a reservation
+    pub fn physical_region(&self, address: u64) -> Option<PhysicalRegion> {
+        self.physical_layout()
+            .into_iter()
+            .rev()
+            .find(|region| region.overlaps(address, address + 1))
+    }
+
+    /// Returns the virtual addresses of the pages backed by the vm memory,
+    /// by guest physical address
+    pub fn physical_mappings(&self) -> BTreeMap<u64, Vec<u64>> {
+        let mut mappings: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+
+        for entry in self.memory.page_entries() {
+            mappings
+                .entry(entry.physical_address)
+                .or_default()
+                .push(entry.address);
+        }
+
+        mappings
+    }
+
+    /// Reserves a page aligned window of the guest physical address space, so
+    /// that no memory slot, MMIO region or other reservation is placed there
+    /// by mistake. MMIO regions may still be registered inside of it by its
+    /// owner.
+    pub fn reserve_physical(&mut self, start: u64, size: u64, name: &str) -> Result<()> {
+        let aligned =
+            start.is_multiple_of(PAGE_SIZE as u64) && size.is_multiple_of(PAGE_SIZE as u64);
+        if size == 0 || !aligned || !self.physical_range_free(start, size, false) {
+            return Err(VmError::InvalidOperation("Invalid physical reservation"));
+        }
+
+        self.reservations.insert(
+            start,
+            Reservation {
+                size,
+                name: name.to_string(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Releases the reserved window starting at `start`
+    pub fn release_physical(&mut self, start: u64) {
+        self.reservations.remove(&start);
+    }
+
+    /// Returns the lowest free page aligned guest physical window of `size`
+    /// bytes above the vm memory, aligned on `align` (a power of two)
+    pub fn find_free_physical(&self, size: u64, align: u64) -> Option<u64> {
+        let align = align.max(PAGE_SIZE as u64);
+        if size == 0 || !align.is_power_of_two() {
+            return None;
+        }
+
+        let mut candidate = self.memory.pmem.end().checked_next_multiple_of(align)?;
+        for region in self.physical_layout() {
+            let end = candidate.checked_add(size)?;
+            if region.overlaps(candidate, end) {
+                candidate = region.end().checked_next_multiple_of(align)?;
+            }
+        }
+
+        candidate.checked_add(size).map(|_| candidate)
+    }
+
+    /// Returns whether or not a guest physical range is free, the reserved
+    /// windows fully holding it being ignored for the MMIO regions
+    pub(super) fn physical_range_free(&self, start: u64, size: u64, mmio: bool) -> bool {
+        let end = match start.checked_add(size) {
+            Some(end) => end,
+            None => return false,
+        };
+
+        self.physical_layout().iter().all(|region| {
+            let inside = start >= region.start && end <= region.end();
+            match &region.kind {
+                PhysicalRegionKind::Reserved(_) if mmio && inside => true,
+                _ => !region.overlaps(start, end),
+            }
+        })
+    }
+}
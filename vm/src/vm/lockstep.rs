@@ -0,0 +1,248 @@
+//! Differential execution of two vms
+
+use super::{Register, Result, Vm, VmExit};
+
+use std::collections::BTreeMap;
+
+/// Registers compared after each step (the flags last, as they usually
+/// follow a diverging value)
+pub(super) const COMPARED_REGISTERS: [Register; 20] = [
+    Register::Rip,
+    Register::Rax,
+    Register::Rbx,
+    Register::Rcx,
+    Register::Rdx,
+    Register::Rsi,
+    Register::Rdi,
+    Register::Rsp,
+    Register::Rbp,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+    Register::FsBase,
+    Register::GsBase,
+    Register::Rflags,
+];
+
+/// Granularity of the lockstep execution
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockstepMode {
+    /// Compare the vms after each instruction (single step)
+    Instruction,
+    /// Compare the vms after each vm exit
+    Exit,
+}
+
+/// State found different between the two vms
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DivergenceKind {
+    /// The vms stopped on different exits
+    Exit {
+        /// Exit of the left vm
+        left: VmExit,
+        /// Exit of the right vm
+        right: VmExit,
+    },
+    /// A register holds different values
+    Register {
+        /// Diverging register
+        register: Register,
+        /// Value in the left vm
+        left: u64,
+        /// Value in the right vm
+        right: u64,
+    },
+    /// A byte of memory is different (None when not mapped)
+    Memory {
+        /// Address of the first diverging byte
+        address: u64,
+        /// Byte in the left vm
+        left: Option<u8>,
+        /// Byte in the right vm
+        right: Option<u8>,
+    },
+}
+
+/// First divergence between the two vms
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Number of steps executed, including the diverging one
+    pub step: u64,
+    /// Diverging state
+    pub kind: DivergenceKind,
+}
+
+/// End of a lockstep run
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LockstepResult {
+    /// The vms diverged
+    Diverged(Divergence),
+    /// Both vms stopped on the same exit (to be handled before resuming)
+    Exited(VmExit),
+    /// The steps limit was reached without divergence
+    StepLimit,
+}
+
+/// Runs two vms side by side and reports the first divergence in their
+/// registers or memory
+pub struct Lockstep<'a> {
+    /// Reference vm
+    left: &'a mut Vm,
+    /// Compared vm
+    right: &'a mut Vm,
+    /// Granularity of the execution
+    mode: LockstepMode,
+    /// Memory areas compared after each step
+    ranges: Vec<(u64, usize)>,
+    /// Compare the pages dirtied by the guests after each step
+    dirty_pages: bool,
+    /// Number of steps executed so far
+    steps: u64,
+}
+
+/// Returns a byte of the vm memory, if mapped
+#[inline]
+fn read_byte(vm: &Vm, address: u64) -> Option<u8> {
+    let mut byte = [0u8; 1];
+    vm.read(address, &mut byte).ok().map(|_| byte[0])
+}
+
+/// Returns the first byte differing between the two vms in a memory area
+fn compare_memory(left: &Vm, right: &Vm, address: u64, size: usize) -> Option<DivergenceKind> {
+    let mut left_data = vec![0u8; size];
+    let mut right_data = vec![0u8; size];
+
+    // Compare the whole area at once before falling back to bytes
+    if left.read(address, &mut left_data).is_ok()
+        && right.read(address, &mut right_data).is_ok()
+        && left_data == right_data
+    {
+        return None;
+    }
+
+    (address..address + size as u64)
+        .map(|a| (a, read_byte(left, a), read_byte(right, a)))
+        .find(|(_, l, r)| l != r)
+        .map(|(address, left, right)| DivergenceKind::Memory {
+            address,
+            left,
+            right,
+        })
+}
+
+impl<'a> Lockstep<'a> {
+    /// Creates a new `Lockstep` driving `left` and `right`, which should
+    /// start from the same state
+    pub fn new(left: &'a mut Vm, right: &'a mut Vm, mode: LockstepMode) -> Self {
+        Lockstep {
+            left,
+            right,
+            mode,
+            ranges: Vec::new(),
+            dirty_pages: false,
+            steps: 0,
+        }
+    }
+
+    /// Compares a memory area after each step
+    pub fn compare_range(&mut self, address: u64, size: usize) -> &mut Self {
+        self.ranges.push((address, size));
+        self
+    }
+
+    /// Compares the pages dirtied by either guest after each step (the whole
+    /// 2MB pages of the vms backed by hugepages)
+    pub fn compare_dirty_pages(&mut self, enabled: bool) -> &mut Self {
+        self.dirty_pages = enabled;
+        self
+    }
+
+    /// Returns the number of steps executed so far
+    #[inline]
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Returns the first divergence between the vms states
+    fn compare(&self, left_exit: VmExit, right_exit: VmExit) -> Option<DivergenceKind> {
+        if left_exit != right_exit {
+            return Some(DivergenceKind::Exit {
+                left: left_exit,
+                right: right_exit,
+            });
+        }
+
+        for &register in COMPARED_REGISTERS.iter() {
+            let (left, right) = (self.left.get_reg(register), self.right.get_reg(register));
+            if left != right {
+                return Some(DivergenceKind::Register {
+                    register,
+                    left,
+                    right,
+                });
+            }
+        }
+
+        for &(address, size) in self.ranges.iter() {
+            if let Some(divergence) = compare_memory(self.left, self.right, address, size) {
+                return Some(divergence);
+            }
+        }
+
+        if !self.dirty_pages {
+            return None;
+        }
+
+        // A page may be mapped with a 2MB page in a single vm
+        let mut pages: BTreeMap<u64, usize> = BTreeMap::new();
+        for mapping in self
+            .left
+            .dirty_mappings()
+            .chain(self.right.dirty_mappings())
+        {
+            let size = pages.entry(mapping.address).or_insert(mapping.size);
+            *size = mapping.size.max(*size);
+        }
+
+        pages
+            .into_iter()
+            .find_map(|(page, size)| compare_memory(self.left, self.right, page, size))
+    }
+
+    /// Runs the vms for at most `max_steps` steps. Execution stops on the
+    /// first divergence, or when both vms stop on an exit other than a
+    /// single step (every exit in `LockstepMode::Exit`): the caller handles
+    /// it in both vms and calls `run` again to continue.
+    pub fn run(&mut self, max_steps: u64) -> Result<LockstepResult> {
+        for _ in 0..max_steps {
+            // The single steps go through the hooked instructions one by one
+            let ((left_exit, left_stepped), (right_exit, right_stepped)) = match self.mode {
+                LockstepMode::Instruction => (
+                    self.left.step_instruction()?,
+                    self.right.step_instruction()?,
+                ),
+                LockstepMode::Exit => ((self.left.run()?, false), (self.right.run()?, false)),
+            };
+            self.steps += 1;
+
+            if let Some(kind) = self.compare(left_exit, right_exit) {
+                return Ok(LockstepResult::Diverged(Divergence {
+                    step: self.steps,
+                    kind,
+                }));
+            }
+
+            // A guest breakpoint is not the end of a step
+            if !left_stepped || !right_stepped {
+                return Ok(LockstepResult::Exited(left_exit));
+            }
+        }
+
+        Ok(LockstepResult::StepLimit)
+    }
+}
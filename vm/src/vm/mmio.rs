@@ -0,0 +1,113 @@
+//! Memory mapped IO regions emulated by user handlers
+
+use super::{HookResult, Result, Vm, VmError, VmExit};
+
+/// Handler of the reads of a MMIO region, called with the offset of the
+/// access in the region and the buffer to fill
+pub type MmioReadFn = dyn FnMut(&mut Vm, u64, &mut [u8]) -> HookResult;
+
+/// Handler of the writes to a MMIO region, called with the offset of the
+/// access in the region and the data written
+pub type MmioWriteFn = dyn FnMut(&mut Vm, u64, &[u8]) -> HookResult;
+
+/// Guest physical region emulated by handlers
+pub(super) struct MmioRegion {
+    /// Size of the region
+    size: u64,
+    /// Reads handler
+    read: Box<MmioReadFn>,
+    /// Writes handler
+    write: Box<MmioWriteFn>,
+}
+
+impl MmioRegion {
+    /// Returns the size of the region
+    #[inline]
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Vm {
+    /// Registers handlers for the guest accesses to a physical region outside
+    /// of the vm memory and the memory slots, possibly inside a window reserved
+    /// with `Vm::reserve_physical` (see `Vm::map_physical` to make it reachable
+    /// from a virtual address). A handler returning `HookResult::Exit` or
+    /// `HookResult::Crash` stops the run after the access, the other results
+    /// resume the execution. The registers changed by the handlers are
+    /// overwritten once the access completes.
+    pub fn register_mmio<R, W>(&mut self, address: u64, size: u64, read: R, write: W) -> Result<()>
+    where
+        R: FnMut(&mut Vm, u64, &mut [u8]) -> HookResult + 'static,
+        W: FnMut(&mut Vm, u64, &[u8]) -> HookResult + 'static,
+    {
+        if size == 0 || !self.physical_range_free(address, size, true) {
+            return Err(VmError::InvalidOperation("Invalid MMIO region"));
+        }
+
+        self.mmio.insert(
+            address,
+            MmioRegion {
+                size,
+                read: Box::new(read),
+                write: Box::new(write),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Removes the handlers of the MMIO region starting at `address`
+    pub fn unregister_mmio(&mut self, address: u64) {
+        self.mmio.remove(&address);
+    }
+
+    /// Completes the instruction which accessed MMIO memory or an IO port: KVM
+    /// executes it on the next KVM_RUN, returning at once with `immediate_exit` set.
+    /// The registers are then pulled, overwriting the changes done since the
+    /// exit.
+    pub(super) fn complete_io(&mut self) {
+        let kvm_run = self.kvm_vcpu_run.as_mut_ref();
+        let interrupted = kvm_run.immediate_exit;
+
+        // Keep the registers of the vcpu, they are set by the instruction
+        kvm_run.kvm_dirty_regs = 0;
+        kvm_run.immediate_exit = 1;
+        let _ = self.kvm_vcpu.run();
+
+        let kvm_run = self.kvm_vcpu_run.as_mut_ref();
+        kvm_run.immediate_exit = interrupted;
+        unsafe {
+            self.registers = kvm_run.s.regs.regs;
+            self.special_registers = kvm_run.s.regs.sregs;
+        }
+    }
+
+    /// Handles a MMIO exit, `data` holding the data written or receiving the
+    /// data read. Returns the exit to report or `None` to resume.
+    pub(super) fn handle_mmio(
+        &mut self,
+        address: u64,
+        data: &mut [u8],
+        write: bool,
+    ) -> Option<VmExit> {
+        let start = match self.mmio.range(..=address).next_back() {
+            Some((&start, region)) if address < start + region.size => start,
+            _ => return Some(VmExit::MmioFault { address, write }),
+        };
+
+        // The handlers may register or remove regions themselves
+        let mut region = self.mmio.remove(&start)?;
+        let result = match write {
+            true => (region.write)(self, address - start, data),
+            false => (region.read)(self, address - start, data),
+        };
+        self.mmio.entry(start).or_insert(region);
+
+        match result {
+            HookResult::Continue | HookResult::Redirect => None,
+            HookResult::Exit => Some(VmExit::HookExit),
+            HookResult::Crash => Some(VmExit::HookCrash),
+        }
+    }
+}
@@ -0,0 +1,4761 @@
+use crate::bits::BitField;
+use crate::memory::{
+    HugePages, Mapping, PageEntry, PagePermissions, PartialRead, PhysicalMemory, VirtualMemory,
+    HUGE_PAGE_SIZE, PAGE_SIZE,
+};
+use crate::snapshot::{MemoryDump, SnapshotInfo, SnapshotRegisters};
+use crate::symbols::Symbols;
+use crate::x64::{
+    ExceptionFrame, ExceptionType, IdtEntry, IdtEntryBuilder, IdtEntryType, PrivilegeLevel, Tss,
+    TssEntry,
+};
+
+use kvm_bindings::{
+    kvm_clear_dirty_log, kvm_enable_cap, kvm_guest_debug, kvm_msr_entry, kvm_regs, kvm_segment,
+    kvm_sregs, kvm_userspace_memory_region, CpuId, Msrs, KVMIO, KVM_API_VERSION,
+    KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2, KVM_DIRTY_LOG_MANUAL_PROTECT_ENABLE, KVM_GUESTDBG_ENABLE,
+    KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP, KVM_GUESTDBG_USE_SW_BP, KVM_MAX_CPUID_ENTRIES,
+    KVM_MEM_LOG_DIRTY_PAGES, KVM_SYNC_X86_REGS, KVM_SYNC_X86_SREGS,
+};
+use kvm_ioctls::{Cap, Kvm, KvmRunWrapper, VcpuExit, VcpuFd, VmFd};
+use nix::errno::Errno;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use vmm_sys_util::ioctl;
+
+mod access;
+mod allocator;
+mod builder;
+mod cfi;
+mod checkpoint;
+mod cpuid;
+mod crash;
+mod delta;
+mod determinism;
+mod diff;
+mod error;
+mod events;
+mod heap;
+mod hooks;
+pub mod hypercall;
+mod integrity;
+mod interrupt;
+mod io;
+mod layout;
+mod lockstep;
+mod mmio;
+mod monitor;
+pub mod msr;
+mod normalize;
+mod region;
+mod rng;
+mod segment;
+mod softmmu;
+mod stats;
+mod step;
+mod syscall;
+mod threads;
+mod trace;
+mod vdso;
+mod watchpoint;
+mod xsave;
+
+pub use access::AccessKind;
+pub use builder::{DirtyLogStrategy, VmBuilder};
+pub use cfi::{BranchKind, CfiViolationDetail};
+pub use checkpoint::{CheckpointConfig, CheckpointStats, DeltaDump};
+pub use cpuid::CpuidEntry;
+pub use crash::{CrashHashConfig, CrashReport, MemoryWindow, StackFrame};
+pub use determinism::DeterminismConfig;
+pub use diff::{diff, MappingDiff, PageDiff, RegisterDiff, VmDiff};
+pub use error::{ErrorContext, HvError, VmError};
+pub use heap::{HeapAllocation, HeapConfig, HeapReport, HeapRuntime, HeapViolation, Quarantine};
+pub use hooks::{BreakpointOwner, HookFn, HookId, HookResult};
+pub use integrity::{IntegrityError, InternalStructure};
+pub use interrupt::{InterruptHandle, INTERRUPT_SIGNAL};
+pub use io::{PortInFn, PortOutFn};
+pub use layout::{PhysicalRegion, PhysicalRegionKind};
+pub use lockstep::{Divergence, DivergenceKind, Lockstep, LockstepMode, LockstepResult};
+pub use mmio::{MmioReadFn, MmioWriteFn};
+pub use normalize::{Normalization, NormalizationKind};
+pub use rng::{SplitMix64, VmRng};
+pub use segment::{Segment, SegmentRegister};
+pub use softmmu::SoftMmuFault;
+pub use stats::{
+    DirtyStats, ExceptionStats, ExecStats, FlakinessDetector, HotPage, MappingDirtiness,
+    Nondeterminism,
+};
+pub use trace::{FileSink, RingBufferSink, TraceRecord, TraceRegisters, TraceSink, Tracer};
+pub use vdso::VdsoFunction;
+pub use watchpoint::{HwBreakpointKind, WatchAccess};
+
+use msr::{IA32_FS_BASE, IA32_GS_BASE};
+
+type Result<T> = std::result::Result<T, VmError>;
+
+ioctl_iowr_nr!(KVM_CLEAR_DIRTY_LOG, KVMIO, 0xC0, kvm_clear_dirty_log);
+ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+
+/// List of available registers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register {
+    /// RAX
+    Rax,
+    /// RBX
+    Rbx,
+    /// RCX
+    Rcx,
+    /// RDX
+    Rdx,
+    /// RSI
+    Rsi,
+    /// RDI
+    Rdi,
+    /// RSP
+    Rsp,
+    /// RBP
+    Rbp,
+    /// R8
+    R8,
+    /// R9
+    R9,
+    /// R10
+    R10,
+    /// R11
+    R11,
+    /// R12
+    R12,
+    /// R13
+    R13,
+    /// R14
+    R14,
+    /// R15
+    R15,
+    /// RIP
+    Rip,
+    /// RFLAGS
+    Rflags,
+    /// FS BASE
+    FsBase,
+    /// GS BASE
+    GsBase,
+    /// CR0
+    Cr0,
+    /// CR2
+    Cr2,
+    /// CR3
+    Cr3,
+    /// CR4
+    Cr4,
+    /// CR8
+    Cr8,
+    /// IA32_EFER
+    Efer,
+}
+
+/// Additional details behind a PageFault exception
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PageFaultDetail {
+    /// Page fault status code (from the exception frame)
+    pub status: u32,
+    /// Address of the access which caused the fault
+    pub address: u64,
+}
+
+impl PageFaultDetail {
+    /// Returns true if the faulty access was made to unmapped memory.
+    #[inline]
+    pub fn unmapped(&self) -> bool {
+        self.status.is_bit_set(0)
+    }
+
+    /// Returns true if the faulty access was a read.
+    #[inline]
+    pub fn read(&self) -> bool {
+        self.status.is_bit_set(1)
+    }
+
+    /// Returns true if the faulty access was a write.
+    #[inline]
+    pub fn write(&self) -> bool {
+        !self.read()
+    }
+
+    /// Returns true if the faulty access was an instruction fetch.
+    #[inline]
+    pub fn instruction_fetch(&self) -> bool {
+        self.status.is_bit_set(15)
+    }
+}
+
+/// Vm exit reason
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VmExit {
+    /// Vm stopped on a halt instruction
+    Hlt,
+    /// Vm stopped on a breakpoint instruction or singlestep
+    Breakpoint,
+    /// Vm interrupted by the hypervisor
+    Interrupted,
+    /// Vm stopped on an invalid instruction
+    InvalidInstruction,
+    /// Vm stopped on a page fault
+    PageFault(PageFaultDetail),
+    /// Vm stopped on an unhandled exception
+    Exception(u64),
+    /// Vm stopped on a syscall instruction (see `Vm::enable_syscalls`)
+    Syscall,
+    /// Vm stopped by a hook returning `HookResult::Exit`
+    HookExit,
+    /// Vm stopped by a hook returning `HookResult::Crash`
+    HookCrash,
+    /// Vm stopped on a return instruction whose return address was modified
+    /// since the function entry
+    RetCorruption {
+        /// Return address pushed by the call
+        expected: u64,
+        /// Return address about to be used
+        found: u64,
+    },
+    /// Vm stopped after an indirect branch to a target not allowed
+    CfiViolation(CfiViolationDetail),
+    /// Vm stopped on a memory safety error detected by the emulated heap
+    HeapViolation(HeapViolation),
+    /// Vmexit unhandled by tartiflette
+    Unhandled,
+    /// Vm executed all the instructions allowed by `Vm::run_steps`
+    Budget,
+    /// Vm still running after the timeout of `Vm::run_with_timeout`
+    Timeout,
+    /// Vm accessed guest physical memory neither backed by memory nor
+    /// registered with `Vm::register_mmio` (the access is completed, reads
+    /// returning zeroes)
+    MmioFault {
+        /// Guest physical address accessed
+        address: u64,
+        /// Whether or not the access is a write
+        write: bool,
+    },
+    /// Vm accessed an IO port without handlers (see `Vm::register_port`),
+    /// the instruction is completed, reads returning zeroes
+    Io {
+        /// Port accessed
+        port: u16,
+        /// Data written (first element of the string instructions)
+        data: u32,
+        /// Whether or not the access is a write
+        write: bool,
+    },
+    /// Vm stopped by the `hypercall::EXIT` hypercall with the given code
+    GuestExit(u64),
+    /// Vm exceeded the time allowed inside an address range (see
+    /// `Vm::add_region_timeout`)
+    RegionTimeout {
+        /// Starting address of the range
+        start: u64,
+        /// Ending address of the range (excluded)
+        end: u64,
+    },
+    /// Vm hit a hardware breakpoint (see `Vm::set_hw_breakpoint`)
+    Watchpoint {
+        /// Address of the breakpoint
+        address: u64,
+        /// Access caught by the breakpoint
+        access: WatchAccess,
+    },
+    /// Vm is about to access a watched range (see `Vm::watch_range`), the
+    /// instruction executes when resuming
+    MemAccess {
+        /// Address accessed
+        addr: u64,
+        /// Kind of access
+        kind: AccessKind,
+    },
+}
+
+/// Tartiflette vm state
+pub struct Vm {
+    /// Kvm device file descriptor
+    _kvm: Kvm,
+    /// Kvm vm file descriptor
+    kvm_vm: VmFd,
+    /// Kvm vm vcpu file descriptor
+    kvm_vcpu: VcpuFd,
+    /// Kvm vcpu run
+    kvm_vcpu_run: KvmRunWrapper,
+    /// Guest cpuid
+    cpuid: CpuId,
+    /// Local copy of kvm registers
+    registers: kvm_regs,
+    /// Local copy of kvm special registers
+    special_registers: kvm_sregs,
+    /// fs_base register
+    fs_base: u64,
+    /// gs_base register
+    gs_base: u64,
+    /// Starting address of the hypercall region
+    hypercall_page: u64,
+    /// Code and stack segments restored after a syscall reaching the stub
+    syscall_segments: Option<(kvm_segment, kvm_segment)>,
+    /// Vm Memory
+    pub memory: VirtualMemory,
+    /// Guest symbols
+    pub symbols: Symbols,
+    /// Options of the crash buckets (see `Vm::crash_hash`)
+    crash_hash_config: CrashHashConfig,
+    /// Software breakpoints and the original byte they replaced
+    breakpoints: BTreeMap<u64, u8>,
+    /// Hooks installed on breakpoints
+    hooks: BTreeMap<u64, Vec<hooks::HookEntry>>,
+    /// Identifier of the next hook installed
+    next_hook_id: u64,
+    /// Breakpoint temporarily removed to single step over its instruction
+    stepping_over: Option<u64>,
+    /// Single step requested by `Vm::step` in progress
+    stepping: bool,
+    /// Return address integrity monitor
+    return_monitor: monitor::ReturnMonitor,
+    /// Indirect branch targets checking policy
+    cfi: cfi::CfiPolicy,
+    /// Emulated heap allocator
+    heap: heap::GuestHeap,
+    /// Pages allocated by `Vm::guest_alloc`
+    allocator: allocator::GuestAllocator,
+    /// Exceptions raised by the guest
+    exception_stats: ExceptionStats,
+    /// Pages restored by the resets
+    dirty_stats: DirtyStats,
+    /// Runs and resets counters
+    exec_stats: ExecStats,
+    /// Options the vm was built with
+    config: VmBuilder,
+    /// Additional guest physical memory regions
+    memory_slots: Vec<PhysicalMemory>,
+    /// Randomness source of the emulated behaviours
+    rng: Box<dyn VmRng>,
+    /// Interruption requests of the `InterruptHandle`s
+    interrupt: Arc<interrupt::InterruptState>,
+    /// Timer of `Vm::run_with_timeout`, created on first use
+    run_timer: Option<interrupt::RunTimer>,
+    /// Expiration of the `Vm::run_with_timeout` timer
+    run_deadline: Option<Instant>,
+    /// Time limits inside address ranges, by starting address
+    region_timeouts: BTreeMap<u64, region::RegionTimeout>,
+    /// Timer kicking the vcpu to enforce the time limits
+    kick_timer: Option<interrupt::RunTimer>,
+    /// Time of the last kick
+    last_kick: Instant,
+    /// Identifier of the thread running on the vcpu
+    current_thread: u64,
+    /// Saved state of the other threads, by identifier
+    threads: BTreeMap<u64, threads::ThreadContext>,
+    /// MMIO regions by guest physical address
+    mmio: BTreeMap<u64, mmio::MmioRegion>,
+    /// Hardware breakpoints by debug register
+    hw_breakpoints: watchpoint::HwBreakpoints,
+    /// Instruction breakpoint being stepped over
+    hw_step_over: Option<u64>,
+    /// Memory ranges traced by `Vm::watch_range`
+    access_watch: access::AccessWatch,
+    /// Instruction tracer
+    tracer: Option<trace::Tracer>,
+    /// Whether or not the memory API accesses are checked
+    soft_mmu: bool,
+    /// Accesses refused by the soft-MMU
+    soft_mmu_faults: RefCell<Vec<SoftMmuFault>>,
+    /// Reserved guest physical windows by address
+    reservations: BTreeMap<u64, layout::Reservation>,
+    /// Periodic checkpoints, when enabled
+    checkpoints: Option<checkpoint::Checkpointer>,
+    /// IO ports handlers
+    ports: BTreeMap<u16, io::PortHandlers>,
+    /// Data given to the guest by the hypercalls
+    guest_input: Vec<u8>,
+    /// Data printed by the guest through the hypercalls
+    guest_output: Vec<u8>,
+    /// Reference state of the integrity checks
+    internal_state: integrity::InternalState,
+    /// Guest debug flags and debug registers last applied to the vcpu
+    applied_guest_debug: (u32, [u64; 8]),
+    /// Dirty log already cleared by kvm, kept for the next reset
+    pending_dirty_log: Vec<u64>,
+    /// Pages written by `Vm::apply_delta`, restored by the next reset
+    written_pages: BTreeSet<u64>,
+    /// Determinism mode options (see `Vm::enable_determinism`)
+    determinism: Option<DeterminismConfig>,
+}
+
+impl Vm {
+    /// Creates a new `Vm` instance with a given memory size
+    /// (the size will be aligned to the nearest page multiple).
+    /// See `VmBuilder` for more options.
+    pub fn new(memory_size: usize) -> Result<Vm> {
+        VmBuilder::new(memory_size).build()
+    }
+
+    /// Sets up a minimal working vm environnement.
+    /// (kvm init + memory + sregs)
+    fn setup_barebones(config: &VmBuilder) -> Result<Vm> {
+        // 1 - Check the layout and allocate the memory
+        if !config.exception_region.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(VmError::InvalidOperation(
+                "Exception region must be page aligned",
+            ));
+        }
+        let misaligned_slot = config.memory_slots.iter().any(|slot| {
+            !slot.guest_address.is_multiple_of(PAGE_SIZE as u64)
+                || !slot.size.is_multiple_of(PAGE_SIZE)
+        });
+        if misaligned_slot {
+            return Err(VmError::InvalidOperation(
+                "Memory slots must be page aligned",
+            ));
+        }
+
+        let misaligned_hole = config.memory_holes.iter().any(|&(start, size)| {
+            !start.is_multiple_of(PAGE_SIZE as u64) || !size.is_multiple_of(PAGE_SIZE as u64)
+        });
+        if misaligned_hole {
+            return Err(VmError::InvalidOperation(
+                "Memory holes must be page aligned",
+            ));
+        }
+
+        let misaligned = config.memory_holes.iter().any(|&(start, size)| {
+            !start.is_multiple_of(HUGE_PAGE_SIZE as u64)
+                || !size.is_multiple_of(HUGE_PAGE_SIZE as u64)
+        });
+        if config.huge_pages != HugePages::Disabled && misaligned {
+            return Err(VmError::InvalidOperation(
+                "Memory holes must be 2MB aligned with hugepages",
+            ));
+        }
+
+        let vm_memory = VirtualMemory::with_backing(
+            config.memory_size,
+            &config.memory_holes,
+            config.huge_pages,
+        )?;
+
+        // 2 - Open the kvm device and check some stuff
+        let kvm_fd =
+            Kvm::new().map_err(|err| HvError::os("Could not open kvm device", err.errno()))?;
+
+        // Check the kvm api version
+        if kvm_fd.get_api_version() as u32 != KVM_API_VERSION {
+            return Err(HvError::new("Wrong KVM api version").into());
+        }
+
+        // Check the `SyncRegs` extension
+        if !kvm_fd.check_extension(Cap::SyncRegs) {
+            return Err(HvError::new("SyncRegs capability not present").into());
+        }
+
+        // Check the KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2 extension, older kernels
+        // fall back to KVM_GET_DIRTY_LOG
+        let ret = unsafe {
+            ioctl::ioctl_with_val(
+                &kvm_fd,
+                KVM_CHECK_EXTENSION(),
+                KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2 as u64,
+            )
+        };
+        let mut dirty_log = match config.dirty_log {
+            DirtyLogStrategy::Auto if ret > 0 => DirtyLogStrategy::ManualProtect,
+            DirtyLogStrategy::Auto => DirtyLogStrategy::GetDirtyLog,
+            DirtyLogStrategy::ManualProtect if ret <= 0 => {
+                return Err(HvError::new("Manual dirty log protect capability not present").into())
+            }
+            strategy => strategy,
+        };
+
+        // 3 - Ask kvm to create a vm
+        let vm_fd = kvm_fd
+            .create_vm()
+            .map_err(|err| HvError::os("Could not create vm fd", err.errno()))?;
+
+        // Enable the KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2 capability
+        if dirty_log == DirtyLogStrategy::ManualProtect {
+            let mut cap = kvm_enable_cap::default();
+            cap.cap = KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2;
+            cap.args[0] = KVM_DIRTY_LOG_MANUAL_PROTECT_ENABLE as u64;
+
+            match vm_fd.enable_cap(&cap) {
+                Ok(()) => {}
+                Err(_) if config.dirty_log == DirtyLogStrategy::Auto => {
+                    dirty_log = DirtyLogStrategy::GetDirtyLog
+                }
+                Err(err) => {
+                    return Err(HvError::os(
+                        "Could not enable KVM_DIRTY_LOG_MANUAL_PROTECT",
+                        err.errno(),
+                    )
+                    .into())
+                }
+            }
+        }
+
+        // 4 - Ask kvm to create a new vcpu for our vm
+        let vcpu_fd = vm_fd
+            .create_vcpu(0)
+            .map_err(|err| HvError::os("Could not create vm vcpu", err.errno()))?;
+
+        // Expose the host supported features to the guest
+        let cpuid = kvm_fd
+            .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map_err(|err| HvError::os("Could not get supported cpuid", err.errno()))?;
+        cpuid::apply_cpuid(&vcpu_fd, &cpuid)?;
+
+        // 5 - Map the VCPU kvm run memory region
+        let vcpu_mmap_size = kvm_fd
+            .get_vcpu_mmap_size()
+            .map_err(|err| HvError::os("Could not get vcpu mmap size", err.errno()))?;
+        let vcpu_run = KvmRunWrapper::mmap_from_fd(&vcpu_fd, vcpu_mmap_size)
+            .map_err(|err| HvError::os("Could not get wrapper arround vcpu", err.errno()))?;
+
+        // 6 - Setup guest memory, a slot for each bank
+        let banks = vm_memory.banks();
+        for (index, bank) in banks.iter().enumerate() {
+            let region = kvm_userspace_memory_region {
+                slot: index as u32,
+                guest_phys_addr: bank.guest_address,
+                memory_size: bank.size as u64,
+                userspace_addr: vm_memory.host_address() + bank.host_offset as u64,
+                flags: KVM_MEM_LOG_DIRTY_PAGES,
+            };
+            unsafe { vm_fd.set_user_memory_region(region) }.map_err(|err| {
+                HvError::os("Could not set memory region for guest", err.errno())
+                    .with_context(ErrorContext::Slot(region.slot))
+            })?;
+        }
+
+        // Setup the additional memory regions
+        let mut memory_slots = Vec::new();
+        for (index, slot) in config.memory_slots.iter().enumerate() {
+            let end = slot.guest_address + slot.size as u64;
+            let overlapping = banks
+                .iter()
+                .any(|bank| bank.guest_address < end && slot.guest_address < bank.end());
+            if overlapping {
+                return Err(VmError::InvalidOperation(
+                    "Memory slot overlapping the vm memory",
+                ));
+            }
+
+            let overlapping = config.memory_slots[..index].iter().any(|other| {
+                other.guest_address < end
+                    && slot.guest_address < other.guest_address + other.size as u64
+            });
+            if overlapping {
+                return Err(VmError::InvalidOperation(
+                    "Memory slot overlapping another slot",
+                ));
+            }
+
+            let memory = PhysicalMemory::new(slot.size)?;
+            let region = kvm_userspace_memory_region {
+                slot: (banks.len() + index) as u32,
+                guest_phys_addr: slot.guest_address,
+                memory_size: slot.size as u64,
+                userspace_addr: memory.host_address() as u64,
+                flags: 0,
+            };
+            unsafe { vm_fd.set_user_memory_region(region) }.map_err(|err| {
+                HvError::os("Could not set memory slot for guest", err.errno())
+                    .with_context(ErrorContext::Slot(region.slot))
+            })?;
+
+            memory_slots.push(memory);
+        }
+
+        // Get registers
+        let regs = vcpu_fd
+            .get_regs()
+            .map_err(|err| HvError::os("Could not get general registers", err.errno()))?;
+        // Get special registers
+        let sregs = vcpu_fd
+            .get_sregs()
+            .map_err(|err| HvError::os("Could not get special registers", err.errno()))?;
+
+        // Construct the new `Vm` object
+        Ok(Vm {
+            _kvm: kvm_fd,
+            kvm_vm: vm_fd,
+            kvm_vcpu: vcpu_fd,
+            kvm_vcpu_run: vcpu_run,
+            cpuid,
+            registers: regs,
+            special_registers: sregs,
+            memory: vm_memory,
+            hypercall_page: 0,
+            syscall_segments: None,
+            fs_base: 0,
+            gs_base: 0,
+            symbols: Symbols::new(),
+            breakpoints: BTreeMap::new(),
+            hooks: BTreeMap::new(),
+            next_hook_id: 0,
+            stepping_over: None,
+            stepping: false,
+            return_monitor: Default::default(),
+            cfi: Default::default(),
+            heap: Default::default(),
+            allocator: Default::default(),
+            crash_hash_config: Default::default(),
+            exception_stats: Default::default(),
+            dirty_stats: Default::default(),
+            exec_stats: Default::default(),
+            config: VmBuilder {
+                dirty_log,
+                ..config.clone()
+            },
+            memory_slots,
+            rng: Box::new(SplitMix64::new(config.seed)),
+            interrupt: Default::default(),
+            run_timer: None,
+            run_deadline: None,
+            region_timeouts: BTreeMap::new(),
+            kick_timer: None,
+            last_kick: Instant::now(),
+            current_thread: 0,
+            threads: BTreeMap::new(),
+            mmio: BTreeMap::new(),
+            hw_breakpoints: Default::default(),
+            hw_step_over: None,
+            access_watch: Default::default(),
+            tracer: None,
+            soft_mmu: false,
+            soft_mmu_faults: RefCell::new(Vec::new()),
+            reservations: BTreeMap::new(),
+            checkpoints: None,
+            ports: BTreeMap::new(),
+            guest_input: Vec::new(),
+            guest_output: Vec::new(),
+            internal_state: Default::default(),
+            applied_guest_debug: Default::default(),
+            pending_dirty_log: Vec::new(),
+            written_pages: BTreeSet::new(),
+            determinism: None,
+        })
+    }
+
+    /// Configures the Vm special registers
+    fn setup_registers(&mut self) -> Result<()> {
+        // Initialize system registers
+        const CR0_PG: u64 = 1 << 31;
+        const CR0_PE: u64 = 1 << 0;
+        const CR0_ET: u64 = 1 << 4;
+        const CR0_WP: u64 = 1 << 16;
+
+        const CR4_PAE: u64 = 1 << 5;
+        const CR4_OSFXSR: u64 = 1 << 9;
+        const IA32_EFER_LME: u64 = 1 << 8;
+        const IA32_EFER_LMA: u64 = 1 << 10;
+        const IA32_EFER_NXE: u64 = 1 << 11;
+
+        // Set the 64 bits code segment
+        let mut seg = kvm_segment {
+            base: 0,
+            limit: 0,
+            selector: 1 << 3, // Index 1, GDT, RPL = 0
+            present: 1,
+            type_: 11, /* Code: execute, read, accessed */
+            dpl: 0,
+            db: 0,
+            s: 1, /* Code/data */
+            l: 1,
+            g: 0,
+            avl: 0,
+            unusable: 0,
+            padding: 0,
+        };
+        self.special_registers.cs = seg;
+
+        // seg.selector = 0;
+        seg.type_ = 3;
+
+        // Set the others 64 bits segments
+        self.special_registers.ds = seg;
+        self.special_registers.es = seg;
+        self.special_registers.fs = seg;
+        self.special_registers.gs = seg;
+        self.special_registers.ss = seg;
+
+        // Paging enable and paging
+        self.special_registers.cr0 = CR0_PE | CR0_PG | CR0_ET | CR0_WP;
+        // Physical address extension (necessary for x64)
+        self.special_registers.cr4 = CR4_PAE | CR4_OSFXSR;
+        // XSAVE (AVX registers) when exposed in the cpuid
+        if cpuid::xsave_supported(&self.cpuid) {
+            self.special_registers.cr4 |= cpuid::CR4_OSXSAVE;
+        }
+        // Sets the page table root address
+        self.special_registers.cr3 = self.memory.page_directory() as u64;
+        // Sets x64 mode enabled (LME), active (LMA), executable disable bit support (NXE), syscall
+        // support (SCE)
+        self.special_registers.efer = IA32_EFER_LME | IA32_EFER_LMA | IA32_EFER_NXE;
+
+        // Set the tss address
+        self.kvm_vm
+            .set_tss_address(0xfffb_d000)
+            .map_err(|err| HvError::os("Could not set tss address", err.errno()))?;
+
+        // Enable vm exit on software breakpoints
+        self.set_singlestep(false)
+    }
+
+    /// Applies the guest debug flags and the debug registers, keeping the
+    /// single step mode of the step over in progress
+    pub(super) fn update_guest_debug(&mut self) -> Result<()> {
+        self.set_singlestep(self.stepping_over.is_some())
+    }
+
+    /// Enables or disables the single step mode (software breakpoints always
+    /// trigger a vm exit). Stays enabled during a `Vm::step`.
+    fn set_singlestep(&mut self, enabled: bool) -> Result<()> {
+        let debug_struct = self.guest_debug(enabled);
+        self.kvm_vcpu
+            .set_guest_debug(&debug_struct)
+            .map_err(|err| HvError::os("Could not set debug registers", err.errno()))?;
+
+        self.applied_guest_debug = (debug_struct.control, debug_struct.arch.debugreg);
+        Ok(())
+    }
+
+    /// Returns the guest debug state to apply, single stepping if `enabled`
+    fn guest_debug(&self, enabled: bool) -> kvm_guest_debug {
+        let mut control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP | self.config.guest_debug;
+        if enabled || self.stepping || self.hw_step_over.is_some() || self.access_watch.stepping() {
+            control |= KVM_GUESTDBG_SINGLESTEP;
+        }
+
+        // Hardware breakpoints
+        let arch = self.debug_registers();
+        if arch.is_some() {
+            control |= KVM_GUESTDBG_USE_HW_BP;
+        }
+
+        kvm_guest_debug {
+            control,
+            pad: 0,
+            arch: arch.unwrap_or_default(),
+        }
+    }
+
+    /// Setups the necessary pieces for handling interrupts (TSS, TSS Stack, GDT slots, IDT)
+    fn setup_exception_handling(&mut self) -> Result<()> {
+        // Defines usefull regions
+        let idt_address = self.config.exception_region;
+        let idt_handlers = idt_address + PAGE_SIZE as u64;
+        let gdt_address = idt_address + (PAGE_SIZE * 2) as u64;
+        let tss_address = idt_address + (PAGE_SIZE * 3) as u64;
+        let stack_address = idt_address + (PAGE_SIZE * 4) as u64;
+
+        // A stack size of 4KB should be enough for simply handling interrupts
+        const STACK_SIZE: usize = PAGE_SIZE;
+
+        // Setting up the GDT
+        self.memory.mmap(
+            gdt_address,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        // Setting up the null segment
+        self.memory.write_val(gdt_address, 0u64)?;
+        // Setting up the 64 bits code segment
+        self.memory
+            .write_val(gdt_address + 8, 0x00209a0000000000u64)?;
+        // Setting up the TSS entry
+        self.memory.write_val(
+            gdt_address + 16,
+            TssEntry::new(tss_address, PrivilegeLevel::Ring0),
+        )?;
+
+        // Set the sepecial registers to reference the GDT
+        self.special_registers.gdt.base = gdt_address;
+        self.special_registers.gdt.limit = (8 * 3) - 1;
+
+        // Setting up the TSS
+        self.memory
+            .mmap(tss_address, PAGE_SIZE, PagePermissions::READ)?;
+
+        // Create the TSS with an IST alternative stack at index 1
+        let mut tss = Tss::new();
+        tss.set_ist(1, stack_address + (STACK_SIZE - 0x100) as u64);
+        // Write the structure in memory
+        self.memory.write_val(tss_address, tss)?;
+
+        // Set the tr register to the TSS
+        self.special_registers.tr = kvm_segment {
+            base: tss_address,
+            limit: (core::mem::size_of::<Tss>() - 1) as u32,
+            selector: 2 << 3, // Index 2, GDT, RPL = 0
+            present: 1,
+            type_: 11,
+            dpl: 0,
+            db: 0,
+            s: 0,
+            l: 1,
+            g: 0,
+            avl: 0,
+            unusable: 0,
+            padding: 0,
+        };
+
+        // Setting up exception handlers
+        self.memory.mmap(
+            idt_handlers,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::EXECUTE,
+        )?;
+        self.hypercall_page = idt_handlers;
+
+        // Loop through IDT handlers
+        for i in 0..32 {
+            let handler_code: &[u8] = &[
+                0x6a, i as u8, // push <exception index>
+                0xf4,    // hlt -> our hypercall
+            ];
+
+            self.memory.write(idt_handlers + (i * 32), handler_code)?;
+        }
+
+        // Setting up the IDT
+        self.memory
+            .mmap(idt_address, PAGE_SIZE, PagePermissions::READ)?;
+
+        let mut entries = [IdtEntry::new(); 32];
+        let entries_size = entries.len() * std::mem::size_of::<IdtEntry>();
+
+        // Loop through IDT entries
+        for i in 0..32 {
+            entries[i] = IdtEntryBuilder::new()
+                .base(idt_handlers + (i * 32) as u64)
+                .dpl(PrivilegeLevel::Ring0)
+                .segment_selector(1, PrivilegeLevel::Ring0)
+                .gate_type(IdtEntryType::Trap)
+                .ist(1)
+                .collect();
+        }
+        self.memory.write_val(idt_address, entries)?;
+
+        // Set the sepecial registers to reference the IDT
+        self.special_registers.idt.base = idt_address;
+        self.special_registers.idt.limit = (entries_size - 1) as u16;
+
+        // Setting up the alternativ stack by allocating it for exception handling
+        self.memory.mmap(
+            stack_address,
+            STACK_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        Ok(())
+    }
+
+    /// Gets a register from the vm state
+    #[inline]
+    pub fn get_reg(&self, regid: Register) -> u64 {
+        match regid {
+            Register::Rax => self.registers.rax,
+            Register::Rbx => self.registers.rbx,
+            Register::Rcx => self.registers.rcx,
+            Register::Rdx => self.registers.rdx,
+            Register::Rsi => self.registers.rsi,
+            Register::Rdi => self.registers.rdi,
+            Register::Rsp => self.registers.rsp,
+            Register::Rbp => self.registers.rbp,
+            Register::R8 => self.registers.r8,
+            Register::R9 => self.registers.r9,
+            Register::R10 => self.registers.r10,
+            Register::R11 => self.registers.r11,
+            Register::R12 => self.registers.r12,
+            Register::R13 => self.registers.r13,
+            Register::R14 => self.registers.r14,
+            Register::R15 => self.registers.r15,
+            Register::Rip => self.registers.rip,
+            Register::Rflags => self.registers.rflags,
+            Register::FsBase => self.fs_base,
+            Register::GsBase => self.gs_base,
+            Register::Cr0 => self.special_registers.cr0,
+            Register::Cr2 => self.special_registers.cr2,
+            Register::Cr3 => self.special_registers.cr3,
+            Register::Cr4 => self.special_registers.cr4,
+            Register::Cr8 => self.special_registers.cr8,
+            Register::Efer => self.special_registers.efer,
+        }
+    }
+
+    /// Sets a register in the vm state
+    #[inline]
+    pub fn set_reg(&mut self, regid: Register, regval: u64) {
+        match regid {
+            Register::Rax => self.registers.rax = regval,
+            Register::Rbx => self.registers.rbx = regval,
+            Register::Rcx => self.registers.rcx = regval,
+            Register::Rdx => self.registers.rdx = regval,
+            Register::Rsi => self.registers.rsi = regval,
+            Register::Rdi => self.registers.rdi = regval,
+            Register::Rsp => self.registers.rsp = regval,
+            Register::Rbp => self.registers.rbp = regval,
+            Register::R8 => self.registers.r8 = regval,
+            Register::R9 => self.registers.r9 = regval,
+            Register::R10 => self.registers.r10 = regval,
+            Register::R11 => self.registers.r11 = regval,
+            Register::R12 => self.registers.r12 = regval,
+            Register::R13 => self.registers.r13 = regval,
+            Register::R14 => self.registers.r14 = regval,
+            Register::R15 => self.registers.r15 = regval,
+            Register::Rip => self.registers.rip = regval,
+            Register::Rflags => self.registers.rflags = regval,
+            Register::FsBase => self.fs_base = regval,
+            Register::GsBase => self.gs_base = regval,
+            Register::Cr0 => self.special_registers.cr0 = regval,
+            Register::Cr2 => self.special_registers.cr2 = regval,
+            Register::Cr3 => self.special_registers.cr3 = regval,
+            Register::Cr4 => self.special_registers.cr4 = regval,
+            Register::Cr8 => self.special_registers.cr8 = regval,
+            Register::Efer => self.special_registers.efer = regval,
+        }
+    }
+
+    /// Maps memory with given permissions in the vm address space
+    #[inline]
+    pub fn mmap(&mut self, vaddr: u64, size: usize, perms: PagePermissions) -> Result<()> {
+        self.memory
+            .mmap(vaddr, size, perms)
+            .map_err(VmError::MemoryError)
+    }
+
+    /// Maps guest physical memory outside of the vm memory (memory slots, MMIO
+    /// regions) in the vm address space
+    #[inline]
+    pub fn map_physical(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        size: usize,
+        perms: PagePermissions,
+    ) -> Result<()> {
+        self.memory
+            .map_physical(vaddr, paddr, size, perms)
+            .map_err(VmError::MemoryError)
+    }
+
+    /// Unmaps memory from the vm address space (unmapped pages are skipped)
+    #[inline]
+    pub fn munmap(&mut self, vaddr: u64, size: usize) -> Result<()> {
+        self.memory
+            .munmap(vaddr, size)
+            .map_err(VmError::MemoryError)
+    }
+
+    /// Changes the permissions of mapped memory in the vm address space
+    #[inline]
+    pub fn mprotect(&mut self, vaddr: u64, size: usize, perms: PagePermissions) -> Result<()> {
+        self.memory
+            .mprotect(vaddr, size, perms)
+            .map_err(VmError::MemoryError)
+    }
+
+    /// Writes given data to the vm memory
+    #[inline]
+    pub fn write(&mut self, vaddr: u64, data: &[u8]) -> Result<()> {
+        self.check_soft_mmu(vaddr, data.len(), true)?;
+        self.memory.write(vaddr, data).map_err(VmError::MemoryError)
+    }
+
+    /// Writes a value to the vm memory
+    #[inline]
+    pub fn write_value<T>(&mut self, address: u64, val: T) -> Result<()> {
+        self.check_soft_mmu(address, std::mem::size_of::<T>(), true)?;
+        self.memory
+            .write_val::<T>(address, val)
+            .map_err(VmError::MemoryError)
+    }
+
+    /// Reads data from the given vm memory
+    #[inline]
+    pub fn read(&self, vaddr: u64, data: &mut [u8]) -> Result<()> {
+        self.check_soft_mmu(vaddr, data.len(), false)?;
+        self.memory.read(vaddr, data).map_err(VmError::MemoryError)
+    }
+
+    /// Reads a NUL terminated string of at most `max` bytes from the vm
+    /// memory (terminator excluded)
+    #[inline]
+    pub fn read_cstring(&self, vaddr: u64, max: usize) -> Result<Vec<u8>> {
+        let string = self
+            .memory
+            .read_cstring(vaddr, max)
+            .map_err(VmError::MemoryError)?;
+
+        // The bytes read up to the terminator
+        self.check_soft_mmu(vaddr, string.len() + 1, false)?;
+        Ok(string)
+    }
+
+    /// Reads a NUL terminated UTF-16 string of at most `max` characters from
+    /// the vm memory (terminator excluded)
+    #[inline]
+    pub fn read_wstring(&self, vaddr: u64, max: usize) -> Result<String> {
+        let string = self
+            .memory
+            .read_wstring(vaddr, max)
+            .map_err(VmError::MemoryError)?;
+
+        // The characters read up to the terminator
+        let length = string.encode_utf16().count();
+        self.check_soft_mmu(vaddr, (length + 1) * 2, false)?;
+        Ok(string)
+    }
+
+    /// Returns the readable subranges `(start, end)` of a vm memory area
+    #[inline]
+    pub fn readable_ranges(&self, vaddr: u64, size: usize) -> Result<Vec<(u64, u64)>> {
+        self.memory
+            .readable_ranges(vaddr, size)
+            .map_err(VmError::MemoryError)
+    }
+
+    /// Reads data from the vm memory, the unmapped or non present pages
+    /// being read as zeroes. Returns the holes `(start, end)` filled.
+    #[inline]
+    pub fn read_zero_filled(&self, vaddr: u64, data: &mut [u8]) -> Result<Vec<(u64, u64)>> {
+        self.memory
+            .read_zero_filled(vaddr, data)
+            .map_err(VmError::MemoryError)
+    }
+
+    /// Reads data from the vm memory up to the first unmapped or non present
+    /// page, returning the number of bytes read along with the error
+    #[inline]
+    pub fn read_prefix(&self, vaddr: u64, data: &mut [u8]) -> std::result::Result<(), PartialRead> {
+        self.memory.read_prefix(vaddr, data)
+    }
+
+    /// Reads a value from the vm memory if the guest could read it
+    #[inline]
+    pub fn read_value_checked<T: Copy>(&self, vaddr: u64) -> Result<T> {
+        self.memory
+            .read_val_checked(vaddr)
+            .map_err(VmError::MemoryError)
+    }
+
+    /// Writes a value to the vm memory if the guest could write it
+    #[inline]
+    pub fn write_value_checked<T: Copy>(&mut self, vaddr: u64, val: T) -> Result<()> {
+        self.memory
+            .write_val_checked(vaddr, val)
+            .map_err(VmError::MemoryError)
+    }
+
+    /// Translates a guest virtual address to its physical address and the
+    /// permissions of its page
+    #[inline]
+    pub fn translate(&self, vaddr: u64) -> Option<(u64, PagePermissions)> {
+        self.memory.translate(vaddr)
+    }
+
+    /// Returns the symbolic form of a guest address resolved through the vm
+    /// `Symbols` (e.g. `libfoo.so!parse_header+0x42`), or its hexadecimal
+    /// form when unknown
+    pub fn symbolize(&self, vaddr: u64) -> String {
+        self.symbols
+            .symbolize(vaddr)
+            .unwrap_or_else(|| format!("0x{:x}", vaddr))
+    }
+
+    /// Returns an iterator over the page table entries of the mapped pages
+    #[inline]
+    pub fn page_entries(&self) -> impl Iterator<Item = PageEntry> + '_ {
+        self.memory.page_entries()
+    }
+
+    /// Returns an iterator over all mappings
+    #[inline]
+    pub fn mappings(&self) -> impl Iterator<Item = Mapping> + '_ {
+        self.memory.mappings()
+    }
+
+    /// Returns an iterator over all dirty mappings
+    #[inline]
+    pub fn dirty_mappings(&self) -> impl Iterator<Item = Mapping> + '_ {
+        self.mappings().filter(|m| m.dirty)
+    }
+
+    /// Clear dirty mappings status
+    #[inline]
+    pub fn clear_dirty_mappings(&mut self) {
+        for (_, pte) in self.memory.raw_pages_mut() {
+            pte.set_dirty(false);
+        }
+    }
+
+    fn flush_registers(&mut self) -> Result<()> {
+        // The second bit of rflags must always be set.
+        self.registers.rflags |= 1 << 1;
+
+        // Set registers and special registers
+        self.kvm_vcpu
+            .set_regs(&self.registers)
+            .map_err(|err| HvError::os("Could not commit registers", err.errno()))?;
+        self.kvm_vcpu
+            .set_sregs(&self.special_registers)
+            .map_err(|err| HvError::os("Could not commit special registers", err.errno()))?;
+
+        // Set gs_base and fs_base through msrs
+        let msrs = Msrs::from_entries(&[
+            kvm_msr_entry {
+                index: IA32_FS_BASE,
+                data: self.fs_base,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: IA32_GS_BASE,
+                data: self.gs_base,
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+        self.kvm_vcpu
+            .set_msrs(&msrs)
+            .map_err(|err| HvError::os("Could not commit fsbase and gsbase", err.errno()))?;
+
+        // Get registers and special registers
+        self.registers = self
+            .kvm_vcpu
+            .get_regs()
+            .map_err(|err| HvError::os("Could not get special registers", err.errno()))?;
+        self.special_registers = self
+            .kvm_vcpu
+            .get_sregs()
+            .map_err(|err| HvError::os("Could not get general registers", err.errno()))?;
+
+        // Update kvm vcpu run region
+        self.kvm_vcpu_run.as_mut_ref().s.regs.regs = self.registers;
+        self.kvm_vcpu_run.as_mut_ref().s.regs.sregs = self.special_registers;
+        self.kvm_vcpu_run.as_mut_ref().kvm_dirty_regs = 0;
+
+        Ok(())
+    }
+
+    /// Commit local copy of registers to kvm
+    #[inline]
+    fn commit_registers(&mut self) -> Result<()> {
+        // The second bit of rflags must always be set.
+        self.registers.rflags |= 1 << 1;
+
+        self.kvm_vcpu_run.as_mut_ref().s.regs.regs = self.registers;
+        self.kvm_vcpu_run.as_mut_ref().s.regs.sregs = self.special_registers;
+        self.kvm_vcpu_run.as_mut_ref().kvm_dirty_regs |=
+            KVM_SYNC_X86_SREGS as u64 | KVM_SYNC_X86_REGS as u64;
+
+        // gs_base and fs_base need to go through msrs
+        let msrs = Msrs::from_entries(&[
+            kvm_msr_entry {
+                index: IA32_FS_BASE,
+                data: self.fs_base,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: IA32_GS_BASE,
+                data: self.gs_base,
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+
+        self.kvm_vcpu
+            .set_msrs(&msrs)
+            .map_err(|err| HvError::os("Could not commit fsbase and gsbase", err.errno()))?;
+
+        Ok(())
+    }
+
+    /// Run the `Vm` instance until the first `Vm` that cannot be
+    /// handled directly
+    pub fn run(&mut self) -> Result<VmExit> {
+        // The traced instructions are single stepped
+        if self.tracer.is_some() && !self.stepping {
+            return self.run_traced();
+        }
+
+        let start = Instant::now();
+
+        self.start_region_kicks()?;
+        self.strip_watched_pages()?;
+        let running = self.enter_run();
+        let result = self.run_vcpu();
+        drop(running);
+        self.restore_watched_pages()?;
+        self.stop_region_kicks()?;
+
+        self.exec_stats.runs += 1;
+        self.exec_stats.run_time += start.elapsed();
+        if let Ok(VmExit::Syscall) = result {
+            *self
+                .exec_stats
+                .syscalls
+                .entry(self.registers.rax)
+                .or_insert(0) += 1;
+        }
+
+        result
+    }
+
+    /// Runs the vcpu until an exit to report
+    fn run_vcpu(&mut self) -> Result<VmExit> {
+        let result = loop {
+            // Interruption requested outside of KVM_RUN
+            if self.take_interrupt() {
+                break VmExit::Interrupted;
+            }
+
+            // Commit potential modification done on registers
+            self.commit_registers()?;
+
+            // Set the valid synchronised registers
+            self.kvm_vcpu_run.as_mut_ref().kvm_valid_regs |=
+                KVM_SYNC_X86_REGS as u64 | KVM_SYNC_X86_SREGS as u64;
+
+            // Ask kvm to run the vm's vcpu
+            let start = Instant::now();
+            let exit = self.kvm_vcpu.run();
+            let reason = match &exit {
+                Ok(VcpuExit::Debug(_)) => "debug",
+                Ok(VcpuExit::Hlt) => "hlt",
+                Ok(VcpuExit::MmioRead(..)) | Ok(VcpuExit::MmioWrite(..)) => "mmio",
+                Ok(VcpuExit::IoIn(..)) | Ok(VcpuExit::IoOut(..)) => "io",
+                Ok(_) => "other",
+                Err(_) => "interrupted",
+            };
+            self.exec_stats.record_exit(reason, start.elapsed());
+
+            // Pull registers and special registers
+            unsafe {
+                self.registers = self.kvm_vcpu_run.as_mut_ref().s.regs.regs;
+                self.special_registers = self.kvm_vcpu_run.as_mut_ref().s.regs.sregs;
+            }
+
+            // Pull fs_base and gs_base
+            let mut msrs = Msrs::from_entries(&[
+                kvm_msr_entry {
+                    index: IA32_FS_BASE,
+                    ..Default::default()
+                },
+                kvm_msr_entry {
+                    index: IA32_GS_BASE,
+                    ..Default::default()
+                },
+            ])
+            .unwrap();
+
+            let count = self
+                .kvm_vcpu
+                .get_msrs(&mut msrs)
+                .map_err(|err| HvError::os("Could not read fs_base and gs_base", err.errno()))?;
+            assert_eq!(count, 2, "Invalid number of msrs returned");
+
+            let msrs_res = msrs.as_slice();
+            self.fs_base = msrs_res[0].data;
+            self.gs_base = msrs_res[1].data;
+
+            // Handle possible interrupts (timeout)
+            if let Err(err) = exit {
+                match Errno::from_i32(err.errno()) {
+                    Errno::EINTR | Errno::EAGAIN => {
+                        if self.take_interrupt() {
+                            break VmExit::Interrupted;
+                        }
+
+                        // Periodic kick enforcing the region timeouts
+                        match self.handle_region_kick() {
+                            Some(exit) => break exit,
+                            None => continue,
+                        }
+                    }
+                    _ => return Err(HvError::os("Unexpected errno in KVM_RUN", err.errno()).into()),
+                }
+            }
+
+            match exit.unwrap() {
+                VcpuExit::Debug(debug) => {
+                    // Hardware breakpoints and watchpoints
+                    match self.handle_hw_breakpoint(debug.dr6)? {
+                        Some(Some(exit)) => break exit,
+                        Some(None) => continue,
+                        None => {}
+                    }
+
+                    // Run the hooks, resume the execution if asked to
+                    if let Some(exit) = self.handle_debug_exit(debug.exception)? {
+                        break exit;
+                    }
+                }
+                VcpuExit::Hlt => {
+                    // Syscall executed with IA32_EFER.SCE enabled
+                    if self.syscall_return() {
+                        break VmExit::Syscall;
+                    }
+
+                    // If code is outside of hypercall region, forward the hlt
+                    if (self.registers.rip < self.hypercall_page)
+                        || (self.registers.rip >= self.hypercall_page + PAGE_SIZE as u64)
+                    {
+                        break VmExit::Hlt;
+                    }
+
+                    // If we are within the hypercall region, handle the
+                    // exception forwarding.
+                    let exception_code: u64 = self.memory.read_val(self.registers.rsp)?;
+
+                    let error_code: Option<u64> =
+                        match ExceptionType::from(exception_code).has_error_code() {
+                            true => Some(self.memory.read_val(self.registers.rsp + 8)?),
+                            false => None,
+                        };
+
+                    let exception_frame: ExceptionFrame = if error_code.is_some() {
+                        self.memory.read_val(self.registers.rsp + 16)?
+                    } else {
+                        self.memory.read_val(self.registers.rsp + 8)?
+                    };
+
+                    // Reset register context to before exception
+                    self.registers.rsp = exception_frame.rsp;
+                    self.registers.rip = exception_frame.rip;
+
+                    match ExceptionType::from(exception_code) {
+                        ExceptionType::PageFault => {
+                            let address = self.special_registers.cr2;
+
+                            // Accesses to the watched pages
+                            let status = error_code.unwrap() as u32;
+                            match self.handle_access_fault(address, status)? {
+                                Some(Some(exit)) => break exit,
+                                Some(None) => continue,
+                                None => {}
+                            }
+
+                            self.exception_stats
+                                .record(exception_code, exception_frame.rip);
+
+                            // Faults on the heap guard and freed pages
+                            if let Some(violation) = self.heap.classify(address) {
+                                break VmExit::HeapViolation(violation);
+                            }
+
+                            break VmExit::PageFault(PageFaultDetail { status, address });
+                        }
+                        ExceptionType::InvalidOpcode => {
+                            // As IA32_EFER.SCE is not enabled by default, a syscall instruction
+                            // will trigger a #UD exception (see `Vm::enable_syscalls` for the
+                            // architectural behaviour).
+                            // To give the opportunity to the Vm user to emulate the syscall, we try
+                            // to detect the instruction bytes, set the rip to after the syscall
+                            // and return with a special `Syscall` VmExit.
+                            let mut code_bytes: [u8; 2] = [0; 2];
+
+                            if self
+                                .memory
+                                .read(self.registers.rip, &mut code_bytes)
+                                .is_ok()
+                            {
+                                //  0f 05 -> syscall
+                                if code_bytes == [0x0f, 0x05] {
+                                    // We advance rip by two bytes to move over the syscall
+                                    // instruction.
+                                    self.registers.rip += 2;
+                                    break VmExit::Syscall;
+                                }
+                            }
+
+                            // rdrand and rdseed hidden by `Vm::trap_rdrand`
+                            if self.emulate_rdrand(exception_frame.rflags) {
+                                continue;
+                            }
+
+                            self.exception_stats
+                                .record(exception_code, exception_frame.rip);
+                            break VmExit::InvalidInstruction;
+                        }
+                        _ => {
+                            self.exception_stats
+                                .record(exception_code, exception_frame.rip);
+                            break VmExit::Exception(exception_code);
+                        }
+                    }
+                }
+                VcpuExit::MmioRead(address, data) => {
+                    let mut buffer = [0u8; 8];
+                    let len = data.len();
+
+                    let exit = self.handle_mmio(address, &mut buffer[..len], false);
+
+                    unsafe {
+                        self.kvm_vcpu_run.as_mut_ref().__bindgen_anon_1.mmio.data[..len]
+                            .copy_from_slice(&buffer[..len]);
+                    }
+                    self.complete_io();
+
+                    if let Some(exit) = exit {
+                        break exit;
+                    }
+                }
+                VcpuExit::MmioWrite(address, data) => {
+                    let mut buffer = [0u8; 8];
+                    let len = data.len();
+                    buffer[..len].copy_from_slice(data);
+
+                    let exit = self.handle_mmio(address, &mut buffer[..len], true);
+                    self.complete_io();
+
+                    if let Some(exit) = exit {
+                        break exit;
+                    }
+                }
+                VcpuExit::IoIn(port, data) => {
+                    let mut buffer = vec![0u8; data.len()];
+                    let data = data.as_mut_ptr();
+
+                    let exit = self.handle_io(port, &mut buffer, false);
+
+                    // The data lives in the kvm run structure, given to the
+                    // guest on the next KVM_RUN
+                    unsafe {
+                        std::slice::from_raw_parts_mut(data, buffer.len()).copy_from_slice(&buffer);
+                    }
+                    self.complete_io();
+
+                    if let Some(exit) = exit {
+                        break exit;
+                    }
+                }
+                VcpuExit::IoOut(port, data) => {
+                    let mut buffer = data.to_vec();
+
+                    let exit = self.handle_io(port, &mut buffer, true);
+                    self.complete_io();
+
+                    if let Some(exit) = exit {
+                        break exit;
+                    }
+                }
+                _ => break VmExit::Unhandled,
+            }
+        };
+
+        Ok(result)
+    }
+
+    // Set `Vm` registers from a `SnapshotRegisters` instance
+    #[inline]
+    pub fn set_regs_snapshot(&mut self, regs: &SnapshotRegisters) {
+        self.set_reg(Register::Rax, regs.rax);
+        self.set_reg(Register::Rbx, regs.rbx);
+        self.set_reg(Register::Rcx, regs.rcx);
+        self.set_reg(Register::Rdx, regs.rdx);
+        self.set_reg(Register::Rsi, regs.rsi);
+        self.set_reg(Register::Rdi, regs.rdi);
+        self.set_reg(Register::Rsp, regs.rsp);
+        self.set_reg(Register::Rbp, regs.rbp);
+        self.set_reg(Register::R8, regs.r8);
+        self.set_reg(Register::R9, regs.r9);
+        self.set_reg(Register::R10, regs.r10);
+        self.set_reg(Register::R11, regs.r11);
+        self.set_reg(Register::R12, regs.r12);
+        self.set_reg(Register::R13, regs.r13);
+        self.set_reg(Register::R14, regs.r14);
+        self.set_reg(Register::R15, regs.r15);
+        self.set_reg(Register::Rip, regs.rip);
+        self.set_reg(Register::Rflags, regs.rflags);
+        self.set_reg(Register::FsBase, regs.fs_base);
+        self.set_reg(Register::GsBase, regs.gs_base);
+    }
+
+    /// Loads a vm state from snapshot files, the memory dump being either a
+    /// flat file or a compressed container (see `MemoryDump::compress`)
+    pub fn from_snapshot<T: AsRef<Path>>(
+        snapshot_info: T,
+        memory_dump: T,
+        memory_size: usize,
+    ) -> Result<Vm> {
+        // Get the snapshot information
+        let info = SnapshotInfo::from_file(snapshot_info)?;
+
+        Vm::from_snapshot_info(&info, memory_dump, memory_size)
+    }
+
+    /// Loads a vm state from an already parsed `SnapshotInfo` and its memory dump
+    pub fn from_snapshot_info<T: AsRef<Path>>(
+        info: &SnapshotInfo,
+        memory_dump: T,
+        memory_size: usize,
+    ) -> Result<Vm> {
+        // Create a new VN instance
+        let mut vm = Vm::new(memory_size)?;
+
+        // Loading the mappings, from a flat or compressed dump
+        let mut dump = MemoryDump::open(memory_dump)?;
+        let mut buf: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+        // Loop through mapping
+        for mapping in info.mappings.iter() {
+            assert!(mapping.start < mapping.end, "mapping.start > mapping.end");
+
+            // Create the mapping
+            let mapping_size = (mapping.end - mapping.start) as usize;
+            vm.mmap(mapping.start, mapping_size, mapping.permissions)?;
+
+            // Only parts of the mapping may be present in the dump, freshly
+            // mapped memory is already zeroed.
+            for part in mapping.backed_parts() {
+                let backed_size = part.end.saturating_sub(part.start) as usize;
+
+                // TODO: Implement more efficient copy to memory
+                // Loop through each page of the part and copy it
+                for off in (0..backed_size).step_by(PAGE_SIZE) {
+                    // Pages truncated by the end of the dump are zero filled
+                    buf.fill(0);
+                    let len = std::cmp::min(PAGE_SIZE, backed_size - off);
+                    dump.read_at(part.physical_offset + off as u64, &mut buf[..len])?;
+                    vm.write(part.start + off as u64, &buf[..len])?;
+                }
+            }
+        }
+
+        // Load the symbols
+        vm.symbols = Symbols::from_snapshot(info);
+
+        // Load all the registers
+        vm.set_regs_snapshot(&info.registers);
+        vm.flush_registers()?;
+
+        // Load the floating point and vector registers
+        if let Some(xsave) = info.registers.xsave.as_deref() {
+            vm.set_xsave(xsave)?;
+        }
+
+        // Load the model specific registers
+        for (&index, &value) in info.registers.msrs.iter() {
+            vm.set_msr(index, value)?;
+        }
+
+        // Load the other threads, scheduled with `Vm::switch_thread`
+        vm.current_thread = info.tid.unwrap_or(0);
+        for thread in info.threads.iter() {
+            vm.add_thread(thread.tid, &thread.registers)?;
+        }
+
+        Ok(vm)
+    }
+
+    /// Loads a vm state from an ELF core dump (e.g. `gcore` output)
+    pub fn from_elf_core<T: AsRef<Path>>(core: T, memory_size: usize) -> Result<Vm> {
+        let info = SnapshotInfo::from_elf_core(core.as_ref())?;
+
+        Vm::from_snapshot_info(&info, core, memory_size)
+    }
+
+    /// Reset the `Vm` state from an other one
+    pub fn reset(&mut self, other: &Vm) {
+        let start = Instant::now();
+        let pages = self.restore_state(other);
+        self.exec_stats.record_reset(pages, start.elapsed());
+    }
+
+    /// Returns the dirty log since the last reset, along with the pages
+    /// written by `Vm::apply_delta` and the frames written by the host when
+    /// changing the mappings. It is kept for the next reset when kvm clears
+    /// it on read.
+    pub(super) fn dirty_log(&mut self) -> Vec<u64> {
+        // Merge the logs of the banks, indexed by guest physical page
+        let pages = self.memory.pmem.end() as usize / PAGE_SIZE;
+        let mut dirty_log = vec![0u64; pages.div_ceil(64)];
+        for (slot, bank) in self.memory.banks().iter().enumerate() {
+            let bitmap = self
+                .kvm_vm
+                .get_dirty_log(slot as u32, bank.size)
+                .expect("Could not get dirty log for current vm");
+            merge_bitmap(
+                &mut dirty_log,
+                bank.guest_address as usize / PAGE_SIZE,
+                &bitmap,
+            );
+        }
+
+        for (entry, pending) in dirty_log.iter_mut().zip(self.pending_dirty_log.iter()) {
+            *entry |= pending;
+        }
+        if self.config.dirty_log == DirtyLogStrategy::GetDirtyLog {
+            self.pending_dirty_log.clone_from(&dirty_log);
+        }
+
+        let written_frames = self.memory.written_frames().iter();
+        for &pa in self.written_pages.iter().chain(written_frames) {
+            let page = pa as usize / PAGE_SIZE;
+            dirty_log[page / 64] |= 1 << (page % 64);
+        }
+
+        dirty_log
+    }
+
+    /// Restores the state of an other vm, returns the number of dirty pages
+    /// restored
+    fn restore_state(&mut self, other: &Vm) -> u64 {
+        // The step over is cancelled, its breakpoint is restored with the memory
+        if self.stepping_over.take().is_some()
+            | self.hw_step_over.take().is_some()
+            | self.access_watch.cancel_step()
+        {
+            self.set_singlestep(false)
+                .expect("Could not disable single step");
+        }
+
+        // A due checkpoint copies the dirty pages before they are restored
+        let mut delta = match self.checkpoints.as_mut() {
+            Some(checkpoints) => checkpoints.begin(&self.registers, self.fs_base, self.gs_base),
+            None => None,
+        };
+
+        // Reset the time spent in the limited ranges
+        self.reset_region_timeouts();
+
+        // Reset the monitored calls
+        self.return_monitor.reset(&other.return_monitor);
+
+        // Draw the same random numbers again
+        self.rng = other.rng.box_clone();
+
+        // Reset the heap and the presence of the pages it changed
+        for page in self.heap.reset(&other.heap) {
+            let present = self.heap.page_present(page);
+
+            // The page may have been unmapped with the page tables restored
+            let _ = self.memory.set_page_present(page, present);
+        }
+
+        // Reset registers and threads
+        self.current_thread = other.current_thread;
+        self.threads.clone_from(&other.threads);
+        self.registers = other.registers;
+        self.special_registers = other.special_registers;
+        self.syscall_segments = other.syscall_segments;
+        self.fs_base = other.fs_base;
+        self.gs_base = other.gs_base;
+
+        // Reset the x87/SSE/AVX state
+        let xsave = other
+            .kvm_vcpu
+            .get_xsave()
+            .expect("Could not get xsave state from source vm");
+        self.kvm_vcpu
+            .set_xsave(&xsave)
+            .expect("Could not reset xsave state");
+
+        // Reset the model specific registers
+        let msrs = other
+            .get_msrs(msr::SNAPSHOT_MSRS)
+            .expect("Could not get msrs from source vm");
+        self.set_msrs(&msrs).expect("Could not reset msrs");
+        self.pin_tsc().expect("Could not pin tsc");
+
+        // Reset memory state
+        // Here we prefer aborting as if you are resetting a vm with a completely different one you
+        // are doing something extremely wrong.
+        assert_eq!(
+            self.memory.banks(),
+            other.memory.banks(),
+            "Vm memory mismatch"
+        );
+
+        // Get the dirty log from kvm
+        self.dirty_stats.resets += 1;
+        let dirty_log = self.dirty_log();
+        self.pending_dirty_log.clear();
+        self.written_pages.clear();
+        self.memory.clear_written_frames();
+
+        // Loop through each dirty page and reset it
+        let mut restored = 0;
+        for (bm_index, bm_entry) in dirty_log.iter().enumerate() {
+            let mut bm = *bm_entry;
+
+            while bm != 0 {
+                // Get next frame dirtied
+                let i = bm.trailing_zeros() as usize;
+                let pa = (bm_index * 64 + i) * PAGE_SIZE;
+                self.dirty_stats.record(pa as u64);
+                restored += 1;
+
+                // Get raw mutable slice to the pmem to restore
+                let mut page_data = self
+                    .memory
+                    .pmem
+                    .raw_slice_mut(pa, PAGE_SIZE)
+                    .expect("Could not restore page in dirty vm");
+
+                if let Some(delta) = delta.as_mut() {
+                    delta.push_page(pa as u64, page_data);
+                }
+
+                // Read original data to the slice
+                other
+                    .memory
+                    .pmem
+                    .read(pa, &mut page_data)
+                    .expect("Could not read physical memory from source vm");
+
+                // Go tp the next bit
+                bm &= bm - 1;
+            }
+        }
+
+        // Restore the frames allocation state along with the page tables
+        self.memory.pmem.restore_allocator(&other.memory.pmem);
+
+        // Restore the guest allocations over the restored page tables
+        self.reset_guest_allocations(other);
+
+        // Restore the additional memory regions (not dirty logged)
+        for (slot, orig) in self.memory_slots.iter_mut().zip(other.memory_slots.iter()) {
+            slot.raw_slice_mut(0, slot.size())
+                .expect("Could not get memory slot")
+                .copy_from_slice(
+                    orig.raw_slice(0, orig.size())
+                        .expect("Could not get memory slot"),
+                );
+        }
+
+        if let (Some(checkpoints), Some(delta)) = (self.checkpoints.as_mut(), delta) {
+            checkpoints.submit(delta);
+        }
+
+        // The dirty log was already cleared by kvm
+        if self.config.dirty_log != DirtyLogStrategy::ManualProtect {
+            return restored;
+        }
+
+        // Clear the dirty log of each bank
+        for (slot, bank) in self.memory.banks().iter().enumerate() {
+            let num_pages = bank.size / PAGE_SIZE;
+            let bitmap = bitmap_range(
+                &dirty_log,
+                bank.guest_address as usize / PAGE_SIZE,
+                num_pages,
+            );
+
+            // Define the dirty log clear structure
+            let clear = kvm_bindings::kvm_clear_dirty_log {
+                slot: slot as u32,
+                num_pages: num_pages as u32,
+                first_page: 0,
+                __bindgen_anon_1: kvm_bindings::kvm_clear_dirty_log__bindgen_ty_1 {
+                    dirty_bitmap: bitmap.as_ptr() as *mut core::ffi::c_void,
+                },
+            };
+
+            let ret = unsafe { ioctl::ioctl_with_ref(&self.kvm_vm, KVM_CLEAR_DIRTY_LOG(), &clear) };
+            if ret != 0 {
+                panic!("Failed to clean dirty log");
+            }
+        }
+
+        restored
+    }
+}
+
+/// Sets in `bitmap` the bits set in `other`, shifted by `first`
+fn merge_bitmap(bitmap: &mut [u64], first: usize, other: &[u64]) {
+    for (index, &entry) in other.iter().enumerate() {
+        let mut entry = entry;
+
+        while entry != 0 {
+            let bit = first + index * 64 + entry.trailing_zeros() as usize;
+            bitmap[bit / 64] |= 1 << (bit % 64);
+            entry &= entry - 1;
+        }
+    }
+}
+
+/// Returns the `count` bits of `bitmap` starting at `first`
+fn bitmap_range(bitmap: &[u64], first: usize, count: usize) -> Vec<u64> {
+    let bits = first..first + count;
+    let mut range = vec![0u64; count.div_ceil(64)];
+
+    for index in bits.start / 64..bits.end.div_ceil(64) {
+        let mut entry = bitmap.get(index).copied().unwrap_or(0);
+
+        while entry != 0 {
+            let bit = index * 64 + entry.trailing_zeros() as usize;
+            if bits.contains(&bit) {
+                range[(bit - first) / 64] |= 1 << ((bit - first) % 64);
+            }
+            entry &= entry - 1;
+        }
+    }
+
+    range
+}
+
+impl Clone for Vm {
+    fn clone(&self) -> Self {
+        let mut vm = self.config.build().expect("Could not create vm for clone");
+
+        // Copy registers
+        vm.registers = self.registers;
+        vm.special_registers = self.special_registers;
+        vm.syscall_segments = self.syscall_segments;
+        vm.fs_base = self.fs_base;
+        vm.gs_base = self.gs_base;
+        vm.current_thread = self.current_thread;
+        vm.threads = self.threads.clone();
+
+        // Copy the cpuid (before the extended states it enables)
+        cpuid::apply_cpuid(&vm.kvm_vcpu, &self.cpuid).expect("Could not set cpuid");
+        vm.cpuid = self.cpuid.clone();
+
+        // Copy the x87/SSE/AVX state
+        let xsave = self
+            .kvm_vcpu
+            .get_xsave()
+            .expect("Could not get original xsave state");
+        vm.kvm_vcpu
+            .set_xsave(&xsave)
+            .expect("Could not set xsave state");
+
+        // Copy the model specific registers
+        let msrs = self
+            .get_msrs(msr::SNAPSHOT_MSRS)
+            .expect("Could not get original msrs");
+        vm.set_msrs(&msrs).expect("Could not set msrs");
+
+        // Copy the determinism mode, the TSC being pinned again
+        if let Some(khz) = self.determinism.as_ref().and_then(|config| config.tsc_khz) {
+            vm.kvm_vcpu
+                .set_tsc_khz(khz)
+                .expect("Could not set tsc frequency");
+        }
+        vm.determinism = self.determinism.clone();
+        vm.pin_tsc().expect("Could not pin tsc");
+
+        // Copy the additional memory regions
+        for (slot, orig) in vm.memory_slots.iter_mut().zip(self.memory_slots.iter()) {
+            slot.raw_slice_mut(0, slot.size())
+                .expect("Could not get memory slot")
+                .copy_from_slice(
+                    orig.raw_slice(0, orig.size())
+                        .expect("Could not get memory slot"),
+                );
+        }
+
+        // Copy symbols and breakpoints (hooks are not cloneable)
+        vm.symbols = self.symbols.clone();
+        vm.crash_hash_config = self.crash_hash_config;
+        vm.breakpoints = self.breakpoints.clone();
+        vm.return_monitor = self.return_monitor.clone();
+        vm.cfi = self.cfi.clone();
+        vm.heap = self.heap.clone();
+        vm.allocator = self.allocator.clone();
+        vm.rng = self.rng.box_clone();
+
+        // Copy the physical reservations (MMIO handlers are not cloneable)
+        vm.reservations = self.reservations.clone();
+        vm.soft_mmu = self.soft_mmu;
+
+        // Copy the hardware breakpoints
+        vm.hw_breakpoints = self.hw_breakpoints;
+        vm.access_watch = self.access_watch.clone();
+        vm.update_guest_debug()
+            .expect("Could not set hardware breakpoints");
+
+        // Copy memory
+        vm.memory.pmem.copy_from(&self.memory.pmem);
+
+        vm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hypercall;
+    use super::msr::{IA32_FS_BASE, IA32_KERNEL_GS_BASE, IA32_LSTAR, IA32_TIME_STAMP_COUNTER};
+    use super::{
+        diff, AccessKind, BranchKind, BreakpointOwner, CfiViolationDetail, CheckpointConfig,
+        CheckpointStats, CpuidEntry, CrashHashConfig, DeltaDump, DeterminismConfig,
+        DirtyLogStrategy, Divergence, DivergenceKind, HeapAllocation, HeapConfig, HeapRuntime,
+        HeapViolation, HookResult, HwBreakpointKind, IntegrityError, InternalStructure, Lockstep,
+        LockstepMode, LockstepResult, MappingDiff, NormalizationKind, PageDiff, PageFaultDetail,
+        PhysicalRegionKind, Quarantine, Register, RegisterDiff, Result, RingBufferSink,
+        SegmentRegister, SoftMmuFault, SplitMix64, TraceRecord, Tracer, VdsoFunction, Vm,
+        VmBuilder, VmError, VmExit, VmRng, WatchAccess,
+    };
+    use crate::memory::{HugePages, MemoryError, PagePermissions, HUGE_PAGE_SIZE, PAGE_SIZE};
+    use crate::snapshot::{MemoryDump, SnapshotInfo, SnapshotMapping, SnapshotRegisters};
+    use crate::testutil::TempPath;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[test]
+    /// Runs a simple piece of code until completion
+    fn test_simple_exec() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Simple shellcode
+        let shellcode: &[u8] = &[
+            0x48, 0x01, 0xc2, // add rdx, rax
+            0xcc, // breakpoint
+        ];
+
+        // Mapping the code
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        // Set registers to known values
+        vm.set_reg(Register::Rax, 0x1000);
+        vm.set_reg(Register::Rdx, 0x337);
+
+        // Execute from beginning of shellcode
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let vmexit = vm.run()?;
+
+        assert_eq!(vmexit, VmExit::Breakpoint);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337003);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests the collection and clearing of dirty pages
+    fn test_dirty_status() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Simple shellcode
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0xcc, // int3
+        ];
+
+        // Mapping the code
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        // Mapping the target page of the write
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        // Set registers to known values
+        vm.set_reg(Register::Rax, 0xdeadbeef);
+        vm.set_reg(Register::Rdx, 0x42424242);
+
+        // Execute from beginning of shellcode
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let vmexit = vm.run()?;
+
+        // Sanity check
+        assert_eq!(vmexit, VmExit::Breakpoint);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337003);
+
+        // Check that the target page was dirtied
+        assert!(vm.dirty_mappings().any(|m| m.address == 0xdeadb000));
+
+        // Reset the pages dirty status
+        vm.clear_dirty_mappings();
+
+        // Check again the dirty pages
+        assert!(vm.dirty_mappings().count() == 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Runs a simple piece of code until completion
+    fn test_simple_syscall() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // The syscall in the shellcode will add rax and rdx together
+        let shellcode: &[u8] = &[
+            0x0f, 0x05, // syscall
+            0xcc, // breakpoint
+        ];
+
+        // Mapping the code
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        // Set registers to known values
+        vm.set_reg(Register::Rax, 0x1000);
+        vm.set_reg(Register::Rdx, 0x337);
+
+        // Execute from beginning of shellcode
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let vmexit = vm.run()?;
+
+        assert_eq!(vmexit, VmExit::Syscall);
+
+        // Emulated syscall doing rax = rax + rdx
+        vm.set_reg(
+            Register::Rax,
+            vm.get_reg(Register::Rax) + vm.get_reg(Register::Rdx),
+        );
+
+        let vmexit_end = vm.run()?;
+
+        assert_eq!(vmexit_end, VmExit::Breakpoint);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337002);
+        assert_eq!(vm.get_reg(Register::Rax), 0x1337);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Runs hooks installed on addresses and symbols
+    fn test_hooks() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Simple shellcode
+        let shellcode: &[u8] = &[
+            0x48, 0x01, 0xc2, // add rdx, rax
+            0xf4, // hlt
+        ];
+
+        // Mapping the code
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        // Hook the first instruction to set rax
+        vm.hook(0x1337000, |vm| {
+            vm.set_reg(Register::Rax, 0x1000);
+            HookResult::Continue
+        })?;
+
+        // Hook the hlt through a symbol
+        vm.symbols.add_module("test.so", 0x1337000, 0x1338000);
+        vm.symbols.add_symbol("test.so", "end", 0x1337003)?;
+        assert_eq!(
+            vm.hook_symbol("test", "end", |_| HookResult::Exit)?,
+            0x1337003
+        );
+        assert!(vm
+            .hook_symbol("test.so", "missing", |_| HookResult::Exit)
+            .is_err());
+
+        let mut byte = [0u8; 1];
+        vm.read(0x1337000, &mut byte)?;
+        assert_eq!(byte[0], 0xcc);
+
+        // Simulate reaching the first breakpoint
+        vm.set_reg(Register::Rdx, 0x337);
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.handle_debug_exit(3)?, None);
+        assert_eq!(vm.get_reg(Register::Rax), 0x1000);
+
+        // The original instruction is restored to be stepped over
+        vm.read(0x1337000, &mut byte)?;
+        assert_eq!(byte[0], 0x48);
+
+        // The add is executed then the breakpoint is re-armed before the hlt
+        vm.unhook(0x1337003)?;
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rdx), 0x1337);
+
+        vm.read(0x1337000, &mut byte)?;
+        assert_eq!(byte[0], 0xcc);
+
+        // Hooks stopping the execution
+        vm.hook(0x1337003, |_| HookResult::Crash)?;
+        vm.set_reg(Register::Rip, 0x1337003);
+        assert_eq!(vm.handle_debug_exit(3)?, Some(VmExit::HookCrash));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Sets vector registers and keeps them across clones and resets
+    fn test_vector_registers() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Simple shellcode
+        let shellcode: &[u8] = &[
+            0x66, 0x0f, 0x6f, 0xc3, // movdqa xmm0, xmm3
+            0xf4, // hlt
+        ];
+
+        // Mapping the code
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let xmm = 0x0011_2233_4455_6677_8899_aabb_ccdd_eeffu128;
+        let ymm = (0x1337u128, 0xdead_beefu128 << 64);
+        vm.set_xmm(3, xmm)?;
+        vm.set_ymm(4, ymm)?;
+        assert_eq!(vm.get_xmm(3)?, xmm);
+        assert_eq!(vm.get_ymm(4)?, ymm);
+
+        // Only 16 registers are available
+        assert!(vm.set_xmm(16, xmm).is_err());
+        assert!(vm.get_ymm(16).is_err());
+
+        let orig = vm.clone();
+        assert_eq!(orig.get_ymm(4)?, ymm);
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_xmm(0)?, xmm);
+        vm.set_ymm(4, (0, 0))?;
+
+        // Reset the vector registers
+        vm.reset(&orig);
+        assert_eq!(vm.get_xmm(0)?, 0);
+        assert_eq!(vm.get_xmm(3)?, xmm);
+        assert_eq!(vm.get_ymm(4)?, ymm);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Detects the modification of a monitored return address
+    fn test_return_monitor() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Function: nop ; ret
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0x90, 0xc3])?;
+        vm.monitor_returns(0x1337000, &[0x1337001])?;
+
+        // Stack with the return address of the call
+        vm.mmap(
+            0x7ff000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.write_value(0x7ff800, 0x401000u64)?;
+        vm.set_reg(Register::Rsp, 0x7ff800);
+
+        // Simulate reaching the entry then the return
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.handle_debug_exit(3)?, None);
+        vm.set_reg(Register::Rip, 0x1337001);
+        assert_eq!(vm.handle_debug_exit(3)?, None);
+
+        // Same thing with a stack smash in between
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.handle_debug_exit(3)?, None);
+        vm.write_value(0x7ff800, 0x4141414141414141u64)?;
+        vm.set_reg(Register::Rip, 0x1337001);
+        assert_eq!(
+            vm.handle_debug_exit(3)?,
+            Some(VmExit::RetCorruption {
+                expected: 0x401000,
+                found: 0x4141414141414141,
+            })
+        );
+
+        vm.clear_return_monitor()?;
+        let mut code = [0u8; 2];
+        vm.read(0x1337000, &mut code)?;
+        assert_eq!(code, [0x90, 0xc3]);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Reads and writes model specific registers
+    fn test_msrs() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        vm.set_msr(IA32_LSTAR, 0xffff_ffff_8100_0000)?;
+        vm.set_msr(IA32_FS_BASE, 0x1337)?;
+        assert_eq!(vm.get_msr(IA32_LSTAR)?, 0xffff_ffff_8100_0000);
+        assert_eq!(vm.get_reg(Register::FsBase), 0x1337);
+        assert!(vm.get_msr(0xdead_beef).is_err());
+
+        // Msrs are carried by clones and resets
+        let orig = vm.clone();
+        assert_eq!(orig.get_msr(IA32_LSTAR)?, 0xffff_ffff_8100_0000);
+
+        vm.set_msr(IA32_LSTAR, 0)?;
+        vm.set_msr(IA32_KERNEL_GS_BASE, 0x4141)?;
+        vm.reset(&orig);
+        assert_eq!(vm.get_msr(IA32_LSTAR)?, 0xffff_ffff_8100_0000);
+        assert_eq!(vm.get_msr(IA32_KERNEL_GS_BASE)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Checks the targets of indirect calls
+    fn test_cfi() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // call rax ; with two possible targets doing hlt
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0xff, 0xd0])?;
+        vm.write(0x1337100, &[0xf4])?;
+        vm.write(0x1337200, &[0xf4])?;
+
+        vm.mmap(
+            0x7ff000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        vm.add_cfi_site(0x1337000)?;
+        vm.symbols.add_module("test.so", 0x1337000, 0x1338000);
+        vm.symbols.add_symbol("test.so", "allowed", 0x1337100)?;
+        assert_eq!(vm.add_cfi_targets_from_symbols(), 1);
+
+        for &(target, violation) in [(0x1337100u64, false), (0x1337200, true)].iter() {
+            vm.set_reg(Register::Rsp, 0x7ff800);
+            vm.set_reg(Register::Rax, target);
+            vm.set_reg(Register::Rip, 0x1337000);
+
+            // Simulate reaching the breakpoint on the call
+            assert_eq!(vm.handle_debug_exit(3)?, None);
+
+            let expected = match violation {
+                true => VmExit::CfiViolation(CfiViolationDetail {
+                    site: 0x1337000,
+                    target,
+                    kind: BranchKind::Call,
+                }),
+                false => VmExit::Hlt,
+            };
+            assert_eq!(vm.run()?, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Detects heap errors with the emulated glibc allocator
+    fn test_heap() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Allocator entry points
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.symbols.add_module("libc.so.6", 0x1337000, 0x1338000);
+        vm.symbols.add_symbol("libc.so.6", "malloc", 0x1337000)?;
+        vm.symbols.add_symbol("libc.so.6", "free", 0x1337010)?;
+
+        let config = HeapConfig {
+            base: 0x1000_0000,
+            size: 0x10_0000,
+            quarantine: Quarantine::Allocations(1),
+        };
+        assert_eq!(vm.install_heap(HeapRuntime::Glibc, config)?, 2);
+
+        // Callers code
+        let shellcode: &[u8] = &[
+            0xc6, 0x40, 0x1f, 0x01, // mov byte [rax+0x1f], 1
+            0xc6, 0x40, 0x20, 0x01, // mov byte [rax+0x20], 1
+        ];
+        vm.mmap(0x401000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x401000, shellcode)?;
+        vm.write(0x401010, &[0x8a, 0x07])?; // mov al, [rdi]
+
+        vm.mmap(
+            0x7ff000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        // Simulate calling an allocator function
+        let call = |vm: &mut Vm, function: u64, arg: u64, ret: u64| {
+            vm.write_value(0x7ff800, ret)?;
+            vm.set_reg(Register::Rsp, 0x7ff800);
+            vm.set_reg(Register::Rdi, arg);
+            vm.set_reg(Register::Rip, function);
+            vm.handle_debug_exit(3)
+        };
+
+        // The allocation ends right before a guard page
+        assert_eq!(call(&mut vm, 0x1337000, 0x20, 0x401000)?, None);
+        let ptr = vm.get_reg(Register::Rax);
+        assert_eq!(ptr % 0x1000, 0xfe0);
+        assert_eq!(vm.get_reg(Register::Rip), 0x401000);
+        assert_eq!(vm.get_reg(Register::Rsp), 0x7ff808);
+
+        assert_eq!(
+            vm.run()?,
+            VmExit::HeapViolation(HeapViolation::OutOfBounds {
+                address: ptr + 0x20,
+                allocation: ptr,
+                size: 0x20,
+                allocation_site: 0x401000,
+            })
+        );
+
+        // Freed allocations are not accessible anymore
+        assert_eq!(call(&mut vm, 0x1337010, ptr, 0x401010)?, None);
+        assert_eq!(
+            vm.run()?,
+            VmExit::HeapViolation(HeapViolation::UseAfterFree {
+                address: ptr,
+                allocation: ptr,
+                size: 0x20,
+                allocation_site: 0x401000,
+                free_site: 0x401010,
+            })
+        );
+
+        assert_eq!(
+            call(&mut vm, 0x1337010, ptr, 0x401010)?,
+            Some(VmExit::HeapViolation(HeapViolation::DoubleFree {
+                allocation: ptr,
+                allocation_site: 0x401000,
+                free_site: 0x401010,
+            }))
+        );
+        assert_eq!(
+            call(&mut vm, 0x1337010, ptr + 8, 0x401010)?,
+            Some(VmExit::HeapViolation(HeapViolation::InvalidFree {
+                address: ptr + 8
+            }))
+        );
+
+        // Overflows within the alignment padding are caught on free
+        assert_eq!(call(&mut vm, 0x1337000, 0x1c, 0x401000)?, None);
+        let ptr2 = vm.get_reg(Register::Rax);
+        vm.write(ptr2 + 0x1c, &[0x41])?;
+        assert_eq!(
+            call(&mut vm, 0x1337010, ptr2, 0x401010)?,
+            Some(VmExit::HeapViolation(HeapViolation::OutOfBounds {
+                address: ptr2 + 0x1c,
+                allocation: ptr2,
+                size: 0x1c,
+                allocation_site: 0x401000,
+            }))
+        );
+
+        // The first chunk left the quarantine after one allocation
+        assert_eq!(call(&mut vm, 0x1337000, 0x10, 0x401000)?, None);
+        assert_eq!(vm.get_reg(Register::Rax), ptr + 0x10);
+
+        let report = vm.heap_report();
+        assert_eq!((report.allocations, report.frees), (3, 1));
+        assert_eq!((report.live, report.peak), (0x2c, 0x2c));
+        assert_eq!(report.leaks.len(), 2);
+
+        // Leaks are relative to the reset point
+        let snapshot = vm.clone();
+        vm.reset(&snapshot);
+        assert_eq!(call(&mut vm, 0x1337000, 0x100, 0x401020)?, None);
+        let leak = HeapAllocation {
+            address: vm.get_reg(Register::Rax),
+            size: 0x100,
+            site: 0x401020,
+        };
+        assert_eq!(vm.heap_report().leaks, vec![leak]);
+        assert_eq!(vm.heap_allocations().count(), 3);
+
+        vm.reset(&snapshot);
+        let report = vm.heap_report();
+        assert_eq!(
+            (report.allocations, report.live, report.peak),
+            (0, 0x2c, 0x2c)
+        );
+        assert!(report.leaks.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Executes syscall instructions with IA32_EFER.SCE enabled
+    fn test_native_syscall() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x0f, 0x05, // syscall
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.enable_syscalls(None)?;
+
+        // Direction flag set, masked during the syscall
+        vm.set_reg(Register::Rflags, 0x402);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        assert_eq!(vm.run()?, VmExit::Syscall);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337002);
+        assert_eq!(vm.get_reg(Register::Rcx), 0x1337002);
+        assert_eq!(vm.get_reg(Register::R11), 0x402);
+        assert_eq!(vm.get_reg(Register::Rflags), 0x402);
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337003);
+
+        // Back to the invalid opcode detection, registers are preserved
+        vm.disable_syscalls();
+        vm.set_reg(Register::Rcx, 0);
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.run()?, VmExit::Syscall);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337002);
+        assert_eq!(vm.get_reg(Register::Rcx), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Reads and writes control and segment registers
+    fn test_system_registers() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Long mode paging set up by the vm
+        assert_ne!(vm.get_reg(Register::Cr0) & (1 << 31), 0);
+        assert_eq!(vm.get_reg(Register::Cr3), vm.memory.page_directory() as u64);
+        assert_ne!(vm.get_reg(Register::Efer) & (1 << 10), 0);
+
+        let cs = vm.get_segment(SegmentRegister::Cs);
+        assert_eq!(cs.selector, 8);
+        assert!(cs.long_mode && cs.present);
+
+        // The fs base is shared with the register
+        let mut fs = vm.get_segment(SegmentRegister::Fs);
+        fs.base = 0x1337000;
+        vm.set_segment(SegmentRegister::Fs, fs);
+        assert_eq!(vm.get_reg(Register::FsBase), 0x1337000);
+        assert_eq!(vm.get_segment(SegmentRegister::Fs), fs);
+
+        // Run with a cr2 and a data segment changed
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0xf4])?; // hlt
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Cr2, 0xdead000);
+
+        let mut ds = vm.get_segment(SegmentRegister::Ds);
+        ds.selector = 0;
+        vm.set_segment(SegmentRegister::Ds, ds);
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Cr2), 0xdead000);
+        assert_eq!(vm.get_segment(SegmentRegister::Ds).selector, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Overrides the cpuid seen by the guest
+    fn test_cpuid() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x0f, 0xa2, // cpuid
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        // Custom vendor and no XSAVE support
+        let mut leaf1 = *vm.cpuid().iter().find(|e| e.function == 1).unwrap();
+        leaf1.ecx &= !(1 << 26 | 1 << 28);
+        vm.set_cpuid(&[
+            CpuidEntry {
+                function: 0,
+                eax: 0xd,
+                ebx: u32::from_le_bytes(*b"Tart"),
+                edx: u32::from_le_bytes(*b"ifle"),
+                ecx: u32::from_le_bytes(*b"tte!"),
+                ..Default::default()
+            },
+            leaf1,
+        ])?;
+        assert!(vm.cpuid().contains(&leaf1));
+        assert_eq!(vm.get_reg(Register::Cr4) & (1 << 18), 0);
+
+        vm.set_reg(Register::Rax, 0);
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(
+            vm.get_reg(Register::Rbx),
+            u32::from_le_bytes(*b"Tart") as u64
+        );
+        assert_eq!(
+            vm.get_reg(Register::Rcx),
+            u32::from_le_bytes(*b"tte!") as u64
+        );
+
+        // The cpuid follows the clones
+        let mut clone = vm.clone();
+        assert_eq!(clone.cpuid(), vm.cpuid());
+
+        clone.set_reg(Register::Rax, 0);
+        clone.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(clone.run()?, VmExit::Hlt);
+        assert_eq!(
+            clone.get_reg(Register::Rdx),
+            u32::from_le_bytes(*b"ifle") as u64
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Counts the guest exceptions across runs
+    fn test_exception_stats() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x8a, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, // mov al, [0]
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        for _ in 0..2 {
+            vm.set_reg(Register::Rip, 0x1337000);
+            assert!(matches!(vm.run()?, VmExit::PageFault(_)));
+        }
+
+        let stats = vm.exception_stats();
+        assert_eq!(stats.total(), 2);
+        assert_eq!(stats.by_vector.get(&14), Some(&2));
+        assert_eq!(stats.by_rip.get(&0x1337000), Some(&2));
+
+        vm.clear_exception_stats();
+        assert_eq!(vm.exception_stats().total(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Builds a vm with a custom exception region, dirty log and memory slot
+    fn test_builder() -> Result<()> {
+        let mut vm = VmBuilder::new(512 * PAGE_SIZE)
+            .exception_region(0xffff_8000_0000_0000)
+            .dirty_log(DirtyLogStrategy::GetDirtyLog)
+            .memory_slot(0x1_0000_0000, PAGE_SIZE)
+            .build()?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0x8a, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, // mov al, [0]
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.set_reg(Register::Rax, 0xdeadb000);
+        vm.set_reg(Register::Rdx, 0x42424242);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let snapshot = vm.clone();
+
+        // Exceptions are still handled from the relocated region
+        assert!(matches!(vm.run()?, VmExit::PageFault(_)));
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337003);
+        assert_eq!(vm.memory.read_val::<u64>(0xdeadb000)?, 0x42424242);
+
+        // The additional slot is restored along with the memory
+        assert_eq!(vm.memory_slot(0), Some(&[0u8; PAGE_SIZE][..]));
+        vm.memory_slot_mut(0).unwrap()[0] = 0x41;
+        assert!(vm.memory_slot(1).is_none());
+
+        vm.reset(&snapshot);
+        assert_eq!(vm.memory.read_val::<u64>(0xdeadb000)?, 0);
+        assert_eq!(vm.memory_slot(0).unwrap()[0], 0);
+
+        // The default strategy depends on the kernel capabilities
+        assert_eq!(vm.dirty_log_strategy(), DirtyLogStrategy::GetDirtyLog);
+        let vm = Vm::new(512 * PAGE_SIZE)?;
+        assert_ne!(vm.dirty_log_strategy(), DirtyLogStrategy::Auto);
+        assert_eq!(vm.clone().dirty_log_strategy(), vm.dirty_log_strategy());
+
+        // The unaligned regions are refused
+        let unaligned = VmBuilder::new(512 * PAGE_SIZE)
+            .exception_region(0xffff_8000_0000_0800)
+            .build();
+        assert!(matches!(unaligned, Err(VmError::InvalidOperation(_))));
+        let unaligned = VmBuilder::new(512 * PAGE_SIZE)
+            .memory_slot(0x1_0000_0000, 0x800)
+            .build();
+        assert!(matches!(unaligned, Err(VmError::InvalidOperation(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Changes the guest mappings after their creation
+    fn test_munmap_mprotect() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.set_reg(Register::Rax, 0xdeadb000);
+
+        // Write to a read only page (protection violation)
+        vm.mprotect(0xdeadb000, PAGE_SIZE, PagePermissions::READ)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        match vm.run()? {
+            VmExit::PageFault(pf) => assert_eq!((pf.status & 1, pf.address), (1, 0xdeadb000)),
+            exit => panic!("Unexpected exit {:?}", exit),
+        }
+
+        // Write to an unmapped page (page not present)
+        vm.munmap(0xdeadb000, PAGE_SIZE)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        match vm.run()? {
+            VmExit::PageFault(pf) => assert_eq!((pf.status & 1, pf.address), (0, 0xdeadb000)),
+            exit => panic!("Unexpected exit {:?}", exit),
+        }
+        assert!(vm
+            .mprotect(0xdeadb000, PAGE_SIZE, PagePermissions::READ)
+            .is_err());
+
+        // The frame is reused by the next mapping
+        vm.mmap(0xdeadb000, PAGE_SIZE, PagePermissions::WRITE)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Restores the page tables and the frames changed by the host on reset
+    fn test_reset_munmap() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x8a, 0x18, // mov bl, [rax]
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(0xdead000, PAGE_SIZE, PagePermissions::READ)?;
+        vm.write_value::<u8>(0xdead000, 0x41)?;
+        vm.set_reg(Register::Rax, 0xdead000);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let orig = vm.clone();
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rbx) & 0xff, 0x41);
+
+        // The unmapped page comes back with the same frame
+        vm.munmap(0xdead000, PAGE_SIZE)?;
+        vm.reset(&orig);
+        assert_eq!(vm.read_value_checked::<u8>(0xdead000)?, 0x41);
+
+        // The frame is not released to the next mapping
+        vm.mmap(0xbeef000, PAGE_SIZE, PagePermissions::READ)?;
+        assert_eq!(vm.read_value_checked::<u8>(0xdead000)?, 0x41);
+        assert_eq!(vm.read_value_checked::<u8>(0xbeef000)?, 0);
+        assert_eq!(orig.read_value_checked::<u8>(0xdead000)?, 0x41);
+        assert!(orig.read_value_checked::<u8>(0xbeef000).is_err());
+
+        // The mapping is undone by the next reset
+        vm.reset(&orig);
+        assert!(vm.read_value_checked::<u8>(0xbeef000).is_err());
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rbx) & 0xff, 0x41);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Emulates rdrand with the seeded randomness source
+    fn test_rdrand() -> Result<()> {
+        let mut vm = VmBuilder::new(512 * PAGE_SIZE).seed(0x1337).build()?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x0f, 0xc7, 0xf3, // rdrand rbx
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        vm.trap_rdrand()?;
+        let snapshot = vm.clone();
+        let expected = SplitMix64::new(0x1337).next_u64();
+
+        // Nested hypervisors may run rdrand natively despite the cpuid, the
+        // #UD of the trap is then injected
+        let mut probe = vm.clone();
+        assert_eq!(probe.run()?, VmExit::Hlt);
+        let trapped = probe.get_reg(Register::Rbx) == expected;
+        if !trapped {
+            eprintln!("rdrand is not trapped by the hypervisor, injecting the #UD");
+        }
+
+        // The guest instruction is emulated through the #UD dispatch
+        for _ in 0..2 {
+            if !trapped {
+                vm.inject_exception(6, None)?;
+            }
+            assert_eq!(vm.run()?, VmExit::Hlt);
+            assert_eq!(vm.get_reg(Register::Rbx), expected);
+            assert_eq!(vm.get_reg(Register::Rflags) & 1, 1);
+            assert_eq!(vm.get_reg(Register::Rip), 0x1337005);
+
+            vm.reset(&snapshot);
+        }
+
+        // An instruction ending the last mapped page is decoded too
+        vm.write(0x1337ffc, &[0x48, 0x0f, 0xc7, 0xf3])?;
+        vm.set_reg(Register::Rip, 0x1337ffc);
+        assert!(vm.emulate_rdrand(0x2));
+        assert_eq!(vm.get_reg(Register::Rip), 0x1338000);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Finds the first divergence between a vm and a patched copy
+    fn test_lockstep() -> Result<()> {
+        let mut left = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+            0x83, 0xc0, 0x01, // add eax, 1
+            0x89, 0x02, // mov [rdx], eax
+            0xf4, // hlt
+        ];
+
+        left.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        left.write(0x1337000, shellcode)?;
+        left.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        left.set_reg(Register::Rdx, 0xdeadb000);
+        left.set_reg(Register::Rip, 0x1337000);
+
+        // Same execution
+        let mut right = left.clone();
+        let mut lockstep = Lockstep::new(&mut left, &mut right, LockstepMode::Instruction);
+        assert_eq!(lockstep.run(10)?, LockstepResult::Exited(VmExit::Hlt));
+        // The halted state is reported after the hlt was single stepped
+        assert_eq!(lockstep.steps(), 5);
+
+        // Patched addition
+        let mut left = left.clone();
+        left.set_reg(Register::Rip, 0x1337000);
+        let mut right = left.clone();
+        right.write_value::<u8>(0x1337007, 2)?;
+
+        let mut lockstep = Lockstep::new(&mut left, &mut right, LockstepMode::Instruction);
+        assert!(matches!(
+            lockstep.run(10)?,
+            LockstepResult::Diverged(Divergence {
+                step: 2,
+                kind: DivergenceKind::Register {
+                    register: Register::Rax,
+                    left: 2,
+                    right: 3,
+                },
+            })
+        ));
+
+        // Only the memory differs in the page written
+        let mut left = left.clone();
+        left.set_reg(Register::Rip, 0x1337008);
+        let mut right = left.clone();
+        right.write_value::<u8>(0xdeadb100, 0x41)?;
+
+        let mut lockstep = Lockstep::new(&mut left, &mut right, LockstepMode::Exit);
+        lockstep.compare_dirty_pages(true);
+        assert!(matches!(
+            lockstep.run(10)?,
+            LockstepResult::Diverged(Divergence {
+                step: 1,
+                kind: DivergenceKind::Memory {
+                    address: 0xdeadb100,
+                    left: Some(0),
+                    right: Some(0x41),
+                },
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Single steps the vms across a hooked instruction
+    fn test_lockstep_hooks() -> Result<()> {
+        let mut left = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+            0x83, 0xc0, 0x01, // add eax, 1
+            0x83, 0xc0, 0x01, // add eax, 1
+            0xf4, // hlt
+        ];
+
+        left.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        left.write(0x1337000, shellcode)?;
+        left.set_reg(Register::Rip, 0x1337000);
+
+        // The software breakpoints are not reported by every nested
+        // hypervisor
+        let mut probe = left.clone();
+        probe.add_breakpoint(0x1337000)?;
+        if probe.run()? != VmExit::Breakpoint {
+            eprintln!("Software breakpoints not reported, skipping the hooks checks");
+            return Ok(());
+        }
+
+        let hook = |vm: &mut Vm| {
+            let rbx = vm.get_reg(Register::Rbx);
+            vm.set_reg(Register::Rbx, rbx + 1);
+            HookResult::Continue
+        };
+
+        // The hooked instruction is a single step
+        let mut right = left.clone();
+        left.hook(0x1337005, hook)?;
+        right.hook(0x1337005, hook)?;
+        let mut lockstep = Lockstep::new(&mut left, &mut right, LockstepMode::Instruction);
+        assert_eq!(lockstep.run(10)?, LockstepResult::Exited(VmExit::Hlt));
+        assert_eq!(lockstep.steps(), 5);
+        assert_eq!(left.get_reg(Register::Rbx), 1);
+
+        // The hook runs along with its instruction
+        let mut left = Vm::new(512 * PAGE_SIZE)?;
+        left.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        left.write(0x1337000, shellcode)?;
+        left.set_reg(Register::Rip, 0x1337000);
+        let mut right = left.clone();
+        right.hook(0x1337005, hook)?;
+
+        let mut lockstep = Lockstep::new(&mut left, &mut right, LockstepMode::Instruction);
+        assert!(matches!(
+            lockstep.run(10)?,
+            LockstepResult::Diverged(Divergence {
+                step: 2,
+                kind: DivergenceKind::Register {
+                    register: Register::Rbx,
+                    left: 0,
+                    right: 1,
+                },
+            })
+        ));
+        assert_eq!(left.get_reg(Register::Rip), 0x1337008);
+        assert_eq!(right.get_reg(Register::Rip), 0x1337008);
+
+        // A guest breakpoint ends the lockstep run
+        let mut left = Vm::new(512 * PAGE_SIZE)?;
+        left.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        left.write(0x1337000, &[0x90, 0xcc, 0xf4])?;
+        left.set_reg(Register::Rip, 0x1337000);
+        let mut right = left.clone();
+
+        let mut lockstep = Lockstep::new(&mut left, &mut right, LockstepMode::Instruction);
+        assert_eq!(
+            lockstep.run(10)?,
+            LockstepResult::Exited(VmExit::Breakpoint)
+        );
+        assert_eq!(lockstep.steps(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Rewrites the random values of a user-space snapshot
+    fn test_normalize_entropy() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+        let rw = PagePermissions::READ | PagePermissions::WRITE;
+        let canary = 0x1122_3344_5566_7700u64;
+
+        // Thread control block with the stack guard
+        vm.mmap(0x10000, PAGE_SIZE, rw)?;
+        vm.write_value(0x10028, canary)?;
+        vm.set_reg(Register::FsBase, 0x10000);
+
+        // Stack with a saved guard and the auxiliary vector, preceded by a
+        // bogus `AT_RANDOM` entry overflowing the address space
+        vm.mmap(0x7ffe0000, 2 * PAGE_SIZE, rw)?;
+        vm.write_value(0x7ffe0ff8, canary)?;
+        vm.write(0x7ffe1f00, &[0x42; 16])?;
+        let auxv = [6, 0x1000, 25, u64::MAX - 8, 25, 0x7ffe1f00, 0, 0];
+        for (i, &word) in auxv.iter().enumerate() {
+            vm.write_value::<u64>(0x7ffe1e00 + i as u64 * 8, word)?;
+        }
+        vm.set_reg(Register::Rsp, 0x7ffe0f00);
+
+        let mapping = |start, end| SnapshotMapping {
+            start,
+            end,
+            physical_offset: 0,
+            permissions: rw,
+            image: None,
+            file_size: None,
+            data: Vec::new(),
+        };
+        let info = SnapshotInfo {
+            redactions: Vec::new(),
+            mappings: vec![mapping(0x10000, 0x11000), mapping(0x7ffe0000, 0x7ffe2000)],
+            registers: SnapshotRegisters::default(),
+            tid: None,
+            threads: Vec::new(),
+            modules: Default::default(),
+            symbols: Default::default(),
+        };
+
+        let report = vm.normalize_entropy(&info)?;
+        let kinds: Vec<(NormalizationKind, u64)> =
+            report.iter().map(|n| (n.kind, n.address)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (NormalizationKind::AtRandom, 0x7ffe1f00),
+                (NormalizationKind::StackGuard, 0x10028),
+                (NormalizationKind::StackGuardCopy, 0x7ffe0ff8),
+            ]
+        );
+
+        // The guard and its copy still match
+        let guard: u64 = vm.memory.read_val(0x10028)?;
+        assert_ne!(guard, canary);
+        assert_eq!(vm.memory.read_val::<u64>(0x7ffe0ff8)?, guard);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Executes instructions one by one and within a budget
+    fn test_step() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x90, // nop
+            0x48, 0xff, 0xc0, // inc rax
+            0x90, // nop
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        assert_eq!(vm.step()?, VmExit::Breakpoint);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337001);
+
+        assert_eq!(vm.run_steps(2)?, VmExit::Budget);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337005);
+        assert_eq!(vm.get_reg(Register::Rax), 1);
+
+        // The budget is not reached
+        assert_eq!(vm.run_steps(10)?, VmExit::Hlt);
+
+        // The single step is disabled afterwards
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rax), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Emulates the vDSO time functions with a fixed time
+    fn test_intercept_vdso() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        vm.mmap(0x7fff1000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.mmap(
+            0x7ffe0000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.symbols.add_module("[vdso]", 0x7fff1000, 0x7fff2000);
+        vm.symbols
+            .add_symbol("[vdso]", "__vdso_clock_gettime", 0x7fff1100)?;
+        vm.symbols.add_symbol("[vdso]", "time", 0x7fff1200)?;
+
+        let time = Duration::new(1_600_000_000, 1337);
+        assert_eq!(
+            vm.intercept_vdso(time)?,
+            [
+                (VdsoFunction::ClockGettime, 0x7fff1100),
+                (VdsoFunction::Time, 0x7fff1200)
+            ]
+        );
+
+        // Simulate a call to clock_gettime
+        vm.write_value(0x7ffe0ff8, 0x401337u64)?;
+        vm.set_reg(Register::Rsp, 0x7ffe0ff8);
+        vm.set_reg(Register::Rsi, 0x7ffe0100);
+        vm.set_reg(Register::Rip, 0x7fff1100);
+        assert_eq!(vm.handle_debug_exit(3)?, None);
+
+        assert_eq!(vm.get_reg(Register::Rip), 0x401337);
+        assert_eq!(vm.get_reg(Register::Rsp), 0x7ffe1000);
+        assert_eq!(vm.get_reg(Register::Rax), 0);
+        assert_eq!(vm.memory.read_val::<u64>(0x7ffe0100)?, 1_600_000_000);
+        assert_eq!(vm.memory.read_val::<u64>(0x7ffe0108)?, 1337);
+
+        // time writes to a read only page
+        vm.set_reg(Register::Rsp, 0x7ffe0ff8);
+        vm.set_reg(Register::Rdi, 0x7fff1000);
+        vm.set_reg(Register::Rip, 0x7fff1200);
+        assert_eq!(vm.handle_debug_exit(3)?, None);
+        assert_eq!(vm.get_reg(Register::Rax), (-14i64) as u64);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Counts the pages restored by the resets
+    fn test_hot_pages() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE * 2,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        vm.set_reg(Register::Rax, 0xdeadbeef);
+        vm.set_reg(Register::Rip, 0x1337000);
+        let snapshot = vm.clone();
+
+        for _ in 0..2 {
+            assert_eq!(vm.run()?, VmExit::Hlt);
+            vm.reset(&snapshot);
+        }
+
+        assert_eq!(vm.dirty_stats().resets, 2);
+        let page = vm
+            .hot_pages()
+            .into_iter()
+            .find(|p| p.address == Some(0xdeadb000))
+            .expect("Written page not reported");
+        assert_eq!(page.dirty_count, 2);
+        assert_eq!(page.ratio, 1.0);
+
+        let rw = PagePermissions::READ | PagePermissions::WRITE;
+        let mapping = |start, end| SnapshotMapping {
+            start,
+            end,
+            physical_offset: 0,
+            permissions: rw,
+            image: None,
+            file_size: None,
+            data: Vec::new(),
+        };
+        let report = vm.hot_page_report(&[
+            mapping(0x1337000, 0x1338000),
+            mapping(0xdeadb000, 0xdeadd000),
+        ]);
+        assert_eq!(report[0].start, 0xdeadb000);
+        assert_eq!(report[0].ratio, 0.5);
+        assert_eq!(report[0].hot_pages, [page]);
+
+        vm.clear_dirty_stats();
+        assert!(vm.hot_pages().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Stops an infinite loop with a timeout and from another thread
+    fn test_run_with_timeout() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0xeb, 0xfe, // jmp $
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        assert_eq!(
+            vm.run_with_timeout(Duration::from_millis(20))?,
+            VmExit::Timeout
+        );
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337000);
+
+        // Interruption from another thread
+        let handle = vm.interrupt_handle();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.interrupt();
+        });
+        assert_eq!(vm.run()?, VmExit::Interrupted);
+        thread.join().unwrap();
+
+        // Interruption requested before the run
+        vm.interrupt_handle().interrupt();
+        assert_eq!(vm.run()?, VmExit::Interrupted);
+
+        // No timeout left armed
+        vm.set_reg(Register::Rip, 0x1337002);
+        assert_eq!(vm.run_with_timeout(Duration::from_secs(1))?, VmExit::Hlt);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Injects exceptions and interrupts in the guest
+    fn test_inject_exception() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0xf4])?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        // The exception is raised before the hlt
+        vm.inject_exception(6, None)?;
+        assert_eq!(vm.run()?, VmExit::InvalidInstruction);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337000);
+
+        vm.set_reg(Register::Cr2, 0xdeadbeef);
+        vm.inject_exception(14, Some(6))?;
+        assert_eq!(
+            vm.run()?,
+            VmExit::PageFault(PageFaultDetail {
+                status: 6,
+                address: 0xdeadbeef,
+            })
+        );
+
+        assert!(vm.inject_exception(14, None).is_err());
+        assert!(vm.inject_exception(6, Some(0)).is_err());
+        assert!(vm.inject_exception(32, None).is_err());
+
+        // No IDT entry for the interrupt: #GP with the IDT entry as error code
+        vm.inject_interrupt(0x80)?;
+        assert_eq!(vm.run()?, VmExit::Exception(13));
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Emulates a MMIO region with handlers
+    fn test_mmio() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x8b, 0x03, // mov eax, [rbx]
+            0x89, 0x4b, 0x08, // mov [rbx+8], ecx
+            0x8b, 0x02, // mov eax, [rdx]
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        let rw = PagePermissions::READ | PagePermissions::WRITE;
+        vm.map_physical(0x40000000, 0x100000000, PAGE_SIZE * 2, rw)?;
+        assert!(vm.map_physical(0x50000000, 0, PAGE_SIZE, rw).is_err());
+
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let log = writes.clone();
+        vm.register_mmio(
+            0x100000000,
+            PAGE_SIZE as u64,
+            |_, offset, data| {
+                data.copy_from_slice(&(0x41424300 + offset as u32).to_le_bytes());
+                HookResult::Continue
+            },
+            move |_, offset, data| {
+                log.borrow_mut().push((offset, data.to_vec()));
+                HookResult::Exit
+            },
+        )?;
+        assert!(vm
+            .register_mmio(
+                0x100000800,
+                8,
+                |_, _, _| HookResult::Continue,
+                |_, _, _| { HookResult::Continue }
+            )
+            .is_err());
+
+        vm.set_reg(Register::Rbx, 0x40000000);
+        vm.set_reg(Register::Rcx, 0x1337);
+        vm.set_reg(Register::Rdx, 0x40001004);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        // The write handler stops the execution
+        assert_eq!(vm.run()?, VmExit::HookExit);
+        assert_eq!(vm.get_reg(Register::Rax), 0x41424300);
+        assert_eq!(*writes.borrow(), [(8, vec![0x37, 0x13, 0, 0])]);
+
+        assert_eq!(
+            vm.run()?,
+            VmExit::MmioFault {
+                address: 0x100001004,
+                write: false
+            }
+        );
+        assert_eq!(vm.run()?, VmExit::Hlt);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Writes delta dumps of the dirty pages from the resets
+    fn test_checkpoints() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        vm.set_reg(Register::Rax, 0xdeadb008);
+        vm.set_reg(Register::Rdx, 0x4142434445464748);
+        vm.set_reg(Register::Rip, 0x1337000);
+        let snapshot = vm.clone();
+
+        let directory = TempPath::new("checkpoints");
+        let config = CheckpointConfig {
+            directory: directory.to_path_buf(),
+            interval: Duration::from_secs(3600),
+        };
+        vm.enable_checkpoints(CheckpointConfig {
+            interval: Duration::ZERO,
+            ..config.clone()
+        })?;
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        vm.reset(&snapshot);
+        assert_eq!(
+            vm.disable_checkpoints()?,
+            CheckpointStats {
+                written: 1,
+                skipped: 0
+            }
+        );
+
+        // Not due yet
+        vm.enable_checkpoints(config.clone())?;
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        vm.reset(&snapshot);
+        assert_eq!(vm.disable_checkpoints()?, CheckpointStats::default());
+        assert!(!config.delta_path(1).exists());
+
+        let delta = DeltaDump::from_file(config.delta_path(0))?;
+
+        // The pages count must match the size of the dump
+        let mut forged = std::fs::read(config.delta_path(0))?;
+        forged[16..24].copy_from_slice(&(u64::MAX / 8).to_le_bytes());
+        std::fs::write(config.delta_path(1), &forged)?;
+        assert!(DeltaDump::from_file(config.delta_path(1)).is_err());
+
+        assert_eq!(delta.index, 0);
+        assert_eq!(delta.registers.rip, 0x1337004);
+        assert_eq!(delta.registers.rdx, 0x4142434445464748);
+
+        let (pa, _) = vm.translate(0xdeadb000).expect("Page not mapped");
+        let page = delta.pages.get(&pa).expect("Dirty page not dumped");
+        assert_eq!(page[8..16], 0x4142434445464748u64.to_le_bytes());
+
+        // The vm memory is still restored
+        assert_eq!(vm.read_value_checked::<u64>(0xdeadb008)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Emulates IO ports and serves the hypercalls
+    fn test_port_io() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, PRINT
+            0x48, 0xbf, 0x00, 0xb0, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00, // mov rdi, 0xdeadb000
+            0xbe, 0x05, 0x00, 0x00, 0x00, // mov esi, 5
+            0xe5, 0x7f, // in eax, HYPERCALL_PORT
+            0x89, 0xc3, // mov ebx, eax
+            0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, REQUEST_INPUT
+            0x48, 0xbf, 0x00, 0xb1, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00, // mov rdi, 0xdeadb100
+            0xbe, 0x10, 0x00, 0x00, 0x00, // mov esi, 16
+            0xe5, 0x7f, // in eax, HYPERCALL_PORT
+            0x89, 0xc1, // mov ecx, eax
+            0xb0, 0x42, // mov al, 0x42
+            0xe6, 0x80, // out 0x80, al
+            0xe4, 0x60, // in al, 0x60
+            0x0f, 0xb6, 0xd0, // movzx edx, al
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, EXIT
+            0xbf, 0x07, 0x00, 0x00, 0x00, // mov edi, 7
+            0xe5, 0x7f, // in eax, HYPERCALL_PORT
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.write(0xdeadb000, b"hello")?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        vm.set_guest_input(b"input");
+        vm.register_port(
+            0x60,
+            |_, _, data| {
+                data[0] = 0x99;
+                HookResult::Continue
+            },
+            |_, _, _| HookResult::Crash,
+        )?;
+        assert!(vm
+            .register_port(
+                hypercall::HYPERCALL_PORT,
+                |_, _, _| HookResult::Continue,
+                |_, _, _| { HookResult::Continue }
+            )
+            .is_err());
+
+        assert_eq!(
+            vm.run()?,
+            VmExit::Io {
+                port: 0x80,
+                data: 0x42,
+                write: true
+            }
+        );
+        assert_eq!(vm.get_reg(Register::Rbx), 5);
+        assert_eq!(vm.get_reg(Register::Rcx), 5);
+        assert_eq!(vm.take_guest_output(), b"hello");
+
+        let mut input = [0u8; 5];
+        vm.read(0xdeadb100, &mut input)?;
+        assert_eq!(&input, b"input");
+
+        assert_eq!(vm.run()?, VmExit::GuestExit(7));
+        assert_eq!(vm.get_reg(Register::Rdx), 0x99);
+        assert_eq!(vm.get_reg(Register::Rax), 0);
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert!(vm.guest_output().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Stops the execution staying too long inside a limited range
+    fn test_region_timeout() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0xeb, 0xfe, // jmp $
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        let snapshot = vm.clone();
+
+        // The loop is outside of the limited range, the kicks are transparent
+        vm.add_region_timeout(0x2000000, 0x2001000, Duration::from_millis(10))?;
+        assert_eq!(
+            vm.run_with_timeout(Duration::from_millis(50))?,
+            VmExit::Timeout
+        );
+        assert_eq!(vm.region_time(0x2000000), Some(Duration::ZERO));
+        vm.remove_region_timeout(0x2000000);
+
+        assert!(vm
+            .add_region_timeout(0x1337000, 0x1337000, Duration::from_millis(10))
+            .is_err());
+        vm.add_region_timeout(0x1337000, 0x1338000, Duration::from_millis(30))?;
+        assert!(vm
+            .add_region_timeout(0x1337800, 0x1339000, Duration::from_millis(10))
+            .is_err());
+
+        let start = std::time::Instant::now();
+        assert_eq!(
+            vm.run()?,
+            VmExit::RegionTimeout {
+                start: 0x1337000,
+                end: 0x1338000
+            }
+        );
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert!(vm.region_time(0x1337000).unwrap() > Duration::from_millis(30));
+
+        vm.reset(&snapshot);
+        assert_eq!(vm.region_time(0x1337000), Some(Duration::ZERO));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Schedules the snapshot threads on the vcpu
+    fn test_switch_thread() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0xf4, 0xf4, 0xf4])?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rax, 1);
+        vm.set_xmm(0, 0x41)?;
+
+        let info = r#"{
+            "mappings": [],
+            "registers": {
+                "rax": "1", "rbx": "0", "rcx": "0", "rdx": "0", "rsi": "0", "rdi": "0",
+                "rsp": "0", "rbp": "0", "r8": "0", "r9": "0", "r10": "0", "r11": "0",
+                "r12": "0", "r13": "0", "r14": "0", "r15": "0", "rip": "1337000",
+                "rflags": "2", "fs_base": "0", "gs_base": "0"
+            },
+            "tid": "64",
+            "threads": [{"tid": "65", "registers": {
+                "rax": "2", "rbx": "0", "rcx": "0", "rdx": "0", "rsi": "0", "rdi": "0",
+                "rsp": "0", "rbp": "0", "r8": "0", "r9": "0", "r10": "0", "r11": "0",
+                "r12": "0", "r13": "0", "r14": "0", "r15": "0", "rip": "1337001",
+                "rflags": "2", "fs_base": "2000", "gs_base": "0"
+            }}]
+        }"#;
+        let info = SnapshotInfo::from_string(info)?;
+        let info = SnapshotInfo::from_string(info.to_json())?;
+        assert_eq!(info.tid, Some(100));
+
+        vm.current_thread = 100;
+        for thread in info.threads.iter() {
+            vm.add_thread(thread.tid, &thread.registers)?;
+        }
+        assert!(vm.add_thread(100, &info.registers).is_err());
+        assert_eq!(vm.thread_ids(), [100, 101]);
+        assert!(vm.switch_thread(5).is_err());
+
+        // The extended state is kept for the threads without one
+        assert_eq!(vm.schedule_next_thread()?, 101);
+        assert_eq!(vm.get_reg(Register::Rax), 2);
+        assert_eq!(vm.get_reg(Register::FsBase), 0x2000);
+        assert_eq!(vm.get_xmm(0)?, 0x41);
+        vm.set_xmm(0, 0x42)?;
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337002);
+
+        assert_eq!(vm.schedule_next_thread()?, 100);
+        assert_eq!(vm.current_thread(), 100);
+        assert_eq!(vm.get_reg(Register::Rax), 1);
+        assert_eq!(vm.get_reg(Register::FsBase), 0);
+        assert_eq!(vm.get_xmm(0)?, 0x41);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337001);
+
+        vm.switch_thread(101)?;
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337002);
+        assert_eq!(vm.get_xmm(0)?, 0x42);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Runs the hooks of an address by priority
+    fn test_hook_priority() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0x90, 0xf4])?;
+
+        // The hooks run by decreasing priority, then installation order
+        vm.hook(0x1337000, |vm| {
+            let rax = vm.get_reg(Register::Rax);
+            vm.set_reg(Register::Rax, rax * 10 + 2);
+            HookResult::Continue
+        })?;
+        let first = vm.hook_with_priority(0x1337000, 10, |vm| {
+            let rax = vm.get_reg(Register::Rax);
+            vm.set_reg(Register::Rax, rax * 10 + 1);
+            HookResult::Continue
+        })?;
+        let last = vm.hook_with_priority(0x1337000, -5, |_| HookResult::Exit)?;
+        vm.hook_with_priority(0x1337000, -5, |vm| {
+            vm.set_reg(Register::Rax, 0);
+            HookResult::Continue
+        })?;
+
+        let owners: Vec<i32> = vm
+            .registered_at(0x1337000)
+            .into_iter()
+            .map(|owner| match owner {
+                BreakpointOwner::Hook { priority, .. } => priority,
+                _ => panic!("unexpected owner {:?}", owner),
+            })
+            .collect();
+        assert_eq!(owners, vec![10, 0, -5, -5]);
+
+        // The exit ends the chain
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.handle_debug_exit(3)?, Some(VmExit::HookExit));
+        assert_eq!(vm.get_reg(Register::Rax), 12);
+
+        // Once removed the next hooks run, and the breakpoint is kept
+        vm.remove_hook(last)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.handle_debug_exit(3)?, None);
+        assert_eq!(vm.get_reg(Register::Rax), 0);
+        assert_eq!(vm.registered_at(0x1337000).len(), 3);
+
+        // The breakpoint goes with the last hook
+        vm.unhook(0x1337000)?;
+        assert!(vm.registered_at(0x1337000).is_empty());
+        assert!(vm.remove_hook(first).is_ok());
+
+        // Plain breakpoints stop the execution
+        vm.add_breakpoint(0x1337001)?;
+        assert_eq!(
+            vm.registered_at(0x1337001),
+            vec![BreakpointOwner::Breakpoint]
+        );
+        vm.set_reg(Register::Rip, 0x1337001);
+        assert_eq!(vm.handle_debug_exit(3)?, Some(VmExit::Breakpoint));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Lists the physical regions and reserves physical windows
+    fn test_physical_layout() -> Result<()> {
+        let mut vm = VmBuilder::new(512 * PAGE_SIZE)
+            .memory_slot(0x1_0000_0000, 2 * PAGE_SIZE)
+            .build()?;
+
+        // Reserve a device window then place a MMIO region in it
+        vm.reserve_physical(0x2_0000_0000, 0x10000, "device")?;
+        vm.register_mmio(
+            0x2_0000_1000,
+            0x100,
+            |_, _, _| HookResult::Continue,
+            |_, _, _| HookResult::Continue,
+        )?;
+
+        let layout = vm.physical_layout();
+        let kinds: Vec<(u64, PhysicalRegionKind)> = layout
+            .iter()
+            .map(|region| (region.start, region.kind.clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (0, PhysicalRegionKind::Memory),
+                (0x1_0000_0000, PhysicalRegionKind::MemorySlot(0)),
+                (0x2_0000_0000, PhysicalRegionKind::Reserved("device".into())),
+                (0x2_0000_1000, PhysicalRegionKind::Mmio),
+            ]
+        );
+        assert_eq!(layout[0].size, (512 * PAGE_SIZE) as u64);
+        assert_eq!(
+            vm.physical_region(0x2_0000_1010).map(|region| region.kind),
+            Some(PhysicalRegionKind::Mmio)
+        );
+        assert!(vm.physical_region(0x3_0000_0000).is_none());
+
+        // Overlaps are rejected
+        assert!(vm
+            .reserve_physical(0x1000, PAGE_SIZE as u64, "memory")
+            .is_err());
+        assert!(vm.reserve_physical(0x1_0000_1000, 0x2000, "slot").is_err());
+        assert!(vm
+            .reserve_physical(0x2_0000_8000, 0x10000, "device")
+            .is_err());
+        assert!(vm
+            .reserve_physical(0x3_0000_0800, PAGE_SIZE as u64, "unaligned")
+            .is_err());
+        assert!(vm
+            .register_mmio(
+                0x1_0000_1000,
+                0x10,
+                |_, _, _| HookResult::Continue,
+                |_, _, _| { HookResult::Continue }
+            )
+            .is_err());
+        assert!(vm
+            .register_mmio(
+                0x2_0000_f000,
+                0x2000,
+                |_, _, _| HookResult::Continue,
+                |_, _, _| { HookResult::Continue }
+            )
+            .is_err());
+
+        // Free windows skip the used ranges
+        assert_eq!(
+            vm.find_free_physical(0x1000, 0),
+            Some(512 * PAGE_SIZE as u64)
+        );
+        assert_eq!(
+            vm.find_free_physical(0x1_0000_0000, 0x1_0000_0000),
+            Some(0x3_0000_0000)
+        );
+
+        vm.release_physical(0x2_0000_0000);
+        assert_eq!(vm.physical_layout().len(), 3);
+
+        // The frames backing the mappings
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::READ)?;
+        let (paddr, _) = vm.memory.translate(0x1337000).unwrap();
+        assert_eq!(vm.physical_mappings().get(&paddr), Some(&vec![0x1337000]));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Stops on the hardware breakpoints and data watchpoints
+    fn test_hw_breakpoints() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x89, 0x04, 0x25, 0x00, 0x80, 0x33, 0x01, // mov [0x1338000], eax
+            0x8b, 0x1c, 0x25, 0x04, 0x80, 0x33, 0x01, // mov ebx, [0x1338004]
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x1338000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        // Invalid conditions and alignments
+        assert!(vm
+            .set_hw_breakpoint(0x1338000, HwBreakpointKind::Write(3))
+            .is_err());
+        assert!(vm
+            .set_hw_breakpoint(0x1338002, HwBreakpointKind::Write(4))
+            .is_err());
+
+        vm.set_hw_breakpoint(0x1338000, HwBreakpointKind::Write(4))?;
+        vm.set_hw_breakpoint(0x1338004, HwBreakpointKind::ReadWrite(8))
+            .expect_err("unaligned watchpoint");
+        vm.set_hw_breakpoint(0x1338008, HwBreakpointKind::ReadWrite(8))?;
+        vm.set_hw_breakpoint(0x1338004, HwBreakpointKind::ReadWrite(4))?;
+        vm.set_hw_breakpoint(0x133700e, HwBreakpointKind::Execute)?;
+        assert!(vm
+            .set_hw_breakpoint(0x1338010, HwBreakpointKind::Write(1))
+            .is_err());
+        vm.remove_hw_breakpoint(0x1338008)?;
+        assert_eq!(vm.hw_breakpoints().count(), 3);
+
+        // The instruction breakpoints are reported before the execution
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rax, 0x41414141);
+        assert_eq!(
+            vm.run()?,
+            VmExit::Watchpoint {
+                address: 0x133700e,
+                access: WatchAccess::Execute
+            }
+        );
+        assert_eq!(vm.get_reg(Register::Rip), 0x133700e);
+        assert_eq!(vm.read_value_checked::<u32>(0x1338000)?, 0x41414141);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+
+        // Simulate the data watchpoints hits (DR6.B0 and DR6.B2)
+        assert_eq!(
+            vm.handle_hw_breakpoint(0xffff0ff1)?,
+            Some(Some(VmExit::Watchpoint {
+                address: 0x1338000,
+                access: WatchAccess::Write
+            }))
+        );
+        assert_eq!(
+            vm.handle_hw_breakpoint(0xffff0ff4)?,
+            Some(Some(VmExit::Watchpoint {
+                address: 0x1338004,
+                access: WatchAccess::ReadWrite
+            }))
+        );
+
+        // Other debug exceptions go to the hooks
+        assert_eq!(vm.handle_hw_breakpoint(0xffff0ff2)?, None);
+        assert_eq!(vm.handle_hw_breakpoint(0xffff4ff0)?, None);
+
+        // Simulate the watchpoint hit by a hooked indirect branch being
+        // stepped over (DR6.BS and DR6.B0), its violation is reported first
+        vm.add_cfi_site(0x1337000)?;
+        vm.set_reg(Register::Rip, 0x1337007);
+        vm.stepping_over = Some(0x1337000);
+        assert_eq!(
+            vm.handle_hw_breakpoint(0xffff4ff1)?,
+            Some(Some(VmExit::CfiViolation(CfiViolationDetail {
+                site: 0x1337000,
+                target: 0x1337007,
+                kind: BranchKind::Unknown,
+            })))
+        );
+
+        vm.add_cfi_targets([0x1337007]);
+        vm.stepping_over = Some(0x1337000);
+        assert_eq!(
+            vm.handle_hw_breakpoint(0xffff4ff1)?,
+            Some(Some(VmExit::Watchpoint {
+                address: 0x1338000,
+                access: WatchAccess::Write
+            }))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Traces the executed instructions into a ring buffer sink
+    fn test_tracer() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0xff, 0xc0, // inc rax
+            0x48, 0xff, 0xc0, // inc rax
+            0x48, 0xff, 0xc0, // inc rax
+            0x90, // nop
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        // Keep the last 3 instructions with their registers
+        let sink = RingBufferSink::new(3);
+        let mut tracer = Tracer::new(sink.clone());
+        tracer.registers(true);
+        vm.enable_tracing(tracer);
+
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rax, 0);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rax), 3);
+        assert_eq!(vm.tracer().map(|tracer| tracer.count()), Some(5));
+
+        let records = sink.records();
+        let rips: Vec<u64> = records.iter().map(|record| record.rip).collect();
+        assert_eq!(rips, vec![0x1337006, 0x1337009, 0x133700a]);
+        assert_eq!(records[0].registers.map(|regs| regs.rax), Some(2));
+
+        // Filter the traced addresses, records given to a closure
+        let rips = Rc::new(RefCell::new(Vec::new()));
+        let recorded = rips.clone();
+        let mut tracer = Tracer::new(move |record: &TraceRecord| {
+            recorded.borrow_mut().push(record.rip);
+            assert!(record.registers.is_none());
+        });
+        tracer.range(0x1337003, 0x1337009);
+        vm.enable_tracing(tracer);
+
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(*rips.borrow(), vec![0x1337003, 0x1337006]);
+
+        assert_eq!(vm.disable_tracing()?.map(|tracer| tracer.count()), Some(2));
+        assert!(vm.tracer().is_none());
+
+        // The code outside the traced ranges runs without single steps
+        let shellcode: &[u8] = &[
+            0xb9, 0x64, 0x00, 0x00, 0x00, // mov ecx, 100
+            0xff, 0xc9, // dec ecx
+            0x75, 0xfc, // jnz -4
+            0xe9, 0xf2, 0xdf, 0xff, 0xff, // jmp 0x1337000
+        ];
+        vm.mmap(0x1339000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1339000, shellcode)?;
+
+        let sink = RingBufferSink::new(16);
+        let mut tracer = Tracer::new(sink.clone());
+        tracer.range(0x1337000, 0x1338000);
+        vm.enable_tracing(tracer);
+
+        let runs = vm.exec_stats().runs;
+        vm.set_reg(Register::Rip, 0x1339000);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert!(vm.exec_stats().runs - runs < 10);
+        assert_eq!(vm.get_reg(Register::Rcx), 0);
+
+        let rips: Vec<u64> = sink.records().iter().map(|record| record.rip).collect();
+        assert_eq!(
+            rips,
+            vec![0x1337000, 0x1337003, 0x1337006, 0x1337009, 0x133700a]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Checks the permissions of the memory API accesses with the soft-MMU
+    fn test_soft_mmu() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        vm.mmap(
+            0x1337000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.mmap(0x1338000, PAGE_SIZE, PagePermissions::READ)?;
+        vm.write(0x1337ffc, b"abcd")?;
+        vm.write(0x1338000, b"efgh\0")?;
+
+        // Without the soft-MMU the permissions are ignored
+        vm.write(0x1338000, b"E")?;
+        assert!(!vm.soft_mmu());
+
+        vm.set_soft_mmu(true);
+
+        // Writes overflowing on a read only page are refused as a whole
+        assert_eq!(
+            vm.write(0x1337ffe, b"XXXX"),
+            Err(VmError::MemoryError(MemoryError::AccessViolation(
+                0x1338000
+            )))
+        );
+        let mut data = [0u8; 8];
+        vm.read(0x1337ffc, &mut data)?;
+        assert_eq!(&data, b"abcdEfgh");
+
+        // Unmapped accesses
+        assert!(vm.write_value(0x1339000, 0u64).is_err());
+        assert!(vm.read(0x1336fff, &mut data[..2]).is_err());
+        assert_eq!(vm.read_cstring(0x1337ffc, 16)?, b"abcdEfgh");
+
+        let faults = vm.take_soft_mmu_faults();
+        assert_eq!(faults.len(), 3);
+        assert_eq!(
+            faults[0],
+            SoftMmuFault {
+                address: 0x1337ffe,
+                size: 4,
+                write: true,
+                error: MemoryError::AccessViolation(0x1338000),
+            }
+        );
+        assert_eq!(faults[1].error, MemoryError::AddressUnmapped(0x1339000));
+        assert_eq!(faults[2].error, MemoryError::AddressUnmapped(0x1336fff));
+        assert!(vm.soft_mmu_faults().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Counts the runs, exits and resets
+    fn test_exec_stats() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x04, 0x25, 0x00, 0x80, 0x33, 0x01, // mov [0x1338000], rax
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x1338000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let orig = vm.clone();
+        for _ in 0..2 {
+            assert_eq!(vm.run()?, VmExit::Hlt);
+            vm.reset(&orig);
+        }
+
+        let stats = vm.exec_stats();
+        assert_eq!(stats.runs, 2);
+        assert_eq!(stats.kvm_exits.get("hlt"), Some(&2));
+        assert_eq!(stats.total_exits(), 2);
+        assert!(stats.kvm_time <= stats.run_time);
+        assert_eq!(stats.host_time(), stats.run_time - stats.kvm_time);
+        assert_eq!(stats.resets, 2);
+        assert!(stats.last_dirty_pages >= 1);
+        assert!(stats.dirty_pages_per_reset() >= 1.0);
+
+        vm.clear_exec_stats();
+        assert_eq!(vm.exec_stats().runs, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Checks the integrity of the internal structures
+    fn test_internal_state() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x90, // nop
+            0x8b, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, // mov eax, [0] -> page fault
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.enable_syscalls(None)?;
+        vm.add_breakpoint(0x1337000)?;
+        vm.set_hw_breakpoint(0x1337001, HwBreakpointKind::Execute)?;
+        vm.verify_internal_state()?;
+
+        // Clones and resets keep the structures intact, the exception handlers
+        // only using their stack
+        let orig = vm.clone();
+        orig.verify_internal_state()?;
+        vm.remove_breakpoint(0x1337000)?;
+        vm.remove_hw_breakpoint(0x1337001)?;
+        assert!(matches!(vm.run()?, VmExit::PageFault(_)));
+        vm.verify_internal_state()?;
+        vm.reset(&orig);
+        vm.add_breakpoint(0x1337000)?;
+        vm.verify_internal_state()?;
+
+        let integrity_error = |vm: &Vm| match vm.verify_internal_state() {
+            Err(VmError::IntegrityError(err)) => Some(err),
+            _ => None,
+        };
+
+        // Corrupted IDT entry
+        let idt = vm.config.exception_region;
+        let entry: u64 = vm.memory.read_val(idt + 16)?;
+        vm.memory.write_val(idt + 16, entry ^ 0xff00)?;
+        assert_eq!(
+            integrity_error(&vm),
+            Some(IntegrityError::Corrupted {
+                structure: InternalStructure::Idt,
+                address: idt + 17,
+            })
+        );
+        vm.memory.write_val(idt + 16, entry)?;
+
+        // Exception stack unmapped, its content is not checked
+        let stack = idt + 4 * PAGE_SIZE as u64;
+        vm.memory.write_val(stack, 0x41414141u64)?;
+        vm.verify_internal_state()?;
+        vm.mprotect(stack, PAGE_SIZE, PagePermissions::READ)?;
+        assert_eq!(
+            integrity_error(&vm),
+            Some(IntegrityError::Unmapped {
+                structure: InternalStructure::Stack,
+                address: stack,
+            })
+        );
+        vm.mprotect(
+            stack,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        // Syscall stub
+        let stub = vm.hypercall_page + super::syscall::SYSCALL_STUB_OFFSET;
+        vm.memory.write_val(stub, 0x90u8)?;
+        assert_eq!(
+            integrity_error(&vm),
+            Some(IntegrityError::Corrupted {
+                structure: InternalStructure::SyscallStub,
+                address: stub,
+            })
+        );
+        vm.memory.write_val(stub, 0xf4u8)?;
+
+        // GDTR no longer referencing the GDT
+        vm.special_registers.gdt.limit = 0xffff;
+        assert_eq!(
+            integrity_error(&vm),
+            Some(IntegrityError::DescriptorRegister(InternalStructure::Gdt))
+        );
+        vm.special_registers.gdt = orig.special_registers.gdt;
+
+        // Overwritten software breakpoint
+        vm.write(0x1337000, &[0x90])?;
+        assert_eq!(
+            integrity_error(&vm),
+            Some(IntegrityError::Breakpoint(0x1337000))
+        );
+        vm.write(0x1337000, &[0xcc])?;
+
+        // Hooks left without their breakpoint
+        vm.hook(0x1337001, |_| HookResult::Continue)?;
+        vm.breakpoints.remove(&0x1337001);
+        assert_eq!(
+            integrity_error(&vm),
+            Some(IntegrityError::DanglingHook(0x1337001))
+        );
+        vm.remove_breakpoint(0x1337001)?;
+
+        // Guest debug state out of sync with the breakpoints
+        vm.hw_breakpoints[0] = Some((0x1337001, HwBreakpointKind::Execute));
+        assert_eq!(integrity_error(&vm), Some(IntegrityError::GuestDebug));
+        vm.update_guest_debug()?;
+        vm.verify_internal_state()?;
+
+        Ok(())
+    }
+
+    #[test]
+    /// Loads a snapshot from a compressed memory dump
+    fn test_compressed_snapshot() -> Result<()> {
+        let info = SnapshotInfo::from_string(
+            r#"{
+            "mappings": [
+                {"start": "1337000", "end": "1339000", "physical_offset": "0",
+                 "permissions": "r-xp"},
+                {"start": "2000000", "end": "2002000", "physical_offset": "2000",
+                 "permissions": "rw-p", "file_size": "1800"}
+            ],
+            "registers": {
+                "rax": "0", "rbx": "0", "rcx": "0", "rdx": "0", "rsi": "0", "rdi": "0",
+                "rsp": "0", "rbp": "0", "r8": "0", "r9": "0", "r10": "0", "r11": "0",
+                "r12": "0", "r13": "0", "r14": "0", "r15": "0", "rip": "1337000",
+                "rflags": "2", "fs_base": "0", "gs_base": "0"
+            }
+        }"#,
+        )?;
+
+        let mut data = vec![0u8; 0x3800];
+        data[..3].copy_from_slice(&[0x48, 0xff, 0xc0]); // inc rax
+        data[3] = 0xf4; // hlt
+        data[0x2000..].fill(0x41);
+
+        let raw = TempPath::new("raw");
+        let packed = TempPath::new("dump");
+        std::fs::write(&raw, &data)?;
+        MemoryDump::compress(&raw, &packed, 3)?;
+
+        let flat = Vm::from_snapshot_info(&info, &raw, 512 * PAGE_SIZE)?;
+        let mut vm = Vm::from_snapshot_info(&info, &packed, 512 * PAGE_SIZE)?;
+
+        for address in [0x1337000, 0x1338000, 0x2000000, 0x2001000] {
+            let mut expected = vec![0u8; PAGE_SIZE];
+            let mut loaded = vec![0u8; PAGE_SIZE];
+            flat.read(address, &mut expected)?;
+            vm.read(address, &mut loaded)?;
+            assert_eq!(loaded, expected);
+        }
+        assert_eq!(vm.read_value_checked::<u8>(0x20017ff)?, 0x41);
+        assert_eq!(vm.read_value_checked::<u8>(0x2001800)?, 0);
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rax), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Builds a delta from the dirty pages and applies it to a clone
+    fn test_snapshot_delta() -> Result<()> {
+        let mut vm = VmBuilder::new(512 * PAGE_SIZE)
+            .dirty_log(DirtyLogStrategy::GetDirtyLog)
+            .build()?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x04, 0x25, 0x00, 0x80, 0x33, 0x01, // mov [0x1338000], rax
+            0x48, 0xc7, 0xc3, 0x42, 0x00, 0x00, 0x00, // mov rbx, 0x42
+            0x66, 0x0f, 0x6f, 0xc3, // movdqa xmm0, xmm3
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x1338000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rax, 0x4141414142424242);
+        vm.set_xmm(3, 0x4141414142424242)?;
+
+        let base = vm.clone();
+        assert_eq!(vm.run()?, VmExit::Hlt);
+
+        let delta = vm.snapshot_delta(&base)?;
+        let (data_page, _) = vm.translate(0x1338000).unwrap();
+        assert_eq!(
+            delta.pages.get(&data_page).map(|page| &page[..8]),
+            Some(&0x4141414142424242u64.to_le_bytes()[..])
+        );
+        assert_eq!(delta.registers.rbx, 0x42);
+        assert_eq!(delta.registers.rip, 0x1337014);
+        assert!(delta.registers.xsave.is_some());
+
+        // The dirty log is kept for the reset, even when cleared by kvm
+        vm.reset(&base);
+        assert_eq!(vm.read_value_checked::<u64>(0x1338000)?, 0);
+        assert!(vm.snapshot_delta(&base)?.pages.is_empty());
+
+        // Applied on a clone of the base, restored by its next reset
+        let mut other = base.clone();
+        other.apply_delta(&delta)?;
+        assert_eq!(
+            other.read_value_checked::<u64>(0x1338000)?,
+            0x4141414142424242
+        );
+        assert_eq!(other.get_reg(Register::Rbx), 0x42);
+        assert_eq!(other.get_reg(Register::Rip), 0x1337014);
+        assert_eq!(other.get_xmm(0)?, 0x4141414142424242);
+        assert_eq!(other.snapshot_delta(&base)?.pages.len(), delta.pages.len());
+
+        other.reset(&base);
+        assert_eq!(other.read_value_checked::<u64>(0x1338000)?, 0);
+        assert_eq!(other.get_reg(Register::Rbx), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Diffs a vm against the vm it was cloned from
+    fn test_vm_diff() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x89, 0x04, 0x25, 0x08, 0x80, 0x33, 0x01, // mov [0x1338008], eax
+            0x48, 0xc7, 0xc3, 0x42, 0x00, 0x00, 0x00, // mov rbx, 0x42
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x1338000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rax, 0x41414141);
+
+        let orig = vm.clone();
+        assert!(diff(&vm, &orig)?.is_empty());
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        vm.mmap(0x1339000, PAGE_SIZE, PagePermissions::READ)?;
+        vm.mprotect(0x1338000, PAGE_SIZE, PagePermissions::READ)?;
+
+        let result = diff(&orig, &vm)?;
+        assert_eq!(
+            result.registers,
+            vec![
+                RegisterDiff {
+                    register: Register::Rip,
+                    left: 0x1337000,
+                    right: 0x133700f
+                },
+                RegisterDiff {
+                    register: Register::Rbx,
+                    left: 0,
+                    right: 0x42
+                },
+            ]
+        );
+        assert_eq!(
+            result.pages,
+            vec![PageDiff {
+                address: 0x1338000,
+                ranges: vec![(8, 4)]
+            }]
+        );
+        assert_eq!(
+            result.mappings,
+            vec![
+                MappingDiff {
+                    address: 0x1338000,
+                    left: Some(PagePermissions::READ | PagePermissions::WRITE),
+                    right: Some(PagePermissions::READ)
+                },
+                MappingDiff {
+                    address: 0x1339000,
+                    left: None,
+                    right: Some(PagePermissions::READ)
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Symbolizes the instruction pointer after an exit
+    fn test_symbolize() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x90, // nop
+            0x90, // nop
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        vm.symbols.add_module("test.so", 0x1337000, 0x1338000);
+        vm.symbols.add_symbol("test.so", "entry", 0x1337000)?;
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.symbolize(vm.get_reg(Register::Rip)), "test.so!entry+0x3");
+        assert_eq!(vm.symbolize(0x1337000), "test.so!entry");
+        assert_eq!(vm.symbolize(0x4141), "0x4141");
+
+        Ok(())
+    }
+
+    #[test]
+    /// Builds the report of a page fault two frames deep
+    fn test_crash_report() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x55, // main: push rbp
+            0x48, 0x89, 0xe5, // mov rbp, rsp
+            0xe8, 0x07, 0x00, 0x00, 0x00, // call f
+            0xf4, // hlt
+            0x90, 0x90, 0x90, 0x90, 0x90, 0x90, // nops
+            0x55, // f: push rbp
+            0x48, 0x89, 0xe5, // mov rbp, rsp
+            0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, // mov rax, [0]
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x2000000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rsp, 0x2000800);
+
+        vm.symbols.add_module("test.so", 0x1337000, 0x1338000);
+        vm.symbols.add_symbol("test.so", "main", 0x1337000)?;
+        vm.symbols.add_symbol("test.so", "f", 0x1337010)?;
+
+        let exit = vm.run()?;
+        assert!(matches!(exit, VmExit::PageFault(_)));
+
+        let report = vm.crash_report(exit);
+        assert_eq!(report.exit, exit);
+        assert!(report.registers.contains(&(Register::Rip, 0x1337014)));
+        assert!(report.instruction.starts_with(&[0x48, 0x8b, 0x04, 0x25]));
+        if cfg!(feature = "disasm") {
+            assert_eq!(report.instruction.len(), 8);
+            assert_eq!(report.disassembly.as_deref(), Some("mov rax,[0]"));
+        } else {
+            assert_eq!(report.instruction.len(), 15);
+            assert_eq!(report.disassembly, None);
+        }
+
+        let symbols: Vec<&str> = report.backtrace.iter().map(|f| f.symbol.as_str()).collect();
+        assert_eq!(symbols, ["test.so!f+0x4", "test.so!main+0x9"]);
+
+        // The fault address is unmapped, only the stack is captured
+        assert_eq!(report.memory.len(), 1);
+        assert_eq!(report.memory[0].address, 0x2000800 - 0x18 - 0x40);
+        assert_eq!(report.memory[0].data.len(), 0x140);
+
+        let json = report.to_json();
+        assert!(json.contains("\"rip\":\"1337014\""));
+        assert!(json.contains("\"symbol\":\"test.so!main+0x9\""));
+
+        // Without frame pointers, the return addresses are found on the stack
+        vm.set_reg(Register::Rbp, 0x4141);
+        let report = vm.crash_report(exit);
+        let addresses: Vec<u64> = report.backtrace.iter().map(|f| f.address).collect();
+        assert_eq!(addresses, [0x1337014, 0x1337009]);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Buckets the crashes of a function reached from two call sites
+    fn test_crash_hash() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x55, // main: push rbp
+            0x48, 0x89, 0xe5, // mov rbp, rsp
+            0x48, 0x85, 0xff, // test rdi, rdi
+            0x75, 0x06, // jne second
+            0xe8, 0x12, 0x00, 0x00, 0x00, // call f
+            0xf4, // hlt
+            0xe8, 0x0c, 0x00, 0x00, 0x00, // second: call f
+            0xf4, // hlt
+            0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, // nops
+            0x55, // f: push rbp
+            0x48, 0x89, 0xe5, // mov rbp, rsp
+            0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, // mov rax, [0]
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x2000000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rsp, 0x2000800);
+        let orig = vm.clone();
+
+        let top_only = CrashHashConfig {
+            frames: 0,
+            ..Default::default()
+        };
+        let mut hashes = Vec::new();
+        for rdi in 0..2 {
+            vm.reset(&orig);
+            vm.set_crash_hash_config(CrashHashConfig::default());
+            vm.set_reg(Register::Rdi, rdi);
+
+            let exit = vm.run()?;
+            assert!(matches!(exit, VmExit::PageFault(_)));
+            assert_eq!(vm.backtrace().len(), 2);
+
+            let hash = vm.crash_hash(exit);
+            assert_eq!(vm.crash_hash(exit), hash);
+            assert_ne!(vm.crash_hash(VmExit::Exception(13)), hash);
+
+            vm.set_crash_hash_config(top_only);
+            hashes.push((hash, vm.crash_hash(exit)));
+
+            // Without the exit kind, only the stack trace is hashed
+            vm.set_crash_hash_config(CrashHashConfig {
+                exit_kind: false,
+                ..Default::default()
+            });
+            assert_eq!(vm.crash_hash(VmExit::Exception(13)), vm.crash_hash(exit));
+        }
+
+        // Same faulting instruction, different callers
+        assert_ne!(hashes[0].0, hashes[1].0);
+        assert_eq!(hashes[0].1, hashes[1].1);
+
+        // The buckets are stable across the toolchains: FNV-1a of the exit
+        // kind and of the top frame
+        assert_eq!(hashes[0].1, 0x6a47_5667_d2cf_eb1a);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Allocates guest pages kept or reclaimed by the resets
+    fn test_guest_alloc() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x8b, 0x07, // mov rax, [rdi]
+            0x48, 0x89, 0x06, // mov [rsi], rax
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        // Allocated before the snapshot, kept by the resets
+        let rw = PagePermissions::READ | PagePermissions::WRITE;
+        let input = vm.guest_alloc(0x10, PagePermissions::READ)?;
+        let output = vm.guest_alloc(PAGE_SIZE + 1, rw)?;
+        assert_eq!(output, input + PAGE_SIZE as u64);
+        vm.write(input, b"tartifle")?;
+        vm.set_reg(Register::Rdi, input);
+        vm.set_reg(Register::Rsi, output);
+        let orig = vm.clone();
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.read_value_checked::<u64>(output)?, 0x656c666974726174);
+
+        // Freed then reused, the memory is zeroed
+        vm.guest_free(output)?;
+        assert!(vm.read_value_checked::<u64>(output).is_err());
+        let scratch = vm.guest_alloc(0x100, rw)?;
+        assert_eq!(scratch, output);
+        assert_eq!(vm.read_value_checked::<u64>(scratch)?, 0);
+        assert!(vm.guest_free(scratch + 1).is_err());
+
+        // The reset reclaims the allocations made since the snapshot
+        let extra = vm.guest_alloc(3 * PAGE_SIZE, rw)?;
+        assert_eq!(extra, scratch + PAGE_SIZE as u64);
+        vm.reset(&orig);
+        assert!(vm
+            .read_value_checked::<u64>(extra + PAGE_SIZE as u64)
+            .is_err());
+        assert_eq!(vm.read_value_checked::<u64>(output)?, 0);
+        assert_eq!(vm.guest_alloc(1, rw)?, output + 2 * PAGE_SIZE as u64);
+
+        vm.reset(&orig);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.read_value_checked::<u64>(output)?, 0x656c666974726174);
+
+        // The region is fixed once allocations are made
+        assert!(vm.set_guest_alloc_region(0x4000_0000, 0x10000).is_err());
+        let mut other = Vm::new(512 * PAGE_SIZE)?;
+        other.set_guest_alloc_region(0x4000_0000, PAGE_SIZE as u64)?;
+        assert_eq!(other.guest_alloc(1, rw)?, 0x4000_0000);
+        assert!(other.guest_alloc(1, rw).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Detects the overflows and underflows of the guest allocations
+    fn test_guest_alloc_sanitizer() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0xc6, 0x07, 0x41, // mov byte [rdi], 'A'
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        // The allocations end against a guard page
+        let rw = PagePermissions::READ | PagePermissions::WRITE;
+        vm.set_guest_alloc_sanitizer(true)?;
+        let buffer = vm.guest_alloc(0x10, rw)?;
+        assert_eq!(buffer % PAGE_SIZE as u64, PAGE_SIZE as u64 - 0x10);
+        assert!(vm.set_guest_alloc_sanitizer(false).is_err());
+        let orig = vm.clone();
+
+        vm.set_reg(Register::Rdi, buffer + 0x10);
+        match vm.run()? {
+            VmExit::PageFault(pf) => assert_eq!(pf.address, buffer + 0x10),
+            exit => panic!("Unexpected exit {:?}", exit),
+        }
+
+        // An underflow in the redzone is caught by the free
+        vm.reset(&orig);
+        vm.set_reg(Register::Rdi, buffer - 1);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert!(vm.guest_free(buffer).is_err());
+        assert!(vm.read_value_checked::<u8>(buffer).is_err());
+
+        // Freed allocations are not reused before the reset
+        let other = vm.guest_alloc(0x10, rw)?;
+        assert_eq!(other, buffer + 3 * PAGE_SIZE as u64);
+        vm.guest_free(other)?;
+
+        vm.reset(&orig);
+        assert_eq!(vm.read_value_checked::<u8>(buffer - 1)?, 0xfa);
+        vm.guest_free(buffer)?;
+
+        Ok(())
+    }
+
+    #[test]
+    /// Reports the accesses to the watched ranges
+    fn test_watch_range() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x8b, 0x07, // mov rax, [rdi]
+            0x48, 0x89, 0x06, // mov [rsi], rax
+            0x48, 0x89, 0x47, 0x08, // mov [rdi + 8], rax
+            0x48, 0x8b, 0x4f, 0x08, // mov rcx, [rdi + 8]
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x1338000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.write_value::<u64>(0x1338010, 0x656c666974726174)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rdi, 0x1338010);
+        vm.set_reg(Register::Rsi, 0x1338100);
+
+        vm.watch_range(0x1338010, 8, AccessKind::Read);
+        vm.watch_range(0x1338018, 8, AccessKind::Write);
+        let orig = vm.clone();
+
+        // The accesses are reported before their instruction executes
+        for _ in 0..2 {
+            let exit = vm.run()?;
+            assert_eq!(
+                exit,
+                VmExit::MemAccess {
+                    addr: 0x1338010,
+                    kind: AccessKind::Read
+                }
+            );
+            assert_eq!(vm.get_reg(Register::Rip), 0x1337000);
+            assert_eq!(vm.read_value_checked::<u64>(0x1338010)?, 0x656c666974726174);
+
+            // The other accesses to the watched pages are transparent
+            let exit = vm.run()?;
+            assert_eq!(
+                exit,
+                VmExit::MemAccess {
+                    addr: 0x1338018,
+                    kind: AccessKind::Write
+                }
+            );
+            assert_eq!(vm.get_reg(Register::Rip), 0x1337006);
+            assert_eq!(vm.read_value_checked::<u64>(0x1338100)?, 0x656c666974726174);
+
+            assert_eq!(vm.run()?, VmExit::Hlt);
+            assert_eq!(vm.get_reg(Register::Rcx), 0x656c666974726174);
+
+            vm.reset(&orig);
+        }
+
+        // Stepping over an access
+        vm.unwatch_range(0x1338010);
+        assert!(matches!(vm.run()?, VmExit::MemAccess { .. }));
+        assert_eq!(vm.step()?, VmExit::Breakpoint);
+        assert_eq!(vm.get_reg(Register::Rip), 0x133700a);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Splits the guest memory in slots around a hole
+    fn test_memory_holes() -> Result<()> {
+        let mut vm = VmBuilder::new(64 * PAGE_SIZE)
+            .memory_hole(0x8000, 0x1_0000_0000 - 0x8000)
+            .memory_slot(0x10000, PAGE_SIZE)
+            .build()?;
+
+        let memory: Vec<_> = vm
+            .physical_layout()
+            .into_iter()
+            .filter(|region| region.kind == PhysicalRegionKind::Memory)
+            .map(|region| (region.start, region.size))
+            .collect();
+        assert_eq!(
+            memory,
+            vec![(0, 0x8000), (0x1_0000_0000, 56 * PAGE_SIZE as u64)]
+        );
+
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        assert!(vm.memory.translate(0xdeadb000).unwrap().0 >= 0x1_0000_0000);
+        vm.set_reg(Register::Rax, 0xdeadb000);
+        vm.set_reg(Register::Rdx, 0x42424242);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let snapshot = vm.clone();
+
+        // The pages above the hole are dirty logged and restored
+        for _ in 0..2 {
+            assert_eq!(vm.run()?, VmExit::Hlt);
+            assert_eq!(vm.memory.read_val::<u64>(0xdeadb000)?, 0x42424242);
+
+            vm.reset(&snapshot);
+            assert_eq!(vm.memory.read_val::<u64>(0xdeadb000)?, 0);
+        }
+
+        // The unaligned holes are refused
+        let unaligned = VmBuilder::new(512 * PAGE_SIZE)
+            .memory_hole(0x8000, 0x800)
+            .build();
+        assert!(matches!(unaligned, Err(VmError::InvalidOperation(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Runs the guest on 2MB pages backed by hugepages
+    fn test_huge_pages() -> Result<()> {
+        let mut vm = VmBuilder::new(8 * HUGE_PAGE_SIZE)
+            .huge_pages(HugePages::Transparent)
+            .build()?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x200000, HUGE_PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x200000, shellcode)?;
+        vm.mmap(
+            0x400000,
+            HUGE_PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        let huge = vm
+            .mappings()
+            .filter(|mapping| mapping.size == HUGE_PAGE_SIZE);
+        assert_eq!(huge.count(), 2);
+        vm.set_reg(Register::Rax, 0x5ff000);
+        vm.set_reg(Register::Rdx, 0x42424242);
+        vm.set_reg(Register::Rip, 0x200000);
+
+        let snapshot = vm.clone();
+
+        // The guest runs on the 2MB pages, restored by the resets
+        for _ in 0..2 {
+            assert_eq!(vm.run()?, VmExit::Hlt);
+            assert_eq!(vm.memory.read_val::<u64>(0x5ff000)?, 0x42424242);
+
+            vm.reset(&snapshot);
+            assert_eq!(vm.memory.read_val::<u64>(0x5ff000)?, 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Pins the TSC and the cpuid in determinism mode
+    fn test_determinism() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x0f, 0x31, // rdtsc
+            0x48, 0xc1, 0xe2, 0x20, // shl rdx, 32
+            0x48, 0x09, 0xd0, // or rax, rdx
+            0x48, 0x89, 0xc6, // mov rsi, rax
+            0x31, 0xc0, // xor eax, eax
+            0x0f, 0xa2, // cpuid
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let vendor = CpuidEntry {
+            function: 0,
+            eax: 0xd,
+            ebx: u32::from_le_bytes(*b"Tart"),
+            edx: u32::from_le_bytes(*b"ifle"),
+            ecx: u32::from_le_bytes(*b"tte!"),
+            ..Default::default()
+        };
+        vm.enable_determinism(DeterminismConfig {
+            tsc: 1 << 40,
+            cpuid: vec![vendor],
+            ..Default::default()
+        })?;
+        assert_eq!(vm.determinism().unwrap().tsc, 1 << 40);
+
+        // rdrand and rdseed are hidden to be emulated
+        let leaf1 = vm.cpuid().into_iter().find(|e| e.function == 1).unwrap();
+        assert_eq!(leaf1.ecx & 1 << 30, 0);
+
+        let snapshot = vm.clone();
+
+        // The pinned cpuid is seen by the runs
+        for _ in 0..2 {
+            assert_eq!(vm.run()?, VmExit::Hlt);
+            assert_eq!(vm.get_reg(Register::Rbx), vendor.ebx as u64);
+
+            vm.reset(&snapshot);
+        }
+
+        // The TSC writes are not always honored by nested hypervisors
+        let tsc = vm.get_msr(IA32_TIME_STAMP_COUNTER)?;
+        if !(1 << 40..(1 << 40) + (1 << 32)).contains(&tsc) {
+            eprintln!("TSC writes are ignored by the hypervisor, skipping the pinning checks");
+            return Ok(());
+        }
+
+        // The counter keeps going while the vcpu runs, but the resets rewind
+        // it: each run reads the pinned value plus a few cycles, far less
+        // than the 100ms spent between the runs
+        for _ in 0..2 {
+            vm.reset(&snapshot);
+            assert_eq!(vm.run()?, VmExit::Hlt);
+            let elapsed = vm.get_reg(Register::Rsi) - (1 << 40);
+            assert!(elapsed < 1 << 24, "TSC not rewound: +{} cycles", elapsed);
+
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Compares the dirty 2MB pages whole
+    fn test_lockstep_huge_pages() -> Result<()> {
+        let mut left = VmBuilder::new(8 * HUGE_PAGE_SIZE)
+            .huge_pages(HugePages::Transparent)
+            .build()?;
+
+        let shellcode: &[u8] = &[
+            0x48, 0xc7, 0x02, 0x01, 0x00, 0x00, 0x00, // mov qword [rdx], 1
+            0xf4, // hlt
+        ];
+
+        left.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        left.write(0x1337000, shellcode)?;
+        left.mmap(
+            0x400000,
+            HUGE_PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        left.set_reg(Register::Rdx, 0x400000);
+        left.set_reg(Register::Rip, 0x1337000);
+
+        let mut right = left.clone();
+        right.write_value::<u8>(0x5ff000, 0x41)?;
+
+        let mut lockstep = Lockstep::new(&mut left, &mut right, LockstepMode::Exit);
+        lockstep.compare_dirty_pages(true);
+        assert!(matches!(
+            lockstep.run(10)?,
+            LockstepResult::Diverged(Divergence {
+                step: 1,
+                kind: DivergenceKind::Memory {
+                    address: 0x5ff000,
+                    left: Some(0),
+                    right: Some(0x41),
+                },
+            })
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! Return address integrity monitor
+
+use super::{Result, Vm, VmExit};
+
+use std::collections::BTreeSet;
+
+/// Shadow stack of the return addresses pushed by monitored calls
+#[derive(Clone, Debug, Default)]
+pub(super) struct ReturnMonitor {
+    /// Entry points of the monitored functions
+    entries: BTreeSet<u64>,
+    /// Return instructions of the monitored functions
+    returns: BTreeSet<u64>,
+    /// Stack slot and expected return address of the active calls
+    shadow_stack: Vec<(u64, u64)>,
+}
+
+impl ReturnMonitor {
+    /// Returns whether or not an address is used by the monitor
+    #[inline]
+    pub(super) fn contains(&self, address: u64) -> bool {
+        self.entries.contains(&address) || self.returns.contains(&address)
+    }
+
+    /// Restores the active calls from an other monitor
+    #[inline]
+    pub(super) fn reset(&mut self, other: &ReturnMonitor) {
+        self.shadow_stack.clone_from(&other.shadow_stack);
+    }
+}
+
+impl Vm {
+    /// Monitors the return address of a function. It is recorded when `entry`
+    /// is reached and validated on each of the `ret` instructions in
+    /// `returns`, stopping with `VmExit::RetCorruption` if it changed.
+    pub fn monitor_returns(&mut self, entry: u64, returns: &[u64]) -> Result<()> {
+        self.add_breakpoint(entry)?;
+        self.return_monitor.entries.insert(entry);
+
+        for &address in returns.iter() {
+            self.add_breakpoint(address)?;
+            self.return_monitor.returns.insert(address);
+        }
+
+        Ok(())
+    }
+
+    /// Stops monitoring return addresses and removes the monitor breakpoints
+    pub fn clear_return_monitor(&mut self) -> Result<()> {
+        let monitor = std::mem::take(&mut self.return_monitor);
+
+        for &address in monitor.entries.iter().chain(monitor.returns.iter()) {
+            if !self.breakpoint_claimed(address) {
+                self.remove_breakpoint(address)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records or validates the return address when a monitored address is
+    /// reached. Returns the exit to report on corruption.
+    pub(super) fn check_return_monitor(&mut self, rip: u64) -> Result<Option<VmExit>> {
+        let rsp = self.registers.rsp;
+
+        // The return address is on top of the stack at the function entry
+        if self.return_monitor.entries.contains(&rip) {
+            let expected: u64 = self.memory.read_val(rsp)?;
+            self.return_monitor.shadow_stack.push((rsp, expected));
+        }
+
+        if !self.return_monitor.returns.contains(&rip) {
+            return Ok(None);
+        }
+
+        // Drop the calls unwound without returning (longjmp, exceptions)
+        let shadow_stack = &mut self.return_monitor.shadow_stack;
+        while shadow_stack.last().is_some_and(|&(slot, _)| slot < rsp) {
+            shadow_stack.pop();
+        }
+
+        // Calls entered before the monitor cannot be checked
+        let expected = match shadow_stack.last() {
+            Some(&(slot, expected)) if slot == rsp => expected,
+            _ => return Ok(None),
+        };
+        shadow_stack.pop();
+
+        let found: u64 = self.memory.read_val(rsp)?;
+        if found != expected {
+            return Ok(Some(VmExit::RetCorruption { expected, found }));
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,121 @@
+//! Model specific registers access
+
+use super::{ErrorContext, HvError, Result, Vm};
+
+use kvm_bindings::{kvm_msr_entry, Msrs};
+
+use std::collections::BTreeMap;
+
+/// Time stamp counter
+pub const IA32_TIME_STAMP_COUNTER: u32 = 0x10;
+/// SYSENTER code segment
+pub const IA32_SYSENTER_CS: u32 = 0x174;
+/// SYSENTER stack pointer
+pub const IA32_SYSENTER_ESP: u32 = 0x175;
+/// SYSENTER instruction pointer
+pub const IA32_SYSENTER_EIP: u32 = 0x176;
+/// SYSCALL segments
+pub const IA32_STAR: u32 = 0xc000_0081;
+/// SYSCALL 64 bits instruction pointer
+pub const IA32_LSTAR: u32 = 0xc000_0082;
+/// SYSCALL compatibility mode instruction pointer
+pub const IA32_CSTAR: u32 = 0xc000_0083;
+/// SYSCALL rflags mask
+pub const IA32_FMASK: u32 = 0xc000_0084;
+/// FS base
+pub const IA32_FS_BASE: u32 = 0xc000_0100;
+/// GS base
+pub const IA32_GS_BASE: u32 = 0xc000_0101;
+/// SWAPGS kernel gs base
+pub const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// MSRs carried by snapshots, resets and clones (fs and gs base excluded)
+pub const SNAPSHOT_MSRS: &[u32] = &[
+    IA32_TIME_STAMP_COUNTER,
+    IA32_SYSENTER_CS,
+    IA32_SYSENTER_ESP,
+    IA32_SYSENTER_EIP,
+    IA32_STAR,
+    IA32_LSTAR,
+    IA32_CSTAR,
+    IA32_FMASK,
+    IA32_KERNEL_GS_BASE,
+];
+
+impl Vm {
+    /// Gets a model specific register from the vm state
+    pub fn get_msr(&self, index: u32) -> Result<u64> {
+        // fs_base and gs_base are cached with the registers
+        match index {
+            IA32_FS_BASE => return Ok(self.fs_base),
+            IA32_GS_BASE => return Ok(self.gs_base),
+            _ => {}
+        }
+
+        Ok(self.get_msrs(&[index])?[&index])
+    }
+
+    /// Sets a model specific register in the vm state
+    pub fn set_msr(&mut self, index: u32, value: u64) -> Result<()> {
+        match index {
+            IA32_FS_BASE => self.fs_base = value,
+            IA32_GS_BASE => self.gs_base = value,
+            _ => {
+                let mut msrs = BTreeMap::new();
+                msrs.insert(index, value);
+                self.set_msrs(&msrs)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a list of model specific registers from kvm
+    pub(super) fn get_msrs(&self, indexes: &[u32]) -> Result<BTreeMap<u32, u64>> {
+        let entries: Vec<kvm_msr_entry> = indexes
+            .iter()
+            .map(|&index| kvm_msr_entry {
+                index,
+                ..Default::default()
+            })
+            .collect();
+        let mut msrs = Msrs::from_entries(&entries).map_err(|_| HvError::new("Too many msrs"))?;
+
+        let count = self
+            .kvm_vcpu
+            .get_msrs(&mut msrs)
+            .map_err(|err| HvError::os("Could not read msrs", err.errno()))?;
+        if let Some(&index) = indexes.get(count) {
+            return Err(HvError::new("Unsupported msr")
+                .with_context(ErrorContext::Msr(index))
+                .into());
+        }
+
+        Ok(msrs.as_slice().iter().map(|e| (e.index, e.data)).collect())
+    }
+
+    /// Writes a list of model specific registers to kvm
+    pub(super) fn set_msrs(&mut self, values: &BTreeMap<u32, u64>) -> Result<()> {
+        let entries: Vec<kvm_msr_entry> = values
+            .iter()
+            .map(|(&index, &data)| kvm_msr_entry {
+                index,
+                data,
+                ..Default::default()
+            })
+            .collect();
+        let msrs = Msrs::from_entries(&entries).map_err(|_| HvError::new("Too many msrs"))?;
+
+        let count = self
+            .kvm_vcpu
+            .set_msrs(&msrs)
+            .map_err(|err| HvError::os("Could not write msrs", err.errno()))?;
+        if let Some(&index) = values.keys().nth(count) {
+            return Err(HvError::new("Unsupported msr")
+                .with_context(ErrorContext::Msr(index))
+                .into());
+        }
+
+        Ok(())
+    }
+}
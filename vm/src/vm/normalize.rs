@@ -0,0 +1,148 @@
+//! Normalization of the nondeterministic values of user-space snapshots
+
+use super::{Register, Result, Vm};
+use crate::memory::PAGE_SIZE;
+use crate::snapshot::{SnapshotInfo, SnapshotMapping};
+
+/// Auxiliary vector entry holding the page size
+const AT_PAGESZ: u64 = 6;
+/// Auxiliary vector entry pointing to 16 random bytes
+const AT_RANDOM: u64 = 25;
+/// Size of the `AT_RANDOM` buffer
+const AT_RANDOM_SIZE: usize = 16;
+/// Fixed content of the `AT_RANDOM` buffer
+const FIXED_RANDOM: [u8; AT_RANDOM_SIZE] = [0x41; AT_RANDOM_SIZE];
+
+/// Offset of the stack guard in the x86_64 glibc and musl thread control block
+const STACK_GUARD_OFFSET: u64 = 0x28;
+/// Fixed stack guard (its first byte is zero, as the libc ones)
+const FIXED_STACK_GUARD: u64 = 0x6574_7465_6c66_6900;
+
+/// Kind of value rewritten by the normalization
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NormalizationKind {
+    /// Random bytes given by the kernel to the libc (`AT_RANDOM`)
+    AtRandom,
+    /// Stack guard of the main thread
+    StackGuard,
+    /// Copy of the stack guard saved by a function prologue
+    StackGuardCopy,
+    /// vDSO data page (zeroed, the vDSO then falls back to the syscalls)
+    VdsoData,
+}
+
+/// Value rewritten by the normalization
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Normalization {
+    /// Kind of the value
+    pub kind: NormalizationKind,
+    /// Address of the value
+    pub address: u64,
+    /// Size of the value
+    pub size: usize,
+}
+
+/// Returns the mapping containing an address
+fn mapping_at(info: &SnapshotInfo, address: u64) -> Option<&SnapshotMapping> {
+    info.mappings
+        .iter()
+        .find(|m| m.start <= address && address < m.end)
+}
+
+impl Vm {
+    /// Returns the address of the `AT_RANDOM` bytes, found in the auxiliary
+    /// vector at the top of the stack
+    fn find_at_random(&self, stack: &SnapshotMapping) -> Option<u64> {
+        let mut data = vec![0u8; (stack.end - stack.start) as usize];
+        self.read(stack.start, &mut data).ok()?;
+
+        let words: Vec<u64> = data
+            .chunks_exact(8)
+            .map(|w| u64::from_le_bytes([w[0], w[1], w[2], w[3], w[4], w[5], w[6], w[7]]))
+            .collect();
+
+        // The vector is an array of (type, value) pairs holding the page size
+        let pagesz = words
+            .windows(2)
+            .rposition(|w| w == [AT_PAGESZ, PAGE_SIZE as u64])?;
+
+        words[pagesz % 2..]
+            .chunks_exact(2)
+            .find(|pair| {
+                pair[0] == AT_RANDOM
+                    && pair[1] >= stack.start
+                    && pair[1]
+                        .checked_add(AT_RANDOM_SIZE as u64)
+                        .is_some_and(|end| end <= stack.end)
+            })
+            .map(|pair| pair[1])
+    }
+
+    /// Rewrites the known sources of nondeterminism of a user-space snapshot
+    /// loaded from `info` to fixed values: the `AT_RANDOM` bytes, the stack
+    /// guard (and its copies on the stack) and the vDSO data pages. Returns
+    /// the values rewritten.
+    pub fn normalize_entropy(&mut self, info: &SnapshotInfo) -> Result<Vec<Normalization>> {
+        let mut report = Vec::new();
+        let stack = mapping_at(info, self.get_reg(Register::Rsp));
+
+        // Random bytes seeding the libc (stack protector, pointer guard...)
+        if let Some(address) = stack.and_then(|stack| self.find_at_random(stack)) {
+            self.write(address, &FIXED_RANDOM)?;
+            report.push(Normalization {
+                kind: NormalizationKind::AtRandom,
+                address,
+                size: AT_RANDOM_SIZE,
+            });
+        }
+
+        // Stack guard, the frames of the running functions hold copies of it
+        let guard_address = self.get_reg(Register::FsBase) + STACK_GUARD_OFFSET;
+        let mut guard = [0u8; 8];
+        if self.read(guard_address, &mut guard).is_ok() && u64::from_le_bytes(guard) != 0 {
+            self.write_value(guard_address, FIXED_STACK_GUARD)?;
+            report.push(Normalization {
+                kind: NormalizationKind::StackGuard,
+                address: guard_address,
+                size: 8,
+            });
+
+            if let Some(stack) = stack {
+                let mut data = vec![0u8; (stack.end - stack.start) as usize];
+                self.read(stack.start, &mut data)?;
+
+                for (i, word) in data.chunks_exact(8).enumerate() {
+                    if word != guard {
+                        continue;
+                    }
+
+                    let address = stack.start + i as u64 * 8;
+                    self.write_value(address, FIXED_STACK_GUARD)?;
+                    report.push(Normalization {
+                        kind: NormalizationKind::StackGuardCopy,
+                        address,
+                        size: 8,
+                    });
+                }
+            }
+        }
+
+        // vDSO data (clock), the vDSO reads the time through syscalls when
+        // its clock mode is not set
+        for mapping in info.mappings.iter() {
+            if mapping.image.as_deref() != Some("[vvar]") {
+                continue;
+            }
+
+            let size = (mapping.end - mapping.start) as usize;
+            self.write(mapping.start, &vec![0u8; size])?;
+            report.push(Normalization {
+                kind: NormalizationKind::VdsoData,
+                address: mapping.start,
+                size,
+            });
+        }
+
+        Ok(report)
+    }
+}
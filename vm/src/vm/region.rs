@@ -0,0 +1,123 @@
+//! Time limits of the execution inside address ranges
+
+use super::interrupt::RunTimer;
+use super::{Result, Vm, VmError, VmExit};
+
+use nix::sys::time::TimeSpec;
+use nix::sys::timer::Expiration;
+
+use std::time::{Duration, Instant};
+
+/// Shortest interval between two kicks of the vcpu
+const MIN_KICK_INTERVAL: Duration = Duration::from_millis(1);
+/// Longest interval between two kicks of the vcpu
+const MAX_KICK_INTERVAL: Duration = Duration::from_millis(10);
+/// Number of kicks at least per time limit
+const KICKS_PER_LIMIT: u32 = 8;
+
+/// Maximum time allowed inside an address range
+pub(super) struct RegionTimeout {
+    /// Ending address (excluded)
+    end: u64,
+    /// Time allowed
+    limit: Duration,
+    /// Time spent inside the range since the last reset
+    spent: Duration,
+}
+
+impl Vm {
+    /// Limits the time spent executing inside `start..end` until the next
+    /// reset: the vcpu is kicked periodically during the runs, the time
+    /// since the previous kick being accounted to the range holding rip.
+    /// The run stops with a `VmExit::RegionTimeout` once the limit is
+    /// exceeded (the measure having the precision of the kicks).
+    pub fn add_region_timeout(&mut self, start: u64, end: u64, limit: Duration) -> Result<()> {
+        let overlapping = self
+            .region_timeouts
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, region)| region.end > start);
+        if start >= end || overlapping {
+            return Err(VmError::InvalidOperation("Invalid region timeout range"));
+        }
+
+        self.region_timeouts.insert(
+            start,
+            RegionTimeout {
+                end,
+                limit,
+                spent: Duration::ZERO,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Removes the time limit of the range starting at `start`
+    pub fn remove_region_timeout(&mut self, start: u64) {
+        self.region_timeouts.remove(&start);
+    }
+
+    /// Returns the time accounted to the range starting at `start` since the
+    /// last reset
+    pub fn region_time(&self, start: u64) -> Option<Duration> {
+        self.region_timeouts.get(&start).map(|region| region.spent)
+    }
+
+    /// Resets the time accounted to the ranges
+    pub(super) fn reset_region_timeouts(&mut self) {
+        for region in self.region_timeouts.values_mut() {
+            region.spent = Duration::ZERO;
+        }
+    }
+
+    /// Starts kicking the vcpu if time limits are set
+    pub(super) fn start_region_kicks(&mut self) -> Result<()> {
+        let shortest = match self.region_timeouts.values().map(|r| r.limit).min() {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let interval = (shortest / KICKS_PER_LIMIT).clamp(MIN_KICK_INTERVAL, MAX_KICK_INTERVAL);
+        let kick_timer = RunTimer::for_current_thread(&mut self.kick_timer)?;
+        kick_timer.set(Expiration::Interval(TimeSpec::from_duration(interval)))?;
+        self.last_kick = Instant::now();
+
+        Ok(())
+    }
+
+    /// Stops kicking the vcpu
+    pub(super) fn stop_region_kicks(&mut self) -> Result<()> {
+        match self.kick_timer.as_mut() {
+            Some(kick_timer) if !self.region_timeouts.is_empty() => kick_timer.disarm(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles a kick of the vcpu, returns `None` if the execution should be
+    /// resumed or `Some(VmExit::Interrupted)` for the interruptions which are
+    /// not kicks.
+    pub(super) fn handle_region_kick(&mut self) -> Option<VmExit> {
+        if self.region_timeouts.is_empty() || self.run_timer_expired() {
+            return Some(VmExit::Interrupted);
+        }
+
+        let now = Instant::now();
+        let elapsed = now - self.last_kick;
+        self.last_kick = now;
+        self.clear_immediate_exit();
+
+        let rip = self.registers.rip;
+        let (&start, region) = self
+            .region_timeouts
+            .range_mut(..=rip)
+            .next_back()
+            .filter(|(_, region)| rip < region.end)?;
+
+        region.spent += elapsed;
+        (region.spent > region.limit).then_some(VmExit::RegionTimeout {
+            start,
+            end: region.end,
+        })
+    }
+}
@@ -0,0 +1,214 @@
+//! Reproducible randomness source of the vm
+
+use super::{CpuidEntry, HvError, Register, Result, Vm};
+
+/// CPUID.01H:ECX RDRAND support bit
+const CPUID_RDRAND: u32 = 1 << 30;
+
+/// CPUID.(EAX=07H, ECX=0):EBX RDSEED support bit
+const CPUID_RDSEED: u32 = 1 << 18;
+
+/// CPUID.00H vendor string of the Intel processors (EBX, EDX, ECX)
+const INTEL_VENDOR: [u32; 3] = [0x756e_6547, 0x4965_6e69, 0x6c65_746e];
+
+/// Maximum length of the rdrand and rdseed instructions (operand size and
+/// REX prefixes, opcode and ModRM)
+const RDRAND_MAX_LENGTH: usize = 5;
+
+/// General purpose registers by instruction encoding
+const GPR_ENCODING: [Register; 16] = [
+    Register::Rax,
+    Register::Rcx,
+    Register::Rdx,
+    Register::Rbx,
+    Register::Rsp,
+    Register::Rbp,
+    Register::Rsi,
+    Register::Rdi,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+];
+
+/// Arithmetic flags set by `rdrand` (CF, PF, AF, ZF, SF, OF)
+const ARITHMETIC_FLAGS: u64 = 0x8d5;
+
+/// Randomness source used by the vm. Its state is restored along with the
+/// vm on resets, so a run draws the same numbers for the same seed.
+pub trait VmRng {
+    /// Returns the next random number
+    fn next_u64(&mut self) -> u64;
+
+    /// Returns a copy of the generator in its current state
+    fn box_clone(&self) -> Box<dyn VmRng>;
+}
+
+/// SplitMix64 generator, the default `VmRng`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SplitMix64 {
+    /// Generator state
+    state: u64,
+}
+
+impl SplitMix64 {
+    /// Creates a new `SplitMix64` instance from a seed
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+}
+
+impl VmRng for SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn box_clone(&self) -> Box<dyn VmRng> {
+        Box::new(*self)
+    }
+}
+
+/// Decode a `rdrand`/`rdseed` instruction, returns the destination register,
+/// its size in bytes and the instruction length
+fn decode_rdrand(code: &[u8]) -> Option<(Register, usize, u64)> {
+    let mut size = 4;
+    let mut rex_b = 0;
+    let mut length = 0;
+
+    // Operand size and REX prefixes
+    for &byte in code.iter() {
+        match byte {
+            0x66 => size = 2,
+            0x40..=0x4f => {
+                if byte & 8 != 0 {
+                    size = 8;
+                }
+                rex_b = (byte & 1) as usize;
+            }
+            _ => break,
+        }
+        length += 1;
+    }
+
+    // 0f c7 /6 (rdrand) or /7 (rdseed) with a register operand
+    match code.get(length..length + 3)? {
+        [0x0f, 0xc7, modrm] if modrm >> 6 == 3 && (modrm >> 3) & 6 == 6 => {
+            let register = GPR_ENCODING[(modrm & 7) as usize | rex_b << 3];
+            Some((register, size, length as u64 + 3))
+        }
+        _ => None,
+    }
+}
+
+impl Vm {
+    /// Replaces the randomness source of the vm (`VmBuilder::seed` sets a
+    /// seeded `SplitMix64` by default)
+    pub fn set_rng(&mut self, rng: Box<dyn VmRng>) {
+        self.rng = rng;
+    }
+
+    /// Returns the randomness source of the vm, to be shared with the hooks
+    /// emulating random behaviours
+    pub fn rng(&mut self) -> &mut dyn VmRng {
+        self.rng.as_mut()
+    }
+
+    /// Hides `rdrand` and `rdseed` from the guest cpuid: KVM then raises an
+    /// invalid opcode exception on them, and the instructions are emulated
+    /// with the vm randomness source. Only effective before the first run.
+    ///
+    /// The exception comes from the RDRAND/RDSEED exiting controls of VMX,
+    /// AMD processors run the instructions natively whatever the cpuid: the
+    /// trap is refused on non Intel hosts.
+    pub fn trap_rdrand(&mut self) -> Result<()> {
+        // Host vendor, the guest leaf 0 being possibly overridden
+        let vendor = std::arch::x86_64::__cpuid(0);
+        if [vendor.ebx, vendor.edx, vendor.ecx] != INTEL_VENDOR {
+            return Err(HvError::new("rdrand exiting not supported by the host").into());
+        }
+
+        let mut entries: Vec<CpuidEntry> = self
+            .cpuid()
+            .into_iter()
+            .filter(|e| e.function == 1 || (e.function == 7 && e.index == 0))
+            .collect();
+
+        for entry in entries.iter_mut() {
+            match entry.function {
+                1 => entry.ecx &= !CPUID_RDRAND,
+                _ => entry.ebx &= !CPUID_RDSEED,
+            }
+        }
+
+        self.set_cpuid(&entries)
+    }
+
+    /// Emulates the `rdrand`/`rdseed` instruction at rip, which raised an
+    /// exception with the given rflags. Returns false if the instruction is
+    /// not one of them.
+    pub(super) fn emulate_rdrand(&mut self, rflags: u64) -> bool {
+        // Read the instruction byte by byte, it can end a page followed by an
+        // unmapped one
+        let mut code = [0u8; RDRAND_MAX_LENGTH];
+        let rip = self.registers.rip;
+        let available = (0..RDRAND_MAX_LENGTH)
+            .take_while(|&i| {
+                let address = rip.wrapping_add(i as u64);
+                self.memory.read(address, &mut code[i..i + 1]).is_ok()
+            })
+            .count();
+
+        let (register, size, length) = match decode_rdrand(&code[..available]) {
+            Some(decoded) => decoded,
+            None => return false,
+        };
+
+        // 16 bits writes keep the upper bits, 32 bits ones clear them
+        let value = self.rng.next_u64();
+        let value = match size {
+            2 => (self.get_reg(register) & !0xffff) | (value & 0xffff),
+            4 => value & 0xffff_ffff,
+            _ => value,
+        };
+        self.set_reg(register, value);
+
+        // A random value is always available (CF set)
+        self.registers.rflags = (rflags & !ARITHMETIC_FLAGS) | 1;
+        self.registers.rip += length;
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_rdrand, Register};
+
+    #[test]
+    /// Decodes the destination of rdrand and rdseed
+    fn test_decode_rdrand() {
+        assert_eq!(
+            decode_rdrand(&[0x48, 0x0f, 0xc7, 0xf0]),
+            Some((Register::Rax, 8, 4))
+        );
+        assert_eq!(
+            decode_rdrand(&[0x0f, 0xc7, 0xf9]),
+            Some((Register::Rcx, 4, 3))
+        );
+        assert_eq!(
+            decode_rdrand(&[0x66, 0x41, 0x0f, 0xc7, 0xf7]),
+            Some((Register::R15, 2, 5))
+        );
+        // cmpxchg8b [rax]
+        assert_eq!(decode_rdrand(&[0x0f, 0xc7, 0x08]), None);
+    }
+}
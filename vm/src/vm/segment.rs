@@ -0,0 +1,139 @@
+//! Segment registers access
+
+use super::{Register, Vm};
+
+use kvm_bindings::kvm_segment;
+
+/// List of the segment registers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SegmentRegister {
+    /// Code segment
+    Cs,
+    /// Data segment
+    Ds,
+    /// Extra segment
+    Es,
+    /// FS segment
+    Fs,
+    /// GS segment
+    Gs,
+    /// Stack segment
+    Ss,
+}
+
+/// Segment selector and its cached descriptor
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Segment {
+    /// Segment selector
+    pub selector: u16,
+    /// Base address
+    pub base: u64,
+    /// Segment limit
+    pub limit: u32,
+    /// Segment type (access rights)
+    pub segment_type: u8,
+    /// Descriptor privilege level
+    pub dpl: u8,
+    /// Segment present
+    pub present: bool,
+    /// Code or data segment (not a system one)
+    pub code_data: bool,
+    /// Default operation size (32 bits)
+    pub db: bool,
+    /// 64 bits code segment
+    pub long_mode: bool,
+    /// Limit in 4KB units
+    pub granularity: bool,
+    /// Available for system software
+    pub avl: bool,
+    /// Segment register unusable (null selector loaded)
+    pub unusable: bool,
+}
+
+impl From<kvm_segment> for Segment {
+    fn from(seg: kvm_segment) -> Self {
+        Segment {
+            selector: seg.selector,
+            base: seg.base,
+            limit: seg.limit,
+            segment_type: seg.type_,
+            dpl: seg.dpl,
+            present: seg.present != 0,
+            code_data: seg.s != 0,
+            db: seg.db != 0,
+            long_mode: seg.l != 0,
+            granularity: seg.g != 0,
+            avl: seg.avl != 0,
+            unusable: seg.unusable != 0,
+        }
+    }
+}
+
+impl From<Segment> for kvm_segment {
+    fn from(seg: Segment) -> Self {
+        kvm_segment {
+            base: seg.base,
+            limit: seg.limit,
+            selector: seg.selector,
+            type_: seg.segment_type,
+            present: seg.present as u8,
+            dpl: seg.dpl,
+            db: seg.db as u8,
+            s: seg.code_data as u8,
+            l: seg.long_mode as u8,
+            g: seg.granularity as u8,
+            avl: seg.avl as u8,
+            unusable: seg.unusable as u8,
+            padding: 0,
+        }
+    }
+}
+
+impl Vm {
+    /// Returns the kvm copy of a segment register
+    fn kvm_segment_mut(&mut self, segment: SegmentRegister) -> &mut kvm_segment {
+        match segment {
+            SegmentRegister::Cs => &mut self.special_registers.cs,
+            SegmentRegister::Ds => &mut self.special_registers.ds,
+            SegmentRegister::Es => &mut self.special_registers.es,
+            SegmentRegister::Fs => &mut self.special_registers.fs,
+            SegmentRegister::Gs => &mut self.special_registers.gs,
+            SegmentRegister::Ss => &mut self.special_registers.ss,
+        }
+    }
+
+    /// Gets a segment register from the vm state
+    pub fn get_segment(&self, segment: SegmentRegister) -> Segment {
+        let seg = match segment {
+            SegmentRegister::Cs => self.special_registers.cs,
+            SegmentRegister::Ds => self.special_registers.ds,
+            SegmentRegister::Es => self.special_registers.es,
+            SegmentRegister::Fs => self.special_registers.fs,
+            SegmentRegister::Gs => self.special_registers.gs,
+            SegmentRegister::Ss => self.special_registers.ss,
+        };
+
+        // fs and gs bases are cached with the registers
+        let base = match segment {
+            SegmentRegister::Fs => self.get_reg(Register::FsBase),
+            SegmentRegister::Gs => self.get_reg(Register::GsBase),
+            _ => seg.base,
+        };
+
+        Segment {
+            base,
+            ..Segment::from(seg)
+        }
+    }
+
+    /// Sets a segment register in the vm state
+    pub fn set_segment(&mut self, segment: SegmentRegister, value: Segment) {
+        *self.kvm_segment_mut(segment) = value.into();
+
+        match segment {
+            SegmentRegister::Fs => self.set_reg(Register::FsBase, value.base),
+            SegmentRegister::Gs => self.set_reg(Register::GsBase, value.base),
+            _ => {}
+        }
+    }
+}
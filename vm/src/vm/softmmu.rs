@@ -0,0 +1,65 @@
+//! Software MMU checking the accesses done through the `Vm` memory API
+
+use super::{Result, Vm, VmError};
+use crate::memory::MemoryError;
+
+/// Access refused by the soft-MMU
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SoftMmuFault {
+    /// Start of the access
+    pub address: u64,
+    /// Size of the access
+    pub size: usize,
+    /// Whether or not the access is a write
+    pub write: bool,
+    /// Reason of the fault, holding the faulting address
+    pub error: MemoryError,
+}
+
+impl Vm {
+    /// Enables or disables the soft-MMU. When enabled the reads and writes of
+    /// the `Vm` memory API (`Vm::read`, `Vm::write`, ...) are checked against
+    /// the page tables before any byte is copied: the whole area must be
+    /// mapped and present, and writable for the writes, as if accessed by the
+    /// guest. A refused access returns an error and is recorded (see
+    /// `Vm::soft_mmu_faults`), the guest not being involved.
+    pub fn set_soft_mmu(&mut self, enabled: bool) {
+        self.soft_mmu = enabled;
+    }
+
+    /// Returns whether or not the soft-MMU is enabled
+    #[inline]
+    pub fn soft_mmu(&self) -> bool {
+        self.soft_mmu
+    }
+
+    /// Returns the accesses refused by the soft-MMU, the oldest first
+    pub fn soft_mmu_faults(&self) -> Vec<SoftMmuFault> {
+        self.soft_mmu_faults.borrow().clone()
+    }
+
+    /// Returns and clears the accesses refused by the soft-MMU
+    pub fn take_soft_mmu_faults(&mut self) -> Vec<SoftMmuFault> {
+        self.soft_mmu_faults.take()
+    }
+
+    /// Checks an access of the memory API when the soft-MMU is enabled,
+    /// recording it if refused
+    pub(super) fn check_soft_mmu(&self, address: u64, size: usize, write: bool) -> Result<()> {
+        if !self.soft_mmu {
+            return Ok(());
+        }
+
+        self.memory
+            .check_access(address, size, write)
+            .map_err(|error| {
+                self.soft_mmu_faults.borrow_mut().push(SoftMmuFault {
+                    address,
+                    size,
+                    write,
+                    error,
+                });
+                VmError::MemoryError(error)
+            })
+    }
+}
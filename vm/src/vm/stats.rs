@@ -0,0 +1,334 @@
+//! Guest exception, dirty pages and execution statistics, nondeterminism
+//! detection
+
+use super::{Vm, VmExit};
+use crate::memory::PAGE_SIZE;
+use crate::snapshot::SnapshotMapping;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+/// Exceptions raised by the guest, accumulated across runs and resets
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExceptionStats {
+    /// Number of exceptions by vector
+    pub by_vector: BTreeMap<u64, u64>,
+    /// Number of exceptions by faulting instruction
+    pub by_rip: BTreeMap<u64, u64>,
+}
+
+impl ExceptionStats {
+    /// Records an exception
+    pub(super) fn record(&mut self, vector: u64, rip: u64) {
+        *self.by_vector.entry(vector).or_insert(0) += 1;
+        *self.by_rip.entry(rip).or_insert(0) += 1;
+    }
+
+    /// Returns the total number of exceptions
+    pub fn total(&self) -> u64 {
+        self.by_vector.values().sum()
+    }
+}
+
+impl Vm {
+    /// Returns the exceptions raised by the guest since the creation of the
+    /// vm (or the last clear)
+    pub fn exception_stats(&self) -> &ExceptionStats {
+        &self.exception_stats
+    }
+
+    /// Clears the exception statistics
+    pub fn clear_exception_stats(&mut self) {
+        self.exception_stats = ExceptionStats::default();
+    }
+}
+
+/// Runs and resets profiling counters, accumulated since the creation of the
+/// vm (or the last clear)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExecStats {
+    /// Number of calls to `Vm::run`
+    pub runs: u64,
+    /// Number of KVM_RUN exits by reason (interrupted runs included)
+    pub kvm_exits: BTreeMap<&'static str, u64>,
+    /// Time spent in `Vm::run`
+    pub run_time: Duration,
+    /// Time spent in KVM_RUN, the remaining run time being spent handling
+    /// the exits on the host
+    pub kvm_time: Duration,
+    /// Number of syscalls reported, by number
+    pub syscalls: BTreeMap<u64, u64>,
+    /// Number of resets
+    pub resets: u64,
+    /// Time spent in `Vm::reset`
+    pub reset_time: Duration,
+    /// Number of pages restored by the resets
+    pub dirty_pages: u64,
+    /// Number of pages restored by the last reset
+    pub last_dirty_pages: u64,
+}
+
+impl ExecStats {
+    /// Records a KVM_RUN exit
+    #[inline]
+    pub(super) fn record_exit(&mut self, reason: &'static str, time: Duration) {
+        *self.kvm_exits.entry(reason).or_insert(0) += 1;
+        self.kvm_time += time;
+    }
+
+    /// Records a reset having restored `pages` pages
+    #[inline]
+    pub(super) fn record_reset(&mut self, pages: u64, time: Duration) {
+        self.resets += 1;
+        self.reset_time += time;
+        self.dirty_pages += pages;
+        self.last_dirty_pages = pages;
+    }
+
+    /// Returns the total number of KVM_RUN exits
+    pub fn total_exits(&self) -> u64 {
+        self.kvm_exits.values().sum()
+    }
+
+    /// Returns the time spent in `Vm::run` outside of KVM_RUN
+    pub fn host_time(&self) -> Duration {
+        self.run_time.saturating_sub(self.kvm_time)
+    }
+
+    /// Returns the average number of pages restored by a reset
+    pub fn dirty_pages_per_reset(&self) -> f64 {
+        match self.resets {
+            0 => 0.0,
+            resets => self.dirty_pages as f64 / resets as f64,
+        }
+    }
+}
+
+impl Vm {
+    /// Returns the runs and resets counters since the creation of the vm (or
+    /// the last clear)
+    pub fn exec_stats(&self) -> &ExecStats {
+        &self.exec_stats
+    }
+
+    /// Clears the runs and resets counters
+    pub fn clear_exec_stats(&mut self) {
+        self.exec_stats = ExecStats::default();
+    }
+}
+
+/// Pages restored by the resets, accumulated across runs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DirtyStats {
+    /// Number of resets recorded
+    pub resets: u64,
+    /// Number of resets having restored each frame, by physical address
+    pub by_frame: BTreeMap<u64, u64>,
+}
+
+impl DirtyStats {
+    /// Records a frame restored by the current reset
+    #[inline]
+    pub(super) fn record(&mut self, physical_address: u64) {
+        *self.by_frame.entry(physical_address).or_insert(0) += 1;
+    }
+
+    /// Returns the fraction of the resets restoring a frame dirtied `count`
+    /// times
+    #[inline]
+    fn ratio(&self, count: u64) -> f64 {
+        match self.resets {
+            0 => 0.0,
+            resets => count as f64 / resets as f64,
+        }
+    }
+}
+
+/// Page dirtied by the guest
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HotPage {
+    /// Virtual address of the page (None for the page tables)
+    pub address: Option<u64>,
+    /// Physical address of the frame backing the page
+    pub physical_address: u64,
+    /// Number of resets having restored the page
+    pub dirty_count: u64,
+    /// Fraction of the resets having restored the page
+    pub ratio: f64,
+}
+
+/// Dirty pages of a snapshot mapping
+#[derive(Clone, Debug, PartialEq)]
+pub struct MappingDirtiness {
+    /// Starting address of the mapping
+    pub start: u64,
+    /// Ending address of the mapping (excluded)
+    pub end: u64,
+    /// File image owning the mapping
+    pub image: Option<String>,
+    /// Average fraction of the mapping pages restored by a reset
+    pub ratio: f64,
+    /// Pages of the mapping dirtied at least once, most dirtied first
+    pub hot_pages: Vec<HotPage>,
+}
+
+impl Vm {
+    /// Returns the pages restored by the resets since the creation of the vm
+    /// (or the last clear)
+    pub fn dirty_stats(&self) -> &DirtyStats {
+        &self.dirty_stats
+    }
+
+    /// Clears the dirty pages statistics
+    pub fn clear_dirty_stats(&mut self) {
+        self.dirty_stats = DirtyStats::default();
+    }
+
+    /// Returns the pages restored by the resets, most dirtied first
+    pub fn hot_pages(&self) -> Vec<HotPage> {
+        let addresses: HashMap<u64, u64> = self
+            .page_entries()
+            .map(|page| (page.physical_address, page.address))
+            .collect();
+
+        let mut pages: Vec<HotPage> = self
+            .dirty_stats
+            .by_frame
+            .iter()
+            .map(|(&physical_address, &dirty_count)| HotPage {
+                address: addresses.get(&physical_address).copied(),
+                physical_address,
+                dirty_count,
+                ratio: self.dirty_stats.ratio(dirty_count),
+            })
+            .collect();
+
+        pages.sort_by_key(|p| std::cmp::Reverse(p.dirty_count));
+        pages
+    }
+
+    /// Returns the dirty pages grouped by snapshot mapping, the most dirtied
+    /// mappings first. Mappings with a high ratio are the ones worth moving
+    /// out of the reset path to reduce its cost.
+    pub fn hot_page_report(&self, mappings: &[SnapshotMapping]) -> Vec<MappingDirtiness> {
+        let pages = self.hot_pages();
+
+        let mut report: Vec<MappingDirtiness> = mappings
+            .iter()
+            .map(|mapping| {
+                let hot_pages: Vec<HotPage> = pages
+                    .iter()
+                    .filter(|p| {
+                        p.address
+                            .is_some_and(|a| mapping.start <= a && a < mapping.end)
+                    })
+                    .copied()
+                    .collect();
+
+                let size = (mapping.end - mapping.start).div_ceil(PAGE_SIZE as u64);
+                let dirty: u64 = hot_pages.iter().map(|p| p.dirty_count).sum();
+
+                MappingDirtiness {
+                    start: mapping.start,
+                    end: mapping.end,
+                    image: mapping.image.clone(),
+                    ratio: self.dirty_stats.ratio(dirty) / size.max(1) as f64,
+                    hot_pages,
+                }
+            })
+            .collect();
+
+        report.sort_by(|a, b| b.ratio.total_cmp(&a.ratio));
+        report
+    }
+}
+
+/// Different exits observed for the same input
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Nondeterminism {
+    /// Hash of the input
+    pub input_hash: u64,
+    /// Exit of the first run of the input
+    pub expected: VmExit,
+    /// Exit of the later run
+    pub found: VmExit,
+}
+
+/// Flags the inputs whose runs do not always end with the same exit, which
+/// reveals a nondeterministic snapshot (uninitialized memory, host time...)
+#[derive(Clone, Debug, Default)]
+pub struct FlakinessDetector {
+    /// First exit seen by input hash
+    exits: HashMap<u64, VmExit>,
+    /// Nondeterministic runs found
+    flaky: Vec<Nondeterminism>,
+}
+
+impl FlakinessDetector {
+    /// Creates a new `FlakinessDetector` instance
+    pub fn new() -> Self {
+        FlakinessDetector::default()
+    }
+
+    /// Records the exit of a run of `input`. Returns the nondeterminism if an
+    /// earlier run of the same input ended differently.
+    pub fn record(&mut self, input: &[u8], exit: VmExit) -> Option<Nondeterminism> {
+        let mut hasher = DefaultHasher::new();
+        input.hash(&mut hasher);
+        let input_hash = hasher.finish();
+
+        let expected = *self.exits.entry(input_hash).or_insert(exit);
+        if expected == exit {
+            return None;
+        }
+
+        let nondeterminism = Nondeterminism {
+            input_hash,
+            expected,
+            found: exit,
+        };
+        self.flaky.push(nondeterminism);
+
+        Some(nondeterminism)
+    }
+
+    /// Returns the nondeterministic runs found so far
+    pub fn flaky(&self) -> &[Nondeterminism] {
+        &self.flaky
+    }
+
+    /// Returns the number of different inputs recorded
+    pub fn inputs(&self) -> usize {
+        self.exits.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlakinessDetector, Nondeterminism};
+    use crate::vm::VmExit;
+
+    #[test]
+    /// Flags the inputs ending with different exits
+    fn test_flakiness_detector() {
+        let mut detector = FlakinessDetector::new();
+
+        assert_eq!(detector.record(b"stable", VmExit::Hlt), None);
+        assert_eq!(detector.record(b"stable", VmExit::Hlt), None);
+        assert_eq!(detector.record(b"flaky", VmExit::Hlt), None);
+
+        let found = detector.record(b"flaky", VmExit::Exception(13));
+        assert!(matches!(
+            found,
+            Some(Nondeterminism {
+                expected: VmExit::Hlt,
+                found: VmExit::Exception(13),
+                ..
+            })
+        ));
+        assert_eq!(detector.flaky(), &[found.unwrap()]);
+        assert_eq!(detector.inputs(), 2);
+    }
+}
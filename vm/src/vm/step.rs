@@ -0,0 +1,53 @@
+//! Single step execution and instruction budgets
+
+use super::{Result, Vm, VmExit};
+
+impl Vm {
+    /// Executes a single instruction. Returns `VmExit::Breakpoint` once the
+    /// instruction is executed, or the exit it raised. The hooks of the
+    /// reached address run on the next step, along with its instruction.
+    pub fn step(&mut self) -> Result<VmExit> {
+        self.step_instruction().map(|(exit, _)| exit)
+    }
+
+    /// Executes at most `count` instructions. Returns `VmExit::Budget` when
+    /// all of them executed without any other exit.
+    pub fn run_steps(&mut self, count: u64) -> Result<VmExit> {
+        for _ in 0..count {
+            let (exit, completed) = self.step_instruction()?;
+            if !completed {
+                return Ok(exit);
+            }
+        }
+
+        Ok(VmExit::Budget)
+    }
+
+    /// Single steps the vm, returns the exit and whether or not it is the end
+    /// of the step (and not an exit raised by the instruction)
+    pub(super) fn step_instruction(&mut self) -> Result<(VmExit, bool)> {
+        self.stepping = true;
+        self.set_singlestep(true)?;
+
+        let result = self.run();
+
+        // Cleared by `complete_step` when the instruction was executed
+        let completed = !self.stepping;
+        self.stepping = false;
+
+        // Keep stepping over a hooked instruction not executed yet
+        self.set_singlestep(self.stepping_over.is_some())?;
+
+        Ok((result?, completed))
+    }
+
+    /// Ends the step requested by `Vm::step`, if any
+    pub(super) fn complete_step(&mut self) -> Option<VmExit> {
+        if !self.stepping {
+            return None;
+        }
+
+        self.stepping = false;
+        Some(VmExit::Breakpoint)
+    }
+}
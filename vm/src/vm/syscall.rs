@@ -0,0 +1,67 @@
+//! Architectural `syscall` instruction support (IA32_EFER.SCE)
+
+use super::msr::{IA32_FMASK, IA32_LSTAR, IA32_STAR};
+use super::{Result, Vm};
+
+/// Offset of the syscall stub in the hypercall page (after the exception
+/// handlers)
+pub(super) const SYSCALL_STUB_OFFSET: u64 = 32 * 32;
+
+/// System call extensions enable bit
+const IA32_EFER_SCE: u64 = 1 << 0;
+
+/// Flags cleared on syscall by default (TF, DF, IF, IOPL, NT, AC), as Linux
+/// does
+const DEFAULT_FMASK: u64 = 0x47700;
+
+impl Vm {
+    /// Enables the `syscall` instruction instead of detecting it through the
+    /// invalid opcode exception. The guest jumps to `entry` (e.g. the kernel
+    /// entry point of a snapshot), or by default to a stub stopping the
+    /// execution with `VmExit::Syscall` once the instruction executed: rcx
+    /// and r11 are clobbered and rflags masked, then rip and rflags are
+    /// restored as `sysret` would.
+    pub fn enable_syscalls(&mut self, entry: Option<u64>) -> Result<()> {
+        let stub = self.hypercall_page + SYSCALL_STUB_OFFSET;
+        self.memory.write_val(stub, 0xf4u8)?; // hlt -> our hypercall
+
+        // Syscalls keep running with the current segments selectors
+        let star = (self.special_registers.cs.selector as u64) << 32;
+
+        self.set_msr(IA32_STAR, star)?;
+        self.set_msr(IA32_LSTAR, entry.unwrap_or(stub))?;
+        self.set_msr(IA32_FMASK, DEFAULT_FMASK)?;
+
+        self.syscall_segments = Some((self.special_registers.cs, self.special_registers.ss));
+        self.special_registers.efer |= IA32_EFER_SCE;
+
+        Ok(())
+    }
+
+    /// Goes back to the invalid opcode based syscall detection
+    pub fn disable_syscalls(&mut self) {
+        self.syscall_segments = None;
+        self.special_registers.efer &= !IA32_EFER_SCE;
+    }
+
+    /// Returns from the syscall stub to the instruction following the
+    /// `syscall`. Returns false if the stub was not reached.
+    pub(super) fn syscall_return(&mut self) -> bool {
+        // The hlt of the stub was executed
+        if self.registers.rip != self.hypercall_page + SYSCALL_STUB_OFFSET + 1 {
+            return false;
+        }
+
+        let (cs, ss) = match self.syscall_segments {
+            Some(segments) => segments,
+            None => return false,
+        };
+
+        self.registers.rip = self.registers.rcx;
+        self.registers.rflags = self.registers.r11;
+        self.special_registers.cs = cs;
+        self.special_registers.ss = ss;
+
+        true
+    }
+}
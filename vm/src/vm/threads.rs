@@ -0,0 +1,131 @@
+//! Snapshot threads scheduled on the vcpu
+
+use super::{Result, Vm, VmError};
+use crate::snapshot::SnapshotRegisters;
+
+use kvm_bindings::kvm_regs;
+
+/// Saved state of a thread not running on the vcpu
+#[derive(Clone, Debug)]
+pub(super) struct ThreadContext {
+    /// General purpose registers
+    registers: kvm_regs,
+    /// fs_base register
+    fs_base: u64,
+    /// gs_base register
+    gs_base: u64,
+    /// x87/SSE/AVX state (the current one is kept when absent)
+    xsave: Option<Vec<u8>>,
+}
+
+impl ThreadContext {
+    /// Builds the context of a snapshot thread
+    pub(super) fn from_snapshot(regs: &SnapshotRegisters) -> ThreadContext {
+        ThreadContext {
+            registers: kvm_regs {
+                rax: regs.rax,
+                rbx: regs.rbx,
+                rcx: regs.rcx,
+                rdx: regs.rdx,
+                rsi: regs.rsi,
+                rdi: regs.rdi,
+                rsp: regs.rsp,
+                rbp: regs.rbp,
+                r8: regs.r8,
+                r9: regs.r9,
+                r10: regs.r10,
+                r11: regs.r11,
+                r12: regs.r12,
+                r13: regs.r13,
+                r14: regs.r14,
+                r15: regs.r15,
+                rip: regs.rip,
+                rflags: regs.rflags,
+            },
+            fs_base: regs.fs_base,
+            gs_base: regs.gs_base,
+            xsave: regs.xsave.clone(),
+        }
+    }
+}
+
+impl Vm {
+    /// Returns the identifier of the thread running on the vcpu (the
+    /// snapshot `tid`, 0 if unknown)
+    #[inline]
+    pub fn current_thread(&self) -> u64 {
+        self.current_thread
+    }
+
+    /// Returns the identifiers of the threads, in scheduling order
+    pub fn thread_ids(&self) -> Vec<u64> {
+        let mut tids: Vec<u64> = self.threads.keys().copied().collect();
+        tids.push(self.current_thread);
+        tids.sort_unstable();
+        tids
+    }
+
+    /// Adds a thread, scheduled with `Vm::switch_thread`
+    pub fn add_thread(&mut self, tid: u64, registers: &SnapshotRegisters) -> Result<()> {
+        if tid == self.current_thread || self.threads.contains_key(&tid) {
+            return Err(VmError::InvalidOperation("Thread already present"));
+        }
+
+        self.threads
+            .insert(tid, ThreadContext::from_snapshot(registers));
+
+        Ok(())
+    }
+
+    /// Switches the vcpu to another thread: the registers (fs_base included)
+    /// and the x87/SSE/AVX state of the current thread are saved and the
+    /// ones of `tid` are restored. The segments, control registers and model
+    /// specific registers are shared by the threads.
+    pub fn switch_thread(&mut self, tid: u64) -> Result<()> {
+        if tid == self.current_thread {
+            return Ok(());
+        }
+
+        let next = self
+            .threads
+            .remove(&tid)
+            .ok_or(VmError::InvalidOperation("Unknown thread"))?;
+
+        let current = ThreadContext {
+            registers: self.registers,
+            fs_base: self.fs_base,
+            gs_base: self.gs_base,
+            xsave: Some(self.xsave()?),
+        };
+        self.threads.insert(self.current_thread, current);
+
+        self.registers = next.registers;
+        self.fs_base = next.fs_base;
+        self.gs_base = next.gs_base;
+        if let Some(xsave) = next.xsave.as_deref() {
+            self.set_xsave(xsave)?;
+        }
+        self.current_thread = tid;
+
+        Ok(())
+    }
+
+    /// Switches to the next thread in round-robin order, returns its
+    /// identifier (the current one when alone)
+    pub fn schedule_next_thread(&mut self) -> Result<u64> {
+        let next = self
+            .threads
+            .range(self.current_thread..)
+            .next()
+            .or_else(|| self.threads.iter().next())
+            .map(|(&tid, _)| tid);
+
+        match next {
+            Some(tid) => {
+                self.switch_thread(tid)?;
+                Ok(tid)
+            }
+            None => Ok(self.current_thread),
+        }
+    }
+}
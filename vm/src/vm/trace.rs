@@ -0,0 +1,352 @@
+//! Instruction tracing by single stepping the guest
+
+use super::{Result, Vm, VmExit};
+
+use kvm_bindings::kvm_regs;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Opcode of the hlt instruction
+const HLT_OPCODE: u8 = 0xf4;
+
+/// General purpose registers before the execution of a traced instruction
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceRegisters {
+    /// RAX
+    pub rax: u64,
+    /// RBX
+    pub rbx: u64,
+    /// RCX
+    pub rcx: u64,
+    /// RDX
+    pub rdx: u64,
+    /// RSI
+    pub rsi: u64,
+    /// RDI
+    pub rdi: u64,
+    /// RSP
+    pub rsp: u64,
+    /// RBP
+    pub rbp: u64,
+    /// R8
+    pub r8: u64,
+    /// R9
+    pub r9: u64,
+    /// R10
+    pub r10: u64,
+    /// R11
+    pub r11: u64,
+    /// R12
+    pub r12: u64,
+    /// R13
+    pub r13: u64,
+    /// R14
+    pub r14: u64,
+    /// R15
+    pub r15: u64,
+    /// RFLAGS
+    pub rflags: u64,
+}
+
+impl From<&kvm_regs> for TraceRegisters {
+    fn from(r: &kvm_regs) -> TraceRegisters {
+        TraceRegisters {
+            rax: r.rax,
+            rbx: r.rbx,
+            rcx: r.rcx,
+            rdx: r.rdx,
+            rsi: r.rsi,
+            rdi: r.rdi,
+            rsp: r.rsp,
+            rbp: r.rbp,
+            r8: r.r8,
+            r9: r.r9,
+            r10: r.r10,
+            r11: r.r11,
+            r12: r.r12,
+            r13: r.r13,
+            r14: r.r14,
+            r15: r.r15,
+            rflags: r.rflags,
+        }
+    }
+}
+
+/// Instruction executed by the guest
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Address of the instruction
+    pub rip: u64,
+    /// Registers, if recorded (see `Tracer::registers`)
+    pub registers: Option<TraceRegisters>,
+}
+
+/// Destination of the trace records
+pub trait TraceSink {
+    /// Receives the record of an instruction about to be executed
+    fn record(&mut self, record: &TraceRecord);
+
+    /// Flushes the buffered records, when the tracing is disabled
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Closures receiving the records
+impl<F> TraceSink for F
+where
+    F: FnMut(&TraceRecord),
+{
+    fn record(&mut self, record: &TraceRecord) {
+        self(record)
+    }
+}
+
+/// Sink keeping the last records in memory. Its clones share the records, one
+/// of them being kept to read the trace once given to the tracer.
+#[derive(Clone, Debug)]
+pub struct RingBufferSink {
+    /// Maximum number of records kept
+    capacity: usize,
+    /// Last records, the oldest first
+    records: Arc<Mutex<VecDeque<TraceRecord>>>,
+}
+
+impl RingBufferSink {
+    /// Creates a sink keeping the last `capacity` records
+    pub fn new(capacity: usize) -> RingBufferSink {
+        RingBufferSink {
+            capacity,
+            records: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Returns the records kept, the oldest first
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.records.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Removes the records kept
+    pub fn clear(&self) {
+        self.records.lock().unwrap().clear();
+    }
+}
+
+impl TraceSink for RingBufferSink {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(*record);
+    }
+}
+
+/// Sink writing the records to a file, one line per instruction
+pub struct FileSink {
+    /// Buffered output file
+    out: BufWriter<File>,
+    /// First write error
+    error: Option<io::Error>,
+}
+
+impl FileSink {
+    /// Creates (or truncates) the trace file
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<FileSink> {
+        Ok(FileSink {
+            out: BufWriter::new(File::create(path)?),
+            error: None,
+        })
+    }
+}
+
+impl TraceSink for FileSink {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let mut line = format!("{:#018x}", record.rip);
+        if let Some(r) = record.registers {
+            let registers = [
+                ("rax", r.rax),
+                ("rbx", r.rbx),
+                ("rcx", r.rcx),
+                ("rdx", r.rdx),
+                ("rsi", r.rsi),
+                ("rdi", r.rdi),
+                ("rsp", r.rsp),
+                ("rbp", r.rbp),
+                ("r8", r.r8),
+                ("r9", r.r9),
+                ("r10", r.r10),
+                ("r11", r.r11),
+                ("r12", r.r12),
+                ("r13", r.r13),
+                ("r14", r.r14),
+                ("r15", r.r15),
+                ("rflags", r.rflags),
+            ];
+            for (name, value) in registers {
+                line.push_str(&format!(" {}={:#x}", name, value));
+            }
+        }
+
+        if let Err(err) = writeln!(self.out, "{}", line) {
+            self.error = Some(err);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.out.flush(),
+        }
+    }
+}
+
+/// Instruction tracer, given to `Vm::enable_tracing`
+pub struct Tracer {
+    /// Records destination
+    sink: Box<dyn TraceSink>,
+    /// Traced address ranges (everything if empty)
+    ranges: Vec<(u64, u64)>,
+    /// Whether or not the registers are recorded
+    registers: bool,
+    /// Number of records emitted
+    count: u64,
+}
+
+impl Tracer {
+    /// Creates a tracer of all the instructions, without registers
+    pub fn new<S: TraceSink + 'static>(sink: S) -> Tracer {
+        Tracer {
+            sink: Box::new(sink),
+            ranges: Vec::new(),
+            registers: false,
+            count: 0,
+        }
+    }
+
+    /// Only traces the instructions in `[start, end)`, along with the other
+    /// ranges added. The guest runs at full speed outside of them, the
+    /// instruction fetches from their pages faulting (see `Vm::watch_range`).
+    #[inline]
+    pub fn range(&mut self, start: u64, end: u64) -> &mut Self {
+        self.ranges.push((start, end));
+        self
+    }
+
+    /// Records the general purpose registers along with rip
+    #[inline]
+    pub fn registers(&mut self, enabled: bool) -> &mut Self {
+        self.registers = enabled;
+        self
+    }
+
+    /// Returns the number of records emitted
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns whether or not the instruction at `rip` is traced
+    fn traced(&self, rip: u64) -> bool {
+        self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&rip))
+    }
+
+    /// Emits the record of the instruction at rip if it is traced
+    fn trace(&mut self, registers: &kvm_regs) {
+        let rip = registers.rip;
+        if !self.traced(rip) {
+            return;
+        }
+
+        let record = TraceRecord {
+            rip,
+            registers: self.registers.then(|| TraceRegisters::from(registers)),
+        };
+        self.sink.record(&record);
+        self.count += 1;
+    }
+}
+
+impl Vm {
+    /// Enables the instruction tracing: `Vm::run` then single steps the traced
+    /// instructions, each of them being given to the tracer before its
+    /// execution. The tracer is not available to the hooks during the run.
+    pub fn enable_tracing(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Disables the instruction tracing, flushing the sink. Returns the
+    /// tracer, if any.
+    pub fn disable_tracing(&mut self) -> Result<Option<Tracer>> {
+        let mut tracer = match self.tracer.take() {
+            Some(tracer) => tracer,
+            None => return Ok(None),
+        };
+
+        tracer.sink.flush()?;
+        Ok(Some(tracer))
+    }
+
+    /// Returns the enabled tracer
+    #[inline]
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    /// Runs the vm one instruction at a time inside the traced ranges,
+    /// tracing each of them, and freely outside of them
+    pub(super) fn run_traced(&mut self) -> Result<VmExit> {
+        let mut tracer = match self.tracer.take() {
+            Some(tracer) => tracer,
+            None => return self.run(),
+        };
+
+        let result = loop {
+            // The vm runs freely until it executes a traced instruction
+            if !tracer.traced(self.registers.rip) {
+                match self.run_until_fetch(&tracer.ranges) {
+                    Ok(None) => {}
+                    Ok(Some(exit)) => break Ok(exit),
+                    Err(err) => break Err(err),
+                }
+            }
+
+            tracer.trace(&self.registers);
+
+            // A single stepped hlt is reported as a completed step, the vcpu
+            // staying halted, it is run instead
+            let mut opcode = [0u8];
+            let halt = self.memory.read(self.registers.rip, &mut opcode).is_ok()
+                && opcode[0] == HLT_OPCODE;
+            if halt {
+                break self.run();
+            }
+
+            match self.step_instruction() {
+                Ok((_, true)) => continue,
+                Ok((exit, false)) => break Ok(exit),
+                Err(err) => break Err(err),
+            }
+        };
+
+        // Unless replaced by a hook
+        self.tracer.get_or_insert(tracer);
+        result
+    }
+}
@@ -0,0 +1,138 @@
+//! Interception of the vDSO functions, which read the time without any
+//! syscall
+
+use super::{HookResult, Register, Result, Vm};
+use crate::symbols::SymbolsError;
+
+use std::time::Duration;
+
+/// Name of the vDSO module in the snapshots
+const VDSO_MODULE: &str = "[vdso]";
+
+/// Error returned for an invalid pointer argument
+const EFAULT: u64 = (-14i64) as u64;
+
+/// Function exported by the vDSO
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VdsoFunction {
+    /// `clock_gettime(clockid, timespec)`
+    ClockGettime,
+    /// `gettimeofday(timeval, timezone)`
+    Gettimeofday,
+    /// `time(tloc)`
+    Time,
+    /// `getcpu(cpu, node, cache)`
+    Getcpu,
+}
+
+impl VdsoFunction {
+    /// Every function exported by the vDSO
+    const ALL: [VdsoFunction; 4] = [
+        VdsoFunction::ClockGettime,
+        VdsoFunction::Gettimeofday,
+        VdsoFunction::Time,
+        VdsoFunction::Getcpu,
+    ];
+
+    /// Returns the names of the function symbol (the second one being a weak
+    /// alias)
+    fn symbols(self) -> [&'static str; 2] {
+        match self {
+            VdsoFunction::ClockGettime => ["__vdso_clock_gettime", "clock_gettime"],
+            VdsoFunction::Gettimeofday => ["__vdso_gettimeofday", "gettimeofday"],
+            VdsoFunction::Time => ["__vdso_time", "time"],
+            VdsoFunction::Getcpu => ["__vdso_getcpu", "getcpu"],
+        }
+    }
+}
+
+impl Vm {
+    /// Loads the symbols of the vDSO from its image in the guest memory,
+    /// returns the number of symbols loaded
+    pub fn load_vdso_symbols(&mut self) -> Result<usize> {
+        let (start, end) = self
+            .symbols
+            .module(VDSO_MODULE)
+            .map(|m| (m.start, m.end))
+            .ok_or_else(|| SymbolsError::UnknownModule(VDSO_MODULE.to_string()))?;
+
+        let mut image = vec![0u8; (end - start) as usize];
+        self.read(start, &mut image)?;
+
+        Ok(self.symbols.load_elf_data(VDSO_MODULE, &image)?)
+    }
+
+    /// Returns the vDSO functions found in the symbols and their address
+    pub fn vdso_functions(&self) -> Vec<(VdsoFunction, u64)> {
+        let module = match self.symbols.module(VDSO_MODULE) {
+            Some(module) => module,
+            None => return Vec::new(),
+        };
+
+        VdsoFunction::ALL
+            .iter()
+            .filter_map(|&function| {
+                function
+                    .symbols()
+                    .iter()
+                    .find_map(|name| module.symbol(name))
+                    .map(|address| (function, address))
+            })
+            .collect()
+    }
+
+    /// Hooks the vDSO functions (loading its symbols if needed) to return a
+    /// fixed time since the epoch and cpu 0, as the guest calls them without
+    /// reaching the syscall emulation. Returns the functions hooked.
+    pub fn intercept_vdso(&mut self, time: Duration) -> Result<Vec<(VdsoFunction, u64)>> {
+        if self.vdso_functions().is_empty() {
+            self.load_vdso_symbols()?;
+        }
+
+        let functions = self.vdso_functions();
+        for &(function, address) in functions.iter() {
+            self.hook(address, move |vm| vm.emulate_vdso_call(function, time))?;
+        }
+
+        Ok(functions)
+    }
+
+    /// Emulates a call to a vDSO function and returns to the caller
+    fn emulate_vdso_call(&mut self, function: VdsoFunction, time: Duration) -> HookResult {
+        let (arg0, arg1) = (self.get_reg(Register::Rdi), self.get_reg(Register::Rsi));
+        let seconds = time.as_secs();
+
+        let result = match function {
+            VdsoFunction::ClockGettime => {
+                self.write_nonnull(arg1, [seconds, time.subsec_nanos() as u64])
+            }
+            VdsoFunction::Gettimeofday => self
+                .write_nonnull(arg0, [seconds, time.subsec_micros() as u64])
+                .and_then(|_| self.write_nonnull(arg1, [0u32; 2])),
+            VdsoFunction::Time => self.write_nonnull(arg0, seconds).map(|_| seconds),
+            VdsoFunction::Getcpu => self
+                .write_nonnull(arg0, 0u32)
+                .and_then(|_| self.write_nonnull(arg1, 0u32)),
+        };
+
+        // Return to the caller
+        let site = match self.read_value_checked::<u64>(self.get_reg(Register::Rsp)) {
+            Ok(site) => site,
+            Err(_) => return HookResult::Crash,
+        };
+        self.set_reg(Register::Rax, result.unwrap_or(EFAULT));
+        self.set_reg(Register::Rip, site);
+        self.set_reg(Register::Rsp, self.get_reg(Register::Rsp) + 8);
+
+        HookResult::Redirect
+    }
+
+    /// Writes an output argument of a vDSO function, if given
+    fn write_nonnull<T: Copy>(&mut self, address: u64, val: T) -> Result<u64> {
+        if address != 0 {
+            self.write_value_checked(address, val)?;
+        }
+
+        Ok(0)
+    }
+}
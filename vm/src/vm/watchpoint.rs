@@ -0,0 +1,185 @@
+//! Hardware breakpoints and data watchpoints (debug registers DR0-DR7)
+
+use super::hooks::DEBUG_VECTOR;
+use super::{Result, Vm, VmError, VmExit};
+
+use kvm_bindings::kvm_guest_debug_arch;
+
+/// Number of debug address registers
+const HW_BREAKPOINTS_COUNT: usize = 4;
+/// DR6 bit set by a single step
+const DR6_BS: u64 = 1 << 14;
+
+/// Access caught by a hardware breakpoint
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchAccess {
+    /// Instruction fetch, reported before the instruction executes
+    Execute,
+    /// Data write, reported after the instruction executed
+    Write,
+    /// Data read or write, reported after the instruction executed
+    ReadWrite,
+}
+
+/// Hardware breakpoint condition, the data watchpoints covering 1, 2, 4 or 8
+/// bytes aligned on their size
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HwBreakpointKind {
+    /// Execution of the instruction at the address
+    Execute,
+    /// Write of the given number of bytes
+    Write(usize),
+    /// Read or write of the given number of bytes
+    ReadWrite(usize),
+}
+
+impl HwBreakpointKind {
+    /// Returns the access caught
+    #[inline]
+    pub fn access(&self) -> WatchAccess {
+        match self {
+            HwBreakpointKind::Execute => WatchAccess::Execute,
+            HwBreakpointKind::Write(_) => WatchAccess::Write,
+            HwBreakpointKind::ReadWrite(_) => WatchAccess::ReadWrite,
+        }
+    }
+
+    /// Returns the DR7 condition and length fields
+    fn dr7_fields(&self) -> Option<(u64, u64)> {
+        let length = |size: usize| match size {
+            1 => Some(0b00),
+            2 => Some(0b01),
+            4 => Some(0b11),
+            8 => Some(0b10),
+            _ => None,
+        };
+
+        match *self {
+            HwBreakpointKind::Execute => Some((0b00, 0b00)),
+            HwBreakpointKind::Write(size) => Some((0b01, length(size)?)),
+            HwBreakpointKind::ReadWrite(size) => Some((0b11, length(size)?)),
+        }
+    }
+
+    /// Returns the size watched (1 for the instruction breakpoints)
+    fn size(&self) -> u64 {
+        match *self {
+            HwBreakpointKind::Execute => 1,
+            HwBreakpointKind::Write(size) | HwBreakpointKind::ReadWrite(size) => size as u64,
+        }
+    }
+}
+
+/// Debug registers state
+pub(super) type HwBreakpoints = [Option<(u64, HwBreakpointKind)>; HW_BREAKPOINTS_COUNT];
+
+impl Vm {
+    /// Sets a hardware breakpoint, one of the 4 debug registers being used.
+    /// Unlike the software breakpoints, the data watchpoints catch the
+    /// accesses to a variable, reported with `VmExit::Watchpoint` once the
+    /// instruction executed. Replaces the breakpoint already set at `address`.
+    pub fn set_hw_breakpoint(&mut self, address: u64, kind: HwBreakpointKind) -> Result<()> {
+        match kind.dr7_fields() {
+            Some(_) if address.is_multiple_of(kind.size()) => {}
+            _ => return Err(VmError::InvalidOperation("Invalid hardware breakpoint")),
+        }
+
+        let slot = self
+            .hw_breakpoints
+            .iter()
+            .position(|bp| bp.is_some_and(|(addr, _)| addr == address))
+            .or_else(|| self.hw_breakpoints.iter().position(Option::is_none))
+            .ok_or(VmError::InvalidOperation("No debug register available"))?;
+
+        self.hw_breakpoints[slot] = Some((address, kind));
+        self.update_guest_debug()
+    }
+
+    /// Removes the hardware breakpoint set at `address`
+    pub fn remove_hw_breakpoint(&mut self, address: u64) -> Result<()> {
+        for bp in self.hw_breakpoints.iter_mut() {
+            if bp.is_some_and(|(addr, _)| addr == address) {
+                *bp = None;
+            }
+        }
+
+        self.update_guest_debug()
+    }
+
+    /// Returns the hardware breakpoints set
+    pub fn hw_breakpoints(&self) -> impl Iterator<Item = (u64, HwBreakpointKind)> + '_ {
+        self.hw_breakpoints.iter().flatten().copied()
+    }
+
+    /// Returns the debug registers given to KVM (KVM_GUESTDBG_USE_HW_BP is
+    /// needed if any of them is enabled)
+    pub(super) fn debug_registers(&self) -> Option<kvm_guest_debug_arch> {
+        let mut arch = kvm_guest_debug_arch::default();
+
+        for (index, bp) in self.hw_breakpoints.iter().enumerate() {
+            // The instruction breakpoint being stepped over is disarmed
+            if let Some((address, kind)) = bp.filter(|&bp| Some(bp.0) != self.hw_step_over) {
+                let (condition, length) = kind.dr7_fields()?;
+                arch.debugreg[index] = address;
+                arch.debugreg[7] |= 1 << (index * 2);
+                arch.debugreg[7] |= (condition | length << 2) << (16 + index * 4);
+            }
+        }
+
+        (arch.debugreg[7] != 0).then_some(arch)
+    }
+
+    /// Handles a debug exception raised by the debug registers, `dr6` holding
+    /// its status. Returns `None` if it is not theirs, otherwise the exit to
+    /// report or `Some(None)` to resume.
+    pub(super) fn handle_hw_breakpoint(&mut self, dr6: u64) -> Result<Option<Option<VmExit>>> {
+        let single_step = dr6 & DR6_BS != 0;
+
+        // The instruction reported by an instruction breakpoint executed, the
+        // breakpoint is armed again
+        let stepped_over = single_step && self.hw_step_over.take().is_some();
+        if stepped_over {
+            self.update_guest_debug()?;
+        }
+
+        let hit = (0..HW_BREAKPOINTS_COUNT)
+            .filter(|index| dr6 & (1 << index) != 0)
+            .find_map(|index| self.hw_breakpoints[index]);
+
+        let (address, kind) = match hit {
+            Some(hit) => hit,
+            // Unless `Vm::step` or a hooked instruction was single stepped too
+            None if stepped_over
+                && !self.stepping
+                && self.stepping_over.is_none()
+                && !self.access_watch.stepping() =>
+            {
+                return Ok(Some(None))
+            }
+            None => return Ok(None),
+        };
+
+        // The single step over a hooked instruction completed as well. An
+        // exit raised by the step (e.g. a CFI violation) is reported first
+        // and the breakpoint fires again when resuming, the plain end of the
+        // step is replaced by the watchpoint
+        if single_step && (self.stepping || self.stepping_over.is_some()) {
+            match self.handle_debug_exit(DEBUG_VECTOR)? {
+                None | Some(VmExit::Breakpoint) => {}
+                exit => return Ok(Some(exit)),
+            }
+        }
+
+        // The instruction breakpoints fault before the execution, the
+        // instruction is single stepped without them when resuming
+        if kind == HwBreakpointKind::Execute {
+            self.hw_step_over = Some(address);
+            self.update_guest_debug()?;
+        }
+
+        Ok(Some(Some(VmExit::Watchpoint {
+            address,
+            access: kind.access(),
+        })))
+    }
+}
@@ -0,0 +1,150 @@
+//! x87/SSE/AVX state management through the XSAVE area
+
+use super::{HvError, Result, Vm, VmError};
+use crate::bits::LeBytes;
+
+use kvm_bindings::kvm_xsave;
+
+/// Offset of the XMM registers in the legacy region
+const XMM_OFFSET: usize = 160;
+/// Offset of the upper halves of the YMM registers (AVX state component)
+const YMM_HI_OFFSET: usize = 576;
+/// Offset of XSTATE_BV in the XSAVE header
+const XSTATE_BV_OFFSET: usize = 512;
+
+/// SSE state component bit
+const XSTATE_SSE: u64 = 1 << 1;
+/// AVX state component bit
+const XSTATE_AVX: u64 = 1 << 2;
+
+/// Number of vector registers available in 64 bits mode
+const VECTOR_REGISTERS_COUNT: usize = 16;
+
+impl Vm {
+    /// Returns the raw standard format XSAVE area of the vm
+    pub fn xsave(&self) -> Result<Vec<u8>> {
+        let xsave = self
+            .kvm_vcpu
+            .get_xsave()
+            .map_err(|err| HvError::os("Could not get xsave state", err.errno()))?;
+
+        Ok(xsave.region.iter().flat_map(|v| v.to_le_bytes()).collect())
+    }
+
+    /// Restores the x87/SSE/AVX state from a raw standard format XSAVE area
+    pub fn set_xsave(&mut self, data: &[u8]) -> Result<()> {
+        let mut xsave = kvm_xsave::default();
+
+        for (entry, chunk) in xsave.region.iter_mut().zip(data.chunks_exact(4)) {
+            *entry = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+
+        self.kvm_vcpu
+            .set_xsave(&xsave)
+            .map_err(|err| HvError::os("Could not set xsave state", err.errno()).into())
+    }
+
+    /// Gets a XMM register from the vm state
+    pub fn get_xmm(&self, index: usize) -> Result<u128> {
+        check_index(index, "Invalid XMM register")?;
+
+        let xsave = self.xsave()?;
+
+        // Components in their initial state are not saved
+        if xsave.u64_at(XSTATE_BV_OFFSET) & XSTATE_SSE == 0 {
+            return Ok(0);
+        }
+
+        Ok(read_u128(&xsave, XMM_OFFSET + index * 16))
+    }
+
+    /// Sets a XMM register in the vm state
+    pub fn set_xmm(&mut self, index: usize, value: u128) -> Result<()> {
+        check_index(index, "Invalid XMM register")?;
+
+        let mut xsave = self.xsave()?;
+        mark_valid(
+            &mut xsave,
+            XSTATE_SSE,
+            XMM_OFFSET,
+            VECTOR_REGISTERS_COUNT * 16,
+        );
+        write_u128(&mut xsave, XMM_OFFSET + index * 16, value);
+
+        self.set_xsave(&xsave)
+    }
+
+    /// Gets a YMM register from the vm state, as its (low, high) halves
+    pub fn get_ymm(&self, index: usize) -> Result<(u128, u128)> {
+        check_index(index, "Invalid YMM register")?;
+
+        let xsave = self.xsave()?;
+        let xstate_bv = xsave.u64_at(XSTATE_BV_OFFSET);
+
+        let low = match xstate_bv & XSTATE_SSE {
+            0 => 0,
+            _ => read_u128(&xsave, XMM_OFFSET + index * 16),
+        };
+        let high = match xstate_bv & XSTATE_AVX {
+            0 => 0,
+            _ => read_u128(&xsave, YMM_HI_OFFSET + index * 16),
+        };
+
+        Ok((low, high))
+    }
+
+    /// Sets a YMM register in the vm state from its (low, high) halves
+    pub fn set_ymm(&mut self, index: usize, value: (u128, u128)) -> Result<()> {
+        check_index(index, "Invalid YMM register")?;
+
+        let mut xsave = self.xsave()?;
+        mark_valid(
+            &mut xsave,
+            XSTATE_SSE,
+            XMM_OFFSET,
+            VECTOR_REGISTERS_COUNT * 16,
+        );
+        mark_valid(
+            &mut xsave,
+            XSTATE_AVX,
+            YMM_HI_OFFSET,
+            VECTOR_REGISTERS_COUNT * 16,
+        );
+        write_u128(&mut xsave, XMM_OFFSET + index * 16, value.0);
+        write_u128(&mut xsave, YMM_HI_OFFSET + index * 16, value.1);
+
+        self.set_xsave(&xsave)
+    }
+}
+
+/// Checks the index of a vector register, `reason` being the error returned
+/// when it does not exist
+fn check_index(index: usize, reason: &'static str) -> Result<()> {
+    if index >= VECTOR_REGISTERS_COUNT {
+        return Err(VmError::InvalidOperation(reason));
+    }
+
+    Ok(())
+}
+
+/// Read a little endian 128 bits value
+fn read_u128(data: &[u8], offset: usize) -> u128 {
+    (data.u64_at(offset) as u128) | ((data.u64_at(offset + 8) as u128) << 64)
+}
+
+/// Write a little endian 128 bits value
+fn write_u128(data: &mut [u8], offset: usize, value: u128) {
+    data[offset..offset + 16].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Mark a state component as valid in XSTATE_BV, zeroing its stale content if
+/// it was in its initial state
+fn mark_valid(xsave: &mut [u8], component: u64, offset: usize, size: usize) {
+    let xstate_bv = xsave.u64_at(XSTATE_BV_OFFSET);
+
+    if xstate_bv & component == 0 {
+        xsave[offset..offset + size].fill(0);
+        xsave[XSTATE_BV_OFFSET..XSTATE_BV_OFFSET + 8]
+            .copy_from_slice(&(xstate_bv | component).to_le_bytes());
+    }
+}
@@ -265,3 +265,20 @@ impl From<u64> for ExceptionType {
         }
     }
 }
+
+impl ExceptionType {
+    /// Returns whether or not the exception pushes an error code
+    pub fn has_error_code(self) -> bool {
+        matches!(
+            self,
+            ExceptionType::DoubleFault
+                | ExceptionType::InvalidTSS
+                | ExceptionType::SegmentNotPresent
+                | ExceptionType::StackFault
+                | ExceptionType::GeneralProtection
+                | ExceptionType::PageFault
+                | ExceptionType::AlignmentCheck
+                | ExceptionType::ControlProtection
+        )
+    }
+}